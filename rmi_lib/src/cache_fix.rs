@@ -1,6 +1,7 @@
 use log::*;
 use crate::models::TrainingKey;
 use crate::RMITrainingData;
+use crate::error::RmiError;
 
 #[derive(Debug)]
 pub struct Spline {
@@ -103,9 +104,13 @@ impl SplineFit {
     }
 }
 
-pub fn cache_fix(data: &RMITrainingData<u64>, line_size: usize) -> Vec<(u64, usize)> {
-    assert!(data.len() > line_size,
-            "Cannot apply a cachefix with fewer items than the line size");
+pub fn cache_fix(data: &RMITrainingData<u64>, line_size: usize)
+                 -> Result<Vec<(u64, usize)>, RmiError> {
+    // a cachefix needs more items than the line size
+    if data.len() <= line_size {
+        return Err(RmiError::NotEnoughData { required: line_size + 1,
+                                             available: data.len() });
+    }
     info!("Fitting cachefix spline to {} datapoints", data.len());
     
     let mut fit = SplineFit::new(line_size);
@@ -146,5 +151,5 @@ pub fn cache_fix(data: &RMITrainingData<u64>, line_size: usize) -> Vec<(u64, usi
           spline.len(), data.len());
 
     
-    return spline;
+    return Ok(spline);
 }
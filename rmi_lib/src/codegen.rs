@@ -15,6 +15,7 @@ use std::collections::HashSet;
 use std::io::Write;
 use std::str;
 use crate::train::TrainedRMI;
+use crate::error::RmiError;
 use std::fs::File;
 use std::io::BufWriter;
 use std::path::Path;
@@ -275,9 +276,15 @@ impl LayerParams {
                                        array_name!(idx),
                                        model_index, bytes_per_model,
                                        offset);
-                                       
-                write!(target, "*(({new_type}*) ({ptr_expr}))",
-                       new_type=c_type, ptr_expr=ptr_expr)?;
+
+                // array parameters are passed as pointers into the block
+                if params[parameter_index].is_array() {
+                    write!(target, "(({new_type}*) ({ptr_expr}))",
+                           new_type=c_type, ptr_expr=ptr_expr)?;
+                } else {
+                    write!(target, "*(({new_type}*) ({ptr_expr}))",
+                           new_type=c_type, ptr_expr=ptr_expr)?;
+                }
                 
             }
         };
@@ -285,7 +292,7 @@ impl LayerParams {
         return Result::Ok(());
     }
 
-    fn with_zipped_errors(&self, lle: &[u64]) -> LayerParams {
+    fn with_zipped_errors(&self, lle: &[[u64; 2]]) -> LayerParams {
         
         let params = self.params();
         // integrate the errors into the model parameters of the last
@@ -296,20 +303,18 @@ impl LayerParams {
         let combined_lle_params: Vec<ModelParam> =
             params.chunks(self.params_per_model())
             .zip(lle)
-            .flat_map(|(mod_params, err)| {
+            .flat_map(|(mod_params, [below, above])| {
                 let mut to_r: Vec<ModelParam> = Vec::new();
                 to_r.extend_from_slice(mod_params);
-                to_r.push(ModelParam::Int(*err));
+                to_r.push(ModelParam::Int(*below));
+                to_r.push(ModelParam::Int(*above));
                 to_r
             }).collect();
 
-        let is_constant = if let LayerParams::Constant(_, _) = self {
-            true
-        } else {
-            false
-        };
+        // the errors are looked up by model index, so keep array access
+        let array_access = !matches!(self, LayerParams::Constant(_, _));
         
-        return LayerParams::new(self.index(), is_constant, self.params_per_model() + 1,
+        return LayerParams::new(self.index(), array_access, self.params_per_model() + 2,
                                 combined_lle_params);
                                 
     }
@@ -332,6 +337,30 @@ impl fmt::Display for LayerParams {
     }
 }
 
+// An array shared by the models on a layer (see `Model::layer_arrays`),
+// passed to each model after its parameters.
+struct LayerArray {
+    layer: usize,
+    idx: usize,
+    values: ModelParam
+}
+
+impl LayerArray {
+    fn for_layer(layer: usize, models: &[Box<dyn Model>]) -> Vec<LayerArray> {
+        return layer_arrays(models).into_iter().enumerate()
+            .map(|(idx, values)| LayerArray { layer, idx, values: values.into() })
+            .collect();
+    }
+
+    fn name(&self) -> String {
+        return format!("L{}_ARRAY{}", self.layer, self.idx);
+    }
+
+    fn requires_malloc(&self) -> bool {
+        return self.values.size() >= 4 * 1024;
+    }
+}
+
 fn params_for_layer(layer_idx: usize,
                     models: &[Box<dyn Model>])
                     -> LayerParams {
@@ -372,6 +401,17 @@ macro_rules! model_index_from_output {
     };
 }
 
+// Converts the lookup key to a model's input type. Integer models see
+// signed keys with their sign bit flipped, matching `ModelInput::as_int`.
+fn key_as(key_type: KeyType, required: &ModelDataType) -> String {
+    if key_type.is_signed() {
+        if let ModelDataType::Int = required {
+            return String::from("(uint64_t)key ^ 0x8000000000000000UL");
+        }
+    }
+    return format!("({})key", required.c_type());
+}
+
 pub fn rmi_size(rmi: &TrainedRMI) -> u64 {
     // compute the RMI size (used in the header, compute here before consuming)
     let mut num_total_bytes = 0;
@@ -380,10 +420,11 @@ pub fn rmi_size(rmi: &TrainedRMI) -> u64 {
         
         // assume all models on this layer have the same size
         num_total_bytes += model_on_this_layer_size * layer.len();
+        num_total_bytes += layer_arrays(layer).iter().map(|a| a.len() * 8).sum::<usize>();
     }
 
-    if !rmi.last_layer_max_l1s.is_empty() {
-        num_total_bytes += rmi.rmi.last().unwrap().len() * 8;
+    if !rmi.last_layer_bounds.is_empty() {
+        num_total_bytes += rmi.rmi.last().unwrap().len() * 16;
     }
 
     if rmi.cache_fix.is_some() {
@@ -447,6 +488,58 @@ uint64_t lookup(uint64_t key, size_t* err) {{
     return Ok(());
 }
 
+const STRING_RANGE_SIG: &str = "uint64_t lookup_range(const char* key, size_t len, size_t* lo, size_t* hi)";
+
+// Emits the string key encoding (see `StringKey::encode`) and a `lookup`
+// function (plus `lookup_range`, if errors are reported) that passes the
+// encoded key to the numeric RMI.
+fn generate_string_key_code<T: Write>(
+    target: &mut T,
+    rmi: &TrainedRMI,
+    lookup_sig: &str,
+    report_errors: bool) -> Result<(), std::io::Error> {
+
+    let prefix: String = rmi.key_prefix.iter()
+        .map(|b| format!("\\x{:02x}", b))
+        .collect();
+    let call = if report_errors {
+        "_rmi_lookup_encoded(encode_key(key, len), err)"
+    } else {
+        "_rmi_lookup_encoded(encode_key(key, len))"
+    };
+
+    writeln!(target,
+             "
+const char KEY_PREFIX[] = \"{}\";
+const size_t KEY_PREFIX_LEN = {};
+
+inline unsigned __int128 encode_key(const char* key, size_t len) {{
+  size_t shared = (len < KEY_PREFIX_LEN ? len : KEY_PREFIX_LEN);
+  int cmp = memcmp(key, KEY_PREFIX, shared);
+  if (cmp < 0 || (cmp == 0 && len < KEY_PREFIX_LEN)) return 0;
+  if (cmp > 0) return ~(unsigned __int128)0;
+
+  unsigned __int128 encoded = 0;
+  for (size_t i = KEY_PREFIX_LEN; i < KEY_PREFIX_LEN + 16; i++) {{
+    encoded = (encoded << 8) | (i < len ? (unsigned char)key[i] : 0);
+  }}
+  return encoded;
+}}
+
+{} {{
+  return {};
+}}", prefix, rmi.key_prefix.len(), lookup_sig, call)?;
+
+    if report_errors {
+        writeln!(target, "
+{} {{
+  return _rmi_lookup_range_encoded(encode_key(key, len), lo, hi);
+}}", STRING_RANGE_SIG)?;
+    }
+
+    return Ok(());
+}
+
 fn generate_code<T: Write>(
     code_output: &mut T,
     data_output: &mut T,
@@ -463,16 +556,20 @@ fn generate_code<T: Write>(
         .map(|(layer_idx, models)| params_for_layer(layer_idx, models))
         .collect();
     
-    let report_last_layer_errors = !rmi.last_layer_max_l1s.is_empty();
+    let report_last_layer_errors = !rmi.last_layer_bounds.is_empty();
 
     let mut report_lle: Vec<u8> = Vec::new();
     if report_last_layer_errors {
-        let lle = &rmi.last_layer_max_l1s;
+        let lle = &rmi.last_layer_bounds;
         if lle.len() > 1 {
             let old_last = layer_params.pop().unwrap();
             let new_last = old_last.with_zipped_errors(lle);
             
-            write!(report_lle, "  *err = ")?;
+            write!(report_lle, "  *below = ")?;
+            new_last.access_by_ref(&mut report_lle, "modelIndex",
+                                   new_last.params_per_model() - 2)?;
+            writeln!(report_lle, ";")?;
+            write!(report_lle, "  *above = ")?;
             new_last.access_by_ref(&mut report_lle, "modelIndex",
                                    new_last.params_per_model() - 1)?;
             writeln!(report_lle, ";")?;
@@ -480,7 +577,7 @@ fn generate_code<T: Write>(
             layer_params.push(new_last);
             
         } else {
-            write!(report_lle, "  *err = {};", lle[0])?;
+            write!(report_lle, "  *below = {};\n  *above = {};", lle[0][0], lle[0][1])?;
         }
     }
 
@@ -514,8 +611,7 @@ fn generate_code<T: Write>(
             LayerParams::MixedArray(idx, _, _) => {
                 let data_path = Path::new(&data_dir)
                     .join(format!("{}_{}", namespace, array_name!(idx)));
-                let f = File::create(data_path)
-                    .expect("Could not write data file to RMI directory");
+                let f = File::create(data_path)?;
                 let mut bw = BufWriter::new(f);
                 
                 lp.write_to(&mut bw)?; // write to data file
@@ -538,6 +634,33 @@ fn generate_code<T: Write>(
             }
         }
     }
+
+    let layer_arrays: Vec<Vec<LayerArray>> = rmi.rmi.iter().enumerate()
+        .map(|(layer_idx, models)| LayerArray::for_layer(layer_idx, models))
+        .collect();
+    for array in layer_arrays.iter().flatten() {
+        if !array.requires_malloc() {
+            writeln!(data_output, "const {} {}{} = {};", array.values.c_type(),
+                     array.name(), array.values.c_type_mod(), array.values.c_val())?;
+            continue;
+        }
+
+        let data_path = Path::new(&data_dir).join(format!("{}_{}", namespace, array.name()));
+        let mut bw = BufWriter::new(File::create(data_path)?);
+        array.values.write_to(&mut bw)?;
+        writeln!(data_output, "{}* {};", array.values.c_type(), array.name())?;
+
+        read_code.push("  {".to_string());
+        read_code.push(format!("    std::ifstream infile(std::filesystem::path(dataPath) / \"{}_{}\", std::ios::in | std::ios::binary);",
+                               namespace, array.name()));
+        read_code.push("    if (!infile.good()) return false;".to_string());
+        read_code.push(format!("    {} = ({}*) malloc({});",
+                               array.name(), array.values.c_type(), array.values.size()));
+        read_code.push(format!("    if ({} == NULL) return false;", array.name()));
+        read_code.push(format!("    infile.read((char*){}, {});", array.name(), array.values.size()));
+        read_code.push("    if (!infile.good()) return false;".to_string());
+        read_code.push("  }".to_string());
+    }
     read_code.push("  return true;".to_string());
     read_code.push("}".to_string());
 
@@ -554,6 +677,11 @@ fn generate_code<T: Write>(
         }
         panic!();
     }
+    for array in layer_arrays.iter().flatten() {
+        if array.requires_malloc() {
+            free_code.push(format!("    free({});", array.name()));
+        }
+    }
     
     free_code.push("}".to_string());
 
@@ -580,6 +708,9 @@ fn generate_code<T: Write>(
     if rmi.cache_fix.is_some() {
         writeln!(code_output, "#include <algorithm>")?;
     }
+    if key_type == KeyType::Str {
+        writeln!(code_output, "#include <cstring>")?;
+    }
 
     writeln!(code_output, "namespace {} {{", namespace)?;
 
@@ -600,9 +731,20 @@ fn generate_code<T: Write>(
     }
 
     // next, the model sigs
-    sigs = HashSet::new();
+    let mut sigs = Vec::new();
     for layer in rmi.rmi.iter() {
-        sigs.insert(layer[0].code());
+        // wrapped models are defined before the models that call them
+        let mut model = Some(layer[0].as_ref());
+        let mut chain = Vec::new();
+        while let Some(m) = model {
+            chain.push(m.code());
+            model = m.wrapped_model();
+        }
+        for code in chain.into_iter().rev() {
+            if !sigs.contains(&code) {
+                sigs.push(code);
+            }
+        }
     }
 
     for sig in sigs {
@@ -618,10 +760,12 @@ inline size_t FCLAMP(double inp, double bound) {{
 }}\n"
     )?;
 
-    let rmi_lookup_name = if rmi.cache_fix.is_none() {
-        "lookup"
-    } else {
+    let rmi_lookup_name = if rmi.cache_fix.is_some() {
         "_rmi_lookup_pre_cachefix"
+    } else if key_type == KeyType::Str {
+        "_rmi_lookup_encoded"
+    } else {
+        "lookup"
     };
     
     let lookup_sig = if report_last_layer_errors {
@@ -629,7 +773,18 @@ inline size_t FCLAMP(double inp, double bound) {{
     } else {
         format!("uint64_t {}({} key)", rmi_lookup_name, key_type.c_type())
     };
-    writeln!(code_output, "{} {{", lookup_sig)?;
+    let range_sig = format!("uint64_t {}({} key, size_t* lo, size_t* hi)",
+                            if key_type == KeyType::Str { "_rmi_lookup_range_encoded" } else { "lookup_range" },
+                            key_type.c_type());
+
+    // with errors, the RMI itself reports how far below and above its
+    // prediction the key can be, and `lookup` and `lookup_range` wrap it
+    if report_last_layer_errors {
+        writeln!(code_output, "inline uint64_t _rmi_lookup_bounds({} key, size_t* below, size_t* above) {{",
+                 key_type.c_type())?;
+    } else {
+        writeln!(code_output, "{} {{", lookup_sig)?;
+    }
 
     let mut needed_vars = HashSet::new();
     if rmi.rmi.len() > 1 {
@@ -702,7 +857,10 @@ inline size_t FCLAMP(double inp, double bound) {{
                 write!(code_output, ", ")?;
             }
         }
-        writeln!(code_output, "({})key);", required_type.c_type())?;
+        for array in layer_arrays[layer_idx].iter() {
+            write!(code_output, "{}, ", array.name())?;
+        }
+        writeln!(code_output, "{});", key_as(key_type, &required_type))?;
 
         last_model_output = layer[0].output_type();
         needs_bounds_check = layer[0].needs_bounds_check();
@@ -717,9 +875,41 @@ inline size_t FCLAMP(double inp, double bound) {{
     )?; // always bounds check the last level
     writeln!(code_output, "}}")?;
 
+    if report_last_layer_errors {
+        writeln!(code_output, "
+{} {{
+  size_t below, above;
+  uint64_t guess = _rmi_lookup_bounds(key, &below, &above);
+  *err = (below > above ? below : above);
+  return guess;
+}}", lookup_sig)?;
+    }
+
+    let has_range = report_last_layer_errors && rmi.cache_fix.is_none();
+    if has_range {
+        writeln!(code_output, "
+{} {{
+  size_t below, above;
+  uint64_t guess = _rmi_lookup_bounds(key, &below, &above);
+  *lo = (below > guess ? 0 : guess - below);
+  *hi = (above > {1}UL - guess ? {1}UL : guess + above);
+  return guess;
+}}", range_sig, rmi.num_rmi_rows)?;
+    }
+
     if rmi.cache_fix.is_some() {
         generate_cache_fix_code(code_output, &rmi, array_name!(layer_params.len()-1))?;
     }
+
+    let string_lookup_sig = if report_last_layer_errors {
+        "uint64_t lookup(const char* key, size_t len, size_t* err)"
+    } else {
+        "uint64_t lookup(const char* key, size_t len)"
+    };
+    if key_type == KeyType::Str {
+        generate_string_key_code(code_output, &rmi, string_lookup_sig,
+                                 report_last_layer_errors)?;
+    }
     
     writeln!(code_output, "}} // namespace")?;
 
@@ -743,8 +933,16 @@ inline size_t FCLAMP(double inp, double bound) {{
         rmi.build_time
     )?;
     writeln!(header_output, "const char NAME[] = \"{}\";", namespace)?;
-    if rmi.cache_fix.is_none() {
+    if key_type == KeyType::Str {
+        writeln!(header_output, "{};", string_lookup_sig)?;
+        if has_range {
+            writeln!(header_output, "{};", STRING_RANGE_SIG)?;
+        }
+    } else if rmi.cache_fix.is_none() {
         writeln!(header_output, "{};", lookup_sig)?;
+        if has_range {
+            writeln!(header_output, "{};", range_sig)?;
+        }
     } else {
         writeln!(header_output, "uint64_t lookup(uint64_t key, size_t* err);")?;
     }
@@ -758,23 +956,22 @@ pub fn output_rmi(namespace: &str,
                   mut trained_model: TrainedRMI,
                   data_dir: &str,
                   key_type: KeyType,
-                  include_errors: bool) -> Result<(), std::io::Error> {
+                  include_errors: bool) -> Result<(), RmiError> {
     
-    let f1 = File::create(format!("{}.cpp", namespace)).expect("Could not write RMI CPP file");
+    let f1 = File::create(format!("{}.cpp", namespace))?;
     let mut bw1 = BufWriter::new(f1);
     
-    let f2 =
-        File::create(format!("{}_data.h", namespace)).expect("Could not write RMI data file");
+    let f2 = File::create(format!("{}_data.h", namespace))?;
     let mut bw2 = BufWriter::new(f2);
     
-    let f3 = File::create(format!("{}.h", namespace)).expect("Could not write RMI header file");
+    let f3 = File::create(format!("{}.h", namespace))?;
     let mut bw3 = BufWriter::new(f3);
 
     if !include_errors {
-        trained_model.last_layer_max_l1s.clear();
+        trained_model.last_layer_bounds = Vec::new().into();
     }
 
-    return generate_code(
+    generate_code(
         &mut bw1,
         &mut bw2,
         &mut bw3,
@@ -782,7 +979,40 @@ pub fn output_rmi(namespace: &str,
         trained_model,
         data_dir,
         key_type
-    );
+    )?;
+
+    return Ok(());
         
     
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    #[test]
+    fn test_small_leaf_layer() {
+        // 64 pgm leaves with their errors are small enough to be constants,
+        // but the leaves must still be selected by index
+        let data: Vec<(u64, usize)> = (0..10_000u64)
+            .map(|i| 3 * i * i + 1)
+            .enumerate()
+            .map(|(idx, key)| (key, idx))
+            .collect();
+        let md = RMITrainingData::new(Box::new(data));
+        let rmi = train(&md, "linear,pgm8", 64).unwrap();
+
+        let data_dir = std::env::temp_dir().join("rmi_test_small_leaf_layer");
+        std::fs::create_dir_all(&data_dir).unwrap();
+        let (mut code, mut data, mut header) = (Vec::new(), Vec::new(), Vec::new());
+        generate_code(&mut code, &mut data, &mut header, "test_rmi", rmi,
+                      data_dir.to_str().unwrap(), KeyType::U64).unwrap();
+
+        let code = String::from_utf8(code).unwrap();
+        let data = String::from_utf8(data).unwrap();
+        assert!(data.contains("uint64_t L1_PARAMETERS[256];"));
+        assert!(code.contains("pgm(L1_PARAMETERS[4*modelIndex + 0], L1_PARAMETERS[4*modelIndex + 1], \
+                               L1_ARRAY0, L1_ARRAY1, L1_ARRAY2, (double)key)"));
+    }
+}
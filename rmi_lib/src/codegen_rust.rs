@@ -0,0 +1,774 @@
+// < begin copyright > 
+// Copyright Ryan Marcus 2020
+// 
+// See root directory of this project for license terms.
+// 
+// < end copyright > 
+ 
+
+use crate::models::*;
+use crate::codegen::rmi_size;
+use crate::train::TrainedRMI;
+use crate::error::RmiError;
+use bytesize::ByteSize;
+use log::*;
+use std::collections::{BTreeSet, HashSet};
+use std::fs::File;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::Path;
+
+// Parameters for a single RMI layer. Small single-model layers are
+// emitted as constants, everything else is written to a data file and
+// decoded into a vector of per-model structs when the RMI is loaded.
+struct RustLayer {
+    idx: usize,
+    num_models: usize,
+    params_per_model: usize,
+    params: Vec<ModelParam>,
+    constant: bool
+}
+
+impl RustLayer {
+    fn new(idx: usize, num_models: usize, params: Vec<ModelParam>) -> RustLayer {
+        let params_per_model = params.len() / num_models;
+        let size: usize = params.iter().map(|p| p.size()).sum();
+        return RustLayer {
+            idx, num_models, params_per_model,
+            constant: num_models == 1 && size <= 4096,
+            params
+        };
+    }
+
+    fn with_zipped_errors(self, lle: &[[u64; 2]]) -> RustLayer {
+        let params: Vec<ModelParam> = self.params.chunks(self.params_per_model)
+            .zip(lle)
+            .flat_map(|(mod_params, [below, above])| {
+                let mut to_r: Vec<ModelParam> = Vec::new();
+                to_r.extend_from_slice(mod_params);
+                to_r.push(ModelParam::Int(*below));
+                to_r.push(ModelParam::Int(*above));
+                to_r
+            }).collect();
+
+        return RustLayer::new(self.idx, self.num_models, params);
+    }
+
+    fn model_params(&self) -> &[ModelParam] {
+        return &self.params[0..self.params_per_model];
+    }
+
+    fn size(&self) -> usize {
+        return self.params.iter().map(|p| p.size()).sum();
+    }
+
+    fn struct_name(&self) -> String {
+        return format!("L{}Params", self.idx);
+    }
+
+    fn field_name(&self) -> String {
+        return format!("l{}", self.idx);
+    }
+
+    fn file_name(&self, namespace: &str) -> String {
+        return format!("{}_L{}_PARAMETERS", namespace, self.idx);
+    }
+
+    fn constant_name(&self, param_idx: usize) -> String {
+        return format!("L{}_PARAMETER{}", self.idx, param_idx);
+    }
+
+    // expression used to pass the given parameter of the model
+    // (bound to `model_var`) into a model function
+    fn access<T: Write>(&self, target: &mut T, model_var: &str,
+                        param_idx: usize) -> Result<(), std::io::Error> {
+        let param = &self.params[param_idx];
+        let borrow = if param.is_array() { "&" } else { "" };
+        if self.constant {
+            write!(target, "{}{}", borrow, self.constant_name(param_idx))?;
+        } else {
+            write!(target, "{}{}.p{}", borrow, model_var, param_idx)?;
+        }
+        return Ok(());
+    }
+
+    fn to_consts<T: Write>(&self, target: &mut T) -> Result<(), std::io::Error> {
+        assert!(self.constant);
+        for (p_idx, param) in self.params.iter().enumerate() {
+            let rust_type = if param.is_array() {
+                format!("[{}; {}]", param.rust_type(), param.len())
+            } else {
+                param.rust_type().to_string()
+            };
+
+            writeln!(target, "const {}: {} = {};",
+                     self.constant_name(p_idx), rust_type, param.rust_val())?;
+        }
+        return Ok(());
+    }
+
+    fn to_struct<T: Write>(&self, target: &mut T) -> Result<(), std::io::Error> {
+        assert!(!self.constant);
+        writeln!(target, "struct {} {{", self.struct_name())?;
+        for (p_idx, param) in self.model_params().iter().enumerate() {
+            if param.is_array() {
+                writeln!(target, "    p{}: Vec<{}>,", p_idx, param.rust_type())?;
+            } else {
+                writeln!(target, "    p{}: {},", p_idx, param.rust_type())?;
+            }
+        }
+        writeln!(target, "}}\n")?;
+        return Ok(());
+    }
+
+    fn to_loader<T: Write>(&self, target: &mut T) -> Result<(), std::io::Error> {
+        assert!(!self.constant);
+        writeln!(target, "fn load_{}(path: &std::path::Path) -> std::io::Result<Vec<{}>> {{",
+                 self.field_name(), self.struct_name())?;
+        writeln!(target, "    let buf = std::fs::read(path)?;")?;
+        writeln!(target, "    if buf.len() != {} {{", self.size())?;
+        writeln!(target, "        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData,")?;
+        writeln!(target, "                                       \"RMI data file has the wrong size\"));")?;
+        writeln!(target, "    }}")?;
+        writeln!(target, "    let mut pos = 0;")?;
+        writeln!(target, "    let mut models = Vec::with_capacity({});", self.num_models)?;
+        writeln!(target, "    for _ in 0..{} {{", self.num_models)?;
+        writeln!(target, "        models.push({} {{", self.struct_name())?;
+        for (p_idx, param) in self.model_params().iter().enumerate() {
+            if param.is_array() {
+                writeln!(target, "            p{}: (0..{}).map(|_| read_{}(&buf, &mut pos)).collect(),",
+                         p_idx, param.len(), param.rust_type())?;
+            } else {
+                writeln!(target, "            p{}: read_{}(&buf, &mut pos),",
+                         p_idx, param.rust_type())?;
+            }
+        }
+        writeln!(target, "        }});")?;
+        writeln!(target, "    }}")?;
+        writeln!(target, "    Ok(models)")?;
+        writeln!(target, "}}\n")?;
+        return Ok(());
+    }
+}
+
+fn reader_code(rust_type: &str) -> Result<String, std::io::Error> {
+    let size = match rust_type {
+        "u16" => 2,
+        "u32" => 4,
+        "u64" | "f64" => 8,
+        "u128" => 16,
+        _ => return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("no reader for parameter type {}", rust_type)))
+    };
+
+    return Ok(format!("
+#[inline]
+fn read_{0}(buf: &[u8], pos: &mut usize) -> {0} {{
+    let mut bytes = [0u8; {1}];
+    bytes.copy_from_slice(&buf[*pos..*pos + {1}]);
+    *pos += {1};
+    {0}::from_le_bytes(bytes)
+}}", rust_type, size));
+}
+
+// Rust equivalent of the `model_index_from_output` macro in the C++ backend.
+fn index_from_output(from: &ModelDataType, bound: usize, needs_check: bool) -> String {
+    return match from {
+        ModelDataType::Float => {
+            if needs_check {
+                format!("fclamp(fpred, {}.0 - 1.0)", bound)
+            } else {
+                String::from("fpred as u64")
+            }
+        }
+        ModelDataType::Int => {
+            if needs_check {
+                format!("u64::min(ipred, {} - 1)", bound)
+            } else {
+                String::from("ipred")
+            }
+        }
+        ModelDataType::Int128 => {
+            if needs_check {
+                format!("u128::min(ipred, {} - 1) as u64", bound)
+            } else {
+                String::from("ipred as u64")
+            }
+        }
+    };
+}
+
+fn key_as(key_type: KeyType, required: &ModelDataType) -> String {
+    if key_type.is_signed() {
+        if let ModelDataType::Int = required {
+            return String::from("(key as u64) ^ (1 << 63)");
+        }
+    }
+    if key_type.rust_type() == required.rust_type() {
+        return String::from("key");
+    }
+    return format!("key as {}", required.rust_type());
+}
+
+fn generate_cache_fix_code<T: Write>(
+    target: &mut T,
+    rmi: &TrainedRMI,
+    spline_layer: &RustLayer) -> Result<(), std::io::Error> {
+
+    let num_splines = rmi.cache_fix.as_ref().unwrap().1.len();
+    let line_size = rmi.cache_fix.as_ref().unwrap().0;
+    let total_keys = rmi.num_data_rows;
+
+    writeln!(target, "
+    pub fn lookup(&self, key: u64) -> (u64, u64) {{
+        let num_spline_pts = {num_splines};
+        let (start, error_on_spline_search) = self.lookup_pre_cachefix(key);
+        let start = start as usize;
+        let error_on_spline_search = error_on_spline_search as usize;
+
+        let upper = usize::min(start + error_on_spline_search, num_spline_pts);
+        let lower = if error_on_spline_search > start {{
+            0
+        }} else {{
+            start - error_on_spline_search
+        }};
+
+        let spline = &self.{field};
+        let res = lower + spline[lower..upper].partition_point(|pt| pt.p0 < key);
+
+        if res == num_spline_pts {{
+            // we've searched for something past the last point
+            return ({total_keys} - 1, {line_size});
+        }}
+
+        if res == 0 {{
+            // we've searched for something before the first point
+            return ((spline[0].p1 / {line_size}) * {line_size}, {line_size});
+        }}
+
+        let pt1 = &spline[res - 1];
+        let pt2 = &spline[res];
+
+        let v0 = pt1.p1 as f64;
+        let v1 = pt2.p1 as f64;
+        let t = ((key - pt1.p0) as f64) / (pt2.p0 - pt1.p0) as f64;
+        ((((1.0 - t).mul_add(v0, t * v1)) as u64 / {line_size}) * {line_size}, {line_size})
+    }}",
+             num_splines=num_splines, field=spline_layer.field_name(),
+             total_keys=total_keys, line_size=line_size)?;
+
+    return Ok(());
+}
+
+// Emits the string key encoding, matching `StringKey::encode`.
+fn generate_string_key_code<T: Write>(
+    target: &mut T,
+    rmi: &TrainedRMI) -> Result<(), std::io::Error> {
+
+    let prefix: Vec<String> = rmi.key_prefix.iter().map(|b| b.to_string()).collect();
+    writeln!(target, "
+const KEY_PREFIX: &[u8] = &[{}];
+
+#[inline]
+fn encode_key(key: &[u8]) -> u128 {{
+    let shared = usize::min(key.len(), KEY_PREFIX.len());
+    match key[..shared].cmp(&KEY_PREFIX[..shared]) {{
+        std::cmp::Ordering::Less => return 0,
+        std::cmp::Ordering::Greater => return u128::MAX,
+        std::cmp::Ordering::Equal if key.len() < KEY_PREFIX.len() => return 0,
+        std::cmp::Ordering::Equal => {{}}
+    }}
+
+    let rest = &key[KEY_PREFIX.len()..];
+    let num_bytes = usize::min(16, rest.len());
+    let mut bytes = [0u8; 16];
+    bytes[..num_bytes].copy_from_slice(&rest[..num_bytes]);
+    u128::from_be_bytes(bytes)
+}}
+", prefix.join(", "))?;
+
+    return Ok(());
+}
+
+fn generate_code<T: Write>(
+    code_output: &mut T,
+    namespace: &str,
+    rmi: TrainedRMI,
+    data_dir: &str,
+    key_type: KeyType
+) -> Result<(), std::io::Error> {
+    let mut layers: Vec<RustLayer> = rmi.rmi
+        .iter()
+        .enumerate()
+        .map(|(layer_idx, models)| RustLayer::new(
+            layer_idx, models.len(),
+            models.iter().flat_map(|m| m.params()).collect()))
+        .collect();
+
+    let report_last_layer_errors = !rmi.last_layer_bounds.is_empty();
+    let zipped_errors = rmi.last_layer_bounds.len() > 1;
+    if zipped_errors {
+        let old_last = layers.pop().unwrap();
+        layers.push(old_last.with_zipped_errors(&rmi.last_layer_bounds));
+    }
+
+    if let Some((_, spline)) = rmi.cache_fix.as_ref() {
+        let cfv: Vec<ModelParam> = spline.iter()
+            .flat_map(|(mi, offset)| vec![(*mi).into(), (*offset).into()])
+            .collect();
+        let mut cache_fix_layer = RustLayer::new(layers.len(), spline.len(), cfv);
+        // the spline search needs to index into the spline points
+        cache_fix_layer.constant = false;
+        layers.push(cache_fix_layer);
+    }
+
+    // arrays shared by the models on a layer, by layer
+    let arrays: Vec<Vec<Vec<f64>>> = rmi.rmi.iter().map(|layer| layer_arrays(layer)).collect();
+    let array_field = |layer_idx: usize, idx: usize| format!("l{}_a{}", layer_idx, idx);
+    let array_file = |layer_idx: usize, idx: usize| format!("{}_L{}_ARRAY{}", namespace, layer_idx, idx);
+    let has_arrays = arrays.iter().any(|layer| !layer.is_empty());
+
+    let model_size_bytes = rmi_size(&rmi);
+    info!("Generated model size: {:?} ({} bytes)", ByteSize(model_size_bytes), model_size_bytes);
+
+    writeln!(code_output, "// RMI {} generated by the RMI compiler.", namespace)?;
+    writeln!(code_output, "// Call `load` with the path to the RMI data directory, then `lookup`.")?;
+    writeln!(code_output)?;
+    writeln!(code_output, "pub const RMI_SIZE: usize = {};", model_size_bytes)?;
+    assert!(rmi.build_time <= u128::from(std::u64::MAX));
+    writeln!(code_output, "pub const BUILD_TIME_NS: u64 = {};", rmi.build_time)?;
+    writeln!(code_output, "pub const NAME: &str = \"{}\";", namespace)?;
+    writeln!(code_output)?;
+
+    // write out constants and the data files for everything else
+    let mut read_types = BTreeSet::new();
+    for layer in layers.iter() {
+        if layer.constant {
+            layer.to_consts(code_output)?;
+            continue;
+        }
+
+        let data_path = Path::new(&data_dir).join(layer.file_name(namespace));
+        let f = File::create(data_path)?;
+        let mut bw = BufWriter::new(f);
+        for param in layer.params.iter() {
+            param.write_to(&mut bw)?;
+        }
+
+        for param in layer.model_params() {
+            read_types.insert(param.rust_type());
+        }
+    }
+    for (layer_idx, layer_arrays) in arrays.iter().enumerate() {
+        for (idx, array) in layer_arrays.iter().enumerate() {
+            let data_path = Path::new(&data_dir).join(array_file(layer_idx, idx));
+            let mut bw = BufWriter::new(File::create(data_path)?);
+            for v in array.iter() {
+                bw.write_all(&v.to_le_bytes())?;
+            }
+        }
+    }
+    if has_arrays {
+        read_types.insert("f64");
+    }
+    writeln!(code_output)?;
+
+    let loaded: Vec<&RustLayer> = layers.iter().filter(|l| !l.constant).collect();
+    for layer in loaded.iter() {
+        layer.to_struct(code_output)?;
+    }
+
+    writeln!(code_output, "pub struct Rmi {{")?;
+    for layer in loaded.iter() {
+        writeln!(code_output, "    {}: Vec<{}>,", layer.field_name(), layer.struct_name())?;
+    }
+    for (layer_idx, layer_arrays) in arrays.iter().enumerate() {
+        for idx in 0..layer_arrays.len() {
+            writeln!(code_output, "    {}: Vec<f64>,", array_field(layer_idx, idx))?;
+        }
+    }
+    writeln!(code_output, "}}\n")?;
+
+    let path_var = if loaded.is_empty() && !has_arrays { "_data_path" } else { "data_path" };
+    writeln!(code_output,
+             "pub fn load<P: AsRef<std::path::Path>>({}: P) -> std::io::Result<Rmi> {{",
+             path_var)?;
+    if path_var == "data_path" {
+        writeln!(code_output, "    let data_path = data_path.as_ref();")?;
+    }
+    writeln!(code_output, "    Ok(Rmi {{")?;
+    for layer in loaded.iter() {
+        writeln!(code_output, "        {}: load_{}(&data_path.join(\"{}\"))?,",
+                 layer.field_name(), layer.field_name(), layer.file_name(namespace))?;
+    }
+    for (layer_idx, layer_arrays) in arrays.iter().enumerate() {
+        for (idx, array) in layer_arrays.iter().enumerate() {
+            writeln!(code_output, "        {}: load_array(&data_path.join(\"{}\"), {})?,",
+                     array_field(layer_idx, idx), array_file(layer_idx, idx), array.len())?;
+        }
+    }
+    writeln!(code_output, "    }})")?;
+    writeln!(code_output, "}}\n")?;
+
+    if has_arrays {
+        writeln!(code_output, "fn load_array(path: &std::path::Path, len: usize) -> std::io::Result<Vec<f64>> {{")?;
+        writeln!(code_output, "    let buf = std::fs::read(path)?;")?;
+        writeln!(code_output, "    if buf.len() != len * 8 {{")?;
+        writeln!(code_output, "        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData,")?;
+        writeln!(code_output, "                                       \"RMI data file has the wrong size\"));")?;
+        writeln!(code_output, "    }}")?;
+        writeln!(code_output, "    let mut pos = 0;")?;
+        writeln!(code_output, "    Ok((0..len).map(|_| read_f64(&buf, &mut pos)).collect())")?;
+        writeln!(code_output, "}}\n")?;
+    }
+
+    for layer in loaded.iter() {
+        layer.to_loader(code_output)?;
+    }
+
+    for rust_type in read_types {
+        writeln!(code_output, "{}", reader_code(rust_type)?)?;
+    }
+
+    // get all of the required stdlib functions together
+    // TODO assumes all layers are homogenous
+    let mut stdlib = HashSet::new();
+    for layer in rmi.rmi.iter() {
+        for func in layer[0].standard_functions() {
+            stdlib.insert(func.rust_code());
+        }
+    }
+
+    for code in stdlib {
+        writeln!(code_output, "{}", code)?;
+    }
+
+    // next, the model functions
+    let mut sigs = Vec::new();
+    for layer in rmi.rmi.iter() {
+        // wrapped models are defined before the models that call them
+        let mut model = Some(layer[0].as_ref());
+        let mut chain = Vec::new();
+        while let Some(m) = model {
+            chain.push(m.rust_code());
+            model = m.wrapped_model();
+        }
+        for code in chain.into_iter().rev() {
+            if !sigs.contains(&code) {
+                sigs.push(code);
+            }
+        }
+    }
+
+    for sig in sigs {
+        writeln!(code_output, "{}", sig)?;
+    }
+
+    writeln!(
+        code_output,
+        "
+#[inline]
+#[allow(dead_code)]
+fn fclamp(inp: f64, bound: f64) -> u64 {{
+    if inp < 0.0 {{
+        return 0;
+    }}
+    (if inp > bound {{ bound }} else {{ inp }}) as u64
+}}\n"
+    )?;
+
+    if key_type == KeyType::Str {
+        generate_string_key_code(code_output, &rmi)?;
+    }
+
+    writeln!(code_output, "impl Rmi {{")?;
+
+    let (rmi_lookup_name, visibility) = if rmi.cache_fix.is_some() {
+        ("lookup_pre_cachefix", "")
+    } else if key_type == KeyType::Str {
+        ("lookup_encoded", "")
+    } else {
+        ("lookup", "pub ")
+    };
+
+    // the cache fix search always needs an error bound
+    let return_errors = report_last_layer_errors || rmi.cache_fix.is_some();
+    let return_type = if return_errors { "(u64, u64)" } else { "u64" };
+    let range_name = if key_type == KeyType::Str { "lookup_range_encoded" } else { "lookup_range" };
+    let range_visibility = if key_type == KeyType::Str { "" } else { "pub " };
+    let has_range = report_last_layer_errors && rmi.cache_fix.is_none();
+
+    // with errors, the RMI itself reports how far below and above its
+    // prediction the key can be, and the lookup functions wrap it
+    if report_last_layer_errors {
+        writeln!(code_output, "    #[inline]")?;
+        writeln!(code_output, "    fn lookup_bounds(&self, key: {}) -> (u64, u64, u64) {{",
+                 key_type.rust_type())?;
+    } else {
+        writeln!(code_output, "    {}fn {}(&self, key: {}) -> {} {{",
+                 visibility, rmi_lookup_name, key_type.rust_type(), return_type)?;
+    }
+
+    let mut last_model_output = key_type.to_model_data_type();
+    let mut needs_bounds_check = true;
+    let mut last_model_var = String::new();
+
+    for (layer_idx, layer) in rmi.rmi.iter().enumerate() {
+        let layer_param = &layers[layer_idx];
+        let required_type = layer[0].input_type();
+        let current_model_output = layer[0].output_type();
+
+        let var_name = match current_model_output {
+            ModelDataType::Int | ModelDataType::Int128 => "ipred",
+            ModelDataType::Float => "fpred",
+        };
+
+        let model_var = format!("m{}", layer_idx);
+        if layer.len() == 1 {
+            if !layer_param.constant {
+                writeln!(code_output, "        let {} = &self.{}[0];",
+                         model_var, layer_param.field_name())?;
+            }
+        } else {
+            // we need to get the model index based on the previous
+            // prediction, and then use ref accessing
+            writeln!(code_output, "        let model_index = {} as usize;",
+                     index_from_output(&last_model_output, layer.len(), needs_bounds_check))?;
+            writeln!(code_output, "        let {} = &self.{}[model_index];",
+                     model_var, layer_param.field_name())?;
+        }
+
+        write!(code_output, "        let {} = {}(", var_name, layer[0].function_name())?;
+        for pidx in 0..layer[0].params().len() {
+            layer_param.access(code_output, &model_var, pidx)?;
+            write!(code_output, ", ")?;
+        }
+        for idx in 0..arrays[layer_idx].len() {
+            write!(code_output, "&self.{}, ", array_field(layer_idx, idx))?;
+        }
+        writeln!(code_output, "{});", key_as(key_type, &required_type))?;
+
+        last_model_output = current_model_output;
+        needs_bounds_check = layer[0].needs_bounds_check();
+        last_model_var = model_var;
+    }
+
+    // always bounds check the last level
+    let guess = index_from_output(&last_model_output, rmi.num_rmi_rows, true);
+    if zipped_errors {
+        let last_layer = &layers[rmi.rmi.len() - 1];
+        writeln!(code_output, "        ({}, {}.p{}, {}.p{})",
+                 guess, last_model_var, last_layer.params_per_model - 2,
+                 last_model_var, last_layer.params_per_model - 1)?;
+    } else if report_last_layer_errors {
+        let [below, above] = rmi.last_layer_bounds[0];
+        writeln!(code_output, "        ({}, {}, {})", guess, below, above)?;
+    } else if return_errors {
+        writeln!(code_output, "        ({}, 0)", guess)?;
+    } else {
+        writeln!(code_output, "        {}", guess)?;
+    }
+    writeln!(code_output, "    }}")?;
+
+    if report_last_layer_errors {
+        writeln!(code_output, "
+    {}fn {}(&self, key: {}) -> (u64, u64) {{
+        let (guess, below, above) = self.lookup_bounds(key);
+        (guess, u64::max(below, above))
+    }}", visibility, rmi_lookup_name, key_type.rust_type())?;
+    }
+
+    if has_range {
+        writeln!(code_output, "
+    {}fn {}(&self, key: {}) -> (u64, u64, u64) {{
+        let (guess, below, above) = self.lookup_bounds(key);
+        (guess, guess.saturating_sub(below), u64::min(guess + above, {}))
+    }}", range_visibility, range_name, key_type.rust_type(), rmi.num_rmi_rows)?;
+    }
+
+    if rmi.cache_fix.is_some() {
+        generate_cache_fix_code(code_output, &rmi, layers.last().unwrap())?;
+    }
+
+    if key_type == KeyType::Str {
+        writeln!(code_output, "
+    pub fn lookup(&self, key: &[u8]) -> {} {{
+        self.lookup_encoded(encode_key(key))
+    }}", return_type)?;
+        if has_range {
+            writeln!(code_output, "
+    pub fn lookup_range(&self, key: &[u8]) -> (u64, u64, u64) {{
+        self.lookup_range_encoded(encode_key(key))
+    }}")?;
+        }
+    }
+
+    writeln!(code_output, "}}")?;
+
+    return Ok(());
+}
+
+pub fn output_rmi_rust(namespace: &str,
+                       mut trained_model: TrainedRMI,
+                       data_dir: &str,
+                       key_type: KeyType,
+                       include_errors: bool) -> Result<(), RmiError> {
+    let f = File::create(format!("{}.rs", namespace))?;
+    let mut bw = BufWriter::new(f);
+
+    if !include_errors {
+        trained_model.last_layer_bounds = Vec::new().into();
+    }
+
+    generate_code(&mut bw, namespace, trained_model, data_dir, key_type)?;
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    #[test]
+    fn test_rust_codegen() {
+        let data: Vec<(u64, usize)> = (0..10_000u64)
+            .map(|i| 3 * i * i + 1)
+            .enumerate()
+            .map(|(idx, key)| (key, idx))
+            .collect();
+        let md = RMITrainingData::new(Box::new(data));
+        let rmi = train(&md, "linear,linear", 64).unwrap();
+
+        let data_dir = std::env::temp_dir().join("rmi_test_rust_codegen");
+        std::fs::create_dir_all(&data_dir).unwrap();
+
+        let mut code = Vec::new();
+        generate_code(&mut code, "test_rmi", rmi,
+                      data_dir.to_str().unwrap(), KeyType::U64).unwrap();
+        let code = String::from_utf8(code).unwrap();
+
+        assert!(code.contains("pub fn lookup(&self, key: u64) -> (u64, u64)"));
+        assert!(code.contains("fn linear(alpha: f64, beta: f64, inp: f64) -> f64"));
+        assert!(code.contains("l1: load_l1(&data_path.join(\"test_rmi_L1_PARAMETERS\"))?"));
+
+        assert!(code.contains("pub fn lookup_range(&self, key: u64) -> (u64, u64, u64)"));
+
+        // 64 models with a slope, intercept, and error below and above each
+        let params = std::fs::metadata(data_dir.join("test_rmi_L1_PARAMETERS")).unwrap();
+        assert_eq!(params.len(), 64 * 4 * 8);
+    }
+
+    #[test]
+    fn test_reader_code_unknown_type() {
+        assert!(reader_code("u64").unwrap().contains("fn read_u64("));
+        let err = reader_code("i8").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    // Generates the module for `rmi`, compiles it with a driver that looks
+    // up each probe (with overflow checks on), and returns the output of
+    // `lookup` for each probe, formatted with `{:?}`.
+    fn run_generated(name: &str, rmi: TrainedRMI, key_type: KeyType,
+                     probes: &[String], lookup: &str) -> Vec<String> {
+        let dir = std::env::temp_dir().join("rmi_test_rust_codegen_runs").join(name);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut code = BufWriter::new(File::create(dir.join(format!("{}.rs", name))).unwrap());
+        generate_code(&mut code, name, rmi, dir.to_str().unwrap(), key_type).unwrap();
+        code.flush().unwrap();
+        drop(code);
+
+        std::fs::write(dir.join("probes.txt"), probes.join("\n")).unwrap();
+        std::fs::write(dir.join("main.rs"), format!("
+#[allow(dead_code)]
+mod {0};
+
+fn main() {{
+    let rmi = {0}::load({1:?}).unwrap();
+    let probes = std::fs::read_to_string({2:?}).unwrap();
+    for line in probes.lines() {{
+        println!(\"{{:?}}\", {3});
+    }}
+}}", name, dir, dir.join("probes.txt"), lookup)).unwrap();
+
+        let driver = dir.join("driver");
+        let output = std::process::Command::new("rustc")
+            .args(&["--edition", "2018", "-C", "overflow-checks=on", "-o"])
+            .arg(&driver)
+            .arg(dir.join("main.rs"))
+            .output()
+            .expect("could not run rustc");
+        assert!(output.status.success(), "generated code for {} did not compile:\n{}",
+                name, String::from_utf8_lossy(&output.stderr));
+
+        let output = std::process::Command::new(&driver).output().unwrap();
+        assert!(output.status.success(), "generated code for {} failed:\n{}",
+                name, String::from_utf8_lossy(&output.stderr));
+        return String::from_utf8(output.stdout).unwrap()
+            .lines().map(String::from).collect();
+    }
+
+    #[test]
+    fn test_rust_codegen_runs() {
+        let data: Vec<(u64, usize)> = (0..10_000u64)
+            .map(|i| 3 * i * i + 1)
+            .enumerate()
+            .map(|(idx, key)| (key, idx))
+            .collect();
+        let md = RMITrainingData::new(Box::new(data.clone()));
+
+        // include keys before, between, and after the training keys
+        let mut probe_keys: Vec<u64> = vec![0, u64::MAX];
+        for &(key, _idx) in data.iter().step_by(7) {
+            probe_keys.push(key);
+            probe_keys.push(key + 1);
+        }
+        let probes: Vec<String> = probe_keys.iter().map(|k| k.to_string()).collect();
+        let lookup = "rmi.lookup(line.parse::<u64>().unwrap())";
+
+        let specs = [("linear,linear", 64), ("radix,cubic", 64), ("histogram,linear", 64),
+                     ("radix22,loglinear", 64), ("radix18,normal", 64), ("linear,pgm8", 64),
+                     ("radix_spline", 1), ("poly2,poly3", 64), ("linear,linear,linear", 8)];
+        for (idx, (models, branch_factor)) in specs.iter().enumerate() {
+            let rmi = train(&md, models, *branch_factor).unwrap();
+            let expected: Vec<String> = probe_keys.iter()
+                .map(|&k| format!("{:?}", rmi.lookup(k))).collect();
+            let actual = run_generated(&format!("rmi_{}", idx), rmi, KeyType::U64,
+                                       &probes, lookup);
+            assert_eq!(actual, expected, "{}", models);
+        }
+
+        let rmi = train_bounded(&md, "linear,linear", 64, 16).unwrap();
+        let expected: Vec<String> = probe_keys.iter()
+            .map(|&k| format!("{:?}", rmi.lookup(k))).collect();
+        assert_eq!(run_generated("rmi_bounded", rmi, KeyType::U64, &probes, lookup), expected);
+
+        let options = TrainingOptions {
+            fallback: Some(Fallback { max_error: 4, node_size: 256 }),
+            ..Default::default()
+        };
+        for models in &["linear,linear", "linear,pgm16"] {
+            let rmi = train_with_options(&md, models, 8, &options).unwrap();
+            assert!(rmi.rmi[1][0].function_name().ends_with("_fallback"));
+            let expected: Vec<String> = probe_keys.iter()
+                .map(|&k| format!("{:?}", rmi.lookup(k))).collect();
+            let name = format!("rmi_fallback_{}", models.replace(',', "_"));
+            assert_eq!(run_generated(&name, rmi, KeyType::U64, &probes, lookup), expected);
+        }
+
+        let mut rmi = train(&md, "linear,linear", 64).unwrap();
+        rmi.last_layer_bounds = Vec::new().into();
+        let expected: Vec<String> = probe_keys.iter()
+            .map(|&k| format!("{:?}", rmi.lookup(k).0)).collect();
+        assert_eq!(run_generated("rmi_no_errors", rmi, KeyType::U64, &probes, lookup), expected);
+
+        let keys: Vec<String> = (0..5_000).map(|i| format!("key/{:08}", i * 7)).collect();
+        let md = RMITrainingData::new(Box::new(StringKeyData::new(&keys)));
+        let rmi = train(&md, "radix,linear", 64).unwrap();
+        let expected: Vec<String> = keys.iter()
+            .map(|k| format!("{:?}", rmi.lookup_string(k))).collect();
+        assert_eq!(run_generated("rmi_strings", rmi, KeyType::Str, &keys,
+                                 "rmi.lookup(line.as_bytes())"), expected);
+    }
+}
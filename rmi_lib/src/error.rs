@@ -0,0 +1,100 @@
+// < begin copyright > 
+// Copyright Ryan Marcus 2020
+// 
+// See root directory of this project for license terms.
+// 
+// < end copyright > 
+ 
+
+use crate::models::KeyType;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum RmiError {
+    /// A model spec named a model type that does not exist.
+    UnknownModel(String),
+
+    /// A model type was used at a layer it does not support, e.g. a
+    /// `radix` model below the root. `required` is "root", "bottommost",
+    /// or "second" (for `pgm` leaves, which need keys routed to them by a
+    /// single top model).
+    InvalidLayerOrder { model: String, required: &'static str },
+
+    /// There were not enough keys to train the requested RMI.
+    NotEnoughData { required: usize, available: usize },
+
+    /// The keys were not sorted: the key at position `index` is smaller
+    /// than the key before it, or cannot be compared to it (NaN).
+    UnsortedData { index: usize },
+
+    /// The position of the key at `index` is smaller than the position of
+    /// the key before it.
+    UnsortedPositions { index: usize },
+
+    /// A model type cannot be trained on keys of the given type, e.g. a
+    /// `radix_spline` model on floating point keys.
+    UnsupportedKeyType { model: String, key_type: KeyType },
+
+    /// `train_for_size` could not find a configuration below the size limit.
+    NoFittingConfig { max_size: usize },
+
+    /// A `Sampling` rate was not in (0, 1].
+    InvalidSampleRate(f64),
+
+    /// A `LeafAllocation` could not be used, for example because the RMI
+    /// does not have exactly two layers.
+    InvalidLeafAllocation(&'static str),
+
+    /// `RMI_OPTIMIZER_PROFILE` was set to an unknown profile.
+    InvalidOptimizerProfile(String),
+
+    /// Reading or writing a file failed.
+    Io(std::io::Error),
+}
+
+impl fmt::Display for RmiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self {
+            RmiError::UnknownModel(model) =>
+                write!(f, "unknown model type: {}", model),
+            RmiError::InvalidLayerOrder { model, required } =>
+                write!(f, "if used, model type {} must be the {} model", model, required),
+            RmiError::NotEnoughData { required, available } =>
+                write!(f, "not enough data: needed at least {} keys, but only {} were given",
+                       required, available),
+            RmiError::UnsortedData { index } =>
+                write!(f, "keys are not sorted: the key at position {} is smaller than the key before it",
+                       index),
+            RmiError::UnsortedPositions { index } =>
+                write!(f, "positions are not monotone: the position of the key at {} is smaller \
+                           than the position of the key before it", index),
+            RmiError::UnsupportedKeyType { model, key_type } =>
+                write!(f, "model type {} does not support {:?} keys", model, key_type),
+            RmiError::NoFittingConfig { max_size } =>
+                write!(f, "could not find any configurations smaller than {}", max_size),
+            RmiError::InvalidSampleRate(rate) =>
+                write!(f, "sample rate must be greater than 0 and at most 1, got {}", rate),
+            RmiError::InvalidLeafAllocation(reason) =>
+                write!(f, "invalid leaf allocation: {}", reason),
+            RmiError::InvalidOptimizerProfile(profile) =>
+                write!(f, "invalid optimizer profile {}", profile),
+            RmiError::Io(err) =>
+                write!(f, "I/O error: {}", err),
+        };
+    }
+}
+
+impl std::error::Error for RmiError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        return match self {
+            RmiError::Io(err) => Some(err),
+            _ => None
+        };
+    }
+}
+
+impl From<std::io::Error> for RmiError {
+    fn from(err: std::io::Error) -> Self {
+        RmiError::Io(err)
+    }
+}
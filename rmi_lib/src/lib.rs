@@ -2,11 +2,20 @@ mod codegen;
 mod models;
 mod train;
 mod cache_fix;
+mod lookup;
+mod codegen_rust;
+mod serialize;
+mod error;
+mod string_keys;
 
 pub mod optimizer;
 pub use models::{RMITrainingData, RMITrainingDataIteratorProvider, ModelInput};
-pub use models::KeyType;
+pub use models::{KeyType, TrainingKey};
+pub use error::RmiError;
+pub use string_keys::{StringKey, StringKeyData, StringKeyDataBuilder};
 pub use optimizer::find_pareto_efficient_configs;
-pub use train::{train, train_for_size, train_bounded};
+pub use train::{train, train_with_options, train_for_size, train_bounded, TrainedRMI};
+pub use train::{TrainingOptions, Sampling, Fallback, LeafAllocation};
 pub use codegen::rmi_size;
 pub use codegen::output_rmi;
+pub use codegen_rust::output_rmi_rust;
@@ -0,0 +1,248 @@
+// < begin copyright > 
+// Copyright Ryan Marcus 2020
+// 
+// See root directory of this project for license terms.
+// 
+// < end copyright > 
+ 
+
+use crate::models::*;
+use crate::train::TrainedRMI;
+use crate::string_keys::StringKey;
+
+// mirrors the FCLAMP function emitted into the generated code
+fn fclamp(inp: f64, bound: f64) -> u64 {
+    if inp < 0.0 { return 0; }
+    return if inp > bound { bound as u64 } else { inp as u64 };
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Prediction {
+    Int(u64),
+    Float(f64)
+}
+
+impl Prediction {
+    fn from_model(model: &dyn Model, inp: &ModelInput) -> Prediction {
+        return match model.output_type() {
+            ModelDataType::Float => Prediction::Float(model.predict_to_float(inp)),
+            ModelDataType::Int | ModelDataType::Int128
+                => Prediction::Int(model.predict_to_int(inp)),
+        };
+    }
+
+    // Rust equivalent of the `model_index_from_output` codegen macro.
+    fn to_index(self, bound: u64, needs_check: bool) -> u64 {
+        return match self {
+            Prediction::Float(fpred) => {
+                if needs_check {
+                    fclamp(fpred, bound as f64 - 1.0)
+                } else {
+                    fpred as u64
+                }
+            }
+            Prediction::Int(ipred) => {
+                if needs_check {
+                    u64::min(ipred, bound - 1)
+                } else {
+                    ipred
+                }
+            }
+        };
+    }
+}
+
+impl TrainedRMI {
+    /// Evaluates the RMI on `key` in-process, returning the predicted
+    /// position and the maximum error of that prediction (zero if the
+    /// last level errors were discarded). The result matches the `lookup`
+    /// function produced by `output_rmi`, including the bounds checks
+    /// between layers and the cache-fix spline search for bounded RMIs.
+    pub fn lookup<K: TrainingKey>(&self, key: K) -> (u64, u64) {
+        let inp = key.to_model_input();
+        let (guess, leaf_idx) = self.lookup_rmi(&inp);
+        let (below, above) = self.leaf_bounds(leaf_idx);
+        let err = u64::max(below, above);
+
+        return match &self.cache_fix {
+            None => (guess, err),
+            Some((line_size, spline)) =>
+                (self.lookup_cache_fix(key.as_uint(), guess, err,
+                                       *line_size, spline),
+                 *line_size as u64)
+        };
+    }
+
+    /// Like `lookup`, for an RMI trained on `StringKeyData`. The key is
+    /// encoded with the common prefix of the training keys.
+    pub fn lookup_string<S: AsRef<[u8]>>(&self, key: S) -> (u64, u64) {
+        return self.lookup(StringKey::encode(&self.key_prefix, key.as_ref()));
+    }
+
+    /// Evaluates the RMI on `key` in-process, returning the predicted
+    /// position and the first and last positions (inclusive) that can
+    /// hold the lower bound of `key`. Leaf models are often biased, so
+    /// this window uses separate errors below and above the prediction
+    /// and can be much tighter than the one given by `lookup`. The last
+    /// position is at most the number of rows, which is the lower bound
+    /// of keys past the end. Matches the `lookup_range` function produced
+    /// by `output_rmi`. Bounded RMIs, for which no `lookup_range` is
+    /// generated, report the window of `lookup`.
+    pub fn lookup_range<K: TrainingKey>(&self, key: K) -> (u64, u64, u64) {
+        if self.cache_fix.is_some() {
+            // the cache fix predicts data rows, not spline points
+            let (guess, err) = self.lookup(key);
+            return (guess, guess.saturating_sub(err),
+                    u64::min(guess + err, self.num_data_rows as u64));
+        }
+
+        let inp = key.to_model_input();
+        let (guess, leaf_idx) = self.lookup_rmi(&inp);
+        let (below, above) = self.leaf_bounds(leaf_idx);
+        return (guess, guess.saturating_sub(below),
+                u64::min(guess + above, self.num_rmi_rows as u64));
+    }
+
+    /// Like `lookup_range`, for an RMI trained on `StringKeyData`.
+    pub fn lookup_string_range<S: AsRef<[u8]>>(&self, key: S) -> (u64, u64, u64) {
+        return self.lookup_range(StringKey::encode(&self.key_prefix, key.as_ref()));
+    }
+
+    // The errors below and above the predictions of the given leaf, zero
+    // if the last level errors were discarded.
+    fn leaf_bounds(&self, leaf_idx: usize) -> (u64, u64) {
+        let [below, above] = match self.last_layer_bounds.len() {
+            0 => [0, 0],
+            1 => self.last_layer_bounds[0],
+            _ => self.last_layer_bounds[leaf_idx]
+        };
+        return (below, above);
+    }
+
+    // Returns the predicted position and the index of the leaf model
+    // that made the prediction.
+    fn lookup_rmi(&self, inp: &ModelInput) -> (u64, usize) {
+        let mut model_index = 0;
+        let mut last_pred: Option<Prediction> = None;
+        let mut needs_bounds_check = true;
+
+        for layer in self.rmi.iter() {
+            if layer.len() > 1 {
+                model_index = last_pred
+                    .expect("the first layer of an RMI must have a single model")
+                    .to_index(layer.len() as u64, needs_bounds_check) as usize;
+            } else {
+                model_index = 0;
+            }
+
+            let model = &layer[model_index];
+            last_pred = Some(Prediction::from_model(model.as_ref(), inp));
+            needs_bounds_check = model.needs_bounds_check();
+        }
+
+        // always bounds check the last level
+        let guess = last_pred.unwrap().to_index(self.num_rmi_rows as u64, true);
+        return (guess, model_index);
+    }
+
+    fn lookup_cache_fix(&self, key: u64, start: u64, err: u64,
+                        line_size: usize, spline: &[(u64, usize)]) -> u64 {
+        let num_spline_pts = spline.len();
+        let start = start as usize;
+        let err = err as usize;
+
+        let upper = usize::min(start + err, num_spline_pts);
+        let lower = if err > start { 0 } else { start - err };
+        let res = lower + spline[lower..upper]
+            .partition_point(|(spline_key, _)| *spline_key < key);
+
+        if res == num_spline_pts {
+            // we've searched for something past the last point
+            return self.num_data_rows as u64 - 1;
+        }
+
+        if res == 0 {
+            // we've searched for something before the first point
+            return ((spline[0].1 / line_size) * line_size) as u64;
+        }
+
+        let pt1 = spline[res - 1];
+        let pt2 = spline[res];
+
+        let v0 = pt1.1 as f64;
+        let v1 = pt2.1 as f64;
+        let t = ((key - pt1.0) as f64) / (pt2.0 - pt1.0) as f64;
+        let pred = (1.0 - t).mul_add(v0, t * v1) as usize;
+        return ((pred / line_size) * line_size) as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use crate::train::tests::{test_keys, test_data, assert_lookups_within_bounds};
+
+    #[test]
+    fn test_lookup_linear() {
+        let rmi = train(&test_data(10_000), "linear,linear", 64).unwrap();
+        assert_lookups_within_bounds(&rmi, &test_keys(10_000), 1, "linear,linear");
+    }
+
+    #[test]
+    fn test_lookup_radix() {
+        let rmi = train(&test_data(10_000), "radix,cubic", 128).unwrap();
+        assert_lookups_within_bounds(&rmi, &test_keys(10_000), 1, "radix,cubic");
+    }
+
+    #[test]
+    fn test_lookup_cache_fix() {
+        let rmi = train_bounded(&test_data(10_000), "linear,linear", 32, 16).unwrap();
+        assert_lookups_within_bounds(&rmi, &test_keys(10_000), 1, "bounded linear,linear");
+    }
+
+    #[test]
+    fn test_lookup_range() {
+        let keys = test_keys(10_000);
+        let md = test_data(10_000);
+
+        for models in &["linear,linear", "radix,cubic", "linear,linear,linear", "pgm8"] {
+            let rmi = train(&md, models, 64).unwrap();
+            let mut range_width = 0;
+            let mut err_width = 0;
+            for (idx, &key) in keys.iter().enumerate() {
+                for (probe, lb) in &[(key, idx), (key + 1, idx + 1)] {
+                    let (guess, err) = rmi.lookup(*probe);
+                    let (range_guess, lo, hi) = rmi.lookup_range(*probe);
+                    assert_eq!(guess, range_guess);
+                    assert!(lo <= *lb as u64 && *lb as u64 <= hi,
+                            "{}: lower bound of {} is {} but the range was {} to {}",
+                            models, probe, lb, lo, hi);
+                    assert!(guess - lo <= err && hi - guess <= err);
+                    range_width += hi - lo;
+                    err_width += 2 * err;
+                }
+            }
+            assert!(range_width < err_width, "{} ranges were not tighter", models);
+        }
+
+        // bounded RMIs search the cache line window
+        let rmi = train_bounded(&md, "linear,linear", 32, 16).unwrap();
+        let (guess, lo, hi) = rmi.lookup_range(keys[100]);
+        assert_eq!((lo, hi), (guess.saturating_sub(16), guess + 16));
+        for (idx, &key) in keys.iter().enumerate() {
+            let (_guess, lo, hi) = rmi.lookup_range(key);
+            assert!(lo <= idx as u64 && idx as u64 <= hi,
+                    "bounded: {} is at {} but the range was {} to {}",
+                    key, idx, lo, hi);
+        }
+    }
+
+    #[test]
+    fn test_lookup_out_of_range() {
+        let keys = test_keys(10_000);
+        let rmi = train(&test_data(10_000), "linear,linear", 64).unwrap();
+
+        let (guess, _err) = rmi.lookup(std::u64::MAX);
+        assert!(guess < keys.len() as u64);
+    }
+}
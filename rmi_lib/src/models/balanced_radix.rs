@@ -15,6 +15,7 @@ use std::f64;
 pub struct BalancedRadixModel {
     params: (u8, u8, u64),
     high: bool,
+    key_bits: u8,
 }
 
 fn chi2<T: TrainingKey>(data: &RMITrainingData<T>,
@@ -39,6 +40,7 @@ fn chi2<T: TrainingKey>(data: &RMITrainingData<T>,
 fn bradix<T: TrainingKey>(data: &RMITrainingData<T>, max_output: u64) -> BalancedRadixModel {
     let bits = num_bits(max_output);
     let common_prefix = common_prefix_size(data);
+    let key_bits = data.key_type().bits();
     trace!("Bradix layer common prefix: {}", common_prefix);
 
     let mut best_result_score = f64::INFINITY;
@@ -49,6 +51,7 @@ fn bradix<T: TrainingKey>(data: &RMITrainingData<T>, max_output: u64) -> Balance
         let high = BalancedRadixModel {
             params: (common_prefix, test_bits, max_output - 1),
             high: true,
+            key_bits,
         };
         let high_score = chi2(data, max_output, &high);
 
@@ -65,6 +68,7 @@ fn bradix<T: TrainingKey>(data: &RMITrainingData<T>, max_output: u64) -> Balance
         let low = BalancedRadixModel {
             params: (common_prefix, test_bits, max_output - bits_max),
             high: false,
+            key_bits,
         };
         let low_score = chi2(data, max_output, &low);
 
@@ -85,11 +89,33 @@ fn bradix<T: TrainingKey>(data: &RMITrainingData<T>, max_output: u64) -> Balance
 }
 
 impl BalancedRadixModel {
+    pub fn from_params(params: &[ModelParam]) -> Option<BalancedRadixModel> {
+        return match params {
+            [ModelParam::Int(prefix), ModelParam::Int(bits),
+             ModelParam::Int(clamp), ModelParam::Int(high)] =>
+                Some(BalancedRadixModel {
+                    params: (*prefix as u8, *bits as u8, *clamp),
+                    high: *high != 0,
+                    key_bits: 64
+                }),
+            [ModelParam::Int(prefix), ModelParam::Int(bits),
+             ModelParam::Int(clamp), ModelParam::Int(high), ModelParam::Int(key_bits)]
+                if *key_bits == 64 || *key_bits == 128 =>
+                Some(BalancedRadixModel {
+                    params: (*prefix as u8, *bits as u8, *clamp),
+                    high: *high != 0,
+                    key_bits: *key_bits as u8
+                }),
+            _ => None
+        };
+    }
+
     pub fn new<T: TrainingKey>(data: &RMITrainingData<T>) -> BalancedRadixModel {
         if data.len() == 0 {
             return BalancedRadixModel {
                 params: (0, 0, 0),
                 high: true,
+                key_bits: data.key_type().bits(),
             };
         }
 
@@ -103,8 +129,13 @@ impl Model for BalancedRadixModel {
     fn predict_to_int(&self, inp: &ModelInput) -> u64 {
         let (left_shift, num_bits, clamp) = self.params;
 
-        let as_int: u64 = inp.as_int();
-        let res = (as_int << left_shift) >> (64 - num_bits);
+        let res = if self.key_bits == 128 {
+            let as_int: u128 = inp.as_int128();
+            ((as_int << left_shift) >> (128 - num_bits)) as u64
+        } else {
+            let as_int: u64 = inp.as_int();
+            (as_int << left_shift) >> (64 - num_bits)
+        };
 
         if self.high {
             return u64::min(res, clamp);
@@ -114,7 +145,7 @@ impl Model for BalancedRadixModel {
     }
 
     fn input_type(&self) -> ModelDataType {
-        return ModelDataType::Int;
+        return if self.key_bits == 128 { ModelDataType::Int128 } else { ModelDataType::Int };
     }
     fn output_type(&self) -> ModelDataType {
         return ModelDataType::Int;
@@ -128,36 +159,79 @@ impl Model for BalancedRadixModel {
         ];
     }
 
+    fn serialized_params(&self) -> Vec<ModelParam> {
+        let mut params = self.params();
+        params.push(ModelParam::Int(self.high as u64));
+        params.push(self.key_bits.into());
+        return params;
+    }
+
     fn code(&self) -> String {
+        let (name, inp_type) = (self.function_name(), self.input_type().c_type());
         if self.high {
-            return String::from(
+            return format!(
                 "
-inline uint64_t bradix_clamp_high(uint64_t prefix_length, 
-                                  uint64_t bits, uint64_t clamp, uint64_t inp) {
-    uint64_t tmp = (inp << prefix_length) >> (64 - bits);
+inline uint64_t {}(uint64_t prefix_length, 
+                                  uint64_t bits, uint64_t clamp, {} inp) {{
+    uint64_t tmp = (inp << prefix_length) >> ({} - bits);
     return (tmp > clamp ? clamp : tmp);
     
-}
+}}
 ",
+                name, inp_type, self.key_bits
             );
         } else {
-            return String::from(
+            return format!(
                 "
-inline uint64_t bradix_clamp_low(uint64_t prefix_length,
-                                 uint64_t bits, uint64_t clamp, uint64_t inp) {
-    uint64_t tmp = (inp << prefix_length) >> (64 - bits);
+inline uint64_t {}(uint64_t prefix_length,
+                                 uint64_t bits, uint64_t clamp, {} inp) {{
+    uint64_t tmp = (inp << prefix_length) >> ({} - bits);
     return (tmp < clamp ? 0 : tmp - clamp);
-}
+}}
+",
+                name, inp_type, self.key_bits
+            );
+        }
+    }
+
+    fn rust_code(&self) -> String {
+        let (name, inp_type) = (self.function_name(), self.input_type().rust_type());
+        let tmp = if self.key_bits == 128 {
+            "((inp << prefix_length) >> (128 - bits)) as u64"
+        } else {
+            "(inp << prefix_length) >> (64 - bits)"
+        };
+        if self.high {
+            return format!(
+                "
+#[inline]
+fn {}(prefix_length: u64, bits: u64, clamp: u64, inp: {}) -> u64 {{
+    let tmp = {};
+    u64::min(tmp, clamp)
+}}
+",
+                name, inp_type, tmp
+            );
+        } else {
+            return format!(
+                "
+#[inline]
+fn {}(prefix_length: u64, bits: u64, clamp: u64, inp: {}) -> u64 {{
+    let tmp = {};
+    if tmp < clamp {{ 0 }} else {{ tmp - clamp }}
+}}
 ",
+                name, inp_type, tmp
             );
         }
     }
 
     fn function_name(&self) -> String {
+        let suffix = if self.key_bits == 128 { "128" } else { "" };
         return if self.high {
-            String::from("bradix_clamp_high")
+            format!("bradix_clamp_high{}", suffix)
         } else {
-            String::from("bradix_clamp_low")
+            format!("bradix_clamp_low{}", suffix)
         };
     }
 
@@ -175,7 +249,7 @@ mod tests {
 
     #[test]
     fn test_empty() {
-        BalancedRadixModel::new(&ModelData::empty());
+        BalancedRadixModel::new(&RMITrainingData::<u64>::empty());
     }
 
 }
@@ -24,10 +24,11 @@ fn cubic<T: TrainingKey>(data: &RMITrainingData<T>) -> (f64, f64, f64, f64) {
         return (0.0, 0.0, 0.0, data.get(0).1 as f64);
     }
 
-    // ensure we have at least two unique values
+    // ensure we have at least two unique values (as floats, since
+    // distinct 128-bit keys may round to the same float)
     {
-        let candidate = data.get(0).0;
-        let uniq = data.iter().any(|(x, _y)| x != candidate);
+        let candidate = data.get(0).0.as_float();
+        let uniq = data.iter().any(|(x, _y)| x.as_float() != candidate);
 
         if !uniq {
             // all the same value!
@@ -105,6 +106,13 @@ pub struct CubicSplineModel {
 }
 
 impl CubicSplineModel {
+    pub fn from_params(params: &[ModelParam]) -> Option<CubicSplineModel> {
+        return match params {
+            [ModelParam::Float(a), ModelParam::Float(b), ModelParam::Float(c), ModelParam::Float(d)] => Some(CubicSplineModel { params: (*a, *b, *c, *d) }),
+            _ => None
+        };
+    }
+
     pub fn new<T: TrainingKey>(data: &RMITrainingData<T>) -> CubicSplineModel {
         let cubic = CubicSplineModel {
             params: cubic(data),
@@ -178,11 +186,24 @@ inline double cubic(double a, double b, double c, double d, double x) {
         );
     }
 
+    fn rust_code(&self) -> String {
+        return String::from(
+            "
+#[inline]
+fn cubic(a: f64, b: f64, c: f64, d: f64, x: f64) -> f64 {
+    let v1 = a.mul_add(x, b);
+    let v2 = v1.mul_add(x, c);
+    v2.mul_add(x, d)
+}",
+        );
+    }
+
     fn function_name(&self) -> String {
         return String::from("cubic");
     }
+    // the spline is only bounded between the first and last training keys
     fn needs_bounds_check(&self) -> bool {
-        return false;
+        return true;
     }
 
     fn set_to_constant_model(&mut self, constant: u64) -> bool {
@@ -198,55 +219,71 @@ mod tests {
 
     #[test]
     fn test_cubic() {
-        let md = ModelData::IntKeyToIntPos(vec![(1, 2), (2, 3), (3, 8), (4, 20)]);
+        let md = RMITrainingData::new(Box::new(vec![(1u64, 2usize), (2, 3), (3, 8), (4, 20)]));
 
         let cubic_mod = CubicSplineModel::new(&md);
 
-        assert_abs_diff_eq!(cubic_mod.predict_to_float(1.into()), 2.0, epsilon = 0.5);
-        assert_abs_diff_eq!(cubic_mod.predict_to_float(4.into()), 20.0, epsilon = 0.5);
+        assert_abs_diff_eq!(cubic_mod.predict_to_float(&1u64.into()), 2.0, epsilon = 0.5);
+        assert_abs_diff_eq!(cubic_mod.predict_to_float(&4u64.into()), 20.0, epsilon = 0.5);
     }
 
     #[test]
     fn test_cubic2() {
-        let md = ModelData::IntKeyToIntPos(vec![(1, 2), (2, 3), (3, 8), (4, 20), (5, 80)]);
+        let md = RMITrainingData::new(Box::new(vec![(1u64, 2usize), (2, 3), (3, 8), (4, 20), (5, 80)]));
 
         let cubic_mod = CubicSplineModel::new(&md);
 
-        assert_abs_diff_eq!(cubic_mod.predict_to_float(1.into()), 2.0, epsilon = 0.5);
-        assert_abs_diff_eq!(cubic_mod.predict_to_float(5.into()), 80.0, epsilon = 0.5);
+        assert_abs_diff_eq!(cubic_mod.predict_to_float(&1u64.into()), 2.0, epsilon = 0.5);
+        assert_abs_diff_eq!(cubic_mod.predict_to_float(&5u64.into()), 80.0, epsilon = 0.5);
+    }
+
+    #[test]
+    fn test_cubic_extrapolation() {
+        // past the training keys the spline is extrapolated, so a cubic
+        // model above the leaves can predict a leaf that does not exist
+        let md = RMITrainingData::new(Box::new(vec![(10u64, 0usize), (20, 1), (30, 8), (40, 9)]));
+        let cubic_mod = CubicSplineModel::new(&md);
+        let far = cubic_mod.predict_to_float(&1000u64.into());
+        assert!(!(0.0..=9.0).contains(&far));
+        assert!(cubic_mod.needs_bounds_check());
+
+        let data: Vec<(u64, usize)> = (0..1000u64).map(|i| (i * i + 1000, i as usize)).collect();
+        let rmi = crate::train(&RMITrainingData::new(Box::new(data)), "cubic,linear", 16).unwrap();
+        assert!(rmi.lookup(0u64).0 < 1000);
+        assert!(rmi.lookup(u64::MAX).0 < 1000);
     }
 
     #[test]
     fn test_cubic_dup() {
-        let md = ModelData::IntKeyToIntPos(vec![(1, 2), (1, 2), (3, 8), (4, 20), (5, 80)]);
+        let md = RMITrainingData::new(Box::new(vec![(1u64, 2usize), (1, 2), (3, 8), (4, 20), (5, 80)]));
 
         let cubic_mod = CubicSplineModel::new(&md);
 
-        assert_abs_diff_eq!(cubic_mod.predict_to_float(1.into()), 2.0, epsilon = 0.5);
-        assert_abs_diff_eq!(cubic_mod.predict_to_float(5.into()), 80.0, epsilon = 0.5);
+        assert_abs_diff_eq!(cubic_mod.predict_to_float(&1u64.into()), 2.0, epsilon = 0.5);
+        assert_abs_diff_eq!(cubic_mod.predict_to_float(&5u64.into()), 80.0, epsilon = 0.5);
     }
 
     #[test]
     fn test_cubic_all_dup() {
-        let md = ModelData::IntKeyToIntPos(vec![(1, 2), (1, 2), (1, 2)]);
+        let md = RMITrainingData::new(Box::new(vec![(1u64, 2usize), (1, 2), (1, 2)]));
 
         let cubic_mod = CubicSplineModel::new(&md);
 
-        assert_abs_diff_eq!(cubic_mod.predict_to_float(1.into()), 2.0, epsilon = 0.5);
+        assert_abs_diff_eq!(cubic_mod.predict_to_float(&1u64.into()), 2.0, epsilon = 0.5);
     }
 
     #[test]
     fn test_linear_spline_single() {
-        let md = ModelData::IntKeyToIntPos(vec![(1, 2)]);
+        let md = RMITrainingData::new(Box::new(vec![(1u64, 2usize)]));
 
         let cubic_mod = CubicSplineModel::new(&md);
 
-        assert_eq!(cubic_mod.predict_to_int(1.into()), 2);
+        assert_eq!(cubic_mod.predict_to_int(&1u64.into()), 2);
     }
 
     #[test]
     fn test_empty() {
-        CubicSplineModel::new(&ModelData::empty());
+        CubicSplineModel::new(&RMITrainingData::<u64>::empty());
     }
 
 }
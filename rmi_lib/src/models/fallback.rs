@@ -0,0 +1,315 @@
+// < begin copyright > 
+// Copyright Ryan Marcus 2020
+// 
+// See root directory of this project for license terms.
+// 
+// < end copyright > 
+ 
+
+// Leaves that a learned model fits poorly can be replaced by a small
+// sorted sample of their keys, searched at lookup time like a B-tree node,
+// as in the hybrid indexes of the original learned index paper. Every
+// model on a layer shares one parameter layout, so once any leaf is
+// replaced, each leaf is wrapped in a `FallbackLeaf`. The nodes are kept
+// in arrays shared by the layer (see `Model::layer_arrays`), and each leaf
+// only stores where its node starts and how long it is: replaced leaves
+// search their node, and the others (with an empty node) defer to their
+// learned model.
+
+use crate::models::*;
+use crate::models::utils::{forwarded_arrays, forwarded_params};
+
+pub struct FallbackLeaf {
+    inner: Box<dyn Model>,
+    // the node is entries node_offset..node_offset + node_len of the
+    // arrays, and empty if the learned model is used
+    node_offset: usize,
+    node_len: usize,
+    node_keys: ParamArray<f64>,
+    node_positions: ParamArray<f64>
+}
+
+impl FallbackLeaf {
+    // Only models that take and return floats can be wrapped, since the
+    // node search compares keys as floats.
+    pub fn supports(model: &dyn Model) -> bool {
+        return matches!(model.input_type(), ModelDataType::Float)
+            && matches!(model.output_type(), ModelDataType::Float);
+    }
+
+    pub fn wrap(inner: Box<dyn Model>) -> FallbackLeaf {
+        assert!(FallbackLeaf::supports(inner.as_ref()));
+        return FallbackLeaf {
+            inner,
+            node_offset: 0,
+            node_len: 0,
+            node_keys: Vec::new().into(),
+            node_positions: Vec::new().into()
+        };
+    }
+
+    // The last two arrays hold the nodes, and the others belong to the
+    // wrapped model.
+    pub fn from_params(model_type: &str, params: &[ModelParam],
+                       arrays: &[ParamArray<f64>]) -> Option<FallbackLeaf> {
+        if params.len() < 2 || arrays.len() < 2 {
+            return None;
+        }
+        let (inner_params, node_params) = params.split_at(params.len() - 2);
+        let (inner_arrays, node_arrays) = arrays.split_at(arrays.len() - 2);
+
+        return match (node_params, node_arrays) {
+            ([ModelParam::Int(offset), ModelParam::Int(len)], [keys, positions]) => {
+                let node_offset = *offset as usize;
+                let node_len = *len as usize;
+                let end = node_offset.checked_add(node_len)?;
+                if end > keys.len() || keys.len() != positions.len() {
+                    return None;
+                }
+                let inner = learned_model_from_params(model_type, inner_params, inner_arrays)?;
+                Some(FallbackLeaf {
+                    inner,
+                    node_offset,
+                    node_len,
+                    node_keys: keys.clone(),
+                    node_positions: positions.clone()
+                })
+            },
+            _ => None
+        };
+    }
+
+    // Replaces the learned model with a node of at most `node_size`
+    // entries, sampled from the distinct keys of the leaf (with the
+    // position of their first occurrence). `end` is the position of the
+    // first key after the leaf. Each entry predicts the middle of the
+    // positions between its key and the next.
+    pub fn use_node(&mut self, keys: &[(f64, usize)], end: usize, node_size: usize) {
+        assert!(node_size > 0);
+        let len = usize::min(node_size, keys.len());
+        let entries: Vec<(f64, usize)> = (0..len).map(|i| keys[i * keys.len() / len]).collect();
+        let mut node_keys = Vec::with_capacity(len);
+        let mut node_positions = Vec::with_capacity(len);
+        for (i, (key, pos)) in entries.iter().enumerate() {
+            let next_pos = entries.get(i + 1).map(|entry| entry.1).unwrap_or(end);
+            node_keys.push(*key);
+            node_positions.push((*pos + next_pos) as f64 / 2.0);
+        }
+
+        self.node_offset = 0;
+        self.node_len = len;
+        self.node_keys = node_keys.into();
+        self.node_positions = node_positions.into();
+    }
+
+    pub fn into_inner(self) -> Box<dyn Model> {
+        return self.inner;
+    }
+
+    // Goes back to the learned model.
+    pub fn use_model(&mut self) {
+        self.node_len = 0;
+    }
+
+    fn node(&self) -> (&[f64], &[f64]) {
+        let range = self.node_offset..self.node_offset + self.node_len;
+        return (&self.node_keys[range.clone()], &self.node_positions[range]);
+    }
+}
+
+impl Model for FallbackLeaf {
+    fn predict_to_float(&self, inp: &ModelInput) -> f64 {
+        if self.node_len == 0 {
+            return self.inner.predict_to_float(inp);
+        }
+
+        // the last node entry at or before the key
+        let x = inp.as_float();
+        let (node_keys, node_positions) = self.node();
+        let idx = node_keys.partition_point(|k| *k <= x);
+        return node_positions[if idx == 0 { 0 } else { idx - 1 }];
+    }
+
+    fn input_type(&self) -> ModelDataType {
+        return ModelDataType::Float;
+    }
+    fn output_type(&self) -> ModelDataType {
+        return ModelDataType::Float;
+    }
+
+    fn params(&self) -> Vec<ModelParam> {
+        let mut params = self.inner.params();
+        params.push(ModelParam::Int(self.node_offset as u64));
+        params.push(ModelParam::Int(self.node_len as u64));
+        return params;
+    }
+
+    fn serialized_params(&self) -> Vec<ModelParam> {
+        let mut params = self.inner.serialized_params();
+        params.push(ModelParam::Int(self.node_offset as u64));
+        params.push(ModelParam::Int(self.node_len as u64));
+        return params;
+    }
+
+    fn code(&self) -> String {
+        let (decls, args) = forwarded_params(self.inner.as_ref(), false);
+        let (array_decls, array_args) = forwarded_arrays(self.inner.as_ref(), false);
+        return format!(
+            "inline double {}({}, uint64_t node_offset, uint64_t node_len, {}const double* node_keys,
+                  const double* node_positions, double inp) {{
+    if (node_len == 0) return {}({}{}inp);
+    node_keys += node_offset;
+    node_positions += node_offset;
+
+    // find the last node entry at or before inp
+    uint64_t lo = 0;
+    uint64_t hi = node_len;
+    while (lo < hi) {{
+        uint64_t mid = lo + (hi - lo) / 2;
+        if (node_keys[mid] <= inp) lo = mid + 1; else hi = mid;
+    }}
+    return node_positions[lo == 0 ? 0 : lo - 1];
+}}", self.function_name(), decls, array_decls, self.inner.function_name(), args, array_args);
+    }
+
+    fn rust_code(&self) -> String {
+        let (decls, args) = forwarded_params(self.inner.as_ref(), true);
+        let (array_decls, array_args) = forwarded_arrays(self.inner.as_ref(), true);
+        return format!(
+            "#[inline]
+#[allow(clippy::too_many_arguments)]
+fn {}({}, node_offset: u64, node_len: u64, {}node_keys: &[f64], node_positions: &[f64],
+       inp: f64) -> f64 {{
+    if node_len == 0 {{
+        return {}({}{}inp);
+    }}
+    let range = node_offset as usize..(node_offset + node_len) as usize;
+    let (node_keys, node_positions) = (&node_keys[range.clone()], &node_positions[range]);
+
+    // find the last node entry at or before inp
+    let idx = node_keys.partition_point(|k| *k <= inp);
+    node_positions[if idx == 0 {{ 0 }} else {{ idx - 1 }}]
+}}", self.function_name(), decls, array_decls, self.inner.function_name(), args, array_args);
+    }
+
+    fn function_name(&self) -> String {
+        return format!("{}_fallback", self.inner.function_name());
+    }
+
+    fn standard_functions(&self) -> HashSet<StdFunctions> {
+        return self.inner.standard_functions();
+    }
+
+    fn needs_bounds_check(&self) -> bool {
+        return self.inner.needs_bounds_check();
+    }
+    fn restriction(&self) -> ModelRestriction {
+        return self.inner.restriction();
+    }
+
+    fn set_to_constant_model(&mut self, constant: u64) -> bool {
+        self.node_len = 0;
+        return self.inner.set_to_constant_model(constant);
+    }
+
+    fn layer_arrays(&self) -> Vec<&[f64]> {
+        let (node_keys, node_positions) = self.node();
+        let mut arrays = self.inner.layer_arrays();
+        arrays.push(node_keys);
+        arrays.push(node_positions);
+        return arrays;
+    }
+    fn use_layer_arrays(&mut self, arrays: &[ParamArray<f64>], offsets: &[usize]) {
+        let n = arrays.len() - 2;
+        self.inner.use_layer_arrays(&arrays[..n], &offsets[..n]);
+        self.node_keys = arrays[n].clone();
+        self.node_positions = arrays[n + 1].clone();
+        self.node_offset = offsets[n];
+    }
+
+    fn wrapped_model(&self) -> Option<&dyn Model> {
+        return Some(self.inner.as_ref());
+    }
+    fn wrapper(&self) -> ModelWrapper {
+        return ModelWrapper::Fallback;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn linear_leaf() -> FallbackLeaf {
+        let data: Vec<(u64, usize)> = (0..100u64).map(|i| (2 * i, i as usize)).collect();
+        let md = RMITrainingData::new(Box::new(data));
+        return FallbackLeaf::wrap(Box::new(LinearModel::new(&md)));
+    }
+
+    #[test]
+    fn test_defers_to_model() {
+        let leaf = linear_leaf();
+        assert_eq!(leaf.node_len, 0);
+        assert_eq!(leaf.predict_to_int(&20u64.into()), 10);
+        assert_eq!(leaf.function_name(), "linear_fallback");
+        assert_eq!(leaf.params().len(), 4);
+
+        // leaves without a node add nothing to the layer arrays
+        assert_eq!(leaf.layer_arrays().len(), 2);
+        assert!(leaf.layer_arrays().iter().all(|array| array.is_empty()));
+    }
+
+    #[test]
+    fn test_node() {
+        let mut leaf = linear_leaf();
+        let keys: Vec<(f64, usize)> = (0..8).map(|i| ((i * i) as f64, 100 + i)).collect();
+        leaf.use_node(&keys, 108, 4);
+        assert_eq!(leaf.node_len, 4);
+
+        // entries for keys 0, 4, 16, and 36
+        assert_eq!(leaf.predict_to_float(&0u64.into()), 101.0);
+        assert_eq!(leaf.predict_to_float(&5u64.into()), 103.0);
+        assert_eq!(leaf.predict_to_float(&36u64.into()), 107.0);
+        assert_eq!(leaf.predict_to_float(&1000u64.into()), 107.0);
+
+        let arrays: Vec<ParamArray<f64>> = leaf.layer_arrays().iter()
+            .map(|array| array.to_vec().into())
+            .collect();
+        let restored = FallbackLeaf::from_params("linear", &leaf.serialized_params(), &arrays)
+            .unwrap();
+        assert_eq!(restored.predict_to_float(&5u64.into()), 103.0);
+        assert!(FallbackLeaf::from_params("linear", &leaf.serialized_params(), &[]).is_none());
+
+        leaf.use_model();
+        assert_eq!(leaf.predict_to_int(&20u64.into()), 10);
+    }
+
+    #[test]
+    fn test_shared_nodes() {
+        let keys: Vec<(f64, usize)> = (0..8).map(|i| ((i * i) as f64, 100 + i)).collect();
+        let mut leaves: Vec<Box<dyn Model>> = Vec::new();
+        for idx in 0..4 {
+            let mut leaf = linear_leaf();
+            if idx % 2 == 1 {
+                leaf.use_node(&keys, 108, 4);
+            }
+            leaves.push(Box::new(leaf));
+        }
+        share_layer_arrays(&mut leaves);
+
+        // only the leaves with a node have entries
+        let arrays = layer_arrays(&leaves);
+        assert_eq!(arrays[0].len(), 8);
+        assert!(matches!(leaves[3].params()[2], ModelParam::Int(4)));
+        assert_eq!(leaves[3].predict_to_float(&5u64.into()), 103.0);
+        assert_eq!(leaves[2].predict_to_int(&20u64.into()), 10);
+    }
+
+    #[test]
+    fn test_small_leaf() {
+        // leaves with fewer keys than the node size use every key
+        let mut leaf = linear_leaf();
+        leaf.use_node(&[(3.0, 7), (9.0, 8)], 9, 16);
+        assert_eq!(leaf.predict_to_float(&3.0f64.into()), 7.5);
+        assert_eq!(leaf.predict_to_float(&9.0f64.into()), 8.5);
+    }
+}
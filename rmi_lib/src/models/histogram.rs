@@ -11,16 +11,23 @@ use crate::models::utils::radix_index;
 use superslice::*;
 use log::*;
 
+// Histograms over 128-bit keys need 128-bit pivots.
+enum Pivots {
+    Narrow(ParamArray<u64>),
+    Wide(ParamArray<u128>)
+}
+
 pub struct EquidepthHistogramModel {
-    params: Vec<u64>,
-    radix: Vec<u64>
+    params: Pivots,
+    radix: ParamArray<u64>
 }
 
 
-fn equidepth_histogram<T: TrainingKey>(data: &RMITrainingData<T>) -> Vec<u64> {
+fn equidepth_histogram<T: TrainingKey, K>(data: &RMITrainingData<T>,
+                                          to_key: impl Fn(&T) -> K) -> Vec<K> {
     assert!(data.len() > 0);
     
-    let mut splits: Vec<u64> = Vec::new();
+    let mut splits: Vec<K> = Vec::new();
     let num_bins = data.get(data.len()-1).1 as usize;
     let items_per_bin = data.len() / num_bins;
 
@@ -29,7 +36,7 @@ fn equidepth_histogram<T: TrainingKey>(data: &RMITrainingData<T>) -> Vec<u64> {
     
     for bin_idx in 0..num_bins {
         let start_idx = bin_idx * items_per_bin;
-        let start_val = data.get_key(start_idx).as_uint();
+        let start_val = to_key(&data.get_key(start_idx));
         splits.push(start_val);
     }
 
@@ -39,15 +46,54 @@ fn equidepth_histogram<T: TrainingKey>(data: &RMITrainingData<T>) -> Vec<u64> {
 
 
 impl EquidepthHistogramModel {
+    pub fn from_params(params: &[ModelParam]) -> Option<EquidepthHistogramModel> {
+        return match params {
+            [ModelParam::Int(_), ModelParam::IntArray(radix), ModelParam::IntArray(pivots)] =>
+                Some(EquidepthHistogramModel {
+                    params: Pivots::Narrow(pivots.clone()),
+                    radix: radix.clone()
+                }),
+            [ModelParam::Int(_), ModelParam::IntArray(radix), ModelParam::Int128Array(pivots)] =>
+                Some(EquidepthHistogramModel {
+                    params: Pivots::Wide(pivots.clone()),
+                    radix: radix.clone()
+                }),
+            _ => None
+        };
+    }
+
     pub fn new<T: TrainingKey>(data: &RMITrainingData<T>) -> EquidepthHistogramModel {
+        let wide = data.key_type().bits() == 128;
         if data.len() == 0 {
-            return EquidepthHistogramModel { params: Vec::new(), radix: Vec::new() };
+            let params = if wide {
+                Pivots::Wide(Vec::new().into())
+            } else {
+                Pivots::Narrow(Vec::new().into())
+            };
+            return EquidepthHistogramModel { params, radix: Vec::new().into() };
         }
 
-        let params = equidepth_histogram(data);
-        let radix = radix_index(&params, 20);
+        if wide {
+            let params = equidepth_histogram(data, |k| k.as_uint128());
+            let radix = radix_index(&params, 128, 20);
+            return EquidepthHistogramModel {
+                params: Pivots::Wide(params.into()),
+                radix: radix.into()
+            };
+        }
+
+        let params = equidepth_histogram(data, |k| k.as_uint());
+        let radix = radix_index(&params, 64, 20);
         return EquidepthHistogramModel {
-            params, radix
+            params: Pivots::Narrow(params.into()),
+            radix: radix.into()
+        };
+    }
+
+    fn is_wide(&self) -> bool {
+        return match self.params {
+            Pivots::Narrow(_) => false,
+            Pivots::Wide(_) => true
         };
     }
 }
@@ -55,9 +101,13 @@ impl EquidepthHistogramModel {
 impl Model for EquidepthHistogramModel {
 
     fn predict_to_int(&self, inp: &ModelInput) -> u64 {
-        let val = inp.as_int();
-        
-        let val = self.params.upper_bound(&val) - 1;
+        let val = match &self.params {
+            Pivots::Narrow(pivots) => pivots.upper_bound(&inp.as_int()),
+            Pivots::Wide(pivots) => pivots.upper_bound(&inp.as_int128())
+        };
+
+        // keys before the first pivot belong to the first bin
+        let val = val.saturating_sub(1);
         return val as u64;
 
         /*for (idx, &split) in self.params.iter().enumerate() {
@@ -67,17 +117,41 @@ impl Model for EquidepthHistogramModel {
         return self.params.len() as u64 - 1;*/
     }
 
-    fn input_type(&self) -> ModelDataType { return ModelDataType::Int; }
+    fn input_type(&self) -> ModelDataType {
+        return if self.is_wide() { ModelDataType::Int128 } else { ModelDataType::Int };
+    }
     fn output_type(&self) -> ModelDataType { return ModelDataType::Int; }
 
     fn params(&self) -> Vec<ModelParam> {
-        return vec![
-            ModelParam::Int(self.params.len() as u64),
-            ModelParam::IntArray(self.radix.clone()),
-            ModelParam::IntArray(self.params.clone())
-        ];
+        return match &self.params {
+            Pivots::Narrow(pivots) => vec![
+                ModelParam::Int(pivots.len() as u64),
+                ModelParam::IntArray(self.radix.clone()),
+                ModelParam::IntArray(pivots.clone())
+            ],
+            Pivots::Wide(pivots) => vec![
+                ModelParam::Int(pivots.len() as u64),
+                ModelParam::IntArray(self.radix.clone()),
+                ModelParam::Int128Array(pivots.clone())
+            ]
+        };
     }
     fn code(&self) -> String {
+        if self.is_wide() {
+            return String::from("
+inline uint64_t ed_histogram128(const uint64_t length,
+                                const uint64_t radix[], 
+                                const unsigned __int128 pivots[], 
+                                unsigned __int128 key) {
+    uint64_t key_radix = key >> (128 - 20);
+    unsigned int radix_lb = radix[key_radix];
+    unsigned int radix_ub = radix[key_radix+1];
+    uint64_t li = bs_upper_bound128(pivots + radix_lb, radix_ub - radix_lb, key) + radix_lb;
+    return (li == 0 ? 0 : li - 1);
+}
+");
+        }
+
         return String::from("
 inline uint64_t ed_histogram(const uint64_t length,
                              const uint64_t radix[], 
@@ -86,19 +160,49 @@ inline uint64_t ed_histogram(const uint64_t length,
     uint64_t key_radix = key >> (64 - 20);
     unsigned int radix_lb = radix[key_radix];
     unsigned int radix_ub = radix[key_radix+1];
-    uint64_t li = bs_upper_bound(pivots + radix_lb, radix_ub - radix_lb, key) + radix_lb - 1;
-    return li;
+    uint64_t li = bs_upper_bound(pivots + radix_lb, radix_ub - radix_lb, key) + radix_lb;
+    return (li == 0 ? 0 : li - 1);
+}
+");
+    }
+
+    fn rust_code(&self) -> String {
+        if self.is_wide() {
+            return String::from("
+#[inline]
+fn ed_histogram128(_length: u64, radix: &[u64], pivots: &[u128], key: u128) -> u64 {
+    let key_radix = (key >> (128 - 20)) as usize;
+    let radix_lb = radix[key_radix] as usize;
+    let radix_ub = radix[key_radix + 1] as usize;
+    (bs_upper_bound128(&pivots[radix_lb..radix_ub], key) + radix_lb as u64).saturating_sub(1)
+}
+");
+        }
+
+        return String::from("
+#[inline]
+fn ed_histogram(_length: u64, radix: &[u64], pivots: &[u64], key: u64) -> u64 {
+    let key_radix = (key >> (64 - 20)) as usize;
+    let radix_lb = radix[key_radix] as usize;
+    let radix_ub = radix[key_radix + 1] as usize;
+    (bs_upper_bound(&pivots[radix_lb..radix_ub], key) + radix_lb as u64).saturating_sub(1)
 }
 ");
     }
 
     fn standard_functions(&self) -> HashSet<StdFunctions> {
         let mut to_r = HashSet::new();
-        to_r.insert(StdFunctions::BinarySearch);
+        if self.is_wide() {
+            to_r.insert(StdFunctions::BinarySearch128);
+        } else {
+            to_r.insert(StdFunctions::BinarySearch);
+        }
         return to_r;
     }
 
-    fn function_name(&self) -> String { return String::from("ed_histogram"); }
+    fn function_name(&self) -> String {
+        return String::from(if self.is_wide() { "ed_histogram128" } else { "ed_histogram" });
+    }
     fn restriction(&self) -> ModelRestriction { return ModelRestriction::MustBeTop; }
     fn needs_bounds_check(&self) -> bool { return false; }
 }
@@ -109,26 +213,40 @@ mod tests {
     
     #[test]
     fn test_ed_hist1() {
-        let mut test_data: Vec<(u64, u64)> = Vec::new();
+        let mut test_data: Vec<(u64, usize)> = Vec::new();
 
         for i in 0..1000 {
-            test_data.push((i*3, i/3));
+            test_data.push((i*3, i as usize/3));
         }
         
-        let md = ModelData::IntKeyToIntPos(test_data);
+        let md = RMITrainingData::new(Box::new(test_data));
 
         let ed_mod = EquidepthHistogramModel::new(&md);
 
-        assert_eq!(ed_mod.predict_to_int((0).into()), 0);
-        assert_eq!(ed_mod.predict_to_int((1*3).into()), 0);
-        assert_eq!(ed_mod.predict_to_int((4*3).into()), 1);
-        assert_eq!(ed_mod.predict_to_int((500*3).into()), 166);
-        assert_eq!(ed_mod.predict_to_int((5000*3).into()), 333);
+        assert_eq!(ed_mod.predict_to_int(&0u64.into()), 0);
+        assert_eq!(ed_mod.predict_to_int(&(1u64*3).into()), 0);
+        assert_eq!(ed_mod.predict_to_int(&(4u64*3).into()), 1);
+        assert_eq!(ed_mod.predict_to_int(&(500u64*3).into()), 166);
+        // the last position (333) is the number of bins, so keys past the
+        // end fall in bin 332
+        assert_eq!(ed_mod.predict_to_int(&(5000u64*3).into()), 332);
+    }
+
+    #[test]
+    fn test_before_first_pivot() {
+        // keys smaller than the first training key belong to the first bin
+        let test_data: Vec<(u64, usize)> = (10..1000u64).map(|i| (i * 3, i as usize / 3)).collect();
+        let ed_mod = EquidepthHistogramModel::new(&RMITrainingData::new(Box::new(test_data)));
+        assert_eq!(ed_mod.predict_to_int(&5u64.into()), 0);
+
+        let test_data: Vec<(u128, usize)> = (10..1000u128).map(|i| (i << 80, i as usize / 3)).collect();
+        let ed_mod = EquidepthHistogramModel::new(&RMITrainingData::new(Box::new(test_data)));
+        assert_eq!(ed_mod.predict_to_int(&5u128.into()), 0);
     }
 
     #[test]
     fn test_empty() {
-        EquidepthHistogramModel::new(&ModelData::empty());
+        EquidepthHistogramModel::new(&RMITrainingData::<u64>::empty());
     }
 
 }
@@ -76,6 +76,13 @@ pub struct LinearModel {
 }
 
 impl LinearModel {
+    pub fn from_params(params: &[ModelParam]) -> Option<LinearModel> {
+        return match params {
+            [ModelParam::Float(a), ModelParam::Float(b)] => Some(LinearModel { params: (*a, *b) }),
+            _ => None
+        };
+    }
+
     pub fn new<T: TrainingKey>(data: &RMITrainingData<T>) -> LinearModel {
         let params = slr(data.iter()
                          .map(|(inp, offset)| (inp.as_float(), offset as f64)));
@@ -109,6 +116,16 @@ inline double linear(double alpha, double beta, double inp) {
         );
     }
 
+    fn rust_code(&self) -> String {
+        return String::from(
+            "
+#[inline]
+fn linear(alpha: f64, beta: f64, inp: f64) -> f64 {
+    beta.mul_add(inp, alpha)
+}",
+        );
+    }
+
     fn function_name(&self) -> String {
         return String::from("linear");
     }
@@ -125,26 +142,26 @@ mod tests {
 
     #[test]
     fn test_linear1() {
-        let md = ModelData::IntKeyToIntPos(vec![(1, 2), (2, 3), (3, 4)]);
+        let md = RMITrainingData::new(Box::new(vec![(1u64, 2usize), (2, 3), (3, 4)]));
 
         let lin_mod = LinearModel::new(&md);
 
-        assert_eq!(lin_mod.predict_to_int(1.into()), 2);
-        assert_eq!(lin_mod.predict_to_int(6.into()), 7);
+        assert_eq!(lin_mod.predict_to_int(&1u64.into()), 2);
+        assert_eq!(lin_mod.predict_to_int(&6u64.into()), 7);
     }
 
     #[test]
     fn test_linear_single() {
-        let md = ModelData::IntKeyToIntPos(vec![(1, 2)]);
+        let md = RMITrainingData::new(Box::new(vec![(1u64, 2usize)]));
 
         let lin_mod = LinearModel::new(&md);
 
-        assert_eq!(lin_mod.predict_to_int(1.into()), 2);
+        assert_eq!(lin_mod.predict_to_int(&1u64.into()), 2);
     }
 
     #[test]
     fn test_empty() {
-        LinearModel::new(&ModelData::empty());
+        LinearModel::new(&RMITrainingData::<u64>::empty());
     }
 
 }
@@ -166,6 +183,13 @@ fn exp1(inp: f64) -> f64 {
 }
 
 impl LogLinearModel {
+    pub fn from_params(params: &[ModelParam]) -> Option<LogLinearModel> {
+        return match params {
+            [ModelParam::Float(a), ModelParam::Float(b)] => Some(LogLinearModel { params: (*a, *b) }),
+            _ => None
+        };
+    }
+
     pub fn new<T: TrainingKey>(data: &RMITrainingData<T>) -> LogLinearModel {
         return LogLinearModel {
             params: loglinear_slr(&data),
@@ -199,6 +223,16 @@ inline double loglinear(double alpha, double beta, double inp) {
         );
     }
 
+    fn rust_code(&self) -> String {
+        return String::from(
+            "
+#[inline]
+fn loglinear(alpha: f64, beta: f64, inp: f64) -> f64 {
+    exp1(beta.mul_add(inp, alpha))
+}",
+        );
+    }
+
     fn function_name(&self) -> String {
         return String::from("loglinear");
     }
@@ -215,17 +249,17 @@ mod loglin_tests {
 
     #[test]
     fn test_loglinear1() {
-        let md = ModelData::IntKeyToIntPos(vec![(2, 2), (3, 4), (4, 16)]);
+        let md = RMITrainingData::new(Box::new(vec![(2u64, 2usize), (3, 4), (4, 16)]));
 
         let loglin_mod = LogLinearModel::new(&md);
 
-        assert_eq!(loglin_mod.predict_to_int(2.into()), 1);
-        assert_eq!(loglin_mod.predict_to_int(4.into()), 13);
+        assert_eq!(loglin_mod.predict_to_int(&2u64.into()), 1);
+        assert_eq!(loglin_mod.predict_to_int(&4u64.into()), 13);
     }
 
     #[test]
     fn test_empty() {
-        LogLinearModel::new(&ModelData::empty());
+        LogLinearModel::new(&RMITrainingData::<u64>::empty());
     }
 }
 
@@ -236,6 +270,13 @@ pub struct RobustLinearModel {
 
 
 impl RobustLinearModel {
+    pub fn from_params(params: &[ModelParam]) -> Option<RobustLinearModel> {
+        return match params {
+            [ModelParam::Float(a), ModelParam::Float(b)] => Some(RobustLinearModel { params: (*a, *b) }),
+            _ => None
+        };
+    }
+
     pub fn new<T: TrainingKey>(data: &RMITrainingData<T>) -> RobustLinearModel {
         let total_items = data.len();
         if data.len() == 0 {
@@ -245,7 +286,13 @@ impl RobustLinearModel {
         }
         
         let bnd = usize::max(1, ((total_items as f64) * 0.0001) as usize);
-        assert!(bnd*2+1 < data.len());
+        if bnd*2+1 >= data.len() {
+            // too few items to trim any outliers, use all of them
+            return RobustLinearModel {
+                params: slr(data.iter()
+                            .map(|(inp, offset)| (inp.as_float(), offset as f64)))
+            };
+        }
         
         let iter = data.iter()
             .skip(bnd)
@@ -286,6 +333,16 @@ inline double linear(double alpha, double beta, double inp) {
         );
     }
     
+    fn rust_code(&self) -> String {
+        return String::from(
+            "
+#[inline]
+fn linear(alpha: f64, beta: f64, inp: f64) -> f64 {
+    beta.mul_add(inp, alpha)
+}",
+        );
+    }
+
     fn function_name(&self) -> String {
         return String::from("linear");
     }
@@ -39,6 +39,13 @@ pub struct LinearSplineModel {
 }
 
 impl LinearSplineModel {
+    pub fn from_params(params: &[ModelParam]) -> Option<LinearSplineModel> {
+        return match params {
+            [ModelParam::Float(a), ModelParam::Float(b)] => Some(LinearSplineModel { params: (*a, *b) }),
+            _ => None
+        };
+    }
+
     pub fn new<T: TrainingKey>(data: &RMITrainingData<T>) -> LinearSplineModel {
         return LinearSplineModel {
             params: linear_splines(data),
@@ -72,6 +79,16 @@ inline double linear(double alpha, double beta, double inp) {
         );
     }
 
+    fn rust_code(&self) -> String {
+        return String::from(
+            "
+#[inline]
+fn linear(alpha: f64, beta: f64, inp: f64) -> f64 {
+    beta.mul_add(inp, alpha)
+}",
+        );
+    }
+
     fn function_name(&self) -> String {
         return String::from("linear");
     }
@@ -88,26 +105,26 @@ mod tests {
 
     #[test]
     fn test_linear_spline1() {
-        let md = ModelData::IntKeyToIntPos(vec![(1, 2), (2, 3), (3, 8)]);
+        let md = RMITrainingData::new(Box::new(vec![(1u64, 2usize), (2, 3), (3, 8)]));
 
         let lin_mod = LinearSplineModel::new(&md);
 
-        assert_eq!(lin_mod.predict_to_int(1.into()), 2);
-        assert_eq!(lin_mod.predict_to_int(3.into()), 8);
+        assert_eq!(lin_mod.predict_to_int(&1u64.into()), 2);
+        assert_eq!(lin_mod.predict_to_int(&3u64.into()), 8);
     }
 
     #[test]
     fn test_linear_spline_single() {
-        let md = ModelData::IntKeyToIntPos(vec![(1, 2)]);
+        let md = RMITrainingData::new(Box::new(vec![(1u64, 2usize)]));
 
         let lin_mod = LinearSplineModel::new(&md);
 
-        assert_eq!(lin_mod.predict_to_int(1.into()), 2);
+        assert_eq!(lin_mod.predict_to_int(&1u64.into()), 2);
     }
 
     #[test]
     fn test_empty() {
-        LinearSplineModel::new(&ModelData::empty());
+        LinearSplineModel::new(&RMITrainingData::<u64>::empty());
     }
 
 }
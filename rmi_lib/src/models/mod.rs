@@ -9,16 +9,23 @@
 
 mod balanced_radix;
 mod cubic_spline;
+mod fallback;
 mod histogram;
 mod linear;
 mod linear_spline;
 mod normal;
+mod param_array;
+mod pgm;
+mod polynomial;
 mod radix;
+mod radix_spline;
+mod remap;
 mod stdlib;
 mod utils;
 
 pub use balanced_radix::BalancedRadixModel;
 pub use cubic_spline::CubicSplineModel;
+pub use fallback::FallbackLeaf;
 pub use histogram::EquidepthHistogramModel;
 pub use linear::LinearModel;
 pub use linear::RobustLinearModel;
@@ -26,10 +33,16 @@ pub use linear::LogLinearModel;
 pub use linear_spline::LinearSplineModel;
 pub use normal::LogNormalModel;
 pub use normal::NormalModel;
+pub use param_array::ParamArray;
+pub use pgm::{PGMModel, pgm_epsilon};
+pub use polynomial::PolynomialModel;
 pub use radix::RadixModel;
 pub use radix::RadixTable;
+pub use radix_spline::{RadixSplineModel, radix_spline_params};
+pub use remap::RemappedModel;
 pub use stdlib::StdFunctions;
 
+use crate::error::RmiError;
 use std::cmp::Ordering;
 use std::collections::HashSet;
 use std::sync::Arc;
@@ -37,9 +50,9 @@ use std::io::Write;
 use byteorder::{WriteBytesExt, LittleEndian};
 
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum KeyType {
-    U32, U64, F64, U128
+    U32, U64, F32, F64, U128, I32, I64, Str
 }
 
 impl KeyType {
@@ -47,8 +60,34 @@ impl KeyType {
         match self {
             KeyType::U32 => "uint32_t",
             KeyType::U64 => "uint64_t",
+            KeyType::F32 => "float",
             KeyType::F64 => "double",
-            KeyType::U128 => "uint128_t"
+            KeyType::U128 => "unsigned __int128",
+            KeyType::I32 => "int32_t",
+            KeyType::I64 => "int64_t",
+            KeyType::Str => "unsigned __int128"
+        }
+    }
+
+    pub fn rust_type(&self) -> &'static str {
+        match self {
+            KeyType::U32 => "u32",
+            KeyType::U64 => "u64",
+            KeyType::F32 => "f32",
+            KeyType::F64 => "f64",
+            KeyType::U128 => "u128",
+            KeyType::I32 => "i32",
+            KeyType::I64 => "i64",
+            KeyType::Str => "u128"
+        }
+    }
+
+    // width of the integer that radix-style models shift keys within
+    pub fn bits(&self) -> u8 {
+        match self {
+            KeyType::U32 | KeyType::U64 | KeyType::F32 | KeyType::F64 => 64,
+            KeyType::I32 | KeyType::I64 => 64,
+            KeyType::U128 | KeyType::Str => 128
         }
     }
 
@@ -57,9 +96,17 @@ impl KeyType {
             KeyType::U32 => ModelDataType::Int,
             KeyType::U64 => ModelDataType::Int,
             KeyType::U128 => ModelDataType::Int128,
-            KeyType::F64 => ModelDataType::Float
+            KeyType::F32 => ModelDataType::Float,
+            KeyType::F64 => ModelDataType::Float,
+            KeyType::I32 => ModelDataType::Int,
+            KeyType::I64 => ModelDataType::Int,
+            KeyType::Str => ModelDataType::Int128
         }
     }
+
+    pub fn is_signed(&self) -> bool {
+        return matches!(self, KeyType::I32 | KeyType::I64);
+    }
 }
 
 pub trait TrainingKey: PartialEq + Copy + Send + Sync + std::fmt::Debug + 'static {
@@ -67,9 +114,11 @@ pub trait TrainingKey: PartialEq + Copy + Send + Sync + std::fmt::Debug + 'stati
     fn zero_value() -> Self;
     fn plus_epsilon(&self) -> Self;
     fn max_value() -> Self;
+    fn key_type() -> KeyType;
 
     fn as_float(&self) -> f64;
     fn as_uint(&self) -> u64;
+    fn as_uint128(&self) -> u128;
     
     fn to_model_input(&self) -> ModelInput;
 }
@@ -79,9 +128,11 @@ impl TrainingKey for u64 {
     fn zero_value() -> Self { 0 }
     fn plus_epsilon(&self) -> Self { *self + 1 }
     fn max_value() -> Self { std::u64::MAX }
+    fn key_type() -> KeyType { KeyType::U64 }
 
     fn as_float(&self) -> f64 { *self as f64 }
     fn as_uint(&self) -> u64 { *self }
+    fn as_uint128(&self) -> u128 { *self as u128 }
     
     fn to_model_input(&self) -> ModelInput { (*self).into() }
 }
@@ -91,9 +142,11 @@ impl TrainingKey for u32 {
     fn zero_value() -> Self { 0 }
     fn plus_epsilon(&self) -> Self { *self + 1 }
     fn max_value() -> Self { std::u32::MAX }
+    fn key_type() -> KeyType { KeyType::U32 }
 
     fn as_float(&self) -> f64 { *self as f64 }
     fn as_uint(&self) -> u64 { *self as u64 }
+    fn as_uint128(&self) -> u128 { *self as u128 }
     
     fn to_model_input(&self) -> ModelInput { (*self).into() }
 }
@@ -103,9 +156,74 @@ impl TrainingKey for f64 {
     fn zero_value() -> Self { 0.0 }
     fn plus_epsilon(&self) -> Self { *self + std::f64::EPSILON }
     fn max_value() -> Self { std::f64::MAX }
+    fn key_type() -> KeyType { KeyType::F64 }
 
     fn as_float(&self) -> f64 { *self }
     fn as_uint(&self) -> u64 { *self as u64 }
+    fn as_uint128(&self) -> u128 { *self as u128 }
+    
+    fn to_model_input(&self) -> ModelInput { (*self).into() }
+}
+
+impl TrainingKey for f32 {
+    fn minus_epsilon(&self) -> Self { *self - std::f32::EPSILON }
+    fn zero_value() -> Self { 0.0 }
+    fn plus_epsilon(&self) -> Self { *self + std::f32::EPSILON }
+    fn max_value() -> Self { std::f32::MAX }
+    fn key_type() -> KeyType { KeyType::F32 }
+
+    fn as_float(&self) -> f64 { *self as f64 }
+    fn as_uint(&self) -> u64 { *self as u64 }
+    fn as_uint128(&self) -> u128 { *self as u128 }
+    
+    fn to_model_input(&self) -> ModelInput { (*self as f64).into() }
+}
+
+// Signed keys are mapped to unsigned integers by flipping the sign bit,
+// which preserves their order (see `ModelInput::SignedInt`).
+fn flip_sign_bit(x: i64) -> u64 {
+    return (x as u64) ^ (1 << 63);
+}
+
+impl TrainingKey for i64 {
+    fn minus_epsilon(&self) -> Self { *self - 1 }
+    // the smallest key, so that it sorts before every other key
+    fn zero_value() -> Self { std::i64::MIN }
+    fn plus_epsilon(&self) -> Self { *self + 1 }
+    fn max_value() -> Self { std::i64::MAX }
+    fn key_type() -> KeyType { KeyType::I64 }
+
+    fn as_float(&self) -> f64 { *self as f64 }
+    fn as_uint(&self) -> u64 { flip_sign_bit(*self) }
+    fn as_uint128(&self) -> u128 { flip_sign_bit(*self) as u128 }
+    
+    fn to_model_input(&self) -> ModelInput { (*self).into() }
+}
+
+impl TrainingKey for i32 {
+    fn minus_epsilon(&self) -> Self { *self - 1 }
+    fn zero_value() -> Self { std::i32::MIN }
+    fn plus_epsilon(&self) -> Self { *self + 1 }
+    fn max_value() -> Self { std::i32::MAX }
+    fn key_type() -> KeyType { KeyType::I32 }
+
+    fn as_float(&self) -> f64 { *self as f64 }
+    fn as_uint(&self) -> u64 { flip_sign_bit(*self as i64) }
+    fn as_uint128(&self) -> u128 { flip_sign_bit(*self as i64) as u128 }
+    
+    fn to_model_input(&self) -> ModelInput { ModelInput::SignedInt(*self as i64) }
+}
+
+impl TrainingKey for u128 {
+    fn minus_epsilon(&self) -> Self { *self - 1 }
+    fn zero_value() -> Self { 0 }
+    fn plus_epsilon(&self) -> Self { *self + 1 }
+    fn max_value() -> Self { std::u128::MAX }
+    fn key_type() -> KeyType { KeyType::U128 }
+
+    fn as_float(&self) -> f64 { *self as f64 }
+    fn as_uint(&self) -> u64 { *self as u64 }
+    fn as_uint128(&self) -> u128 { *self }
     
     fn to_model_input(&self) -> ModelInput { (*self).into() }
 }
@@ -119,6 +237,16 @@ pub trait RMITrainingDataIteratorProvider: Send + Sync {
     fn get(&self, idx: usize) -> Option<(Self::InpType, usize)> {
         return Some(self.cdf_iter().nth(idx).unwrap());
     }
+
+    // the common prefix removed from string keys before encoding them
+    fn key_prefix(&self) -> &[u8] { return &[]; }
+
+    // one more than the largest position. Positions are usually 0..len,
+    // but may be sparse (e.g., byte offsets or page numbers).
+    fn target_range(&self) -> usize {
+        if self.len() == 0 { return 0; }
+        return self.get(self.len() - 1).unwrap().1 + 1;
+    }
 }
 
 impl <K: TrainingKey> RMITrainingDataIteratorProvider for Vec<(K, usize)> {
@@ -133,7 +261,7 @@ impl <K: TrainingKey> RMITrainingDataIteratorProvider for Vec<(K, usize)> {
                         .map(|(key, offset)| (key.into(), offset)));
     }
 
-    fn key_type(&self) -> KeyType { return KeyType::U64; }
+    fn key_type(&self) -> KeyType { return K::key_type(); }
     fn get(&self, idx: usize) -> Option<(Self::InpType, usize)> {
         self.as_slice().get(idx).map(|(key, offset)| ((*key).into(), *offset))
     }
@@ -177,7 +305,7 @@ impl <K, T> Iterator for FixDupsIter<K, T> where
                             return Some(nxt);
                         }
                     }
-                    None => { self.last_item.take() }
+                    None => { return None; }
                 }
             }
         }
@@ -261,6 +389,12 @@ impl <T: TrainingKey> RMITrainingData<T> {
 
     pub fn len(&self) -> usize { return self.iterable.len(); }
 
+    pub fn key_type(&self) -> KeyType { return self.iterable.key_type(); }
+
+    pub fn key_prefix(&self) -> &[u8] { return self.iterable.key_prefix(); }
+
+    pub fn target_range(&self) -> usize { return self.iterable.target_range(); }
+
     pub fn set_scale(&mut self, scale: f64) {
         self.scale = scale;
     }
@@ -308,6 +442,25 @@ impl <T: TrainingKey> RMITrainingData<T> {
         base + (cmp == Ordering::Less) as usize
     }
     
+    // RMIs can only be trained on sorted keys with non-decreasing
+    // positions, and the training code assumes they are without checking
+    pub fn check_sorted(&self) -> Result<(), RmiError> {
+        let mut last: Option<(ModelInput, usize)> = None;
+        for (idx, (key, pos)) in self.iterable.cdf_iter().enumerate() {
+            let key = key.to_model_input();
+            if let Some((last_key, last_pos)) = last {
+                if !(last_key <= key) {
+                    return Err(RmiError::UnsortedData { index: idx });
+                }
+                if pos < last_pos {
+                    return Err(RmiError::UnsortedPositions { index: idx });
+                }
+            }
+            last = Some((key, pos));
+        }
+        return Ok(());
+    }
+
     pub fn soft_copy(&self) -> RMITrainingData<T> {
         return RMITrainingData {
             scale: self.scale,
@@ -371,25 +524,21 @@ impl PartialOrd for ModelInput {
 #[derive(Clone, Copy, Debug)]
 pub enum ModelInput {
     Int(u64),
+    Int128(u128),
+    // models that need an integer see signed inputs with their sign bit
+    // flipped, so that negative keys sort before positive ones
+    SignedInt(i64),
     Float(f64),
 }
 
 impl PartialEq for ModelInput {
     fn eq(&self, other: &Self) -> bool {
-        match self {
-            ModelInput::Int(x) => {
-                match other {
-                    ModelInput::Int(y) => x == y,
-                    ModelInput::Float(_) => false
-                }
-            }
-
-            ModelInput::Float(x) => {
-                match other {
-                    ModelInput::Int(_) => false,
-                    ModelInput::Float(y) => x == y // exact equality is intentional
-                }
-            }
+        match (self, other) {
+            (ModelInput::Int(x), ModelInput::Int(y)) => x == y,
+            (ModelInput::Int128(x), ModelInput::Int128(y)) => x == y,
+            (ModelInput::SignedInt(x), ModelInput::SignedInt(y)) => x == y,
+            (ModelInput::Float(x), ModelInput::Float(y)) => x == y, // exact equality is intentional
+            _ => false
         }
     }
 }
@@ -398,19 +547,12 @@ impl Eq for ModelInput { }
 
 impl PartialOrd for ModelInput {
     fn partial_cmp(&self, other: &ModelInput) -> Option<Ordering> {
-        match self {
-            ModelInput::Int(x) => {
-                match other {
-                    ModelInput::Int(y) => x.partial_cmp(y),
-                    ModelInput::Float(_) => None
-                }
-            }
-            ModelInput::Float(x) => {
-                match other {
-                    ModelInput::Int(_) => None,
-                    ModelInput::Float(y) => x.partial_cmp(y) 
-                }
-            }
+        match (self, other) {
+            (ModelInput::Int(x), ModelInput::Int(y)) => x.partial_cmp(y),
+            (ModelInput::Int128(x), ModelInput::Int128(y)) => x.partial_cmp(y),
+            (ModelInput::SignedInt(x), ModelInput::SignedInt(y)) => x.partial_cmp(y),
+            (ModelInput::Float(x), ModelInput::Float(y)) => x.partial_cmp(y),
+            _ => None
         }
     }
 }
@@ -421,45 +563,64 @@ impl ModelInput {
     pub fn as_float(&self) -> f64 {
         return match self {
             ModelInput::Int(x) => *x as f64,
+            ModelInput::Int128(x) => *x as f64,
+            ModelInput::SignedInt(x) => *x as f64,
             ModelInput::Float(x) => *x,
         };
     }
 
+    // 128-bit inputs are truncated, like the `(uint64_t) key` cast in the
+    // generated code
     pub fn as_int(&self) -> u64 {
         return match self {
             ModelInput::Int(x) => *x,
+            ModelInput::Int128(x) => *x as u64,
+            ModelInput::SignedInt(x) => flip_sign_bit(*x),
             ModelInput::Float(x) => *x as u64,
         };
     }
 
+    pub fn as_int128(&self) -> u128 {
+        return match self {
+            ModelInput::Int(x) => *x as u128,
+            ModelInput::Int128(x) => *x,
+            ModelInput::SignedInt(x) => flip_sign_bit(*x) as u128,
+            ModelInput::Float(x) => *x as u128,
+        };
+    }
+
     pub fn max_value(&self) -> ModelInput {
         return match self {
             ModelInput::Int(_) => std::u64::MAX.into(),
+            ModelInput::Int128(_) => std::u128::MAX.into(),
+            ModelInput::SignedInt(_) => std::i64::MAX.into(),
             ModelInput::Float(_) => std::f64::MAX.into()
         };
     }
 
     pub fn min_value(&self) -> ModelInput {
         return match self {
-            ModelInput::Int(_) => 0.into(),
+            ModelInput::Int(_) => 0u64.into(),
+            ModelInput::Int128(_) => 0u128.into(),
+            ModelInput::SignedInt(_) => std::i64::MIN.into(),
             ModelInput::Float(_) => std::f64::MIN.into()
         };
     }
 
     pub fn minus_epsilon(&self) -> ModelInput {
         return match self {
-            ModelInput::Int(x) => if *x > 0 { (x - 1).into() } else { 0.into() }
+            ModelInput::Int(x) => x.saturating_sub(1).into(),
+            ModelInput::Int128(x) => x.saturating_sub(1).into(),
+            ModelInput::SignedInt(x) => x.saturating_sub(1).into(),
             ModelInput::Float(x) => (x - std::f64::EPSILON).into()
         };
     }
 
     pub fn plus_epsilon(&self) -> ModelInput {
         return match self {
-            ModelInput::Int(x) => if *x < std::u64::MAX {
-                (x + 1).into()
-            } else {
-                std::u64::MAX.into()
-            }
+            ModelInput::Int(x) => x.saturating_add(1).into(),
+            ModelInput::Int128(x) => x.saturating_add(1).into(),
+            ModelInput::SignedInt(x) => x.saturating_add(1).into(),
             ModelInput::Float(x) => (x + std::f64::EPSILON).into()
         };
     }
@@ -471,12 +632,24 @@ impl From<u64> for ModelInput {
     }
 }
 
+impl From<u128> for ModelInput {
+    fn from(i: u128) -> Self {
+        ModelInput::Int128(i)
+    }
+}
+
 impl From<u32> for ModelInput {
     fn from(i: u32) -> Self {
         ModelInput::Int(i as u64)
     }
 }
 
+impl From<i64> for ModelInput {
+    fn from(i: i64) -> Self {
+        ModelInput::SignedInt(i)
+    }
+}
+
 impl From<i32> for ModelInput {
     fn from(i: i32) -> Self {
         assert!(i >= 0);
@@ -500,20 +673,29 @@ impl ModelDataType {
     pub fn c_type(&self) -> &'static str {
         match self {
             ModelDataType::Int => "uint64_t",
-            ModelDataType::Int128 => "uint128_t",
+            ModelDataType::Int128 => "unsigned __int128",
             ModelDataType::Float => "double",
         }
     }
+
+    pub fn rust_type(&self) -> &'static str {
+        match self {
+            ModelDataType::Int => "u64",
+            ModelDataType::Int128 => "u128",
+            ModelDataType::Float => "f64",
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub enum ModelParam {
     Int(u64),
     Float(f64),
-    ShortArray(Vec<u16>),
-    IntArray(Vec<u64>),
-    Int32Array(Vec<u32>),
-    FloatArray(Vec<f64>),
+    ShortArray(ParamArray<u16>),
+    IntArray(ParamArray<u64>),
+    Int32Array(ParamArray<u32>),
+    Int128Array(ParamArray<u128>),
+    FloatArray(ParamArray<f64>),
 }
 
 impl ModelParam {
@@ -525,6 +707,7 @@ impl ModelParam {
             ModelParam::ShortArray(a) => 2 * a.len(),
             ModelParam::IntArray(a) => 8 * a.len(),
             ModelParam::Int32Array(a) => 4 * a.len(),
+            ModelParam::Int128Array(a) => 16 * a.len(),
             ModelParam::FloatArray(a) => 8 * a.len(),
         }
     }
@@ -536,6 +719,7 @@ impl ModelParam {
             ModelParam::ShortArray(_) => "short",
             ModelParam::IntArray(_) => "uint64_t",
             ModelParam::Int32Array(_) => "uint32_t",
+            ModelParam::Int128Array(_) => "unsigned __int128",
             ModelParam::FloatArray(_) => "double",
         }
     }
@@ -547,6 +731,7 @@ impl ModelParam {
             ModelParam::ShortArray(_) => true,
             ModelParam::IntArray(_) => true,
             ModelParam::Int32Array(_) => true,
+            ModelParam::Int128Array(_) => true,
             ModelParam::FloatArray(_) => true
         }
     }
@@ -558,6 +743,7 @@ impl ModelParam {
             ModelParam::ShortArray(_) => "[]",
             ModelParam::IntArray(_) => "[]",
             ModelParam::Int32Array(_) => "[]",
+            ModelParam::Int128Array(_) => "[]",
             ModelParam::FloatArray(_) => "[]",
         }
     }
@@ -584,6 +770,14 @@ impl ModelParam {
                 let itms: Vec<String> = arr.iter().map(|i| format!("{}UL", i)).collect();
                 return format!("{{ {} }}", itms.join(", "));
             },
+            ModelParam::Int128Array(arr) => {
+                // C++ has no 128-bit integer literals
+                let itms: Vec<String> = arr.iter()
+                    .map(|i| format!("((unsigned __int128) {}UL << 64 | {}UL)",
+                                     i >> 64, *i as u64))
+                    .collect();
+                return format!("{{ {} }}", itms.join(", "));
+            },
             ModelParam::FloatArray(arr) => {
                 let itms: Vec<String> = arr
                     .iter()
@@ -595,6 +789,50 @@ impl ModelParam {
         }
     }
 
+    // for arrays, the type of each element
+    pub fn rust_type(&self) -> &'static str {
+        match self {
+            ModelParam::Int(_) => "u64",
+            ModelParam::Float(_) => "f64",
+            ModelParam::ShortArray(_) => "u16",
+            ModelParam::IntArray(_) => "u64",
+            ModelParam::Int32Array(_) => "u32",
+            ModelParam::Int128Array(_) => "u128",
+            ModelParam::FloatArray(_) => "f64",
+        }
+    }
+
+    pub fn rust_val(&self) -> String {
+        match self {
+            ModelParam::Int(v) => format!("{}", v),
+            ModelParam::Float(_) => self.c_val(),
+            ModelParam::ShortArray(arr) => {
+                let itms: Vec<String> = arr.iter().map(|i| format!("{}", i)).collect();
+                return format!("[{}]", itms.join(", "));
+            },
+            ModelParam::IntArray(arr) => {
+                let itms: Vec<String> = arr.iter().map(|i| format!("{}", i)).collect();
+                return format!("[{}]", itms.join(", "));
+            },
+            ModelParam::Int32Array(arr) => {
+                let itms: Vec<String> = arr.iter().map(|i| format!("{}", i)).collect();
+                return format!("[{}]", itms.join(", "));
+            },
+            ModelParam::Int128Array(arr) => {
+                let itms: Vec<String> = arr.iter().map(|i| format!("{}", i)).collect();
+                return format!("[{}]", itms.join(", "));
+            },
+            ModelParam::FloatArray(arr) => {
+                let itms: Vec<String> = arr
+                    .iter()
+                    .map(|i| format!("{:.}", i))
+                    .map(|s| if !s.contains('.') { s + ".0" } else { s })
+                    .collect();
+                return format!("[{}]", itms.join(", "));
+            }
+        }
+    }
+
     /* useful for debugging floating point issues
     pub fn as_bits(&self) -> u64 {
         return match self {
@@ -615,7 +853,7 @@ impl ModelParam {
             ModelParam::Int(v) => target.write_u64::<LittleEndian>(*v),
             ModelParam::Float(v) => target.write_f64::<LittleEndian>(*v),
             ModelParam::ShortArray(arr) => {
-                for v in arr {
+                for v in arr.iter() {
                     target.write_u16::<LittleEndian>(*v)?;
                 }
 
@@ -623,7 +861,7 @@ impl ModelParam {
             },
             
             ModelParam::IntArray(arr) => {
-                for v in arr {
+                for v in arr.iter() {
                     target.write_u64::<LittleEndian>(*v)?;
                 }
 
@@ -631,15 +869,23 @@ impl ModelParam {
             },
 
             ModelParam::Int32Array(arr) => {
-                for v in arr {
+                for v in arr.iter() {
                     target.write_u32::<LittleEndian>(*v)?;
                 }
 
                 Ok(())
             },
 
+            ModelParam::Int128Array(arr) => {
+                for v in arr.iter() {
+                    target.write_u128::<LittleEndian>(*v)?;
+                }
+
+                Ok(())
+            },
+
             ModelParam::FloatArray(arr) => {
-                for v in arr {
+                for v in arr.iter() {
                     target.write_f64::<LittleEndian>(*v)?;
                 }
 
@@ -657,6 +903,7 @@ impl ModelParam {
             ModelParam::ShortArray(_) => panic!("Cannot treat a short array parameter as a float"),
             ModelParam::IntArray(_) => panic!("Cannot treat an int array parameter as a float"),
             ModelParam::Int32Array(_) => panic!("Cannot treat an int32 array parameter as a float"),
+            ModelParam::Int128Array(_) => panic!("Cannot treat an int128 array parameter as a float"),
             ModelParam::FloatArray(_) => panic!("Cannot treat an float array parameter as a float"),
         }
     }
@@ -668,6 +915,7 @@ impl ModelParam {
             ModelParam::ShortArray(p) => p.len(),
             ModelParam::IntArray(p) => p.len(),
             ModelParam::Int32Array(p) => p.len(),
+            ModelParam::Int128Array(p) => p.len(),
             ModelParam::FloatArray(p) => p.len()
         }
     }
@@ -699,25 +947,31 @@ impl From<f64> for ModelParam {
 
 impl From<Vec<u16>> for ModelParam {
     fn from(f: Vec<u16>) -> Self {
-        ModelParam::ShortArray(f)
+        ModelParam::ShortArray(f.into())
     }
 }
 
 impl From<Vec<u64>> for ModelParam {
     fn from(f: Vec<u64>) -> Self {
-        ModelParam::IntArray(f)
+        ModelParam::IntArray(f.into())
     }
 }
 
 impl From<Vec<u32>> for ModelParam {
     fn from(f: Vec<u32>) -> Self {
-        ModelParam::Int32Array(f)
+        ModelParam::Int32Array(f.into())
+    }
+}
+
+impl From<Vec<u128>> for ModelParam {
+    fn from(f: Vec<u128>) -> Self {
+        ModelParam::Int128Array(f.into())
     }
 }
 
 impl From<Vec<f64>> for ModelParam {
     fn from(f: Vec<f64>) -> Self {
-        ModelParam::FloatArray(f)
+        ModelParam::FloatArray(f.into())
     }
 }
 
@@ -727,6 +981,16 @@ pub enum ModelRestriction {
     MustBeBottom,
 }
 
+// The kind of model that wraps a learned model, if any. Saved RMIs store
+// it for each layer, so that `model_from_params` knows how to rebuild
+// the layer's models.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ModelWrapper {
+    None,
+    Fallback,
+    Remap
+}
+
 pub trait Model: Sync + Send {
     fn predict_to_float(&self, inp: &ModelInput) -> f64 {
         return self.predict_to_int(inp) as f64;
@@ -741,7 +1005,14 @@ pub trait Model: Sync + Send {
 
     fn params(&self) -> Vec<ModelParam>;
 
+    // parameters written by `TrainedRMI::save`, must contain everything
+    // `model_from_params` needs to rebuild the model
+    fn serialized_params(&self) -> Vec<ModelParam> {
+        return self.params();
+    }
+
     fn code(&self) -> String;
+    fn rust_code(&self) -> String;
     fn function_name(&self) -> String;
 
     fn standard_functions(&self) -> HashSet<StdFunctions> {
@@ -761,6 +1032,106 @@ pub trait Model: Sync + Send {
     fn set_to_constant_model(&mut self, _constant: u64) -> bool {
         return false;
     }
+
+    // Generated code requires every model on a layer to have the same
+    // parameter layout, so models with a varying number of entries (such
+    // as pgm segments) keep them in float arrays shared by the whole layer
+    // and locate them with their parameters. Returns the entries of this
+    // model, one slice per array.
+    fn layer_arrays(&self) -> Vec<&[f64]> {
+        return Vec::new();
+    }
+
+    // Switches the model to the shared `arrays` (see `share_layer_arrays`),
+    // where its entries of each array start at the matching `offsets`.
+    fn use_layer_arrays(&mut self, _arrays: &[ParamArray<f64>], _offsets: &[usize]) {}
+
+    // Models that wrap another model return it here, so that code
+    // generation can emit the wrapped model's function once, even if
+    // another layer uses the same model type.
+    fn wrapped_model(&self) -> Option<&dyn Model> {
+        return None;
+    }
+    fn wrapper(&self) -> ModelWrapper {
+        return ModelWrapper::None;
+    }
+}
+
+// The arrays shared by the models on a layer: the entries of each model,
+// in order.
+pub fn layer_arrays(models: &[Box<dyn Model>]) -> Vec<Vec<f64>> {
+    let mut arrays: Vec<Vec<f64>> = vec![Vec::new(); models[0].layer_arrays().len()];
+    for model in models.iter() {
+        for (array, entries) in arrays.iter_mut().zip(model.layer_arrays()) {
+            array.extend_from_slice(entries);
+        }
+    }
+    return arrays;
+}
+
+// Moves the entries of every model on a layer into the layer's shared
+// arrays, so that each model only stores where its entries start.
+pub fn share_layer_arrays(models: &mut [Box<dyn Model>]) {
+    let arrays: Vec<ParamArray<f64>> = layer_arrays(models).into_iter()
+        .map(|array| array.into())
+        .collect();
+    if arrays.is_empty() {
+        return;
+    }
+
+    let mut offsets = vec![0; arrays.len()];
+    for model in models.iter_mut() {
+        let lens: Vec<usize> = model.layer_arrays().iter().map(|a| a.len()).collect();
+        model.use_layer_arrays(&arrays, &offsets);
+        for (offset, len) in offsets.iter_mut().zip(lens) {
+            *offset += len;
+        }
+    }
+}
+
+// Rebuilds a model of the given type (as named in an RMI model spec),
+// wrapped in `wrapper`, from the output of `Model::serialized_params` and
+// the arrays shared by its layer (see `layer_arrays`).
+pub fn model_from_params(model_type: &str, wrapper: ModelWrapper, params: &[ModelParam],
+                         arrays: &[ParamArray<f64>]) -> Option<Box<dyn Model>> {
+    return match wrapper {
+        ModelWrapper::None => learned_model_from_params(model_type, params, arrays),
+        ModelWrapper::Fallback =>
+            Some(Box::new(FallbackLeaf::from_params(model_type, params, arrays)?)),
+        ModelWrapper::Remap if arrays.is_empty() =>
+            Some(Box::new(RemappedModel::from_params(model_type, params)?)),
+        ModelWrapper::Remap => None
+    };
+}
+
+fn learned_model_from_params(model_type: &str, params: &[ModelParam],
+                             arrays: &[ParamArray<f64>]) -> Option<Box<dyn Model>> {
+    let model: Box<dyn Model> = match model_type {
+        "linear" => Box::new(LinearModel::from_params(params)?),
+        "robust_linear" => Box::new(RobustLinearModel::from_params(params)?),
+        "linear_spline" => Box::new(LinearSplineModel::from_params(params)?),
+        "cubic" => Box::new(CubicSplineModel::from_params(params)?),
+        "poly2" | "poly3" | "poly4" | "poly5"
+            => Box::new(PolynomialModel::from_params(params)?),
+        "loglinear" => Box::new(LogLinearModel::from_params(params)?),
+        "normal" => Box::new(NormalModel::from_params(params)?),
+        "lognormal" => Box::new(LogNormalModel::from_params(params)?),
+        "radix" => Box::new(RadixModel::from_params(params)?),
+        "radix8" | "radix18" | "radix22" | "radix26" | "radix28"
+            => Box::new(RadixTable::from_params(params)?),
+        "bradix" => Box::new(BalancedRadixModel::from_params(params)?),
+        "histogram" => Box::new(EquidepthHistogramModel::from_params(params)?),
+        _ if pgm_epsilon(model_type).is_some()
+            => Box::new(PGMModel::from_params(params, arrays)?),
+        _ if radix_spline_params(model_type).is_some()
+            => Box::new(RadixSplineModel::from_params(params)?),
+        _ => return None,
+    };
+
+    if model.layer_arrays().len() != arrays.len() {
+        return None;
+    }
+    return Some(model);
 }
 
 #[cfg(test)]
@@ -769,11 +1140,11 @@ mod tests {
 
     #[test]
     fn test_scale() {
-        let mut v = ModelData::IntKeyToIntPos(vec![(0, 0), (1, 1), (3, 2), (100, 3)]);
+        let mut v = RMITrainingData::new(Box::new(vec![(0u64, 0usize), (1, 1), (3, 2), (100, 3)]));
 
-        v.scale_targets_to(50, 4);
+        v.set_scale(50.0 / 4.0);
 
-        let results = v.as_int_int();
+        let results: Vec<(u64, usize)> = v.iter().collect();
         assert_eq!(results[0].1, 0);
         assert_eq!(results[1].1, 12);
         assert_eq!(results[2].1, 25);
@@ -782,11 +1153,11 @@ mod tests {
 
     #[test]
     fn test_iter() {
-        let data = vec![(0, 1), (1, 2), (3, 3), (100, 4)];
+        let data = vec![(0u64, 1usize), (1, 2), (3, 3), (100, 4)];
 
-        let v = ModelData::IntKeyToIntPos(data.clone());
+        let v = RMITrainingData::new(Box::new(data.clone()));
 
-        let iterated: Vec<(u64, u64)> = v.iter_uint_uint().collect();
+        let iterated: Vec<(u64, usize)> = v.iter().collect();
         assert_eq!(data, iterated);
     }
 }
@@ -80,6 +80,13 @@ pub struct NormalModel {
 }
 
 impl NormalModel {
+    pub fn from_params(params: &[ModelParam]) -> Option<NormalModel> {
+        return match params {
+            [ModelParam::Float(a), ModelParam::Float(b), ModelParam::Float(c)] => Some(NormalModel { params: (*a, *b, *c) }),
+            _ => None
+        };
+    }
+
     pub fn new<T: TrainingKey>(data: &RMITrainingData<T>) -> NormalModel {
         return NormalModel { params: ncdf(data) };
     }
@@ -115,6 +122,16 @@ inline double ncdf(double mean, double stdev, double scale, double inp) {
         );
     }
 
+    fn rust_code(&self) -> String {
+        return String::from(
+            "
+#[inline]
+fn ncdf(mean: f64, stdev: f64, scale: f64, inp: f64) -> f64 {
+    phi((inp - mean) / stdev) * scale
+}",
+        );
+    }
+
     fn function_name(&self) -> String {
         return String::from("ncdf");
     }
@@ -132,17 +149,17 @@ mod ncdf_tests {
 
     #[test]
     fn test_ncdf1() {
-        let md = ModelData::IntKeyToIntPos(vec![(1, 1), (2, 3), (3, 5)]);
+        let md = RMITrainingData::new(Box::new(vec![(1u64, 1usize), (2, 3), (3, 5)]));
 
         let ncdf_mod = NormalModel::new(&md);
 
-        assert_eq!(ncdf_mod.predict_to_int(2.into()), 2);
-        assert_eq!(ncdf_mod.predict_to_int(1.into()), 0);
+        assert_eq!(ncdf_mod.predict_to_int(&2u64.into()), 2);
+        assert_eq!(ncdf_mod.predict_to_int(&1u64.into()), 0);
     }
 
     #[test]
     fn test_empty() {
-        NormalModel::new(&ModelData::empty());
+        NormalModel::new(&RMITrainingData::<u64>::empty());
     }
 
 }
@@ -152,6 +169,13 @@ pub struct LogNormalModel {
 }
 
 impl LogNormalModel {
+    pub fn from_params(params: &[ModelParam]) -> Option<LogNormalModel> {
+        return match params {
+            [ModelParam::Float(a), ModelParam::Float(b), ModelParam::Float(c)] => Some(LogNormalModel { params: (*a, *b, *c) }),
+            _ => None
+        };
+    }
+
     pub fn new<T: TrainingKey>(data: &RMITrainingData<T>) -> LogNormalModel {
         return LogNormalModel {
             params: lncdf(data),
@@ -190,6 +214,16 @@ inline double lncdf(double mean, double stdev, double scale, double inp) {
         );
     }
 
+    fn rust_code(&self) -> String {
+        return String::from(
+            "
+#[inline]
+fn lncdf(mean: f64, stdev: f64, scale: f64, inp: f64) -> f64 {
+    phi((f64::max(0.0, inp.ln()) - mean) / stdev) * scale
+}",
+        );
+    }
+
     fn function_name(&self) -> String {
         return String::from("lncdf");
     }
@@ -207,17 +241,17 @@ mod lncdf_tests {
 
     #[test]
     fn test_lncdf1() {
-        let md = ModelData::IntKeyToIntPos(vec![(1, 1), (2, 2), (3, 20)]);
+        let md = RMITrainingData::new(Box::new(vec![(1u64, 1usize), (2, 2), (3, 20)]));
 
         let lncdf_mod = LogNormalModel::new(&md);
 
-        assert_eq!(lncdf_mod.predict_to_int(2.into()), 11);
-        assert_eq!(lncdf_mod.predict_to_int(1.into()), 2);
+        assert_eq!(lncdf_mod.predict_to_int(&2u64.into()), 11);
+        assert_eq!(lncdf_mod.predict_to_int(&1u64.into()), 2);
     }
 
     #[test]
     fn test_empty() {
-        LogNormalModel::new(&ModelData::empty());
+        LogNormalModel::new(&RMITrainingData::<u64>::empty());
     }
 
 }
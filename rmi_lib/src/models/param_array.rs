@@ -0,0 +1,74 @@
+// < begin copyright > 
+// Copyright Ryan Marcus 2020
+// 
+// See root directory of this project for license terms.
+// 
+// < end copyright > 
+ 
+
+use memmap::Mmap;
+use std::ops::Deref;
+use std::sync::Arc;
+
+// The values of an array model parameter. Trained models own their
+// arrays (shared, so that models on a layer can use one array, see
+// `share_layer_arrays`), while RMIs loaded with `TrainedRMI::load_mmap`
+// borrow them directly from the mapped file.
+#[derive(Clone)]
+pub enum ParamArray<T> {
+    Owned(Arc<Vec<T>>),
+    Mapped { map: Arc<Mmap>, offset: usize, len: usize }
+}
+
+impl <T> ParamArray<T> {
+    // Only the serialization code creates mapped arrays, and only for the
+    // plain numeric types stored in a `ModelParam` (and the `[u64; 2]`
+    // error bounds of `TrainedRMI::last_layer_bounds`).
+    pub(crate) fn mapped(map: Arc<Mmap>, offset: usize, len: usize) -> ParamArray<T> {
+        assert_eq!(offset % std::mem::align_of::<T>(), 0,
+                   "mapped parameter array is not aligned");
+        assert!(offset + len * std::mem::size_of::<T>() <= map.len(),
+                "mapped parameter array is out of bounds");
+        return ParamArray::Mapped { map, offset, len };
+    }
+
+    pub fn is_mapped(&self) -> bool {
+        return match self {
+            ParamArray::Owned(_) => false,
+            ParamArray::Mapped { .. } => true
+        };
+    }
+}
+
+impl <T> Deref for ParamArray<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        return match self {
+            ParamArray::Owned(v) => v.as_slice(),
+            ParamArray::Mapped { map, offset, len } => unsafe {
+                // alignment and bounds are checked in `mapped`, and the
+                // map is kept alive by the Arc we hold
+                std::slice::from_raw_parts(map.as_ptr().add(*offset) as *const T, *len)
+            }
+        };
+    }
+}
+
+impl <T> From<Vec<T>> for ParamArray<T> {
+    fn from(v: Vec<T>) -> Self {
+        ParamArray::Owned(Arc::new(v))
+    }
+}
+
+impl <T: PartialEq> PartialEq for ParamArray<T> {
+    fn eq(&self, other: &Self) -> bool {
+        return **self == **other;
+    }
+}
+
+impl <T: std::fmt::Debug> std::fmt::Debug for ParamArray<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return f.debug_list().entries(self.iter()).finish();
+    }
+}
@@ -0,0 +1,556 @@
+// < begin copyright > 
+// Copyright Ryan Marcus 2020
+// 
+// See root directory of this project for license terms.
+// 
+// < end copyright > 
+ 
+
+// An error-bounded piecewise linear leaf model, in the style of the PGM
+// index. The segments are built with the optimal streaming algorithm of
+// O'Rourke (as used by PGM): each segment covers as many keys as possible
+// while predicting every one of them within the fitting tolerance.
+// Lookups binary search the segment start keys, and a prediction never
+// exceeds the first prediction of the next segment, which keeps lookups
+// for absent keys near their lower bound.
+
+use crate::models::*;
+
+// Returns the error bound of a `pgm{epsilon}` model name, e.g. 64 for pgm64.
+pub fn pgm_epsilon(model_type: &str) -> Option<u64> {
+    return model_type.strip_prefix("pgm")?.parse().ok();
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Point {
+    x: f64,
+    y: f64
+}
+
+// the slope between two points, compared without dividing
+#[derive(Clone, Copy, Debug)]
+struct Slope {
+    dx: f64,
+    dy: f64
+}
+
+impl Slope {
+    fn between(to: Point, from: Point) -> Slope {
+        return Slope { dx: to.x - from.x, dy: to.y - from.y };
+    }
+
+    fn less(self, other: Slope) -> bool {
+        return self.dy * other.dx < self.dx * other.dy;
+    }
+
+    fn greater(self, other: Slope) -> bool {
+        return self.dy * other.dx > self.dx * other.dy;
+    }
+
+    fn value(self) -> f64 {
+        return self.dy / self.dx;
+    }
+}
+
+fn cross(o: Point, a: Point, b: Point) -> f64 {
+    let oa = Slope::between(a, o);
+    let ob = Slope::between(b, o);
+    return oa.dx * ob.dy - oa.dy * ob.dx;
+}
+
+// Builds one segment at a time from points with increasing x, keeping the
+// convex hulls of the upper (y + epsilon) and lower (y - epsilon) points
+// and the extreme feasible lines (the `rect` corners).
+struct SegmentBuilder {
+    epsilon: f64,
+    lower: Vec<Point>,
+    upper: Vec<Point>,
+    lower_start: usize,
+    upper_start: usize,
+    num_points: usize,
+    first_x: f64,
+    rect: [Point; 4]
+}
+
+impl SegmentBuilder {
+    fn new(epsilon: f64) -> SegmentBuilder {
+        let origin = Point { x: 0.0, y: 0.0 };
+        return SegmentBuilder {
+            epsilon,
+            lower: Vec::new(), upper: Vec::new(),
+            lower_start: 0, upper_start: 0,
+            num_points: 0,
+            first_x: 0.0,
+            rect: [origin; 4]
+        };
+    }
+
+    // Adds a point to the current segment, returning false if the point
+    // cannot be covered by it.
+    fn add_point(&mut self, x: f64, y: f64) -> bool {
+        let p1 = Point { x, y: y + self.epsilon };
+        let p2 = Point { x, y: y - self.epsilon };
+
+        if self.num_points == 0 {
+            self.first_x = x;
+            self.rect[0] = p1;
+            self.rect[1] = p2;
+            self.upper.clear();
+            self.lower.clear();
+            self.upper.push(p1);
+            self.lower.push(p2);
+            self.upper_start = 0;
+            self.lower_start = 0;
+            self.num_points = 1;
+            return true;
+        }
+
+        if self.num_points == 1 {
+            self.rect[2] = p2;
+            self.rect[3] = p1;
+            self.upper.push(p1);
+            self.lower.push(p2);
+            self.num_points = 2;
+            return true;
+        }
+
+        let slope1 = Slope::between(self.rect[2], self.rect[0]);
+        let slope2 = Slope::between(self.rect[3], self.rect[1]);
+        let outside_line1 = Slope::between(p1, self.rect[2]).less(slope1);
+        let outside_line2 = Slope::between(p2, self.rect[3]).greater(slope2);
+
+        if outside_line1 || outside_line2 {
+            return false;
+        }
+
+        if Slope::between(p1, self.rect[1]).less(slope2) {
+            // find the extreme slope
+            let mut min = Slope::between(self.lower[self.lower_start], p1);
+            let mut min_i = self.lower_start;
+            for i in (self.lower_start + 1)..self.lower.len() {
+                let val = Slope::between(self.lower[i], p1);
+                if val.greater(min) { break; }
+                min = val;
+                min_i = i;
+            }
+            self.rect[1] = self.lower[min_i];
+            self.rect[3] = p1;
+            self.lower_start = min_i;
+
+            // update the upper hull
+            let mut end = self.upper.len();
+            while end >= self.upper_start + 2
+                && cross(self.upper[end - 2], self.upper[end - 1], p1) <= 0.0 {
+                end -= 1;
+            }
+            self.upper.truncate(end);
+            self.upper.push(p1);
+        }
+
+        if Slope::between(p2, self.rect[0]).greater(slope1) {
+            // find the extreme slope
+            let mut max = Slope::between(self.upper[self.upper_start], p2);
+            let mut max_i = self.upper_start;
+            for i in (self.upper_start + 1)..self.upper.len() {
+                let val = Slope::between(self.upper[i], p2);
+                if val.less(max) { break; }
+                max = val;
+                max_i = i;
+            }
+            self.rect[0] = self.upper[max_i];
+            self.rect[2] = p2;
+            self.upper_start = max_i;
+
+            // update the lower hull
+            let mut end = self.lower.len();
+            while end >= self.lower_start + 2
+                && cross(self.lower[end - 2], self.lower[end - 1], p2) >= 0.0 {
+                end -= 1;
+            }
+            self.lower.truncate(end);
+            self.lower.push(p2);
+        }
+
+        self.num_points += 1;
+        return true;
+    }
+
+    fn reset(&mut self) {
+        self.num_points = 0;
+    }
+
+    // The (start key, slope, intercept) of the current segment, where the
+    // intercept is the prediction at the start key. Of the feasible lines,
+    // the one through the intersection of the extreme lines with the
+    // middle slope is chosen, unless that slope is negative: keys are
+    // sorted, so a non-negative slope is always feasible, and it keeps
+    // predictions for absent keys between those of their neighbors.
+    fn segment(&self) -> (f64, f64, f64) {
+        if self.num_points == 1 {
+            return (self.first_x, 0.0, (self.rect[0].y + self.rect[1].y) / 2.0);
+        }
+
+        let [p0, p1, p2, p3] = self.rect;
+        let slope1 = Slope::between(p2, p0);
+        let slope2 = Slope::between(p3, p1);
+
+        let (i_x, i_y) = {
+            let a = slope1.dx * slope2.dy - slope1.dy * slope2.dx;
+            if a == 0.0 {
+                (p0.x, p0.y)
+            } else {
+                let p0p1 = Slope::between(p1, p0);
+                let b = (p0p1.dx * slope2.dy - p0p1.dy * slope2.dx) / a;
+                (p0.x + b * slope1.dx, p0.y + b * slope1.dy)
+            }
+        };
+
+        let (min_slope, max_slope) = (slope1.value(), slope2.value());
+        let mut slope = (min_slope + max_slope) / 2.0;
+        if slope < 0.0 {
+            slope = f64::min(0.0, max_slope);
+        }
+
+        let intercept = slope.mul_add(self.first_x - i_x, i_y);
+        return (self.first_x, slope, intercept);
+    }
+}
+
+pub struct PGMModel {
+    // the segments are entries offset..offset + num_segments of the
+    // arrays, which are shared by every leaf once training is done
+    starts: ParamArray<f64>,
+    slopes: ParamArray<f64>,
+    intercepts: ParamArray<f64>,
+    offset: usize,
+    num_segments: usize
+}
+
+impl PGMModel {
+    pub fn from_params(params: &[ModelParam], arrays: &[ParamArray<f64>]) -> Option<PGMModel> {
+        return match (params, arrays) {
+            ([ModelParam::Int(offset), ModelParam::Int(n)], [starts, slopes, intercepts]) => {
+                let offset = *offset as usize;
+                let num_segments = *n as usize;
+                let end = offset.checked_add(num_segments)?;
+                if num_segments == 0 || end > starts.len()
+                    || starts.len() != slopes.len() || starts.len() != intercepts.len() {
+                    return None;
+                }
+                Some(PGMModel {
+                    starts: starts.clone(),
+                    slopes: slopes.clone(),
+                    intercepts: intercepts.clone(),
+                    offset,
+                    num_segments
+                })
+            },
+            _ => None
+        };
+    }
+
+    pub fn new<T: TrainingKey>(data: &RMITrainingData<T>, epsilon: u64) -> PGMModel {
+        // Besides the error of the segments, the error reported for the
+        // leaf covers rounding, lower bound searches for absent keys, and
+        // the gaps between positions (see `finish_leaf_layer`). Fit with a
+        // smaller tolerance so that the reported error stays within
+        // epsilon. Gaps too large for any tolerance (for example, where
+        // the upper layers route keys to leaves out of order) are ignored,
+        // since the bound cannot hold there anyway.
+        let mut max_gap = 0;
+        let mut last_y: Option<usize> = None;
+        for (_x, y) in data.iter() {
+            if let Some(last_y) = last_y {
+                let gap = (y - last_y) as u64;
+                if 2 * gap + 2 <= epsilon {
+                    max_gap = u64::max(max_gap, gap);
+                }
+            }
+            last_y = Some(y);
+        }
+        let tolerance = f64::max(0.0, epsilon as f64 - (2 * max_gap + 2) as f64);
+
+        let mut starts = Vec::new();
+        let mut slopes = Vec::new();
+        let mut intercepts = Vec::new();
+        let mut builder = SegmentBuilder::new(tolerance);
+        let mut last_x: Option<f64> = None;
+        let mut last_y = 0;
+
+        for (x, y) in data.iter_model_input() {
+            let x = x.as_float();
+            // only the first position of each key is fit, which is the
+            // one lower bound searches need
+            if last_x.map(|last_x| x <= last_x).unwrap_or(false) {
+                continue;
+            }
+            last_x = Some(x);
+            last_y = y;
+
+            if !builder.add_point(x, y as f64) {
+                let (start, slope, intercept) = builder.segment();
+                starts.push(start);
+                slopes.push(slope);
+                intercepts.push(intercept);
+                builder.reset();
+                assert!(builder.add_point(x, y as f64));
+            }
+        }
+
+        if builder.num_points > 0 {
+            let (start, slope, intercept) = builder.segment();
+            starts.push(start);
+            slopes.push(slope);
+            intercepts.push(intercept);
+
+            // a flat segment at the last key, so that keys past the end of
+            // the leaf predict its last position instead of extrapolating
+            starts.push(last_x.unwrap());
+            slopes.push(0.0);
+            intercepts.push(last_y as f64);
+        }
+
+        if starts.is_empty() {
+            // no training data
+            starts.push(0.0);
+            slopes.push(0.0);
+            intercepts.push(0.0);
+        }
+
+        let num_segments = starts.len();
+        return PGMModel {
+            starts: starts.into(),
+            slopes: slopes.into(),
+            intercepts: intercepts.into(),
+            offset: 0,
+            num_segments
+        };
+    }
+
+    // The starts, slopes, and intercepts of this model's segments.
+    fn segments(&self) -> (&[f64], &[f64], &[f64]) {
+        let range = self.offset..self.offset + self.num_segments;
+        return (&self.starts[range.clone()], &self.slopes[range.clone()],
+                &self.intercepts[range]);
+    }
+}
+
+impl Model for PGMModel {
+    fn predict_to_float(&self, inp: &ModelInput) -> f64 {
+        let x = inp.as_float();
+        let (starts, slopes, intercepts) = self.segments();
+        let idx = starts.partition_point(|start| *start <= x);
+        if idx == 0 {
+            return intercepts[0];
+        }
+        let seg = idx - 1;
+
+        let pred = slopes[seg].mul_add(x - starts[seg], intercepts[seg]);
+        if seg + 1 < starts.len() && pred > intercepts[seg + 1] {
+            return intercepts[seg + 1];
+        }
+        return pred;
+    }
+
+    fn input_type(&self) -> ModelDataType {
+        return ModelDataType::Float;
+    }
+    fn output_type(&self) -> ModelDataType {
+        return ModelDataType::Float;
+    }
+
+    fn params(&self) -> Vec<ModelParam> {
+        return vec![
+            ModelParam::Int(self.offset as u64),
+            ModelParam::Int(self.num_segments as u64)
+        ];
+    }
+
+    fn code(&self) -> String {
+        return String::from(
+            "
+inline double pgm(uint64_t offset, uint64_t num_segments, const double* starts,
+                  const double* slopes, const double* intercepts, double inp) {
+    starts += offset;
+    slopes += offset;
+    intercepts += offset;
+
+    // find the last segment starting at or before inp
+    uint64_t lo = 0;
+    uint64_t hi = num_segments;
+    while (lo < hi) {
+        uint64_t mid = lo + (hi - lo) / 2;
+        if (starts[mid] <= inp) lo = mid + 1; else hi = mid;
+    }
+    if (lo == 0) return intercepts[0];
+    uint64_t seg = lo - 1;
+
+    double pred = std::fma(slopes[seg], inp - starts[seg], intercepts[seg]);
+    if (seg + 1 < num_segments && pred > intercepts[seg + 1])
+        return intercepts[seg + 1];
+    return pred;
+}",
+        );
+    }
+
+    fn rust_code(&self) -> String {
+        return String::from(
+            "
+#[inline]
+fn pgm(offset: u64, num_segments: u64, starts: &[f64], slopes: &[f64], intercepts: &[f64],
+       inp: f64) -> f64 {
+    let range = offset as usize..(offset + num_segments) as usize;
+    let (starts, slopes, intercepts) = (&starts[range.clone()], &slopes[range.clone()],
+                                        &intercepts[range]);
+
+    // find the last segment starting at or before inp
+    let n = starts.len();
+    let idx = starts.partition_point(|start| *start <= inp);
+    if idx == 0 {
+        return intercepts[0];
+    }
+    let seg = idx - 1;
+
+    let pred = slopes[seg].mul_add(inp - starts[seg], intercepts[seg]);
+    if seg + 1 < n && pred > intercepts[seg + 1] {
+        return intercepts[seg + 1];
+    }
+    pred
+}",
+        );
+    }
+
+    fn function_name(&self) -> String {
+        return String::from("pgm");
+    }
+
+    fn restriction(&self) -> ModelRestriction {
+        return ModelRestriction::MustBeBottom;
+    }
+
+    fn layer_arrays(&self) -> Vec<&[f64]> {
+        let (starts, slopes, intercepts) = self.segments();
+        return vec![starts, slopes, intercepts];
+    }
+
+    fn use_layer_arrays(&mut self, arrays: &[ParamArray<f64>], offsets: &[usize]) {
+        // every array has one entry per segment, so the offsets agree
+        assert_eq!(arrays.len(), 3);
+        assert!(offsets.iter().all(|offset| *offset == offsets[0]));
+        self.starts = arrays[0].clone();
+        self.slopes = arrays[1].clone();
+        self.intercepts = arrays[2].clone();
+        self.offset = offsets[0];
+    }
+
+    fn set_to_constant_model(&mut self, constant: u64) -> bool {
+        self.starts = vec![0.0].into();
+        self.slopes = vec![0.0].into();
+        self.intercepts = vec![constant as f64].into();
+        self.offset = 0;
+        self.num_segments = 1;
+        return true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fit(data: Vec<(u64, usize)>, epsilon: u64) -> PGMModel {
+        let md = RMITrainingData::new(Box::new(data));
+        return PGMModel::new(&md, epsilon);
+    }
+
+    #[test]
+    fn test_pgm_epsilon() {
+        assert_eq!(pgm_epsilon("pgm64"), Some(64));
+        assert_eq!(pgm_epsilon("pgm"), None);
+        assert_eq!(pgm_epsilon("pgmx"), None);
+        assert_eq!(pgm_epsilon("linear"), None);
+    }
+
+    #[test]
+    fn test_pgm_linear_data() {
+        // a single line fits any epsilon
+        let data: Vec<(u64, usize)> = (0..1000).map(|i| (10 * i + 3, i as usize)).collect();
+        let model = fit(data.clone(), 8);
+        assert_eq!(model.num_segments, 2);
+        for (x, y) in data {
+            let pred = model.predict_to_float(&x.into());
+            assert!((pred - y as f64).abs() <= 4.0);
+        }
+    }
+
+    #[test]
+    fn test_pgm_error_bound() {
+        let data: Vec<(u64, usize)> = (0..20_000u64)
+            .map(|i| i * i + i * 100 + (i * 7919) % 97)
+            .enumerate()
+            .map(|(idx, key)| (key, idx))
+            .collect();
+
+        for epsilon in &[4u64, 16, 64] {
+            let model = fit(data.clone(), *epsilon);
+            let tolerance = *epsilon as f64 - 4.0;
+            for (x, y) in data.iter() {
+                let pred = model.predict_to_float(&(*x).into());
+                assert!((pred - *y as f64).abs() <= tolerance + 1e-6,
+                        "pgm{} predicted {} for position {}", epsilon, pred, y);
+            }
+        }
+
+        // a looser bound needs fewer segments
+        assert!(fit(data.clone(), 64).num_segments < fit(data, 16).num_segments);
+    }
+
+    #[test]
+    fn test_pgm_duplicates() {
+        let data = vec![(1u64, 0), (1, 1), (1, 2), (5, 3), (9, 4), (9, 5), (12, 6)];
+        let model = fit(data, 4);
+        assert!(model.predict_to_float(&1u64.into()).abs() <= 1.0);
+    }
+
+    #[test]
+    fn test_shared_arrays() {
+        let data: Vec<(u64, usize)> = (0..1000u64).map(|i| (i * i * i, i as usize)).collect();
+        let mut models: Vec<Box<dyn Model>> = vec![
+            Box::new(fit(data.clone(), 4)),
+            Box::new(fit(data[..10].to_vec(), 4)),
+            Box::new(fit(data.clone(), 16))
+        ];
+        let predict = |models: &[Box<dyn Model>]| -> Vec<Vec<f64>> {
+            return models.iter()
+                .map(|m| data.iter().map(|(x, _)| m.predict_to_float(&(*x).into())).collect())
+                .collect();
+        };
+        let before = predict(&models);
+        let num_segments: Vec<u64> = models.iter().map(|m| m.layer_arrays()[0].len() as u64).collect();
+
+        share_layer_arrays(&mut models);
+        assert_eq!(predict(&models), before);
+
+        // the segments of each model follow those of the previous one
+        let arrays: Vec<ParamArray<f64>> = layer_arrays(&models).into_iter()
+            .map(|array| array.into()).collect();
+        assert_eq!(arrays[0].len() as u64, num_segments.iter().sum::<u64>());
+        let offset = num_segments[0] + num_segments[1];
+        assert!(matches!(models[2].params()[0], ModelParam::Int(o) if o == offset));
+
+        let restored: Vec<Box<dyn Model>> = models.iter()
+            .map(|m| Box::new(PGMModel::from_params(&m.params(), &arrays).unwrap()) as Box<dyn Model>)
+            .collect();
+        assert_eq!(predict(&restored), before);
+
+        // segments past the end of the arrays
+        let params = vec![ModelParam::Int(arrays[0].len() as u64 - 1), ModelParam::Int(2)];
+        assert!(PGMModel::from_params(&params, &arrays).is_none());
+    }
+
+    #[test]
+    fn test_empty() {
+        let model = PGMModel::new(&RMITrainingData::<u64>::empty(), 16);
+        assert_eq!(model.predict_to_int(&5u64.into()), 0);
+        assert_eq!(model.num_segments, 1);
+    }
+}
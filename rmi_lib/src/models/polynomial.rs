@@ -0,0 +1,275 @@
+// < begin copyright > 
+// Copyright Ryan Marcus 2020
+// 
+// See root directory of this project for license terms.
+// 
+// < end copyright > 
+ 
+
+// Least squares polynomial models of degree 2 through 5. Inputs are
+// centered and scaled into [-1, 1] before fitting, which keeps the normal
+// equations well conditioned even for large keys, and predictions are
+// evaluated with Horner's method using fused multiply-adds so that the
+// generated code matches `predict_to_float` exactly.
+
+use crate::models::*;
+
+pub struct PolynomialModel {
+    center: f64,
+    scale: f64,
+    // coefficients of the scaled input, lowest degree first
+    coeffs: Vec<f64>
+}
+
+// Solves the (row major) linear system a * x = b with Gaussian
+// elimination and partial pivoting. Returns None if the system is
+// (numerically) singular.
+fn solve(mut a: Vec<Vec<f64>>, mut b: Vec<f64>) -> Option<Vec<f64>> {
+    let n = b.len();
+    let max_diag = (0..n).map(|i| a[i][i].abs()).fold(0.0, f64::max);
+    if max_diag == 0.0 {
+        return None;
+    }
+
+    for col in 0..n {
+        let pivot = (col..n)
+            .max_by(|&i, &j| a[i][col].abs().partial_cmp(&a[j][col].abs()).unwrap())
+            .unwrap();
+        if a[pivot][col].abs() <= 1e-10 * max_diag {
+            return None;
+        }
+        a.swap(col, pivot);
+        b.swap(col, pivot);
+
+        for row in (col + 1)..n {
+            let factor = a[row][col] / a[col][col];
+            for k in col..n {
+                a[row][k] -= factor * a[col][k];
+            }
+            b[row] -= factor * b[col];
+        }
+    }
+
+    let mut x = vec![0.0; n];
+    for row in (0..n).rev() {
+        let sum: f64 = ((row + 1)..n).map(|k| a[row][k] * x[k]).sum();
+        x[row] = (b[row] - sum) / a[row][row];
+    }
+
+    return Some(x);
+}
+
+impl PolynomialModel {
+    pub fn from_params(params: &[ModelParam]) -> Option<PolynomialModel> {
+        let floats: Vec<f64> = params.iter()
+            .map(|p| match p {
+                ModelParam::Float(v) => Some(*v),
+                _ => None
+            })
+            .collect::<Option<_>>()?;
+
+        return match floats.as_slice() {
+            [center, scale, coeffs @ ..] if (3..=6).contains(&coeffs.len()) =>
+                Some(PolynomialModel {
+                    center: *center,
+                    scale: *scale,
+                    coeffs: coeffs.to_vec()
+                }),
+            _ => None
+        };
+    }
+
+    pub fn new<T: TrainingKey>(data: &RMITrainingData<T>, degree: usize) -> PolynomialModel {
+        assert!((2..=5).contains(&degree));
+        let mut coeffs = vec![0.0; degree + 1];
+
+        if data.len() == 0 {
+            return PolynomialModel { center: 0.0, scale: 0.0, coeffs };
+        }
+
+        let min_x = data.get_key(0).as_float();
+        let max_x = data.get_key(data.len() - 1).as_float();
+        let center = (min_x + max_x) / 2.0;
+        let scale = if max_x > min_x { 2.0 / (max_x - min_x) } else { 0.0 };
+
+        // power sums for the normal equations
+        let mut x_powers = vec![0.0; 2 * degree + 1];
+        let mut xy_powers = vec![0.0; degree + 1];
+        for (x, y) in data.iter() {
+            let x = (x.as_float() - center) * scale;
+            let y = y as f64;
+            let mut power = 1.0;
+            for k in 0..=(2 * degree) {
+                x_powers[k] += power;
+                if k <= degree {
+                    xy_powers[k] += power * y;
+                }
+                power *= x;
+            }
+        }
+
+        // too few distinct keys for the full degree leave the normal
+        // equations singular, so fall back to lower degrees
+        for fit_degree in (0..=degree).rev() {
+            let a: Vec<Vec<f64>> = (0..=fit_degree)
+                .map(|row| x_powers[row..=(row + fit_degree)].to_vec())
+                .collect();
+            let b = xy_powers[0..=fit_degree].to_vec();
+
+            if let Some(solution) = solve(a, b) {
+                coeffs[..=fit_degree].copy_from_slice(&solution);
+                break;
+            }
+        }
+
+        return PolynomialModel { center, scale, coeffs };
+    }
+
+    fn degree(&self) -> usize {
+        return self.coeffs.len() - 1;
+    }
+}
+
+impl Model for PolynomialModel {
+    fn predict_to_float(&self, inp: &ModelInput) -> f64 {
+        let x = (inp.as_float() - self.center) * self.scale;
+        let (last, rest) = self.coeffs.split_last().unwrap();
+
+        let mut v = *last;
+        for c in rest.iter().rev() {
+            v = v.mul_add(x, *c);
+        }
+        return v;
+    }
+
+    fn input_type(&self) -> ModelDataType {
+        return ModelDataType::Float;
+    }
+    fn output_type(&self) -> ModelDataType {
+        return ModelDataType::Float;
+    }
+
+    fn params(&self) -> Vec<ModelParam> {
+        let mut params = vec![self.center.into(), self.scale.into()];
+        params.extend(self.coeffs.iter().map(|c| ModelParam::Float(*c)));
+        return params;
+    }
+
+    fn code(&self) -> String {
+        let degree = self.degree();
+        let args: Vec<String> = (0..=degree).map(|i| format!("double c{}", i)).collect();
+        let steps: String = (0..degree).rev()
+            .map(|i| format!("    v = std::fma(v, x, c{});\n", i))
+            .collect();
+
+        return format!(
+            "
+inline double {}(double center, double scale, {}, double inp) {{
+    double x = (inp - center) * scale;
+    double v = c{};
+{}    return v;
+}}", self.function_name(), args.join(", "), degree, steps);
+    }
+
+    fn rust_code(&self) -> String {
+        let degree = self.degree();
+        let args: Vec<String> = (0..=degree).map(|i| format!("c{}: f64", i)).collect();
+        let steps: String = (0..degree).rev()
+            .map(|i| format!("    v = v.mul_add(x, c{});\n", i))
+            .collect();
+
+        return format!(
+            "
+#[inline]
+#[allow(clippy::too_many_arguments)]
+fn {}(center: f64, scale: f64, {}, inp: f64) -> f64 {{
+    let x = (inp - center) * scale;
+    let mut v = c{};
+{}    v
+}}", self.function_name(), args.join(", "), degree, steps);
+    }
+
+    fn function_name(&self) -> String {
+        return format!("poly{}", self.degree());
+    }
+
+    fn set_to_constant_model(&mut self, constant: u64) -> bool {
+        self.center = 0.0;
+        self.scale = 0.0;
+        for c in self.coeffs.iter_mut() {
+            *c = 0.0;
+        }
+        self.coeffs[0] = constant as f64;
+        return true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fit(data: Vec<(u64, usize)>, degree: usize) -> PolynomialModel {
+        let md = RMITrainingData::new(Box::new(data));
+        return PolynomialModel::new(&md, degree);
+    }
+
+    #[test]
+    fn test_exact_fit() {
+        // y = x^2 is fit exactly by every degree from 2 up
+        let data: Vec<(u64, usize)> = (0..100u64).map(|i| (i, (i * i) as usize)).collect();
+        for degree in 2..=5 {
+            let model = fit(data.clone(), degree);
+            for (x, y) in data.iter() {
+                let pred = model.predict_to_float(&(*x).into());
+                assert!((pred - *y as f64).abs() < 1e-6,
+                        "poly{} predicted {} for {}", degree, pred, y);
+            }
+        }
+    }
+
+    #[test]
+    fn test_large_keys() {
+        // centering keeps the fit accurate far from zero
+        let base = 1u64 << 50;
+        let data: Vec<(u64, usize)> = (0..1000u64)
+            .map(|i| (base + 1000 * i, (i * i / 1000) as usize))
+            .collect();
+        let model = fit(data.clone(), 3);
+        for (x, y) in data.iter() {
+            let pred = model.predict_to_float(&(*x).into());
+            assert!((pred - *y as f64).abs() <= 1.0);
+        }
+    }
+
+    #[test]
+    fn test_few_keys() {
+        let model = fit(vec![(10, 3)], 4);
+        assert_eq!(model.predict_to_int(&10u64.into()), 3);
+
+        // two keys fall back to a line through both
+        let model = fit(vec![(10, 3), (20, 7)], 5);
+        assert!((model.predict_to_float(&10u64.into()) - 3.0).abs() < 1e-9);
+        assert!((model.predict_to_float(&20u64.into()) - 7.0).abs() < 1e-9);
+        assert!((model.predict_to_float(&15u64.into()) - 5.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_constant_and_params() {
+        let data: Vec<(u64, usize)> = (0..100u64).map(|i| (i * 3, i as usize)).collect();
+        let mut model = fit(data, 4);
+        let restored = PolynomialModel::from_params(&model.params()).unwrap();
+        assert_eq!(restored.function_name(), "poly4");
+        assert_eq!(restored.predict_to_float(&50u64.into()),
+                   model.predict_to_float(&50u64.into()));
+
+        assert!(model.set_to_constant_model(42));
+        assert_eq!(model.predict_to_int(&7u64.into()), 42);
+        assert_eq!(model.params().len(), 7);
+    }
+
+    #[test]
+    fn test_empty() {
+        let model = PolynomialModel::new(&RMITrainingData::<u64>::empty(), 2);
+        assert_eq!(model.predict_to_int(&5u64.into()), 0);
+    }
+}
@@ -12,12 +12,34 @@ use log::*;
 
 pub struct RadixModel {
     params: (u8, u8),
+    key_bits: u8
+}
+
+// Radix models trained on 128-bit keys keep all of the key's bits, and
+// store the key width as an extra serialized parameter.
+fn key_bits_param(params: &[ModelParam]) -> Option<u8> {
+    return match params {
+        [] => Some(64),
+        [ModelParam::Int(64)] => Some(64),
+        [ModelParam::Int(128)] => Some(128),
+        _ => None
+    };
 }
 
 impl RadixModel {
+    pub fn from_params(params: &[ModelParam]) -> Option<RadixModel> {
+        return match params {
+            [ModelParam::Int(prefix), ModelParam::Int(bits), rest @ ..] =>
+                Some(RadixModel { params: (*prefix as u8, *bits as u8),
+                                  key_bits: key_bits_param(rest)? }),
+            _ => None
+        };
+    }
+
     pub fn new<T: TrainingKey>(data: &RMITrainingData<T>) -> RadixModel {
+        let key_bits = data.key_type().bits();
         if data.len() == 0 {
-            return RadixModel { params: (0, 0) };
+            return RadixModel { params: (0, 0), key_bits };
         }
 
         let largest_value = data.iter().map(|(_x, y)| y).max().unwrap() as u64;
@@ -35,6 +57,7 @@ impl RadixModel {
 
         return RadixModel {
             params: (common_prefix, bits),
+            key_bits
         };
     }
 }
@@ -43,6 +66,11 @@ impl Model for RadixModel {
     fn predict_to_int(&self, inp: &ModelInput) -> u64 {
         let (left_shift, num_bits) = self.params;
 
+        if self.key_bits == 128 {
+            let as_int: u128 = inp.as_int128();
+            return ((as_int << left_shift) >> (128 - num_bits)) as u64;
+        }
+
         let as_int: u64 = inp.as_int();
         let res = (as_int << left_shift) >> (64 - num_bits);
 
@@ -50,7 +78,7 @@ impl Model for RadixModel {
     }
 
     fn input_type(&self) -> ModelDataType {
-        return ModelDataType::Int;
+        return if self.key_bits == 128 { ModelDataType::Int128 } else { ModelDataType::Int };
     }
     fn output_type(&self) -> ModelDataType {
         return ModelDataType::Int;
@@ -60,7 +88,22 @@ impl Model for RadixModel {
         return vec![self.params.0.into(), self.params.1.into()];
     }
 
+    fn serialized_params(&self) -> Vec<ModelParam> {
+        let mut params = self.params();
+        params.push(self.key_bits.into());
+        return params;
+    }
+
     fn code(&self) -> String {
+        if self.key_bits == 128 {
+            return String::from(
+                "
+inline uint64_t radix128(uint64_t prefix_length, uint64_t bits, unsigned __int128 inp) {
+    return (inp << prefix_length) >> (128 - bits);
+}",
+            );
+        }
+
         return String::from(
             "
 inline uint64_t radix(uint64_t prefix_length, uint64_t bits, uint64_t inp) {
@@ -69,8 +112,28 @@ inline uint64_t radix(uint64_t prefix_length, uint64_t bits, uint64_t inp) {
         );
     }
 
+    fn rust_code(&self) -> String {
+        if self.key_bits == 128 {
+            return String::from(
+                "
+#[inline]
+fn radix128(prefix_length: u64, bits: u64, inp: u128) -> u64 {
+    ((inp << prefix_length) >> (128 - bits)) as u64
+}",
+            );
+        }
+
+        return String::from(
+            "
+#[inline]
+fn radix(prefix_length: u64, bits: u64, inp: u64) -> u64 {
+    (inp << prefix_length) >> (64 - bits)
+}",
+        );
+    }
+
     fn function_name(&self) -> String {
-        return String::from("radix");
+        return String::from(if self.key_bits == 128 { "radix128" } else { "radix" });
     }
     fn needs_bounds_check(&self) -> bool {
         return false;
@@ -83,19 +146,37 @@ inline uint64_t radix(uint64_t prefix_length, uint64_t bits, uint64_t inp) {
 pub struct RadixTable {
     prefix_bits: u8,
     table_bits: u8,
-    hint_table: Vec<u32>
+    key_bits: u8,
+    hint_table: ParamArray<u32>
 }
 
 impl RadixTable {
+    pub fn from_params(params: &[ModelParam]) -> Option<RadixTable> {
+        return match params {
+            [ModelParam::Int(prefix), ModelParam::Int(bits), ModelParam::Int32Array(table),
+             rest @ ..] =>
+                Some(RadixTable {
+                    prefix_bits: *prefix as u8,
+                    table_bits: *bits as u8,
+                    key_bits: key_bits_param(rest)?,
+                    hint_table: table.clone()
+                }),
+            _ => None
+        };
+    }
+
     pub fn new<T: TrainingKey>(data: &RMITrainingData<T>, bits: u8) -> RadixTable {
         let prefix = common_prefix_size(data);
+        let key_bits = data.key_type().bits();
         let mut hint_table: Vec<u32> = vec![0 ; 1 << bits];
 
         let mut last_radix = 0;
+        let mut last_y = 0;
         for (inp, y) in data.iter_model_input() {
-            let x = inp.as_int();
-            let num_bits = if prefix + bits > 64 { 0 } else { 64 - (prefix + bits) };
-            let current_radix = ((x << prefix) >> prefix) >> num_bits;
+            last_y = y;
+            let x = inp.as_int128();
+            let num_bits = if prefix + bits > key_bits { 0 } else { key_bits - (prefix + bits) };
+            let current_radix = (((x << prefix) >> prefix) >> num_bits) as u64;
             if current_radix == last_radix { continue; }
             assert!(current_radix < hint_table.len() as u64);
 
@@ -108,58 +189,82 @@ impl RadixTable {
             last_radix = current_radix;
         }
 
+        // keys past the last one belong to the last model
         for i in (last_radix as usize + 1)..hint_table.len() {
-            hint_table[i as usize] = hint_table.len() as u32; 
+            hint_table[i as usize] = last_y as u32;
         }
 
         return RadixTable {
             prefix_bits: prefix,
             table_bits: bits,
-            hint_table
+            key_bits,
+            hint_table: hint_table.into()
         };
     }
+
+    fn shift_bits(&self) -> u8 {
+        let used_bits = self.prefix_bits + self.table_bits;
+        return if used_bits > self.key_bits { 0 } else { self.key_bits - used_bits };
+    }
 }
 
 impl Model for RadixTable {
     fn predict_to_int(&self, inp: &ModelInput) -> u64 {
-        let as_int: u64 = inp.as_int();
         let prefix = self.prefix_bits;
-        let bits = self.table_bits;
-        let num_bits = if prefix + bits > 64 { 0 } else { 64 - (prefix + bits) };
-        let res = ((as_int << prefix) >> prefix) >> num_bits;
+        let num_bits = self.shift_bits();
+        let res = if self.key_bits == 128 {
+            let as_int: u128 = inp.as_int128();
+            (((as_int << prefix) >> prefix) >> num_bits) as u64
+        } else {
+            let as_int: u64 = inp.as_int();
+            ((as_int << prefix) >> prefix) >> num_bits
+        };
         let idx = self.hint_table[res as usize] as u64;
         
         return idx;
     }
 
     fn input_type(&self) -> ModelDataType {
-        return ModelDataType::Int;
+        return if self.key_bits == 128 { ModelDataType::Int128 } else { ModelDataType::Int };
     }
     fn output_type(&self) -> ModelDataType {
         return ModelDataType::Int;
     }
 
     fn params(&self) -> Vec<ModelParam> {
-        return vec![self.hint_table.clone().into()];
+        return vec![ModelParam::Int32Array(self.hint_table.clone())];
+    }
+
+    fn serialized_params(&self) -> Vec<ModelParam> {
+        return vec![
+            self.prefix_bits.into(),
+            self.table_bits.into(),
+            ModelParam::Int32Array(self.hint_table.clone()),
+            self.key_bits.into()
+        ];
     }
 
     fn code(&self) -> String {
-        let num_bits = if self.prefix_bits + self.table_bits > 64 {
-            0
-        } else {
-            64 - (self.prefix_bits + self.table_bits)
-        };
-        
         return format!(
             "
-inline uint64_t radix_table(const uint32_t* table, const uint64_t inp) {{
-    return table[((inp << {0}) >> {0}) >> {1}];
-}}", self.prefix_bits, num_bits
+inline uint64_t {0}(const uint32_t* table, const {1} inp) {{
+    return table[((inp << {2}) >> {2}) >> {3}];
+}}", self.function_name(), self.input_type().c_type(), self.prefix_bits, self.shift_bits()
+        );
+    }
+
+    fn rust_code(&self) -> String {
+        return format!(
+            "
+#[inline]
+fn {0}(table: &[u32], inp: {1}) -> u64 {{
+    table[(((inp << {2}) >> {2}) >> {3}) as usize] as u64
+}}", self.function_name(), self.input_type().rust_type(), self.prefix_bits, self.shift_bits()
         );
     }
 
     fn function_name(&self) -> String {
-        return String::from("radix_table");
+        return String::from(if self.key_bits == 128 { "radix_table128" } else { "radix_table" });
     }
     fn needs_bounds_check(&self) -> bool {
         return false;
@@ -175,7 +280,19 @@ mod tests {
 
     #[test]
     fn test_empty() {
-        RadixModel::new(&ModelData::empty());
+        RadixModel::new(&RMITrainingData::<u64>::empty());
+    }
+
+    #[test]
+    fn test_radix_table_past_last_key() {
+        // keys past the last training key (but with the same prefix) are
+        // routed to the last leaf, not past it
+        let data: Vec<(u64, usize)> = (0..100u64).map(|i| (i * 1000, i as usize)).collect();
+        let mut md = RMITrainingData::new(Box::new(data));
+        md.set_scale(0.1);
+        let table = RadixTable::new(&md, 8);
+        assert_eq!(table.predict_to_int(&99_000u64.into()), 9);
+        assert_eq!(table.predict_to_int(&130_000u64.into()), 9);
     }
 
 }
@@ -0,0 +1,345 @@
+// < begin copyright > 
+// Copyright Ryan Marcus 2020
+// 
+// See root directory of this project for license terms.
+// 
+// < end copyright > 
+ 
+
+// A RadixSpline-style model: an error-bounded linear spline over the keys,
+// plus a radix table over the spline points that narrows the search for
+// the spline segment containing a key down to the points sharing its
+// most significant bits. Keys are treated as (at most 64-bit) integers.
+
+use crate::models::utils::num_bits;
+use crate::models::*;
+use log::*;
+
+const DEFAULT_RADIX_BITS: u8 = 18;
+const DEFAULT_SPLINE_ERROR: u64 = 32;
+const MAX_RADIX_BITS: u8 = 28;
+
+// Returns the radix bits and spline error of a radix spline model name,
+// which is `radix_spline`, `radix_spline{bits}`, or
+// `radix_spline{bits}_{error}` (for example, radix_spline20_16).
+pub fn radix_spline_params(model_type: &str) -> Option<(u8, u64)> {
+    let rest = model_type.strip_prefix("radix_spline")?;
+    if rest.is_empty() {
+        return Some((DEFAULT_RADIX_BITS, DEFAULT_SPLINE_ERROR));
+    }
+
+    let (bits, error) = match rest.split_once('_') {
+        Some((bits, error)) => (bits, error.parse().ok()?),
+        None => (rest, DEFAULT_SPLINE_ERROR)
+    };
+    let bits: u8 = bits.parse().ok()?;
+    if bits == 0 || bits > MAX_RADIX_BITS {
+        return None;
+    }
+
+    return Some((bits, error));
+}
+
+// Whether the turn from the first to the second direction is clockwise
+// (negative) or counterclockwise (positive).
+fn orientation(dx1: f64, dy1: f64, dx2: f64, dy2: f64) -> f64 {
+    return dx1 * dy2 - dy1 * dx2;
+}
+
+// Fits the spline with the greedy corridor algorithm: a new spline point
+// is only added when the segment from the last spline point can no
+// longer stay within the error of every point since it.
+fn fit_spline(points: &[(u64, f64)], error: f64) -> Vec<(u64, f64)> {
+    let mut spline: Vec<(u64, f64)> = Vec::new();
+    let (first, rest) = match points.split_first() {
+        Some(split) => split,
+        None => return spline
+    };
+    spline.push(*first);
+
+    let mut prev = *first;
+    let mut upper = (0, 0.0);
+    let mut lower = (0, 0.0);
+    for (idx, &(x, y)) in rest.iter().enumerate() {
+        if idx == 0 {
+            upper = (x, y + error);
+            lower = (x, y - error);
+            prev = (x, y);
+            continue;
+        }
+
+        let last = *spline.last().unwrap();
+        let dx = (x - last.0) as f64;
+        let dy = y - last.1;
+        let upper_dx = (upper.0 - last.0) as f64;
+        let lower_dx = (lower.0 - last.0) as f64;
+
+        if orientation(upper_dx, upper.1 - last.1, dx, dy) > 0.0
+            || orientation(lower_dx, lower.1 - last.1, dx, dy) < 0.0 {
+            // the point is outside of the corridor, so the previous point
+            // ends the current segment
+            spline.push(prev);
+            upper = (x, y + error);
+            lower = (x, y - error);
+        } else {
+            // narrow the corridor
+            if orientation(upper_dx, upper.1 - last.1, dx, y + error - last.1) < 0.0 {
+                upper = (x, y + error);
+            }
+            if orientation(lower_dx, lower.1 - last.1, dx, y - error - last.1) > 0.0 {
+                lower = (x, y - error);
+            }
+        }
+
+        prev = (x, y);
+    }
+
+    if spline.last().unwrap().0 != prev.0 {
+        spline.push(prev);
+    }
+
+    return spline;
+}
+
+pub struct RadixSplineModel {
+    min_key: u64,
+    max_key: u64,
+    shift: u8,
+    table: ParamArray<u32>,
+    keys: ParamArray<u64>,
+    positions: ParamArray<f64>
+}
+
+impl RadixSplineModel {
+    pub fn from_params(params: &[ModelParam]) -> Option<RadixSplineModel> {
+        return match params {
+            [ModelParam::Int(min_key), ModelParam::Int(max_key), ModelParam::Int(shift),
+             ModelParam::IntArray(keys), ModelParam::FloatArray(positions),
+             ModelParam::Int32Array(table)] => {
+                if keys.is_empty() || keys.len() != positions.len() || table.len() < 2 {
+                    return None;
+                }
+                Some(RadixSplineModel {
+                    min_key: *min_key,
+                    max_key: *max_key,
+                    shift: *shift as u8,
+                    table: table.clone(),
+                    keys: keys.clone(),
+                    positions: positions.clone()
+                })
+            },
+            _ => None
+        };
+    }
+
+    pub fn new<T: TrainingKey>(data: &RMITrainingData<T>,
+                               radix_bits: u8, spline_error: u64) -> RadixSplineModel {
+        // fit the first position of each key
+        let mut points: Vec<(u64, f64)> = Vec::new();
+        for (x, y) in data.iter_model_input() {
+            let x = x.as_int();
+            if points.last().map(|(last_x, _y)| x <= *last_x).unwrap_or(false) {
+                continue;
+            }
+            points.push((x, y as f64));
+        }
+
+        if points.is_empty() {
+            points.push((0, 0.0));
+        }
+
+        let spline = fit_spline(&points, spline_error as f64);
+        trace!("Radix spline compressed {} keys to {} spline points",
+               points.len(), spline.len());
+
+        let min_key = spline.first().unwrap().0;
+        let max_key = spline.last().unwrap().0;
+
+        // shift keys (relative to the smallest key) so that they fit into
+        // the radix bits. Tables with many more entries than spline points
+        // do not narrow the search any further, so small splines (such as
+        // those of top models) use fewer bits.
+        let radix_bits = u8::min(radix_bits, num_bits(spline.len() as u64) + 2);
+        let key_bits = 64 - (max_key - min_key).leading_zeros() as u8;
+        let shift = key_bits.saturating_sub(radix_bits);
+
+        // table[p] holds the index of the first spline point whose prefix
+        // is at least p, so the points with prefix p are in
+        // table[p]..table[p + 1]
+        let num_prefixes = (((max_key - min_key) >> shift) + 1) as usize;
+        let mut table: Vec<u32> = vec![0; num_prefixes + 1];
+        let mut last_prefix = 0;
+        for (idx, (key, _pos)) in spline.iter().enumerate() {
+            let prefix = ((key - min_key) >> shift) as usize;
+            for entry in table.iter_mut().take(prefix + 1).skip(last_prefix + 1) {
+                *entry = idx as u32;
+            }
+            last_prefix = usize::max(last_prefix, prefix);
+        }
+        for entry in table.iter_mut().skip(last_prefix + 1) {
+            *entry = spline.len() as u32;
+        }
+
+        // keep any parameters stored after the table 8-byte aligned
+        if table.len() % 2 == 1 {
+            table.push(spline.len() as u32);
+        }
+
+        let (keys, positions): (Vec<u64>, Vec<f64>) = spline.into_iter().unzip();
+        return RadixSplineModel {
+            min_key, max_key, shift,
+            table: table.into(),
+            keys: keys.into(),
+            positions: positions.into()
+        };
+    }
+}
+
+impl Model for RadixSplineModel {
+    fn predict_to_float(&self, inp: &ModelInput) -> f64 {
+        let key = u64::min(u64::max(inp.as_int(), self.min_key), self.max_key);
+        let prefix = ((key - self.min_key) >> self.shift) as usize;
+        let begin = self.table[prefix] as usize;
+        let end = self.table[prefix + 1] as usize;
+
+        // the first spline point at or after the key
+        let idx = begin + self.keys[begin..end].partition_point(|k| *k < key);
+        if idx == 0 || self.keys[idx] == key {
+            return self.positions[idx];
+        }
+
+        let (x0, y0) = (self.keys[idx - 1], self.positions[idx - 1]);
+        let (x1, y1) = (self.keys[idx], self.positions[idx]);
+        let t = (key - x0) as f64 / (x1 - x0) as f64;
+        return t.mul_add(y1 - y0, y0);
+    }
+
+    fn input_type(&self) -> ModelDataType {
+        return ModelDataType::Int;
+    }
+    fn output_type(&self) -> ModelDataType {
+        return ModelDataType::Float;
+    }
+
+    fn params(&self) -> Vec<ModelParam> {
+        return vec![
+            self.min_key.into(),
+            self.max_key.into(),
+            (self.shift as u64).into(),
+            ModelParam::IntArray(self.keys.clone()),
+            ModelParam::FloatArray(self.positions.clone()),
+            ModelParam::Int32Array(self.table.clone())
+        ];
+    }
+
+    fn code(&self) -> String {
+        return String::from(
+            "
+inline double radix_spline(uint64_t min_key, uint64_t max_key, uint64_t shift,
+                           const uint64_t* keys, const double* positions,
+                           const uint32_t* table, uint64_t inp) {
+    uint64_t key = (inp < min_key ? min_key : (inp > max_key ? max_key : inp));
+    uint64_t prefix = (key - min_key) >> shift;
+
+    // find the first spline point at or after the key
+    uint64_t lo = table[prefix];
+    uint64_t hi = table[prefix + 1];
+    while (lo < hi) {
+        uint64_t mid = lo + (hi - lo) / 2;
+        if (keys[mid] < key) lo = mid + 1; else hi = mid;
+    }
+
+    if (lo == 0 || keys[lo] == key) return positions[lo];
+    double t = (double)(key - keys[lo - 1]) / (double)(keys[lo] - keys[lo - 1]);
+    return std::fma(t, positions[lo] - positions[lo - 1], positions[lo - 1]);
+}",
+        );
+    }
+
+    fn rust_code(&self) -> String {
+        return String::from(
+            "
+#[inline]
+fn radix_spline(min_key: u64, max_key: u64, shift: u64, keys: &[u64], positions: &[f64],
+                table: &[u32], inp: u64) -> f64 {
+    let key = inp.clamp(min_key, max_key);
+    let prefix = ((key - min_key) >> shift) as usize;
+
+    // find the first spline point at or after the key
+    let begin = table[prefix] as usize;
+    let end = table[prefix + 1] as usize;
+    let idx = begin + keys[begin..end].partition_point(|k| *k < key);
+
+    if idx == 0 || keys[idx] == key {
+        return positions[idx];
+    }
+    let t = (key - keys[idx - 1]) as f64 / (keys[idx] - keys[idx - 1]) as f64;
+    t.mul_add(positions[idx] - positions[idx - 1], positions[idx - 1])
+}",
+        );
+    }
+
+    fn function_name(&self) -> String {
+        return String::from("radix_spline");
+    }
+
+    fn restriction(&self) -> ModelRestriction {
+        return ModelRestriction::MustBeTop;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::train::tests::{test_keys, test_data};
+
+    #[test]
+    fn test_radix_spline_params() {
+        assert_eq!(radix_spline_params("radix_spline"), Some((18, 32)));
+        assert_eq!(radix_spline_params("radix_spline20"), Some((20, 32)));
+        assert_eq!(radix_spline_params("radix_spline12_8"), Some((12, 8)));
+        assert_eq!(radix_spline_params("radix_spline40"), None);
+        assert_eq!(radix_spline_params("radix_spline0_8"), None);
+        assert_eq!(radix_spline_params("radix_splinex"), None);
+        assert_eq!(radix_spline_params("radix"), None);
+    }
+
+    #[test]
+    fn test_spline_error() {
+        let keys = test_keys(20_000);
+        let md = test_data(20_000);
+
+        for (bits, error) in &[(18, 32), (10, 4), (4, 64), (14, 0)] {
+            let model = RadixSplineModel::new(&md, *bits, *error);
+            for (y, x) in keys.iter().enumerate() {
+                let pred = model.predict_to_float(&(*x).into());
+                assert!((pred - y as f64).abs() <= *error as f64 + 1e-6,
+                        "{} bits, error {}: predicted {} for {}", bits, error, pred, y);
+            }
+        }
+
+        let coarse = RadixSplineModel::new(&md, 18, 64).keys.len();
+        let fine = RadixSplineModel::new(&md, 18, 4).keys.len();
+        assert!(coarse < fine);
+    }
+
+    #[test]
+    fn test_out_of_range() {
+        let keys = test_keys(20_000);
+        let last_key = *keys.last().unwrap();
+        let model = RadixSplineModel::new(&test_data(20_000), 12, 8);
+
+        assert_eq!(model.predict_to_float(&0u64.into()), 0.0);
+        assert_eq!(model.predict_to_float(&std::u64::MAX.into()), (keys.len() - 1) as f64);
+
+        let restored = RadixSplineModel::from_params(&model.params()).unwrap();
+        assert_eq!(restored.predict_to_float(&(last_key / 2).into()),
+                   model.predict_to_float(&(last_key / 2).into()));
+    }
+
+    #[test]
+    fn test_empty() {
+        let model = RadixSplineModel::new(&RMITrainingData::<u64>::empty(), 18, 32);
+        assert_eq!(model.predict_to_float(&5u64.into()), 0.0);
+    }
+}
@@ -0,0 +1,197 @@
+// < begin copyright > 
+// Copyright Ryan Marcus 2020
+// 
+// See root directory of this project for license terms.
+// 
+// < end copyright > 
+ 
+
+// A top model followed by a learned remapping of its output. The top
+// model is trained to predict one of `num_slots` fine-grained slots, and
+// a monotone table maps each slot to a leaf model. Training builds the
+// table so that the leaves are spent where the keys (or the hard parts of
+// the CDF) are, while the leaves still form a single array in the
+// generated code.
+
+use crate::models::*;
+use crate::models::utils::forwarded_params;
+
+pub struct RemappedModel {
+    inner: Box<dyn Model>,
+    table: ParamArray<u32>
+}
+
+impl RemappedModel {
+    // `table[slot]` is the leaf for keys that `inner` predicts to be in
+    // `slot`, and must be non-decreasing so that the RMI stays monotone.
+    pub fn new(inner: Box<dyn Model>, table: Vec<u32>) -> RemappedModel {
+        assert!(!table.is_empty());
+        assert!(table.windows(2).all(|w| w[0] <= w[1]));
+        return RemappedModel { inner, table: table.into() };
+    }
+
+    pub fn from_params(model_type: &str, params: &[ModelParam]) -> Option<RemappedModel> {
+        if params.len() < 2 {
+            return None;
+        }
+        let (inner_params, table_params) = params.split_at(params.len() - 2);
+
+        return match table_params {
+            [ModelParam::Int(num_slots), ModelParam::Int32Array(table)]
+                if *num_slots as usize == table.len() && !table.is_empty() => {
+                let inner = learned_model_from_params(model_type, inner_params, &[])?;
+                Some(RemappedModel { inner, table: table.clone() })
+            },
+            _ => None
+        };
+    }
+
+    // The slot (before remapping) of the given input.
+    pub fn slot(inner: &dyn Model, num_slots: u64, inp: &ModelInput) -> u64 {
+        return u64::min(inner.predict_to_int(inp), num_slots - 1);
+    }
+
+    fn num_slots(&self) -> u64 {
+        return self.table.len() as u64;
+    }
+}
+
+impl Model for RemappedModel {
+    fn predict_to_int(&self, inp: &ModelInput) -> u64 {
+        let slot = RemappedModel::slot(self.inner.as_ref(), self.num_slots(), inp);
+        return self.table[slot as usize] as u64;
+    }
+
+    fn input_type(&self) -> ModelDataType {
+        return self.inner.input_type();
+    }
+    fn output_type(&self) -> ModelDataType {
+        return ModelDataType::Int;
+    }
+
+    fn params(&self) -> Vec<ModelParam> {
+        let mut params = self.inner.params();
+        params.push(ModelParam::Int(self.num_slots()));
+        params.push(ModelParam::Int32Array(self.table.clone()));
+        return params;
+    }
+
+    fn serialized_params(&self) -> Vec<ModelParam> {
+        let mut params = self.inner.serialized_params();
+        params.push(ModelParam::Int(self.num_slots()));
+        params.push(ModelParam::Int32Array(self.table.clone()));
+        return params;
+    }
+
+    fn code(&self) -> String {
+        let (decls, args) = forwarded_params(self.inner.as_ref(), false);
+        let slot = match self.inner.output_type() {
+            ModelDataType::Float => format!(
+                "double fpred = {}({}inp);
+    uint64_t slot = !(fpred > 0.0) ? 0
+        : (fpred >= num_slots - 1 ? num_slots - 1 : (uint64_t) fpred);",
+                self.inner.function_name(), args),
+            _ => format!(
+                "uint64_t slot = {}({}inp);
+    if (slot > num_slots - 1) slot = num_slots - 1;",
+                self.inner.function_name(), args)
+        };
+
+        return format!(
+            "inline uint64_t {}({}, uint64_t num_slots, const uint32_t* table, {} inp) {{
+    {}
+    return table[slot];
+}}", self.function_name(), decls, self.input_type().c_type(), slot);
+    }
+
+    fn rust_code(&self) -> String {
+        let (decls, args) = forwarded_params(self.inner.as_ref(), true);
+        let slot = match self.inner.output_type() {
+            ModelDataType::Float => format!(
+                "let fpred = {}({}inp);
+    let slot = if fpred <= 0.0 {{ 0 }} else {{ u64::min(fpred as u64, num_slots - 1) }};",
+                self.inner.function_name(), args),
+            _ => format!(
+                "let slot = u64::min({}({}inp), num_slots - 1);",
+                self.inner.function_name(), args)
+        };
+
+        return format!(
+            "#[inline]
+#[allow(clippy::too_many_arguments)]
+fn {}({}, num_slots: u64, table: &[u32], inp: {}) -> u64 {{
+    {}
+    table[slot as usize] as u64
+}}", self.function_name(), decls, self.input_type().rust_type(), slot);
+    }
+
+    fn function_name(&self) -> String {
+        return format!("{}_remap", self.inner.function_name());
+    }
+
+    fn standard_functions(&self) -> HashSet<StdFunctions> {
+        return self.inner.standard_functions();
+    }
+
+    fn needs_bounds_check(&self) -> bool {
+        return false;
+    }
+    fn restriction(&self) -> ModelRestriction {
+        return ModelRestriction::MustBeTop;
+    }
+
+    fn wrapped_model(&self) -> Option<&dyn Model> {
+        return Some(self.inner.as_ref());
+    }
+    fn wrapper(&self) -> ModelWrapper {
+        return ModelWrapper::Remap;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn linear_top(num_slots: u64) -> Box<dyn Model> {
+        let data: Vec<(u64, usize)> = (0..100u64).map(|i| (i, i as usize)).collect();
+        let mut md = RMITrainingData::new(Box::new(data));
+        md.set_scale(num_slots as f64 / 100.0);
+        return Box::new(LinearModel::new(&md));
+    }
+
+    #[test]
+    fn test_remap() {
+        // 10 slots of 10 keys each, the first half shares one leaf
+        let table = vec![0, 0, 0, 0, 0, 1, 2, 3, 4, 5];
+        let model = RemappedModel::new(linear_top(10), table);
+
+        assert_eq!(model.predict_to_int(&5u64.to_model_input()), 0);
+        assert_eq!(model.predict_to_int(&49u64.to_model_input()), 0);
+        assert_eq!(model.predict_to_int(&55u64.to_model_input()), 1);
+        assert_eq!(model.predict_to_int(&98u64.to_model_input()), 5);
+
+        // predictions past the last slot are clamped
+        assert_eq!(model.predict_to_int(&1000u64.to_model_input()), 5);
+        assert_eq!(model.function_name(), "linear_remap");
+    }
+
+    #[test]
+    fn test_nan_slot() {
+        // casting a NaN to an integer is undefined in C++, so NaN
+        // predictions must take the first branch
+        let model = RemappedModel::new(linear_top(10), vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
+        assert!(model.code().contains("!(fpred > 0.0) ? 0"));
+    }
+
+    #[test]
+    fn test_from_params() {
+        let model = RemappedModel::new(linear_top(4), vec![0, 1, 1, 2]);
+        let loaded = model_from_params("linear", ModelWrapper::Remap,
+                                       &model.serialized_params(), &[]).unwrap();
+        assert_eq!(loaded.function_name(), "linear_remap");
+        for key in 0..120u64 {
+            assert_eq!(model.predict_to_int(&key.to_model_input()),
+                       loaded.predict_to_int(&key.to_model_input()));
+        }
+    }
+}
@@ -11,6 +11,7 @@ pub enum StdFunctions {
     EXP1,
     PHI,
     BinarySearch,
+    BinarySearch128,
 }
 
 impl StdFunctions {
@@ -21,6 +22,9 @@ impl StdFunctions {
             StdFunctions::BinarySearch => {
                 "uint64_t bs_lower_bound(const uint64_t a[], uint64_t n, uint64_t x);"
             }
+            StdFunctions::BinarySearch128 => {
+                "uint64_t bs_upper_bound128(const unsigned __int128 a[], uint64_t n, unsigned __int128 x);"
+            }
         }
     }
 
@@ -59,6 +63,64 @@ uint64_t bs_upper_bound(const uint64_t a[], uint64_t n, uint64_t x) {
     return l;
 }
 
+"
+            }
+            StdFunctions::BinarySearch128 => {
+                "
+uint64_t bs_upper_bound128(const unsigned __int128 a[], uint64_t n, unsigned __int128 x) {
+    int l = 0;
+    int h = n; // Not n - 1
+    while (l < h) {
+        int mid = (l + h) / 2;
+        if (x >= a[mid]) {
+            l = mid + 1;
+        } else {
+            h = mid;
+        }
+    }
+    return l;
+}
+
+"
+            }
+        }
+    }
+
+    pub fn rust_code(&self) -> &'static str {
+        match self {
+            StdFunctions::EXP1 => {
+                "
+#[inline]
+fn exp1(x: f64) -> f64 {
+    let mut x = 1.0 + x / 64.0;
+    x *= x; x *= x; x *= x; x *= x;
+    x *= x; x *= x;
+    x
+}
+"
+            }
+            StdFunctions::PHI => {
+                "
+#[inline]
+fn phi(x: f64) -> f64 {
+    1.0 / (1.0 + exp1(-1.65451 * x))
+}
+"
+            }
+            StdFunctions::BinarySearch => {
+                "
+#[inline]
+fn bs_upper_bound(a: &[u64], x: u64) -> u64 {
+    a.partition_point(|&v| x >= v) as u64
+}
+"
+            }
+            StdFunctions::BinarySearch128 => {
+                "
+#[inline]
+fn bs_upper_bound128(a: &[u128], x: u128) -> u64 {
+    a.partition_point(|&v| x >= v) as u64
+}
 "
             }
         }
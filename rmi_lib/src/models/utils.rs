@@ -20,41 +20,75 @@ pub fn num_bits(largest_target: u64) -> u8 {
   return nbits;
 }
 
+// The parameter declarations of a function that wraps `model`'s
+// function, and the arguments (each followed by a comma) that forward
+// them to it.
+pub fn forwarded_params(model: &dyn Model, rust: bool) -> (String, String) {
+  let params = model.params();
+  let decls: Vec<String> = params.iter().enumerate()
+    .map(|(idx, p)| match (rust, p.is_array()) {
+      (false, false) => format!("{} p{}", p.c_type(), idx),
+      (false, true) => format!("const {}* p{}", p.c_type(), idx),
+      (true, false) => format!("p{}: {}", idx, p.rust_type()),
+      (true, true) => format!("p{}: &[{}]", idx, p.rust_type())
+    }).collect();
+  let args: Vec<String> = (0..params.len()).map(|idx| format!("p{}, ", idx)).collect();
+  return (decls.join(", "), args.concat());
+}
+
+// Like `forwarded_params`, for the layer arrays of `model` (see
+// `Model::layer_arrays`), with each declaration followed by a comma.
+pub fn forwarded_arrays(model: &dyn Model, rust: bool) -> (String, String) {
+  let num_arrays = model.layer_arrays().len();
+  let decls: Vec<String> = (0..num_arrays)
+    .map(|idx| if rust {
+      format!("a{}: &[f64], ", idx)
+    } else {
+      format!("const double* a{}, ", idx)
+    }).collect();
+  let args: Vec<String> = (0..num_arrays).map(|idx| format!("a{}, ", idx)).collect();
+  return (decls.concat(), args.concat());
+}
+
 pub fn common_prefix_size<T: TrainingKey>(data: &RMITrainingData<T>) -> u8 {
-  let mut any_ones: u64 = 0;
-  let mut no_ones: u64 = !0;
+  let mut any_ones: u128 = 0;
+  let mut no_ones: u128 = !0;
 
   for (x, _y) in data.iter_model_input() {
-    any_ones |= x.as_int();
-    no_ones &= x.as_int();
+    any_ones |= x.as_int128();
+    no_ones &= x.as_int128();
   }
 
-  let any_zeros = !no_ones;
-
-  let prefix_bits = any_zeros ^ any_ones;
-  return (!prefix_bits).leading_zeros() as u8;        
+  return prefix_from_bits(any_ones, no_ones, data.key_type().bits());
 }
 
-fn common_prefix_size2(data: &[u64]) -> u8 {
-  let mut any_ones: u64 = 0;
-  let mut no_ones: u64 = !0;
+fn common_prefix_size2<K: Copy + Into<u128>>(data: &[K], key_bits: u8) -> u8 {
+  let mut any_ones: u128 = 0;
+  let mut no_ones: u128 = !0;
 
   for x in data {
-    any_ones |= x;
-    no_ones &= x;
+    any_ones |= (*x).into();
+    no_ones &= (*x).into();
   }
 
+  return prefix_from_bits(any_ones, no_ones, key_bits);
+}
+
+// keys narrower than 128 bits have a common prefix of zeros in their
+// upper bits, which is not part of the key
+fn prefix_from_bits(any_ones: u128, no_ones: u128, key_bits: u8) -> u8 {
   let any_zeros = !no_ones;
 
   let prefix_bits = any_zeros ^ any_ones;
-  return (!prefix_bits).leading_zeros() as u8;        
+  return (!prefix_bits).leading_zeros().saturating_sub(128 - key_bits as u32) as u8;
 }
 
 
 
-pub fn radix_index(points: &[u64], num_bits: u8) -> Vec<u64> {
+pub fn radix_index<K>(points: &[K], key_bits: u8, num_bits: u8) -> Vec<u64>
+where K: Copy + Ord + Into<u128> + std::fmt::Display {
   // build the radix index
-  let cps = common_prefix_size2(points);
+  let cps = common_prefix_size2(points, key_bits);
   if cps != 0 {
     warn!("Radix index currently assumes the common prefix size is 0, but it was {}",
           cps);
@@ -64,7 +98,7 @@ pub fn radix_index(points: &[u64], num_bits: u8) -> Vec<u64> {
 
   let mut last_radix = 0;
   for (idx, p) in points.iter().enumerate() {
-    let radix = p >> (64 - num_bits);
+    let radix = ((*p).into() >> (key_bits - num_bits)) as u64;
     assert!(radix < radix_index.len() as u64);
 
     if radix == last_radix { continue; }
@@ -85,7 +119,7 @@ pub fn radix_index(points: &[u64], num_bits: u8) -> Vec<u64> {
   
   // verify the radix construction
   for p in points {
-    let radix = p >> (64 - num_bits);
+    let radix = ((*p).into() >> (key_bits - num_bits)) as u64;
     let radix_lb = radix_index[radix as usize];
     let radix_ub = radix_index[radix as usize + 1];
 
@@ -109,18 +143,14 @@ mod tests {
   
   #[test]
   fn test_common_prefix1() {
-    let data = ModelData::IntKeyToIntPos(vec![
-      (1, 0), (4, 4), (8, 8)
-    ]);
+    let data = RMITrainingData::new(Box::new(vec![(1u64, 0usize), (4, 4), (8, 8)]));
 
     assert_eq!(common_prefix_size(&data), 64-4);
   }
 
   #[test]
   fn test_common_prefix2() {
-    let data = ModelData::IntKeyToIntPos(vec![
-      (1, 0), (8, 1), (9, 4), (12, 8)
-    ]);
+    let data = RMITrainingData::new(Box::new(vec![(1u64, 0usize), (8, 1), (9, 4), (12, 8)]));
 
     assert_eq!(common_prefix_size(&data), 64-4);
   }
@@ -1,8 +1,10 @@
 use crate::models::*;
 use crate::train;
+use crate::train::Sampling;
 use crate::codegen;
+use crate::error::RmiError;
 use log::*;
-use json::*;
+use json::{object, JsonValue};
 use indicatif::{ProgressBar};
 use rayon::prelude::*;
 use std::collections::BTreeSet;
@@ -12,48 +14,51 @@ use tabular::{Table, row};
 //const ANYWHERE_LAYERS: &[&str] = &["linear", "cubic", "linear_spline"];
 //const SPECIALTY_TOP_LAYERS: &[&str] = &["histogram", "loglinear", "normal", "lognormal", "bradix"];
 
-fn top_only_layers() -> Vec<&'static str> {
-    return match std::env::var_os("RMI_OPTIMIZER_PROFILE") {
+fn top_only_layers() -> Result<Vec<&'static str>, RmiError> {
+    return Ok(match std::env::var_os("RMI_OPTIMIZER_PROFILE") {
         None => vec!["radix", "radix18", "radix22", "robust_linear"],
         Some(x) => {
-            match x.to_str().unwrap() {
+            match x.to_string_lossy().as_ref() {
                 "fast" => vec!["robust_linear"],
                 "memory" => vec!["radix", "radix18", "radix22", "robust_linear"],
                 "disk" => vec!["radix", "radix18", "radix22", "robust_linear",
                                "normal", "lognormal", "loglinear"],
-                _ => panic!("Invalid optimizer profile {}", x.to_str().unwrap())
+                _ => return Err(RmiError::InvalidOptimizerProfile(
+                    String::from(x.to_string_lossy())))
             }
         }
-    };
+    });
 }
 
-fn anywhere_layers() -> Vec<&'static str> {
-    return match std::env::var_os("RMI_OPTIMIZER_PROFILE") {
+fn anywhere_layers() -> Result<Vec<&'static str>, RmiError> {
+    return Ok(match std::env::var_os("RMI_OPTIMIZER_PROFILE") {
         None => vec!["linear", "cubic", "linear_spline"],
         Some(x) => {
-            match x.to_str().unwrap() {
+            match x.to_string_lossy().as_ref() {
                 "fast" => vec!["linear", "cubic"],
                 "memory" | "disk" => vec!["linear", "cubic", "linear_spline"],
-                _ => panic!("Invalid optimizer profile {}", x.to_str().unwrap())
+                _ => return Err(RmiError::InvalidOptimizerProfile(
+                    String::from(x.to_string_lossy())))
             }
         }
-    };
+    });
 }
 
-fn get_branching_factors() -> Vec<u64> {
+fn get_branching_factors() -> Result<Vec<u64>, RmiError> {
     let range = match std::env::var_os("RMI_OPTIMIZER_PROFILE") {
         None => (6..25).step_by(1),
         Some(x) => {
-            match x.to_str().unwrap() {
+            match x.to_string_lossy().as_ref() {
                 "fast" => (6..25).step_by(2),
                 "memory" => (6..25).step_by(1),
                 "disk" => (6..28).step_by(1),
-                _ => panic!("Invalid optimizer profile {}", x.to_str().unwrap())
+                _ => return Err(RmiError::InvalidOptimizerProfile(
+                    String::from(x.to_string_lossy())))
             }
         }
     };
 
-    return range.map(|i| (2 as u64).pow(i)).collect();
+    return Ok(range.map(|i| (2 as u64).pow(i)).collect());
 }
 
 fn pareto_front(results: &[RMIStatistics]) -> Vec<RMIStatistics> {
@@ -107,24 +112,25 @@ fn narrow_front(results: &[RMIStatistics], desired_size: usize) -> Vec<RMIStatis
 
 }
 
-fn first_phase_configs() -> Vec<(String, u64)> {
+fn first_phase_configs() -> Result<Vec<(String, u64)>, RmiError> {
     let mut results = Vec::new();
     let mut all_top_models = Vec::new();
-    all_top_models.extend(top_only_layers());
-    all_top_models.extend(anywhere_layers());
+    all_top_models.extend(top_only_layers()?);
+    all_top_models.extend(anywhere_layers()?);
     
     for top_model in all_top_models {
-        for bottom_model in anywhere_layers() {
-            for branching_factor in get_branching_factors().iter().step_by(5) {
+        for bottom_model in anywhere_layers()? {
+            for branching_factor in get_branching_factors()?.iter().step_by(5) {
                 results.push((format!("{},{}", top_model, bottom_model), *branching_factor));
             }
         }
     }
 
-    return results;
+    return Ok(results);
 }
 
-fn second_phase_configs(first_phase: &[RMIStatistics]) -> Vec<(String, u64)> {
+fn second_phase_configs(first_phase: &[RMIStatistics])
+                        -> Result<Vec<(String, u64)>, RmiError> {
     let qualifying_model_configs = {
         let on_front = pareto_front(first_phase);
         let mut qualifying = BTreeSet::new();
@@ -138,7 +144,7 @@ fn second_phase_configs(first_phase: &[RMIStatistics]) -> Vec<(String, u64)> {
     let mut results = Vec::new();
 
     for model in qualifying_model_configs.iter() {
-        for branching_factor in get_branching_factors() {
+        for branching_factor in get_branching_factors()? {
             if first_phase.iter().any(|v| v.has_config(&model, branching_factor)) {
                 continue;
             }
@@ -147,7 +153,7 @@ fn second_phase_configs(first_phase: &[RMIStatistics]) -> Vec<(String, u64)> {
         }
     }
     
-    return results;
+    return Ok(results);
 }
 
 #[derive(Clone, Debug)]
@@ -218,32 +224,114 @@ impl RMIStatistics {
 }
 
 fn measure_rmis<T: TrainingKey>(data: &RMITrainingData<T>,
-                configs: &[(String, u64)]) -> Vec<RMIStatistics> {
+                configs: &[(String, u64)]) -> Result<Vec<RMIStatistics>, RmiError> {
     let pbar = ProgressBar::new(configs.len() as u64);
     
    configs.par_iter()
         .map(|(models, branch_factor)| {
             let mut loc_data = data.soft_copy();
-            let res = train::train(&mut loc_data, models, *branch_factor);
+            let res = train::train(&mut loc_data, models, *branch_factor)?;
             pbar.inc(1);
-            RMIStatistics::from_trained(&res)
+            Ok(RMIStatistics::from_trained(&res))
         }).collect()
 }
 
+// Draws a stratified sample of about sample_size keys to evaluate
+// configurations on, and returns it with the factor its errors should be
+// scaled by. Against their positions in the full data, errors smaller than
+// the gap between sampled keys cannot be told apart, so the sampled keys
+// are given their positions in the sample instead.
+fn relative_sample<T: TrainingKey>(data: &RMITrainingData<T>, sample_size: usize)
+                                   -> Result<(RMITrainingData<T>, f64), RmiError> {
+    let rate = usize::max(sample_size, 1) as f64 / data.len() as f64;
+    let sample = train::sample(data, Sampling::Stratified { rate }, 0)?;
+    info!("Evaluating configurations on a sample of {} of {} keys",
+          sample.len(), data.len());
+
+    let relative: Vec<(T, usize)> = (0..sample.len())
+        .map(|idx| (sample.get_key(idx), idx))
+        .collect();
+    let scale = data.len() as f64 / relative.len() as f64;
+    return Ok((RMITrainingData::new(Box::new(relative)), scale));
+}
+
+// Measures the configurations on a sample whose errors are scale times
+// smaller than on the full data.
+fn measure_scaled_rmis<T: TrainingKey>(data: &RMITrainingData<T>,
+                                       configs: &[(String, u64)],
+                                       scale: f64) -> Result<Vec<RMIStatistics>, RmiError> {
+    let mut results = measure_rmis(data, configs)?;
+    for result in results.iter_mut() {
+        result.average_log2_error += scale.log2();
+        result.max_log2_error += scale.log2();
+    }
+    return Ok(results);
+}
+
 pub fn find_pareto_efficient_configs<T: TrainingKey>(
     data: &RMITrainingData<T>, restrict: usize)
-    -> Vec<RMIStatistics>{
-    let initial_configs  = first_phase_configs();
-    let first_phase_results = measure_rmis(data, &initial_configs);
+    -> Result<Vec<RMIStatistics>, RmiError> {
+    return find_pareto_efficient_configs_sampled(data, restrict, data.len());
+}
+
+/// Like `find_pareto_efficient_configs`, but evaluates the candidate
+/// configurations on a stratified sample of about `sample_size` keys.
+/// The models are fit to the keys' positions within the sample, and the
+/// errors measured on the sample are scaled up by the sampling rate to
+/// estimate the errors on the full data. Only the final Pareto front is
+/// retrained on the full data, so the returned statistics are exact.
+pub fn find_pareto_efficient_configs_sampled<T: TrainingKey>(
+    data: &RMITrainingData<T>, restrict: usize, sample_size: usize)
+    -> Result<Vec<RMIStatistics>, RmiError> {
+    data.check_sorted()?;
+    let sampled = sample_size < data.len();
+    let (eval_data, scale) = if sampled {
+        relative_sample(data, sample_size)?
+    } else {
+        (data.soft_copy(), 1.0)
+    };
 
-    let next_configs = second_phase_configs(&first_phase_results);
-    let second_phase_results = measure_rmis(data, &next_configs);
+    let initial_configs  = first_phase_configs()?;
+    let first_phase_results = measure_scaled_rmis(&eval_data, &initial_configs, scale)?;
+
+    let next_configs = second_phase_configs(&first_phase_results)?;
+    let second_phase_results = measure_scaled_rmis(&eval_data, &next_configs, scale)?;
     
     let mut final_front = pareto_front(&second_phase_results);
     final_front = narrow_front(&final_front, restrict);
+
+    if sampled {
+        info!("Retraining {} configurations on the full data", final_front.len());
+        let configs: Vec<(String, u64)> = final_front.iter()
+            .map(|r| (r.models.clone(), r.branching_factor))
+            .collect();
+        final_front = measure_rmis(data, &configs)?;
+    }
+    
     final_front.sort_by(
         |a, b| a.average_log2_error.partial_cmp(&b.average_log2_error).unwrap()
     );
 
-    return final_front;
+    return Ok(final_front);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scaled_sample_errors() {
+        let data: Vec<(u64, usize)> = (0..200_000u64)
+            .map(|i| (i * i / 1000 + i + 1, i as usize))
+            .collect();
+        let md = RMITrainingData::new(Box::new(data));
+        let configs = vec![(String::from("linear,linear"), 16)];
+
+        let exact = &measure_rmis(&md, &configs).unwrap()[0];
+        let (sample, scale) = relative_sample(&md, 10_000).unwrap();
+        let estimate = &measure_scaled_rmis(&sample, &configs, scale).unwrap()[0];
+
+        assert!((estimate.average_log2_error - exact.average_log2_error).abs() < 1.0,
+                "estimated {} for {}", estimate.average_log2_error, exact.average_log2_error);
+    }
 }
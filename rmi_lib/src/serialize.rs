@@ -0,0 +1,697 @@
+// < begin copyright > 
+// Copyright Ryan Marcus 2020
+// 
+// See root directory of this project for license terms.
+// 
+// < end copyright > 
+ 
+
+// Single-file binary format for trained RMIs. All values are little endian.
+//
+//   magic           8 bytes, "RMIMODEL"
+//   version         u32
+//   metadata        u64 length, then the metadata section (below)
+//   checksum        u64 FNV-1a hash of the metadata section
+//   data            zero padding up to a 16-byte file offset, then the
+//                   data section: the elements of every array, each
+//                   starting at a multiple of 8 bytes (16 bytes for
+//                   128-bit arrays)
+//   checksum        u64 FNV-1a hash of the data section
+//
+// The metadata section contains:
+//
+//   key type        u8
+//   key prefix      u64 length + bytes, only for string keys
+//   num_rmi_rows, num_data_rows, branching_factor, build_time (u128)
+//   error statistics (avg, avg l2, avg log2, max, max idx, max log2)
+//   model spec      u64 length + UTF-8 bytes (e.g., "linear,cubic")
+//   layers          u64 count, then for each layer a u64 model count, a
+//                   u8 tag for the wrapper around its models (none,
+//                   fallback nodes, or a remapping table), a u64 count of
+//                   the arrays shared by its models (see
+//                   `Model::layer_arrays`) followed by the arrays as tagged
+//                   parameters, and, for each model, a u64 parameter count
+//                   followed by the tagged parameters. Array parameters
+//                   store their length and the offset of their elements in
+//                   the data section.
+//   errors          u64 length and data section offset of how far below
+//                   and above its prediction a key can be for each leaf
+//                   (last_layer_bounds), stored as two u64s
+//   cache fix       u8 flag, then line size and (key, offset) spline points
+//
+// Each layer's model type is recovered from the model spec, so loading
+// does not need any training data.
+//
+// Because arrays are aligned, `TrainedRMI::load_mmap` can hand out arrays
+// that point straight into the mapped file. The data section has its own
+// checksum so that `TrainedRMI::load_mmap_unchecked` can skip it, and only
+// read the arrays as lookups touch them.
+
+use crate::models::*;
+use crate::train::TrainedRMI;
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+use memmap::Mmap;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read};
+use std::path::Path;
+use std::sync::Arc;
+
+const MAGIC: &[u8; 8] = b"RMIMODEL";
+const VERSION: u32 = 1;
+const ARRAY_ALIGN: usize = 8;
+const DATA_ALIGN: usize = 16;
+
+const TAG_INT: u8 = 0;
+const TAG_FLOAT: u8 = 1;
+const TAG_SHORT_ARRAY: u8 = 2;
+const TAG_INT_ARRAY: u8 = 3;
+const TAG_INT32_ARRAY: u8 = 4;
+const TAG_FLOAT_ARRAY: u8 = 5;
+const TAG_INT128_ARRAY: u8 = 6;
+
+fn invalid(msg: String) -> Error {
+    return Error::new(ErrorKind::InvalidData, msg);
+}
+
+fn checksum(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in data {
+        hash ^= u64::from(*b);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    return hash;
+}
+
+fn key_type_tag(key_type: KeyType) -> u8 {
+    return match key_type {
+        KeyType::U32 => 0,
+        KeyType::U64 => 1,
+        KeyType::F64 => 2,
+        KeyType::U128 => 3,
+        KeyType::I32 => 4,
+        KeyType::I64 => 5,
+        KeyType::Str => 6,
+        KeyType::F32 => 7,
+    };
+}
+
+fn key_type_from_tag(tag: u8) -> Result<KeyType, Error> {
+    return match tag {
+        0 => Ok(KeyType::U32),
+        1 => Ok(KeyType::U64),
+        2 => Ok(KeyType::F64),
+        3 => Ok(KeyType::U128),
+        4 => Ok(KeyType::I32),
+        5 => Ok(KeyType::I64),
+        6 => Ok(KeyType::Str),
+        7 => Ok(KeyType::F32),
+        _ => Err(invalid(format!("unknown key type tag {}", tag)))
+    };
+}
+
+fn wrapper_tag(wrapper: ModelWrapper) -> u8 {
+    return match wrapper {
+        ModelWrapper::None => 0,
+        ModelWrapper::Fallback => 1,
+        ModelWrapper::Remap => 2,
+    };
+}
+
+fn wrapper_from_tag(tag: u8) -> Result<ModelWrapper, Error> {
+    return match tag {
+        0 => Ok(ModelWrapper::None),
+        1 => Ok(ModelWrapper::Fallback),
+        2 => Ok(ModelWrapper::Remap),
+        _ => Err(invalid(format!("unknown model wrapper tag {}", tag)))
+    };
+}
+
+fn array_align(tag: u8) -> usize {
+    return if tag == TAG_INT128_ARRAY { 16 } else { ARRAY_ALIGN };
+}
+
+// The two sections of a serialized RMI, see the format above.
+struct Sections {
+    meta: Vec<u8>,
+    data: Vec<u8>
+}
+
+impl Sections {
+    // Writes the length of an array and the offset of its elements to the
+    // metadata, and its elements (written by `write`) to the data section.
+    fn write_array<F>(&mut self, len: usize, align: usize, write: F) -> Result<(), Error>
+    where F: FnOnce(&mut Vec<u8>) -> Result<(), Error> {
+        while self.data.len() % align != 0 {
+            self.data.write_u8(0)?;
+        }
+        self.meta.write_u64::<LittleEndian>(len as u64)?;
+        self.meta.write_u64::<LittleEndian>(self.data.len() as u64)?;
+        return write(&mut self.data);
+    }
+}
+
+fn write_param(out: &mut Sections, param: &ModelParam) -> Result<(), Error> {
+    let tag = match param {
+        ModelParam::Int(_) => TAG_INT,
+        ModelParam::Float(_) => TAG_FLOAT,
+        ModelParam::ShortArray(_) => TAG_SHORT_ARRAY,
+        ModelParam::IntArray(_) => TAG_INT_ARRAY,
+        ModelParam::Int32Array(_) => TAG_INT32_ARRAY,
+        ModelParam::FloatArray(_) => TAG_FLOAT_ARRAY,
+        ModelParam::Int128Array(_) => TAG_INT128_ARRAY,
+    };
+    out.meta.write_u8(tag)?;
+
+    if param.is_array() {
+        return out.write_array(param.len(), array_align(tag), |data| param.write_to(data));
+    }
+    return param.write_to(&mut out.meta);
+}
+
+// The data section of a serialized RMI, its offset in the file, and the
+// file's memory map if it has one.
+struct DataSection<'a> {
+    data: &'a [u8],
+    offset: usize,
+    map: Option<&'a Arc<Mmap>>
+}
+
+impl <'a> DataSection<'a> {
+    // Reads the length and offset of an array from the metadata, and
+    // returns the array, borrowed from the memory map if there is one.
+    // Otherwise, the elements are decoded with `decode`.
+    fn read_array<T, F>(&self, inp: &mut &[u8], align: usize,
+                        decode: F) -> Result<ParamArray<T>, Error>
+    where T: Clone + Default, F: FnOnce(&[u8], &mut [T]) {
+        let len = inp.read_u64::<LittleEndian>()? as usize;
+        let offset = inp.read_u64::<LittleEndian>()? as usize;
+        let end = len.checked_mul(std::mem::size_of::<T>())
+            .and_then(|num_bytes| num_bytes.checked_add(offset))
+            .filter(|end| *end <= self.data.len())
+            .ok_or_else(|| invalid(String::from("array exceeds data section")))?;
+        if offset % align != 0 {
+            return Err(invalid(String::from("array is not aligned")));
+        }
+
+        return Ok(match self.map {
+            Some(map) if cfg!(target_endian = "little") =>
+                ParamArray::mapped(Arc::clone(map), self.offset + offset, len),
+            _ => {
+                let mut arr = vec![T::default(); len];
+                decode(&self.data[offset..end], &mut arr);
+                arr.into()
+            }
+        });
+    }
+}
+
+fn read_param(inp: &mut &[u8], src: &DataSection) -> Result<ModelParam, Error> {
+    let tag = inp.read_u8()?;
+    let align = array_align(tag);
+    return match tag {
+        TAG_INT => Ok(ModelParam::Int(inp.read_u64::<LittleEndian>()?)),
+        TAG_FLOAT => Ok(ModelParam::Float(inp.read_f64::<LittleEndian>()?)),
+        TAG_SHORT_ARRAY =>
+            Ok(ModelParam::ShortArray(src.read_array(inp, align, LittleEndian::read_u16_into)?)),
+        TAG_INT_ARRAY =>
+            Ok(ModelParam::IntArray(src.read_array(inp, align, LittleEndian::read_u64_into)?)),
+        TAG_INT32_ARRAY =>
+            Ok(ModelParam::Int32Array(src.read_array(inp, align, LittleEndian::read_u32_into)?)),
+        TAG_FLOAT_ARRAY =>
+            Ok(ModelParam::FloatArray(src.read_array(inp, align, LittleEndian::read_f64_into)?)),
+        TAG_INT128_ARRAY =>
+            Ok(ModelParam::Int128Array(src.read_array(inp, align, LittleEndian::read_u128_into)?)),
+        _ => Err(invalid(format!("unknown parameter tag {}", tag)))
+    };
+}
+
+fn decode_bounds(bytes: &[u8], bounds: &mut [[u64; 2]]) {
+    for (pair, chunk) in bounds.iter_mut().zip(bytes.chunks_exact(16)) {
+        pair[0] = LittleEndian::read_u64(&chunk[..8]);
+        pair[1] = LittleEndian::read_u64(&chunk[8..]);
+    }
+}
+
+// The file offset of the data section, after the metadata section of the
+// given length and its checksum.
+fn data_offset(meta_len: usize) -> usize {
+    let end = MAGIC.len() + 4 + 8 + meta_len + 8;
+    return (end + DATA_ALIGN - 1) / DATA_ALIGN * DATA_ALIGN;
+}
+
+impl TrainedRMI {
+    fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut out = Sections { meta: Vec::new(), data: Vec::new() };
+        out.meta.write_u8(key_type_tag(self.key_type))?;
+        if self.key_type == KeyType::Str {
+            out.meta.write_u64::<LittleEndian>(self.key_prefix.len() as u64)?;
+            out.meta.extend_from_slice(&self.key_prefix);
+        }
+
+        out.meta.write_u64::<LittleEndian>(self.num_rmi_rows as u64)?;
+        out.meta.write_u64::<LittleEndian>(self.num_data_rows as u64)?;
+        out.meta.write_u64::<LittleEndian>(self.branching_factor)?;
+        out.meta.write_u128::<LittleEndian>(self.build_time)?;
+
+        out.meta.write_f64::<LittleEndian>(self.model_avg_error)?;
+        out.meta.write_f64::<LittleEndian>(self.model_avg_l2_error)?;
+        out.meta.write_f64::<LittleEndian>(self.model_avg_log2_error)?;
+        out.meta.write_u64::<LittleEndian>(self.model_max_error)?;
+        out.meta.write_u64::<LittleEndian>(self.model_max_error_idx as u64)?;
+        out.meta.write_f64::<LittleEndian>(self.model_max_log2_error)?;
+
+        out.meta.write_u64::<LittleEndian>(self.models.len() as u64)?;
+        out.meta.extend_from_slice(self.models.as_bytes());
+
+        out.meta.write_u64::<LittleEndian>(self.rmi.len() as u64)?;
+        for layer in self.rmi.iter() {
+            out.meta.write_u64::<LittleEndian>(layer.len() as u64)?;
+            out.meta.write_u8(wrapper_tag(layer[0].wrapper()))?;
+            let arrays = layer_arrays(layer);
+            out.meta.write_u64::<LittleEndian>(arrays.len() as u64)?;
+            for array in arrays {
+                write_param(&mut out, &array.into())?;
+            }
+            for model in layer.iter() {
+                let params = model.serialized_params();
+                out.meta.write_u64::<LittleEndian>(params.len() as u64)?;
+                for param in params.iter() {
+                    write_param(&mut out, param)?;
+                }
+            }
+        }
+
+        let bounds = &self.last_layer_bounds;
+        out.write_array(bounds.len(), ARRAY_ALIGN, |data| {
+            for [below, above] in bounds.iter() {
+                data.write_u64::<LittleEndian>(*below)?;
+                data.write_u64::<LittleEndian>(*above)?;
+            }
+            return Ok(());
+        })?;
+
+        match &self.cache_fix {
+            None => out.meta.write_u8(0)?,
+            Some((line_size, spline)) => {
+                out.meta.write_u8(1)?;
+                out.meta.write_u64::<LittleEndian>(*line_size as u64)?;
+                out.meta.write_u64::<LittleEndian>(spline.len() as u64)?;
+                for (key, offset) in spline.iter() {
+                    out.meta.write_u64::<LittleEndian>(*key)?;
+                    out.meta.write_u64::<LittleEndian>(*offset as u64)?;
+                }
+            }
+        };
+
+        let mut buf: Vec<u8> = Vec::new();
+        buf.extend_from_slice(MAGIC);
+        buf.write_u32::<LittleEndian>(VERSION)?;
+        buf.write_u64::<LittleEndian>(out.meta.len() as u64)?;
+        buf.extend_from_slice(&out.meta);
+        buf.write_u64::<LittleEndian>(checksum(&out.meta))?;
+        buf.resize(data_offset(out.meta.len()), 0);
+        buf.extend_from_slice(&out.data);
+        buf.write_u64::<LittleEndian>(checksum(&out.data))?;
+        return Ok(buf);
+    }
+
+    fn from_bytes(file: &[u8], map: Option<&Arc<Mmap>>,
+                  check_data: bool) -> Result<TrainedRMI, Error> {
+        if file.len() < MAGIC.len() + 4 || &file[0..MAGIC.len()] != MAGIC {
+            return Err(invalid(String::from("not a serialized RMI")));
+        }
+
+        let mut inp = &file[MAGIC.len()..];
+        let version = inp.read_u32::<LittleEndian>()?;
+        if version != VERSION {
+            return Err(invalid(format!("unsupported serialized RMI version {}", version)));
+        }
+
+        let meta_len = inp.read_u64::<LittleEndian>()? as usize;
+        if meta_len > inp.len() {
+            return Err(invalid(String::from("metadata exceeds file size")));
+        }
+        let (meta, mut rest) = inp.split_at(meta_len);
+        if rest.read_u64::<LittleEndian>()? != checksum(meta) {
+            return Err(invalid(String::from("serialized RMI metadata checksum mismatch")));
+        }
+
+        let data_start = data_offset(meta_len);
+        if data_start + 8 > file.len() {
+            return Err(invalid(String::from("serialized RMI is missing its data section")));
+        }
+        let (data, mut hash_bytes) = file[data_start..].split_at(file.len() - data_start - 8);
+        if check_data && hash_bytes.read_u64::<LittleEndian>()? != checksum(data) {
+            return Err(invalid(String::from("serialized RMI data checksum mismatch")));
+        }
+        let src = DataSection { data, offset: data_start, map };
+
+        let mut inp = meta;
+        let key_type = key_type_from_tag(inp.read_u8()?)?;
+        let mut key_prefix = Vec::new();
+        if key_type == KeyType::Str {
+            let prefix_len = inp.read_u64::<LittleEndian>()? as usize;
+            if prefix_len > inp.len() {
+                return Err(invalid(String::from("key prefix exceeds file size")));
+            }
+            key_prefix.extend_from_slice(&inp[..prefix_len]);
+            inp = &inp[prefix_len..];
+        }
+
+        let num_rmi_rows = inp.read_u64::<LittleEndian>()? as usize;
+        let num_data_rows = inp.read_u64::<LittleEndian>()? as usize;
+        let branching_factor = inp.read_u64::<LittleEndian>()?;
+        let build_time = inp.read_u128::<LittleEndian>()?;
+
+        let model_avg_error = inp.read_f64::<LittleEndian>()?;
+        let model_avg_l2_error = inp.read_f64::<LittleEndian>()?;
+        let model_avg_log2_error = inp.read_f64::<LittleEndian>()?;
+        let model_max_error = inp.read_u64::<LittleEndian>()?;
+        let model_max_error_idx = inp.read_u64::<LittleEndian>()? as usize;
+        let model_max_log2_error = inp.read_f64::<LittleEndian>()?;
+
+        let models_len = inp.read_u64::<LittleEndian>()? as usize;
+        let mut models_bytes = vec![0; models_len];
+        inp.read_exact(&mut models_bytes)?;
+        let models = String::from_utf8(models_bytes)
+            .map_err(|_| invalid(String::from("model spec is not valid UTF-8")))?;
+        let model_types: Vec<&str> = models.split(',').collect();
+
+        let num_layers = inp.read_u64::<LittleEndian>()? as usize;
+        if num_layers != model_types.len() {
+            return Err(invalid(format!("model spec {} does not match {} layers",
+                                       models, num_layers)));
+        }
+
+        let mut rmi: Vec<Vec<Box<dyn Model>>> = Vec::with_capacity(num_layers);
+        for model_type in model_types.iter() {
+            let num_models = inp.read_u64::<LittleEndian>()? as usize;
+            let wrapper = wrapper_from_tag(inp.read_u8()?)?;
+            let num_arrays = inp.read_u64::<LittleEndian>()? as usize;
+            let mut arrays: Vec<ParamArray<f64>> = Vec::new();
+            for _ in 0..num_arrays {
+                match read_param(&mut inp, &src)? {
+                    ModelParam::FloatArray(array) => arrays.push(array),
+                    _ => return Err(invalid(String::from("layer arrays must be float arrays")))
+                };
+            }
+
+            let mut layer: Vec<Box<dyn Model>> = Vec::with_capacity(num_models);
+            for _ in 0..num_models {
+                let num_params = inp.read_u64::<LittleEndian>()? as usize;
+                let params = (0..num_params)
+                    .map(|_| read_param(&mut inp, &src))
+                    .collect::<Result<Vec<ModelParam>, Error>>()?;
+
+                let model = model_from_params(model_type, wrapper, &params, &arrays)
+                    .ok_or_else(|| invalid(format!("invalid parameters for model type {}",
+                                                   model_type)))?;
+                layer.push(model);
+            }
+            rmi.push(layer);
+        }
+
+        let last_layer_bounds = src.read_array(&mut inp, ARRAY_ALIGN, decode_bounds)?;
+        // lookups use a single bound for every leaf, or one bound per leaf
+        let num_leaves = rmi.last().map(|layer| layer.len()).unwrap_or(0);
+        if last_layer_bounds.len() > 1 && last_layer_bounds.len() != num_leaves {
+            return Err(invalid(format!("{} leaf error bounds for {} leaves",
+                                       last_layer_bounds.len(), num_leaves)));
+        }
+
+        let cache_fix = if inp.read_u8()? == 0 {
+            None
+        } else {
+            let line_size = inp.read_u64::<LittleEndian>()? as usize;
+            let num_pts = inp.read_u64::<LittleEndian>()? as usize;
+            let mut spline = Vec::with_capacity(num_pts);
+            for _ in 0..num_pts {
+                let key = inp.read_u64::<LittleEndian>()?;
+                let offset = inp.read_u64::<LittleEndian>()? as usize;
+                spline.push((key, offset));
+            }
+            Some((line_size, spline))
+        };
+
+        if !inp.is_empty() {
+            return Err(invalid(String::from("trailing data after serialized RMI")));
+        }
+
+        return Ok(TrainedRMI {
+            num_rmi_rows,
+            num_data_rows,
+            model_avg_error,
+            model_avg_l2_error,
+            model_avg_log2_error,
+            model_max_error,
+            model_max_error_idx,
+            model_max_log2_error,
+            last_layer_bounds,
+            rmi,
+            models,
+            branching_factor,
+            key_type,
+            key_prefix,
+            cache_fix,
+            build_time
+        });
+    }
+
+    /// Writes the trained RMI to a single versioned, checksummed file.
+    /// The file can be read back with `TrainedRMI::load` to evaluate the
+    /// RMI or to generate code without retraining.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        return std::fs::write(path, self.to_bytes()?);
+    }
+
+    /// Reads an RMI written by `TrainedRMI::save`. Returns an error of kind
+    /// `InvalidData` if the file is corrupt or was written by an
+    /// incompatible version.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<TrainedRMI, Error> {
+        return TrainedRMI::from_bytes(&std::fs::read(path)?, None, true);
+    }
+
+    /// Like `TrainedRMI::load`, but memory maps the file and borrows every
+    /// array (e.g., radix tables, histograms, the arrays shared by a
+    /// layer's models, and the leaf error bounds) directly from the mapping
+    /// instead of copying them. The whole file is still read once to verify
+    /// its checksums. The file must not be modified while the RMI is in
+    /// use. Files loaded on big endian machines are copied instead.
+    pub fn load_mmap<P: AsRef<Path>>(path: P) -> Result<TrainedRMI, Error> {
+        return TrainedRMI::map_file(path, true);
+    }
+
+    /// Like `TrainedRMI::load_mmap`, but only verifies the checksum of the
+    /// metadata (the model spec, parameter counts, and array offsets and
+    /// lengths), so that the arrays are not read until lookups touch them.
+    /// Corrupt array contents are not detected, and give wrong lookup
+    /// results instead of an error.
+    pub fn load_mmap_unchecked<P: AsRef<Path>>(path: P) -> Result<TrainedRMI, Error> {
+        return TrainedRMI::map_file(path, false);
+    }
+
+    fn map_file<P: AsRef<Path>>(path: P, check_data: bool) -> Result<TrainedRMI, Error> {
+        let file = File::open(path)?;
+        let map = Arc::new(unsafe { Mmap::map(&file)? });
+        return TrainedRMI::from_bytes(&map, Some(&map), check_data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MAGIC;
+    use crate::*;
+    use crate::models::ModelParam;
+    use crate::train::tests::{test_keys, test_keys_u128, test_data};
+
+    fn round_trip(rmi: &TrainedRMI, name: &str) -> TrainedRMI {
+        let path = std::env::temp_dir().join(name);
+        rmi.save(&path).unwrap();
+        return TrainedRMI::load(&path).unwrap();
+    }
+
+    fn check_same(a: &TrainedRMI, b: &TrainedRMI, keys: &[u64]) {
+        assert_eq!(a.models, b.models);
+        assert_eq!(a.key_type, b.key_type);
+        assert_eq!(a.num_rmi_rows, b.num_rmi_rows);
+        assert_eq!(a.num_data_rows, b.num_data_rows);
+        assert_eq!(a.branching_factor, b.branching_factor);
+        assert_eq!(a.last_layer_bounds, b.last_layer_bounds);
+        assert_eq!(a.cache_fix, b.cache_fix);
+        assert_eq!(a.build_time, b.build_time);
+        for &key in keys {
+            assert_eq!(a.lookup(key), b.lookup(key));
+            assert_eq!(a.lookup(key + 1), b.lookup(key + 1));
+        }
+    }
+
+    #[test]
+    fn test_save_load() {
+        let keys = test_keys(10_000);
+        let md = test_data(10_000);
+
+        for models in &["linear,linear", "cubic,linear_spline", "radix,cubic",
+                        "radix,loglinear", "histogram,linear", "radix18,normal",
+                        "linear,pgm16", "radix_spline", "radix_spline12_8,linear",
+                        "linear,poly3", "poly2,poly5"] {
+            let rmi = train(&md, models, 64).unwrap();
+            let loaded = round_trip(&rmi, "rmi_test_save_load");
+            check_same(&rmi, &loaded, &keys);
+        }
+    }
+
+    #[test]
+    fn test_save_load_bounded() {
+        let keys = test_keys(10_000);
+        let md = test_data(10_000);
+        let rmi = train_bounded(&md, "linear,linear", 32, 16).unwrap();
+        let loaded = round_trip(&rmi, "rmi_test_save_load_bounded");
+        check_same(&rmi, &loaded, &keys);
+    }
+
+    #[test]
+    fn test_save_load_fallback() {
+        let keys = test_keys(10_000);
+        let md = test_data(10_000);
+        let options = TrainingOptions {
+            fallback: Some(Fallback { max_error: 4, node_size: 8 }), ..Default::default()
+        };
+
+        for models in &["linear,linear", "radix,linear_spline", "linear,pgm16"] {
+            let rmi = train_with_options(&md, models, 64, &options).unwrap();
+            let loaded = round_trip(&rmi, "rmi_test_save_load_fallback");
+            check_same(&rmi, &loaded, &keys);
+        }
+    }
+
+    #[test]
+    fn test_save_load_leaf_allocation() {
+        let keys = test_keys(10_000);
+        let md = test_data(10_000);
+
+        for allocation in &[LeafAllocation::EquiDepth { oversample: 4 },
+                            LeafAllocation::Merge { oversample: 4 }] {
+            let options = TrainingOptions { leaf_allocation: Some(*allocation), ..Default::default() };
+            for models in &["linear,linear", "radix,cubic", "radix_spline12_8,linear"] {
+                let rmi = train_with_options(&md, models, 64, &options).unwrap();
+                let loaded = round_trip(&rmi, "rmi_test_save_load_leaf_allocation");
+                check_same(&rmi, &loaded, &keys);
+            }
+        }
+    }
+
+    #[test]
+    fn test_load_mmap() {
+        let keys = test_keys(10_000);
+        let md = test_data(10_000);
+
+        for models in &["radix18,linear", "histogram,cubic"] {
+            let rmi = train(&md, models, 64).unwrap();
+            let path = std::env::temp_dir().join("rmi_test_load_mmap");
+            rmi.save(&path).unwrap();
+
+            let loaded = TrainedRMI::load_mmap(&path).unwrap();
+            check_same(&rmi, &loaded, &keys);
+
+            let mapped = loaded.rmi[0][0].params().iter().any(|p| match p {
+                ModelParam::IntArray(arr) => arr.is_mapped(),
+                ModelParam::Int32Array(arr) => arr.is_mapped(),
+                _ => false
+            });
+            assert!(mapped, "{} did not map its parameter arrays", models);
+        }
+    }
+
+    #[test]
+    fn test_load_mmap_u128() {
+        let keys = test_keys_u128(10_000);
+        let data: Vec<(u128, usize)> = keys.iter().copied()
+            .enumerate().map(|(idx, key)| (key, idx)).collect();
+        let md = RMITrainingData::new(Box::new(data));
+
+        let rmi = train(&md, "histogram,linear", 64).unwrap();
+        let path = std::env::temp_dir().join("rmi_test_load_mmap_u128");
+        rmi.save(&path).unwrap();
+
+        let loaded = TrainedRMI::load_mmap(&path).unwrap();
+        assert_eq!(loaded.key_type, KeyType::U128);
+        for &key in keys.iter() {
+            assert_eq!(rmi.lookup(key), loaded.lookup(key));
+            assert_eq!(rmi.lookup(key + 1), loaded.lookup(key + 1));
+        }
+
+        let mapped = loaded.rmi[0][0].params().iter().any(|p| match p {
+            ModelParam::Int128Array(arr) => arr.is_mapped(),
+            _ => false
+        });
+        assert!(mapped, "128-bit histogram pivots were not mapped");
+    }
+
+    #[test]
+    fn test_load_mmap_layer_arrays() {
+        let keys = test_keys(10_000);
+        let md = test_data(10_000);
+        let options = TrainingOptions {
+            fallback: Some(Fallback { max_error: 4, node_size: 8 }), ..Default::default()
+        };
+        let rmi = train_with_options(&md, "linear,pgm16", 64, &options).unwrap();
+        let path = std::env::temp_dir().join("rmi_test_load_mmap_layer_arrays");
+        rmi.save(&path).unwrap();
+
+        let file = std::fs::File::open(&path).unwrap();
+        let map = std::sync::Arc::new(unsafe { memmap::Mmap::map(&file).unwrap() });
+        let loaded = TrainedRMI::from_bytes(&map, Some(&map), false).unwrap();
+        check_same(&rmi, &loaded, &keys);
+
+        let range = map.as_ptr_range();
+        assert!(loaded.last_layer_bounds.is_mapped());
+        for model in loaded.rmi[1].iter() {
+            for array in model.layer_arrays() {
+                assert!(array.is_empty() || range.contains(&(array.as_ptr() as *const u8)),
+                        "layer array was copied out of the mapping");
+            }
+        }
+    }
+
+    fn corrupt_at(path: &std::path::Path, offset: usize) {
+        let mut bytes = std::fs::read(path).unwrap();
+        bytes[offset] ^= 0xff;
+        std::fs::write(path, &bytes).unwrap();
+    }
+
+    #[test]
+    fn test_load_corrupt() {
+        let rmi = train(&test_data(10_000), "linear,linear", 64).unwrap();
+
+        let path = std::env::temp_dir().join("rmi_test_load_corrupt");
+
+        // the metadata is checked by every loader
+        rmi.save(&path).unwrap();
+        corrupt_at(&path, MAGIC.len() + 4 + 8 + 1);
+        let err = TrainedRMI::load(&path).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        let err = TrainedRMI::load_mmap_unchecked(&path).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        // the data section is checked unless the caller opts out
+        rmi.save(&path).unwrap();
+        let len = std::fs::metadata(&path).unwrap().len() as usize;
+        corrupt_at(&path, len - 9);
+        let err = TrainedRMI::load(&path).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        let err = TrainedRMI::load_mmap(&path).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(TrainedRMI::load_mmap_unchecked(&path).is_ok());
+
+        // a leaf error bound for each leaf, or one for all of them
+        let mut wrong_bounds = train(&test_data(10_000), "linear,linear", 64).unwrap();
+        wrong_bounds.last_layer_bounds = vec![[1, 1]; 3].into();
+        wrong_bounds.save(&path).unwrap();
+        let err = TrainedRMI::load_mmap_unchecked(&path).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        std::fs::write(&path, b"not an RMI").unwrap();
+        let err = TrainedRMI::load(&path).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+}
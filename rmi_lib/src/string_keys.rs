@@ -0,0 +1,283 @@
+// < begin copyright > 
+// Copyright Ryan Marcus 2020
+// 
+// See root directory of this project for license terms.
+// 
+// < end copyright > 
+ 
+
+// String keys are indexed through an order-preserving 128-bit encoding:
+// the prefix shared by every key is removed, and the next 16 bytes are
+// read as a big endian integer (shorter keys are padded with zeros). Keys
+// that only differ after those 16 bytes have the same encoding. The leaf
+// errors already include the longest run of equal keys in each leaf, so
+// a lookup still brackets the lower bound of every key in such a run.
+
+use crate::models::*;
+use std::cmp::Ordering;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct StringKey(pub u128);
+
+impl StringKey {
+    /// Encodes `key` after removing `prefix`. Keys that sort before every
+    /// string starting with `prefix` encode to zero, and keys that sort
+    /// after all of them encode to `u128::MAX`, so the encoding preserves
+    /// order for any key, not just the ones the RMI was trained on.
+    pub fn encode(prefix: &[u8], key: &[u8]) -> StringKey {
+        let shared = usize::min(prefix.len(), key.len());
+        match key[..shared].cmp(&prefix[..shared]) {
+            Ordering::Less => return StringKey(0),
+            Ordering::Greater => return StringKey(std::u128::MAX),
+            Ordering::Equal if key.len() < prefix.len() => return StringKey(0),
+            Ordering::Equal => {}
+        };
+
+        let rest = &key[prefix.len()..];
+        let num_bytes = usize::min(16, rest.len());
+        let mut bytes = [0u8; 16];
+        bytes[..num_bytes].copy_from_slice(&rest[..num_bytes]);
+        return StringKey(u128::from_be_bytes(bytes));
+    }
+}
+
+impl TrainingKey for StringKey {
+    fn minus_epsilon(&self) -> Self { StringKey(self.0.saturating_sub(1)) }
+    fn zero_value() -> Self { StringKey(0) }
+    fn plus_epsilon(&self) -> Self { StringKey(self.0.saturating_add(1)) }
+    fn max_value() -> Self { StringKey(std::u128::MAX) }
+    fn key_type() -> KeyType { KeyType::Str }
+
+    fn as_float(&self) -> f64 { self.0 as f64 }
+    fn as_uint(&self) -> u64 { self.0 as u64 }
+    fn as_uint128(&self) -> u128 { self.0 }
+
+    fn to_model_input(&self) -> ModelInput { self.0.into() }
+}
+
+/// Training data for an RMI over sorted string keys, where the key at
+/// index `i` is at position `i`. The strings themselves are not kept.
+pub struct StringKeyData {
+    prefix: Vec<u8>,
+    keys: Vec<(StringKey, usize)>
+}
+
+impl StringKeyData {
+    pub fn new<S: AsRef<[u8]>>(keys: &[S]) -> StringKeyData {
+        let positions: Vec<usize> = (0..keys.len()).collect();
+        return StringKeyData::with_positions(keys, &positions);
+    }
+
+    /// Like `new`, but the key at index `i` is at `positions[i]` instead of
+    /// `i`.
+    pub fn with_positions<S: AsRef<[u8]>>(keys: &[S], positions: &[usize]) -> StringKeyData {
+        assert_eq!(keys.len(), positions.len());
+        let mut builder = StringKeyDataBuilder::new();
+        for (key, pos) in keys.iter().zip(positions.iter()) {
+            builder.push(key.as_ref(), *pos);
+        }
+        return builder.finish();
+    }
+}
+
+/// Builds a `StringKeyData` from keys that are added one at a time, so
+/// that the strings never have to be held in memory together (e.g., when
+/// streaming them from a file).
+///
+/// The prefix shared by every key is only known once all keys are added,
+/// so each key is encoded against the prefix shared by the keys added so
+/// far. That prefix can only get shorter, and the bytes it loses are the
+/// same for every earlier key, so `finish` re-encodes them from their
+/// encodings alone.
+pub struct StringKeyDataBuilder {
+    first: Vec<u8>,
+    prefix_len: usize,
+    // (index of the first key encoded against it, prefix length) for each
+    // prefix used so far
+    prefixes: Vec<(usize, usize)>,
+    keys: Vec<(StringKey, usize)>
+}
+
+impl StringKeyDataBuilder {
+    pub fn new() -> StringKeyDataBuilder {
+        return StringKeyDataBuilder {
+            first: Vec::new(), prefix_len: 0, prefixes: Vec::new(), keys: Vec::new()
+        };
+    }
+
+    pub fn push(&mut self, key: &[u8], position: usize) {
+        if self.keys.is_empty() {
+            self.first = key.to_vec();
+            self.prefix_len = key.len();
+            self.prefixes.push((0, self.prefix_len));
+        } else {
+            let shared = self.first[..self.prefix_len].iter().zip(key.iter())
+                .take_while(|(a, b)| a == b)
+                .count();
+            if shared < self.prefix_len {
+                self.prefix_len = shared;
+                self.prefixes.push((self.keys.len(), shared));
+            }
+        }
+
+        self.keys.push((StringKey::encode(&self.first[..self.prefix_len], key), position));
+    }
+
+    pub fn finish(mut self) -> StringKeyData {
+        let prefix_len = self.prefix_len;
+        let ends: Vec<usize> = self.prefixes.iter().skip(1).map(|(start, _len)| *start)
+            .chain(std::iter::once(self.keys.len()))
+            .collect();
+
+        for ((start, len), end) in self.prefixes.iter().zip(ends) {
+            // every key encoded against a longer prefix continues with the
+            // first key's bytes between the two prefixes
+            let lost = &self.first[prefix_len..*len];
+            if lost.is_empty() {
+                continue;
+            }
+
+            if lost.len() >= 16 {
+                let mut bytes = [0u8; 16];
+                bytes.copy_from_slice(&lost[..16]);
+                for key in self.keys[*start..end].iter_mut() {
+                    key.0 = StringKey(u128::from_be_bytes(bytes));
+                }
+            } else {
+                let mut bytes = [0u8; 16];
+                bytes[..lost.len()].copy_from_slice(lost);
+                let high = u128::from_be_bytes(bytes);
+                let shift = 8 * lost.len() as u32;
+                for key in self.keys[*start..end].iter_mut() {
+                    key.0 = StringKey(high | (key.0).0 >> shift);
+                }
+            }
+        }
+
+        self.first.truncate(prefix_len);
+        return StringKeyData { prefix: self.first, keys: self.keys };
+    }
+}
+
+impl Default for StringKeyDataBuilder {
+    fn default() -> Self {
+        return StringKeyDataBuilder::new();
+    }
+}
+
+impl RMITrainingDataIteratorProvider for StringKeyData {
+    type InpType = StringKey;
+
+    fn len(&self) -> usize { self.keys.len() }
+
+    fn cdf_iter(&self) -> Box<dyn Iterator<Item = (Self::InpType, usize)> + '_> {
+        Box::new(self.keys.iter().cloned())
+    }
+
+    fn key_type(&self) -> KeyType { KeyType::Str }
+
+    fn get(&self, idx: usize) -> Option<(Self::InpType, usize)> {
+        self.keys.as_slice().get(idx).copied()
+    }
+
+    fn key_prefix(&self) -> &[u8] { &self.prefix }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    fn test_keys() -> Vec<String> {
+        let mut keys: Vec<String> = (0..5000u64)
+            .map(|i| format!("https://shop.example.com/sku/{}", 7 * i * i + 3))
+            .collect();
+
+        // these share their first 16 bytes after the prefix
+        keys.extend((0..50).map(|i| format!("https://shop.example.com/sku/long-category-name-{:03}", i)));
+        keys.sort();
+        return keys;
+    }
+
+    fn check_lower_bounds(rmi: &TrainedRMI, keys: &[String]) {
+        let mut probes: Vec<String> = vec![String::new(), String::from("a"),
+                                           String::from("https://"), String::from("zzz")];
+        for key in keys.iter() {
+            probes.push(key.clone());
+            probes.push(format!("{}!", key));
+            probes.push(String::from(&key[..key.len() - 1]));
+        }
+
+        for probe in probes.iter() {
+            let lb = keys.partition_point(|k| k < probe);
+            let lb = usize::min(lb, keys.len() - 1) as u64;
+            let (guess, err) = rmi.lookup_string(probe);
+            let diff = if guess > lb { guess - lb } else { lb - guess };
+            assert!(diff <= err, "lower bound of {} is {} but lookup returned {} +/- {}",
+                    probe, lb, guess, err);
+        }
+    }
+
+    #[test]
+    fn test_encode() {
+        let prefix = b"ab";
+        assert_eq!(StringKey::encode(prefix, b"a"), StringKey(0));
+        assert_eq!(StringKey::encode(prefix, b"aa"), StringKey(0));
+        assert_eq!(StringKey::encode(prefix, b"ab"), StringKey(0));
+        assert_eq!(StringKey::encode(prefix, b"b"), StringKey(std::u128::MAX));
+        assert!(StringKey::encode(prefix, b"abc") < StringKey::encode(prefix, b"abd"));
+        assert!(StringKey::encode(prefix, b"abc") < StringKey::encode(prefix, b"abca"));
+        assert_eq!(StringKey::encode(prefix, b"ab0123456789abcdefX"),
+                   StringKey::encode(prefix, b"ab0123456789abcdefY"));
+    }
+
+    #[test]
+    fn test_builder() {
+        // encoding each key against the final prefix gives the same keys
+        let keys = test_keys();
+        let data = StringKeyData::new(&keys);
+        assert_eq!(data.prefix, b"https://shop.example.com/sku/");
+        for (key, (encoded, pos)) in keys.iter().zip(data.keys.iter()) {
+            assert_eq!(*encoded, StringKey::encode(&data.prefix, key.as_bytes()));
+            assert_eq!(keys[*pos], *key);
+        }
+
+        // the prefix shrinks by more than 16 bytes
+        let keys = [&b"0123456789abcdefghij-a"[..], b"0123456789abcdefghij-b", b"0123x"];
+        let data = StringKeyData::new(&keys);
+        assert_eq!(data.prefix, b"0123");
+        for (key, (encoded, _pos)) in keys.iter().zip(data.keys.iter()) {
+            assert_eq!(*encoded, StringKey::encode(b"0123", key));
+        }
+
+        assert!(StringKeyData::new::<&[u8]>(&[]).prefix.is_empty());
+    }
+
+    #[test]
+    fn test_string_keys() {
+        let keys = test_keys();
+        let md = RMITrainingData::new(Box::new(StringKeyData::new(&keys)));
+        assert_eq!(md.key_prefix(), b"https://shop.example.com/sku/");
+
+        for models in &["linear,linear", "radix,linear", "radix18,linear", "histogram,cubic"] {
+            let rmi = train(&md, models, 32).unwrap();
+            assert_eq!(rmi.key_type, KeyType::Str);
+            check_lower_bounds(&rmi, &keys);
+        }
+    }
+
+    #[test]
+    fn test_string_keys_save_load() {
+        let keys = test_keys();
+        let md = RMITrainingData::new(Box::new(StringKeyData::new(&keys)));
+        let rmi = train(&md, "radix,linear", 32).unwrap();
+
+        let path = std::env::temp_dir().join("rmi_test_string_keys.rmi");
+        rmi.save(&path).unwrap();
+        let loaded = TrainedRMI::load(&path).unwrap();
+        assert_eq!(loaded.key_prefix, rmi.key_prefix);
+        for key in keys.iter() {
+            assert_eq!(loaded.lookup_string(key), rmi.lookup_string(key));
+        }
+    }
+}
@@ -25,10 +25,10 @@ fn find_first_above<T: Copy>(data: &[Option<T>], idx: usize) -> Option<(usize, T
     }
 }
 
-// next_for_leaf[i] stores the (key index, key) pairs for the first key in the 
+// next_for_leaf[i] stores the (key position, key) pairs for the first key in the 
 // leaf model after leaf i. next_for_leaf[last leaf index] stores the maximum possible key.
 fn compute_next_for_leaf<T: TrainingKey>(num_leaf_models: u64,
-                                        num_keys: usize,
+                                        target_range: usize,
                                         first_key_for_leaf: &[Option<(usize, T)>])
                                         -> Vec<(usize, T)> {
 
@@ -45,7 +45,7 @@ fn compute_next_for_leaf<T: TrainingKey>(num_leaf_models: u64,
             },
             None => {
                 for i in idx..num_leaf_models as usize {
-                    next_for_leaf[i] = (num_keys, T::max_value());
+                    next_for_leaf[i] = (target_range, T::max_value());
                 }
                 break;
             }
@@ -85,7 +85,7 @@ pub struct LowerBoundCorrection<T> {
     last: Vec<Option<(usize, T)>>,
     next: Vec<(usize, T)>,
     prev: Vec<(usize, T)>,
-    run_lengths: Vec<u64>
+    gaps: Vec<u64>
 }
 
 impl <T: TrainingKey> LowerBoundCorrection<T> {
@@ -96,35 +96,35 @@ impl <T: TrainingKey> LowerBoundCorrection<T> {
             = vec![None ; num_leaf_models as usize];
         let mut last_key_for_leaf: Vec<Option<(usize, T)>>
             = vec![None ; num_leaf_models as usize];
-        let mut max_run_length: Vec<u64> = vec![0 ; num_leaf_models as usize];
+        // the largest distance between the position of a key in each leaf and
+        // the position of the next distinct key. For dense positions, this
+        // is the length of the longest run of duplicate keys.
+        let mut max_gap: Vec<u64> = vec![0 ; num_leaf_models as usize];
         
-        let mut last_target = 0;
-        let mut current_run_length = 0;
-        let mut current_run_key = data.get_key(0);
+        let mut last: Option<(usize, usize)> = None;
         for (x, y) in data.iter() {
             let leaf_idx = pred_func(x.into());
             let target = u64::min(num_leaf_models - 1, leaf_idx) as usize;
             
-            if target == last_target && x == current_run_key {
-                current_run_length += 1;
-            } else if target != last_target || x != current_run_key {
-                max_run_length[last_target] = u64::max(
-                    max_run_length[last_target],
-                    current_run_length
-                );
-                
-                current_run_length = 1;
-                current_run_key = x;
-                last_target = target;
+            if let Some((last_target, last_y)) = last {
+                if y != last_y {
+                    max_gap[last_target] = u64::max(max_gap[last_target],
+                                                    (y - last_y) as u64);
+                }
             }
+            last = Some((target, y));
             
             if first_key_for_leaf[target].is_none() {
                 first_key_for_leaf[target] = Some((y, x));
             }
             last_key_for_leaf[target] = Some((y, x));
         }
+        if let Some((last_target, last_y)) = last {
+            max_gap[last_target] = u64::max(max_gap[last_target],
+                                            (data.target_range() - last_y) as u64);
+        }
 
-        let next_for_leaf = compute_next_for_leaf(num_leaf_models, data.len(), &first_key_for_leaf);
+        let next_for_leaf = compute_next_for_leaf(num_leaf_models, data.target_range(), &first_key_for_leaf);
         let prev_for_leaf = compute_prev_for_leaf(num_leaf_models, &last_key_for_leaf);
         
         return LowerBoundCorrection {
@@ -132,7 +132,7 @@ impl <T: TrainingKey> LowerBoundCorrection<T> {
             last: last_key_for_leaf,
             next: next_for_leaf,
             prev: prev_for_leaf,
-            run_lengths: max_run_length
+            gaps: max_gap
         };
     }
     
@@ -140,6 +140,14 @@ impl <T: TrainingKey> LowerBoundCorrection<T> {
         return self.first[leaf_idx].map(|x| x.1);
     }
     
+    pub fn num_leaf_models(&self) -> u64 {
+        return self.first.len() as u64;
+    }
+
+    pub fn first_index(&self, leaf_idx: usize) -> Option<usize> {
+        return self.first[leaf_idx].map(|x| x.0);
+    }
+
     pub fn last_key(&self, leaf_idx: usize) -> Option<T> {
         return self.last[leaf_idx].map(|x| x.1);
     }
@@ -156,7 +164,21 @@ impl <T: TrainingKey> LowerBoundCorrection<T> {
         return self.prev[leaf_idx].1;
     }
 
-    pub fn longest_run(&self, leaf_idx: usize) -> u64 {
-        return self.run_lengths[leaf_idx];
+    pub fn largest_gap(&self, leaf_idx: usize) -> u64 {
+        return self.gaps[leaf_idx];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gap_of_last_key() {
+        // the last key repeats until the end of the data, so a search for
+        // it may have to skip the whole run
+        let data = vec![(1u64, 0usize), (2, 1), (3, 2), (3, 3), (3, 4), (3, 5)];
+        let lbc = LowerBoundCorrection::new(|_x: u64| 0, 1, &RMITrainingData::new(Box::new(data)));
+        assert_eq!(lbc.largest_gap(0), 4);
     }
 }
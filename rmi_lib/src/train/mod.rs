@@ -8,12 +8,17 @@
 
 use crate::models::*;
 use crate::cache_fix::cache_fix;
+use crate::error::RmiError;
 use log::*;
 use std::time::SystemTime;
 
 mod two_layer;
-//mod multi_layer;
+mod multi_layer;
 mod lower_bound_correction;
+mod sample;
+mod remap;
+
+pub(crate) use sample::sample;
 
 pub struct TrainedRMI {
     pub num_rmi_rows: usize,
@@ -24,21 +29,114 @@ pub struct TrainedRMI {
     pub model_max_error: u64,
     pub model_max_error_idx: usize,
     pub model_max_log2_error: f64,
-    pub last_layer_max_l1s: Vec<u64>,
+    // for each leaf, how far below and above its prediction the lower
+    // bound of a key can be
+    pub last_layer_bounds: ParamArray<[u64; 2]>,
     pub rmi: Vec<Vec<Box<dyn Model>>>,
     pub models: String,
     pub branching_factor: u64,
+    pub key_type: KeyType,
+    pub key_prefix: Vec<u8>,
     pub cache_fix: Option<(usize, Vec<(u64, usize)>)>,
     pub build_time: u128
 }
 
+impl TrainedRMI {
+    /// The maximum error of each leaf, the larger of its errors below and
+    /// above its predictions.
+    pub fn last_layer_max_l1s(&self) -> Vec<u64> {
+        return self.last_layer_bounds.iter()
+            .map(|[below, above]| u64::max(*below, *above))
+            .collect();
+    }
+}
+
+/// How the sample used by `TrainingOptions::sampling` is drawn.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Sampling {
+    /// Each key is included independently with probability `rate`.
+    Uniform { rate: f64 },
+
+    /// The keys are split into consecutive groups of about `1 / rate`
+    /// keys, and one key is drawn at random from each group.
+    Stratified { rate: f64 },
+}
+
+/// Replaces leaf models whose error is larger than `max_error` with a
+/// sorted sample of `node_size` of their keys, which lookups search like a
+/// B-tree node. Each entry of a node predicts the middle of the positions
+/// between its key and the next, so a leaf with `n` keys ends up with an
+/// error of about `n / (2 * node_size)`. A leaf keeps its model if the
+/// node would not reduce its error.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Fallback {
+    pub max_error: u64,
+    pub node_size: usize,
+}
+
+/// How `TrainingOptions::leaf_allocation` spreads the leaf models over
+/// the keys. The top model is trained to predict one of `oversample` times
+/// as many slots as there are leaves, and a table built from the training
+/// keys maps each slot to a leaf.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LeafAllocation {
+    /// Each leaf gets about the same number of keys.
+    EquiDepth { oversample: u64 },
+
+    /// Starting from one leaf per slot, neighboring leaves are merged
+    /// while their keys are well fit by a single line, so that more
+    /// leaves cover the parts of the CDF that are hard to fit.
+    Merge { oversample: u64 },
+}
+
+impl LeafAllocation {
+    fn oversample(&self) -> u64 {
+        return match self {
+            LeafAllocation::EquiDepth { oversample } => *oversample,
+            LeafAllocation::Merge { oversample } => *oversample
+        };
+    }
+}
+
+/// Options for `train_with_options`. The default fits every model on the
+/// full data, like `train`.
+#[derive(Clone, Debug, Default)]
+pub struct TrainingOptions {
+    /// Fit the models above the leaves on a sample of the keys. The last
+    /// level errors are always computed over every key, so the error
+    /// bounds of the trained RMI hold for the full data.
+    pub sampling: Option<Sampling>,
+
+    /// Fit the leaf models on the sample as well. Leaves that receive no
+    /// sampled keys are left untrained, so this is only worthwhile when
+    /// the sample is much larger than the number of leaves.
+    pub sample_leaves: bool,
+
+    /// Seed used to draw the sample.
+    pub seed: u64,
+
+    /// Replace poorly fit leaves with fallback nodes. Because every leaf
+    /// on a layer shares the same parameter layout, this adds space for a
+    /// node to every leaf once any leaf is replaced.
+    pub fallback: Option<Fallback>,
+
+    /// Remap the output of the top model so that leaf models are spent
+    /// where they are needed, instead of on equal ranges of the top
+    /// model's output. Only supported for two-layer RMIs.
+    pub leaf_allocation: Option<LeafAllocation>,
+}
+
 fn train_model<T: TrainingKey>(model_type: &str,
-                              data: &RMITrainingData<T>) -> Box<dyn Model> {
+                              data: &RMITrainingData<T>) -> Result<Box<dyn Model>, RmiError> {
     let model: Box<dyn Model> = match model_type {
         "linear" => Box::new(LinearModel::new(data)),
         "robust_linear" => Box::new(RobustLinearModel::new(data)),
         "linear_spline" => Box::new(LinearSplineModel::new(data)),
         "cubic" => Box::new(CubicSplineModel::new(data)),
+        "poly2" => Box::new(PolynomialModel::new(data, 2)),
+        "poly3" => Box::new(PolynomialModel::new(data, 3)),
+        "poly4" => Box::new(PolynomialModel::new(data, 4)),
+        "poly5" => Box::new(PolynomialModel::new(data, 5)),
         "loglinear" => Box::new(LogLinearModel::new(data)),
         "normal" => Box::new(NormalModel::new(data)),
         "lognormal" => Box::new(LogNormalModel::new(data)),
@@ -50,38 +148,60 @@ fn train_model<T: TrainingKey>(model_type: &str,
         "radix28" => Box::new(RadixTable::new(data, 28)),
         "bradix" => Box::new(BalancedRadixModel::new(data)),
         "histogram" => Box::new(EquidepthHistogramModel::new(data)),
-        _ => panic!("Unknown model type: {}", model_type),
+        _ => if let Some(epsilon) = pgm_epsilon(model_type) {
+            Box::new(PGMModel::new(data, epsilon))
+        } else if let Some((bits, error)) = radix_spline_params(model_type) {
+            // the spline searches keys as 64-bit integers
+            if !matches!(T::key_type().to_model_data_type(), ModelDataType::Int) {
+                return Err(RmiError::UnsupportedKeyType {
+                    model: String::from(model_type), key_type: T::key_type()
+                });
+            }
+            Box::new(RadixSplineModel::new(data, bits, error))
+        } else {
+            return Err(RmiError::UnknownModel(String::from(model_type)));
+        },
     };
 
-    return model;
+    return Ok(model);
 }
 
-fn validate(model_spec: &[String]) {
+fn validate(model_spec: &[String]) -> Result<(), RmiError> {
     let num_layers = model_spec.len();
     let empty_container: RMITrainingData<u64> = RMITrainingData::empty();
 
     for (idx, model) in model_spec.iter().enumerate() {
-        let restriction = train_model(model, &empty_container).restriction();
+        let restriction = train_model(model, &empty_container)?.restriction();
 
         match restriction {
             ModelRestriction::None => {}
             ModelRestriction::MustBeTop => {
-                assert_eq!(
-                    idx, 0,
-                    "if used, model type {} must be the root model",
-                    model
-                );
+                if idx != 0 {
+                    return Err(RmiError::InvalidLayerOrder {
+                        model: model.clone(), required: "root"
+                    });
+                }
             }
             ModelRestriction::MustBeBottom => {
-                assert_eq!(
-                    idx,
-                    num_layers - 1,
-                    "if used, model type {} must be the bottommost model",
-                    model
-                );
+                if idx != num_layers - 1 {
+                    return Err(RmiError::InvalidLayerOrder {
+                        model: model.clone(), required: "bottommost"
+                    });
+                }
             }
         }
     }
+
+    // a pgm leaf's error bound relies on keys reaching the leaves in order,
+    // which a single monotonic top model guarantees but deeper RMIs do not
+    if num_layers > 2 && pgm_epsilon(&model_spec[num_layers - 1]).is_some() {
+        return Err(RmiError::InvalidLayerOrder {
+            model: model_spec[num_layers - 1].clone(),
+            required: "second"
+        });
+    }
+
+    return Ok(());
 }
 
 /*fn test_rmi_input(test_key: u64, data: &RMITrainingData, rmi: &TrainedRMI) {
@@ -98,69 +218,118 @@ fn validate(model_spec: &[String]) {
 }*/
 
 pub fn train<T: TrainingKey>(data: &RMITrainingData<T>,
-                            model_spec: &str, branch_factor: u64) -> TrainedRMI {
+                            model_spec: &str, branch_factor: u64)
+                            -> Result<TrainedRMI, RmiError> {
+    return train_with_options(data, model_spec, branch_factor, &TrainingOptions::default());
+}
+
+pub fn train_with_options<T: TrainingKey>(data: &RMITrainingData<T>,
+                                         model_spec: &str, branch_factor: u64,
+                                         options: &TrainingOptions)
+                                         -> Result<TrainedRMI, RmiError> {
 
     let start_time = SystemTime::now();
     let (model_list, last_model): (Vec<String>, String) = {
         let mut all_models: Vec<String> = model_spec.split(',').map(String::from).collect();
-        validate(&all_models);
+        validate(&all_models)?;
         let last = all_models.pop().unwrap();
         (all_models, last)
     };
 
+    if data.len() == 0 {
+        return Err(RmiError::NotEnoughData { required: 1, available: 0 });
+    }
+    data.check_sorted()?;
+
+    let mut md_container = data.soft_copy();
+    md_container.set_scale(1.0);
+
+    let fit_data = match options.sampling {
+        Some(sampling) => {
+            let sampled = sample::sample(&md_container, sampling, options.seed)?;
+            info!("Fitting models on a sample of {} of {} keys", sampled.len(), data.len());
+            sampled
+        },
+        None => md_container.soft_copy()
+    };
+    let leaf_data = if options.sample_leaves { fit_data.soft_copy() } else { md_container.soft_copy() };
+
+    if let Some(allocation) = options.leaf_allocation {
+        if model_list.len() != 1 {
+            return Err(RmiError::InvalidLeafAllocation("only two-layer RMIs can remap their leaves"));
+        }
+        if allocation.oversample() == 0 {
+            return Err(RmiError::InvalidLeafAllocation("oversample must be at least 1"));
+        }
+        if branch_factor > u32::MAX as u64
+            || branch_factor.checked_mul(allocation.oversample()).is_none() {
+            return Err(RmiError::InvalidLeafAllocation("too many leaf models to remap"));
+        }
+    }
+
     if model_list.len() == 1 {
-        let mut res = two_layer::train_two_layer(&mut data.soft_copy(), &model_list[0],
-                                             &last_model, branch_factor);
+        let mut res = two_layer::train_two_layer(&md_container, &mut fit_data.soft_copy(),
+                                                 &leaf_data, &model_list[0],
+                                                 &last_model, branch_factor,
+                                                 options)?;
         let build_time = SystemTime::now()
             .duration_since(start_time)
             .map(|d| d.as_nanos())
             .unwrap_or(std::u128::MAX);
         res.build_time = build_time;
         
-        return res;
+        return Ok(res);
     }
 
     // it is not a simple, two layer rmi
-    //return multi_layer::train_multi_layer(data, &model_list, last_model, branch_factor);
-    panic!(); // TODO
+    let mut res = multi_layer::train_multi_layer(&md_container, &fit_data, &leaf_data,
+                                                 &model_list, &last_model, branch_factor,
+                                                 options.fallback.as_ref())?;
+    let build_time = SystemTime::now()
+        .duration_since(start_time)
+        .map(|d| d.as_nanos())
+        .unwrap_or(std::u128::MAX);
+    res.build_time = build_time;
+
+    return Ok(res);
 }
 
 pub fn train_for_size<T: TrainingKey>(data: &RMITrainingData<T>,
-                                     max_size: usize) -> TrainedRMI {
+                                     max_size: usize) -> Result<TrainedRMI, RmiError> {
 
     let start_time = SystemTime::now();
-    let pareto = crate::find_pareto_efficient_configs(data, 1000);
+    let pareto = crate::find_pareto_efficient_configs(data, 1000)?;
     // go down the front until we find something small enough
 
     let config = pareto.into_iter()
         .filter(|x| x.size < max_size as u64)
         .next()
-        .expect(format!(
-            "Could not find any configurations smaller than {}", max_size).as_str());
+        .ok_or(RmiError::NoFittingConfig { max_size })?;
 
     let models = config.models;
     let bf = config.branching_factor;
 
     info!("Found RMI config {} {} with size {} and average log2 {}",
           models, bf, config.size, config.average_log2_error);
-    let mut res = train(data, models.as_str(), bf);
+    let mut res = train(data, models.as_str(), bf)?;
     
     let build_time = SystemTime::now()
             .duration_since(start_time)
             .map(|d| d.as_nanos())
             .unwrap_or(std::u128::MAX);
     res.build_time = build_time;
-    return res;
+    return Ok(res);
 }
 
 pub fn train_bounded(data: &RMITrainingData<u64>,
                      model_spec: &str,
                      branch_factor: u64,
-                     line_size: usize) -> TrainedRMI {
+                     line_size: usize) -> Result<TrainedRMI, RmiError> {
     let start_time = SystemTime::now();
+    data.check_sorted()?;
+    
     // first, transform our data into error-bounded spline points
-    let spline = cache_fix(data, line_size);
-    std::mem::drop(data);
+    let spline = cache_fix(data, line_size)?;
 
     // reindex the spline points so we can build an RMI on top
     let reindexed_splines: Vec<(u64, usize)> = spline.iter()
@@ -171,7 +340,7 @@ pub fn train_bounded(data: &RMITrainingData<u64>,
     // construct new training data from our spline points
     let mut new_data = RMITrainingData::new(Box::new(reindexed_splines));
 
-    let mut res = crate::train(&mut new_data, model_spec, branch_factor);
+    let mut res = crate::train(&mut new_data, model_spec, branch_factor)?;
     res.cache_fix = Some((line_size, spline));
     res.num_data_rows = data.len();
     
@@ -180,5 +349,412 @@ pub fn train_bounded(data: &RMITrainingData<u64>,
         .map(|d| d.as_nanos())
         .unwrap_or(std::u128::MAX);
     res.build_time = build_time;
-    return res;
+    return Ok(res);
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::*;
+
+    pub(crate) fn test_keys(n: u64) -> Vec<u64> {
+        return (0..n).map(|i| 3 * i * i + i % 7 + 1).collect();
+    }
+
+    pub(crate) fn test_data(n: u64) -> RMITrainingData<u64> {
+        let data: Vec<(u64, usize)> = test_keys(n).into_iter()
+            .enumerate()
+            .map(|(idx, key)| (key, idx))
+            .collect();
+        return RMITrainingData::new(Box::new(data));
+    }
+
+    // IPv6-like keys, all sharing a 32-bit prefix
+    pub(crate) fn test_keys_u128(n: u128) -> Vec<u128> {
+        return (0..n).map(|i| (0x2001_0db8 << 96) + ((3 * i * i + i % 7) << 40)).collect();
+    }
+
+    // Checks that every `step`th key, and the absent key just after it, is
+    // within the error bound of its position.
+    pub(crate) fn assert_lookups_within_bounds(rmi: &TrainedRMI, keys: &[u64], step: usize, name: &str) {
+        for (idx, key) in keys.iter().enumerate().step_by(step) {
+            for (probe, pos) in &[(*key, idx), (key + 1, usize::min(idx + 1, keys.len() - 1))] {
+                let (guess, err) = rmi.lookup(*probe);
+                let pos = *pos as u64;
+                let diff = if guess > pos { guess - pos } else { pos - guess };
+                assert!(diff <= err, "{} gave {} +/- {} for {}", name, guess, err, pos);
+            }
+        }
+    }
+
+    #[test]
+    fn test_unknown_model() {
+        match train(&test_data(100), "linear,quintic", 8) {
+            Err(RmiError::UnknownModel(m)) => assert_eq!(m, "quintic"),
+            other => panic!("expected an unknown model error, got {:?}", other.err())
+        }
+    }
+
+    #[test]
+    fn test_invalid_layer_order() {
+        match train(&test_data(100), "linear,radix", 8) {
+            Err(RmiError::InvalidLayerOrder { model, required }) => {
+                assert_eq!(model, "radix");
+                assert_eq!(required, "root");
+            },
+            other => panic!("expected a layer order error, got {:?}", other.err())
+        }
+    }
+
+    #[test]
+    fn test_not_enough_data() {
+        match train_bounded(&test_data(10), "linear,linear", 8, 16) {
+            Err(RmiError::NotEnoughData { required, available }) => {
+                assert_eq!(required, 17);
+                assert_eq!(available, 10);
+            },
+            other => panic!("expected a not enough data error, got {:?}", other.err())
+        }
+
+        assert!(train(&test_data(0), "linear,linear", 8).is_err());
+    }
+
+    #[test]
+    fn test_u128_keys() {
+        let keys = test_keys_u128(10_000);
+        let data: Vec<(u128, usize)> = keys.iter().copied()
+            .enumerate().map(|(idx, key)| (key, idx)).collect();
+        let md = RMITrainingData::new(Box::new(data));
+
+        for models in &["linear,linear", "radix,linear", "radix18,cubic", "histogram,linear"] {
+            let rmi = train(&md, models, 64).unwrap();
+            assert_eq!(rmi.key_type, KeyType::U128);
+            for (idx, key) in keys.iter().enumerate() {
+                let (guess, err) = rmi.lookup(*key);
+                let diff = if guess > idx as u64 { guess - idx as u64 } else { idx as u64 - guess };
+                assert!(diff <= err, "{} gave {} +/- {} for index {}", models, guess, err, idx);
+            }
+        }
+    }
+
+    #[test]
+    fn test_signed_keys() {
+        // keys on both sides of zero, so the sign bit matters
+        let keys: Vec<i64> = (0..10_000i64)
+            .map(|i| (i - 5000) * (i - 5000).abs() * 3 + i % 7)
+            .collect();
+        let data: Vec<(i64, usize)> = keys.iter().copied()
+            .enumerate().map(|(idx, key)| (key, idx)).collect();
+        let md = RMITrainingData::new(Box::new(data));
+
+        for models in &["linear,linear", "radix,linear", "radix18,cubic", "histogram,linear"] {
+            let rmi = train(&md, models, 64).unwrap();
+            assert_eq!(rmi.key_type, KeyType::I64);
+            for (idx, key) in keys.iter().enumerate() {
+                let (guess, err) = rmi.lookup(*key);
+                let diff = if guess > idx as u64 { guess - idx as u64 } else { idx as u64 - guess };
+                assert!(diff <= err, "{} gave {} +/- {} for index {}", models, guess, err, idx);
+            }
+        }
+
+        let data: Vec<(i32, usize)> = keys.iter()
+            .map(|&key| key as i32)
+            .enumerate().map(|(idx, key)| (key, idx)).collect();
+        let md = RMITrainingData::new(Box::new(data));
+        let rmi = train(&md, "radix,linear", 64).unwrap();
+        assert_eq!(rmi.key_type, KeyType::I32);
+        for (idx, key) in keys.iter().enumerate() {
+            let (guess, err) = rmi.lookup(*key as i32);
+            let diff = if guess > idx as u64 { guess - idx as u64 } else { idx as u64 - guess };
+            assert!(diff <= err, "radix,linear gave {} +/- {} for index {}", guess, err, idx);
+        }
+    }
+
+    #[test]
+    fn test_f32_keys() {
+        // sensor readings, stored as single-precision floats
+        let keys: Vec<f32> = (0..10_000)
+            .map(|i| i as f32 * 0.37 + (i % 7) as f32 * 0.01 - 1000.0)
+            .collect();
+        let data: Vec<(f32, usize)> = keys.iter().copied()
+            .enumerate().map(|(idx, key)| (key, idx)).collect();
+        let md = RMITrainingData::new(Box::new(data));
+
+        for models in &["linear,linear", "cubic,linear", "linear_spline,cubic"] {
+            let rmi = train(&md, models, 64).unwrap();
+            assert_eq!(rmi.key_type, KeyType::F32);
+            for (idx, key) in keys.iter().enumerate() {
+                let (guess, err) = rmi.lookup(*key);
+                let diff = if guess > idx as u64 { guess - idx as u64 } else { idx as u64 - guess };
+                assert!(diff <= err, "{} gave {} +/- {} for index {}", models, guess, err, idx);
+            }
+        }
+    }
+
+    #[test]
+    fn test_unsorted_data() {
+        let data: Vec<(u64, usize)> = vec![1, 5, 9, 7, 12].into_iter()
+            .enumerate().map(|(idx, key)| (key, idx)).collect();
+        let md = RMITrainingData::new(Box::new(data));
+        match train(&md, "linear,linear", 2) {
+            Err(RmiError::UnsortedData { index }) => assert_eq!(index, 3),
+            other => panic!("expected an unsorted data error, got {:?}", other.err())
+        }
+
+        let data: Vec<(f64, usize)> = vec![1.0, std::f64::NAN, 2.0].into_iter()
+            .enumerate().map(|(idx, key)| (key, idx)).collect();
+        let md = RMITrainingData::new(Box::new(data));
+        assert!(matches!(train(&md, "linear,linear", 2),
+                         Err(RmiError::UnsortedData { index: 1 })));
+
+        // duplicates are fine
+        let data: Vec<(u64, usize)> = vec![1, 5, 5, 5, 7].into_iter()
+            .enumerate().map(|(idx, key)| (key, idx)).collect();
+        let md = RMITrainingData::new(Box::new(data));
+        assert!(train(&md, "linear,linear", 2).is_ok());
+    }
+
+    #[test]
+    fn test_sparse_positions() {
+        // keys mapped to the byte offsets of variable-length records
+        let keys = test_keys(10_000);
+        let mut data = Vec::new();
+        let mut offset = 4096;
+        for key in keys.iter() {
+            data.push((*key, offset));
+            offset += 16 + (*key as usize % 113);
+        }
+        let md = RMITrainingData::new(Box::new(data.clone()));
+        assert_eq!(md.target_range(), data.last().unwrap().1 + 1);
+
+        for models in &["linear,linear", "radix,linear", "cubic,linear", "linear,linear,linear"] {
+            let rmi = train(&md, models, 64).unwrap();
+            assert_eq!(rmi.num_rmi_rows, md.target_range());
+            assert_eq!(rmi.num_data_rows, data.len());
+            for (key, pos) in data.iter() {
+                let (guess, err) = rmi.lookup(*key);
+                let diff = if guess > *pos as u64 { guess - *pos as u64 } else { *pos as u64 - guess };
+                assert!(diff <= err, "{} gave {} +/- {} for position {}", models, guess, err, pos);
+            }
+        }
+
+        // absent keys should find the position of the next key
+        let rmi = train(&md, "linear,linear", 64).unwrap();
+        for win in data.windows(2) {
+            let (guess, err) = rmi.lookup(win[0].0 + 1);
+            let pos = win[1].1 as u64;
+            let diff = if guess > pos { guess - pos } else { pos - guess };
+            assert!(diff <= err, "gave {} +/- {} for position {}", guess, err, pos);
+        }
+
+        let mut bad = data;
+        bad[20].1 = 0;
+        let md = RMITrainingData::new(Box::new(bad));
+        assert!(matches!(train(&md, "linear,linear", 64),
+                         Err(RmiError::UnsortedPositions { index: 20 })));
+    }
+
+    #[test]
+    fn test_sampled_training() {
+        let keys = test_keys(50_000);
+        let md = test_data(keys.len() as u64);
+        let samplings = [Sampling::Uniform { rate: 0.05 }, Sampling::Stratified { rate: 0.05 }];
+
+        for models in &["linear,linear", "cubic,linear", "radix,linear,linear"] {
+            let full = train(&md, models, 128).unwrap();
+            for sampling in samplings.iter() {
+                for sample_leaves in &[false, true] {
+                    let options = TrainingOptions {
+                        sampling: Some(*sampling),
+                        sample_leaves: *sample_leaves,
+                        seed: 7,
+                        fallback: None,
+                        leaf_allocation: None
+                    };
+                    let rmi = train_with_options(&md, models, 128, &options).unwrap();
+                    assert_eq!(rmi.num_rmi_rows, full.num_rmi_rows);
+                    assert_eq!(rmi.last_layer_bounds.len(), full.last_layer_bounds.len());
+
+                    // errors are computed over every key, so lookups of
+                    // present and absent keys stay within bounds
+                    let name = format!("{} {:?} (sample leaves: {})", models, sampling, sample_leaves);
+                    assert_lookups_within_bounds(&rmi, &keys, 1, &name);
+                }
+            }
+        }
+
+        let options = TrainingOptions { sampling: Some(Sampling::Uniform { rate: 0.0 }), ..Default::default() };
+        assert!(matches!(train_with_options(&md, "linear,linear", 64, &options),
+                         Err(RmiError::InvalidSampleRate(_))));
+    }
+
+    #[test]
+    fn test_split_at_last_key() {
+        // the midpoint leaf boundary falls on the last key
+        let data: Vec<(u64, usize)> = vec![(1, 0), (2, 1), (3, 2)];
+        let md = RMITrainingData::new(Box::new(data));
+        let rmi = train(&md, "linear,linear", 4).unwrap();
+        assert_eq!(rmi.last_layer_bounds.len(), 4);
+    }
+
+    #[test]
+    fn test_first_leaf_lower_error() {
+        // a key before the first key belongs at position 0, not after the
+        // first leaf's keys
+        let data: Vec<(u64, usize)> = (0..1000).map(|i| (i + 1000, i as usize)).collect();
+        let md = RMITrainingData::new(Box::new(data));
+        let rmi = train(&md, "linear,linear", 4).unwrap();
+        assert!(rmi.last_layer_max_l1s()[0] <= 4, "error of {}", rmi.last_layer_max_l1s()[0]);
+
+        let (guess, err) = rmi.lookup(5);
+        assert!(guess <= err);
+    }
+
+    #[test]
+    fn test_pgm_error_bound() {
+        let keys = test_keys(50_000);
+        let md = test_data(keys.len() as u64);
+
+        for (models, epsilon) in &[("linear,pgm8", 8), ("radix,pgm32", 32), ("cubic,pgm16", 16)] {
+            let rmi = train(&md, models, 64).unwrap();
+            assert!(rmi.last_layer_max_l1s().iter().all(|err| err <= epsilon),
+                    "{} exceeded its error bound", models);
+
+            assert_lookups_within_bounds(&rmi, &keys, 1, models);
+        }
+
+        assert!(matches!(train(&md, "pgm8,linear", 64),
+                         Err(RmiError::InvalidLayerOrder { .. })));
+        assert!(matches!(train(&md, "cubic,linear,pgm16", 64),
+                         Err(RmiError::InvalidLayerOrder { required: "second", .. })));
+    }
+
+    #[test]
+    fn test_radix_spline() {
+        let keys = test_keys(50_000);
+        let md = test_data(keys.len() as u64);
+
+        // on its own, the spline error bounds the lookup error (with one
+        // more for absent keys, and one for rounding)
+        let rmi = train(&md, "radix_spline16_8", 1).unwrap();
+        assert!(rmi.last_layer_max_l1s()[0] <= 10);
+
+        for models in &["radix_spline16_8", "radix_spline,linear", "radix_spline10_64,cubic"] {
+            let rmi = train(&md, models, 128).unwrap();
+            assert_lookups_within_bounds(&rmi, &keys, 1, models);
+        }
+
+        assert!(matches!(train(&md, "linear,radix_spline", 64),
+                         Err(RmiError::InvalidLayerOrder { .. })));
+        assert!(matches!(train(&md, "radix_spline99,linear", 64),
+                         Err(RmiError::UnknownModel(_))));
+
+        let floats: Vec<(f64, usize)> = keys.iter().enumerate().map(|(idx, key)| (*key as f64, idx)).collect();
+        assert!(matches!(train(&RMITrainingData::new(Box::new(floats)), "radix_spline,linear", 64),
+                         Err(RmiError::UnsupportedKeyType { key_type: KeyType::F64, .. })));
+        let wide: Vec<(u128, usize)> = keys.iter().enumerate().map(|(idx, key)| (*key as u128, idx)).collect();
+        assert!(matches!(train(&RMITrainingData::new(Box::new(wide)), "radix_spline", 1),
+                         Err(RmiError::UnsupportedKeyType { key_type: KeyType::U128, .. })));
+    }
+
+    #[test]
+    fn test_polynomial_leaves() {
+        let keys = test_keys(50_000);
+        let md = test_data(keys.len() as u64);
+
+        for models in &["linear,poly2", "radix,poly3", "poly4,linear", "cubic,poly5"] {
+            let rmi = train(&md, models, 256).unwrap();
+            assert_lookups_within_bounds(&rmi, &keys, 3, models);
+        }
+    }
+
+    #[test]
+    fn test_fallback_leaves() {
+        let keys: Vec<u64> = (0..50_000u64).map(|i| 3 * i * i * i + i % 7 + 1).collect();
+        let data: Vec<(u64, usize)> = keys.iter().cloned().zip(0..).collect();
+        let md = RMITrainingData::new(Box::new(data));
+        let fallback = Fallback { max_error: 128, node_size: 64 };
+        let options = TrainingOptions { fallback: Some(fallback), ..Default::default() };
+
+        for models in &["linear,linear", "radix,linear", "linear,linear,linear"] {
+            let plain = train(&md, models, 8).unwrap();
+            let hybrid = train_with_options(&md, models, 8, &options).unwrap();
+            assert!(hybrid.model_max_error < plain.model_max_error);
+            assert!(hybrid.model_avg_error < plain.model_avg_error);
+            assert!(rmi_size(&hybrid) > rmi_size(&plain));
+
+            let leaves = hybrid.rmi.last().unwrap();
+            assert!(leaves.iter().any(|leaf| leaf.function_name().ends_with("_fallback")));
+            for (leaf, (err, plain_err)) in leaves.iter().zip(hybrid.last_layer_max_l1s().iter()
+                                                             .zip(plain.last_layer_max_l1s().iter())) {
+                assert!(err <= plain_err);
+                assert_eq!(leaf.params().len(), leaves[0].params().len());
+            }
+
+            assert_lookups_within_bounds(&hybrid, &keys, 3, models);
+        }
+
+        // no leaf needs a node, so the leaves are left alone
+        let options = TrainingOptions {
+            fallback: Some(Fallback { max_error: 1 << 40, node_size: 64 }), ..Default::default()
+        };
+        let rmi = train_with_options(&md, "linear,linear", 8, &options).unwrap();
+        assert_eq!(rmi.rmi[1][0].function_name(), "linear");
+    }
+
+    #[test]
+    fn test_leaf_allocation() {
+        // most keys are packed into a small part of the key space
+        let keys: Vec<u64> = (0..50_000u64).map(|i| i * i * i / 1000 + 3 * i + 1).collect();
+        let data: Vec<(u64, usize)> = keys.iter().cloned().zip(0..).collect();
+        let md = RMITrainingData::new(Box::new(data));
+
+        for models in &["linear,linear", "radix,linear", "cubic,cubic"] {
+            let plain = train(&md, models, 64).unwrap();
+            for allocation in &[LeafAllocation::EquiDepth { oversample: 16 },
+                                LeafAllocation::Merge { oversample: 16 }] {
+                let options = TrainingOptions { leaf_allocation: Some(*allocation), ..Default::default() };
+                let rmi = train_with_options(&md, models, 64, &options).unwrap();
+                assert_eq!(rmi.rmi[1].len(), 64);
+                assert_eq!(rmi.last_layer_bounds.len(), 64);
+                assert!(rmi.rmi[0][0].function_name().ends_with("_remap"));
+                assert!(rmi.model_max_error < plain.model_max_error,
+                        "{} {:?} had a max error of {}, up from {}",
+                        models, allocation, rmi.model_max_error, plain.model_max_error);
+
+                let name = format!("{} {:?}", models, allocation);
+                assert_lookups_within_bounds(&rmi, &keys, 3, &name);
+            }
+        }
+
+        let options = TrainingOptions {
+            leaf_allocation: Some(LeafAllocation::EquiDepth { oversample: 4 }), ..Default::default()
+        };
+        assert!(matches!(train_with_options(&md, "linear,linear,linear", 16, &options),
+                         Err(RmiError::InvalidLeafAllocation(_))));
+        let options = TrainingOptions {
+            leaf_allocation: Some(LeafAllocation::Merge { oversample: 0 }), ..Default::default()
+        };
+        assert!(matches!(train_with_options(&md, "linear,linear", 16, &options),
+                         Err(RmiError::InvalidLeafAllocation(_))));
+    }
+
+    #[test]
+    fn test_empty_last_leaf() {
+        // no key reaches the last of the radix model's leaves, which
+        // should predict the end of the data rather than fall back to an
+        // error covering every key
+        let keys: Vec<u64> = (0..0x6000u64).collect();
+        let md = RMITrainingData::new(Box::new(keys.iter().map(|k| (*k, *k as usize)).collect::<Vec<_>>()));
+        let rmi = train(&md, "radix,linear", 4).unwrap();
+        for probe in 0x6000..0x8000u64 {
+            let (guess, err) = rmi.lookup(probe);
+            assert!(keys.len() as u64 - 1 - guess <= err && err <= 8,
+                    "{} gave {} +/- {}", probe, guess, err);
+        }
+    }
+
+    #[test]
+    fn test_robust_linear_tiny() {
+        assert!(train(&test_data(3), "robust_linear,linear", 2).is_ok());
+    }
 }
@@ -5,114 +5,224 @@
 // 
 // < end copyright > 
  
+
 use crate::models::*;
-use crate::train::{train_model, TrainedRMI};
+use crate::train::{validate, train_model, TrainedRMI, Fallback};
+use crate::error::RmiError;
+use crate::train::two_layer::{finish_leaf_layer, leaf_index};
 use log::*;
+use rayon::prelude::*;
+use std::sync::Arc;
+
+// The training data of one model of an inner layer: a range of the
+// layer's data, which is shared by all of the layer's models so that it
+// does not have to be copied for each of them.
+struct Partition<T> {
+    data: Arc<Vec<(T, usize)>>,
+    start: usize,
+    end: usize
+}
+
+impl <T: TrainingKey> RMITrainingDataIteratorProvider for Partition<T> {
+    type InpType = T;
+    fn len(&self) -> usize {
+        return self.end - self.start;
+    }
+
+    fn cdf_iter(&self) -> Box<dyn Iterator<Item = (Self::InpType, usize)> + '_> {
+        return Box::new(self.data[self.start..self.end].iter().copied());
+    }
+
+    fn key_type(&self) -> KeyType { return T::key_type(); }
+    fn get(&self, idx: usize) -> Option<(Self::InpType, usize)> {
+        return self.data[self.start..self.end].get(idx).copied();
+    }
+}
 
-pub fn train_multi_layer(data: &mut RMITrainingData,
-                         model_list: &[String],
-                         last_model: String,
-                         branch_factor: u64) -> TrainedRMI {
-    
+// Replaces each model that received no training data with a constant
+// model that predicts the next-layer index of the first key after it,
+// so that lookups for missing keys are routed to the right place. The
+// data of model i is data[starts[i]..starts[i + 1]].
+fn fix_empty_models<T: TrainingKey>(models: &mut [Box<dyn Model>],
+                                   data: &[(T, usize)],
+                                   starts: &[usize],
+                                   next_layer_size: u64) {
+    let mut next_target = next_layer_size - 1;
+    let mut could_not_replace = false;
+    for idx in (0..models.len()).rev() {
+        if starts[idx] < starts[idx + 1] {
+            let (x, _y) = data[starts[idx]];
+            let pred = models[idx].predict_to_int(&x.to_model_input());
+            next_target = u64::min(next_layer_size - 1, pred);
+        } else if !models[idx].set_to_constant_model(next_target) {
+            could_not_replace = true;
+        }
+    }
+
+    if could_not_replace {
+        warn!("Some empty inner models could not be replaced with constants, \
+               negative lookup performance may be poor.");
+    }
+}
+
+// Fits the inner layers on top_data and the leaf models on leaf_data, which
+// are either md_container itself or samples of it. The last level errors
+// are always computed over md_container.
+pub fn train_multi_layer<T: TrainingKey>(md_container: &RMITrainingData<T>,
+                                        top_data: &RMITrainingData<T>,
+                                        leaf_data: &RMITrainingData<T>,
+                                        model_list: &[String],
+                                        last_model: &str,
+                                        branch_factor: u64,
+                                        fallback: Option<&Fallback>)
+                                        -> Result<TrainedRMI, RmiError> {
+    let mut all_models: Vec<String> = model_list.to_vec();
+    all_models.push(String::from(last_model));
+    validate(&all_models)?;
+
+    let target_range = md_container.target_range();
     let mut rmi: Vec<Vec<Box<dyn Model>>> = Vec::new();
-    let mut data_partitions = vec![data.clone().into_data()];
-    let num_rows = data_partitions[0].len();
+
+    // the data of the current layer, grouped by the model it is routed to
+    // (the data of model i is data[starts[i]..starts[i + 1]])
+    let mut data: Arc<Vec<(T, usize)>> = Arc::new(top_data.iter().collect());
+    let mut starts: Vec<usize> = vec![0, data.len()];
 
     let mut current_model_count = 1;
-    for (_layer_idx, model_type) in model_list.iter().enumerate() {
-        info!("Training {} model layer", model_type);
-        // data_partition contains all of our data partitioned into groups
-        // based on the previous RMI layer's output
+    for model_type in model_list.iter() {
         let next_layer_size = current_model_count * branch_factor;
-        let mut next_layer_data =
-            vec![Vec::with_capacity(num_rows / next_layer_size as usize); next_layer_size as usize];
-        let mut models: Vec<Box<dyn Model>> = Vec::with_capacity(next_layer_size as usize);
-
-        for model_data in data_partitions.into_iter() {
-            let mut md_container = RMITrainingData::new(&model_data);
+        trace!("Training {} model layer (num models = {})",
+               model_type, current_model_count);
 
-            // not at the last layer -- rescale
-            md_container.set_scale(next_layer_size as f64 / num_rows as f64);
-            let model = train_model(model_type.as_str(), &md_container);
+        // each model predicts an index into the next layer
+        let mut models: Vec<Box<dyn Model>> = starts.par_windows(2)
+            .map(|range| {
+                let partition = Partition { data: Arc::clone(&data), start: range[0], end: range[1] };
+                let mut container = RMITrainingData::new(Box::new(partition));
+                container.set_scale(next_layer_size as f64 / target_range as f64);
+                train_model(model_type.as_str(), &container)
+            }).collect::<Result<_, _>>()?;
 
-            // rescale back for next layer
-            md_container.set_scale(1.0);
+        fix_empty_models(&mut models, &data, &starts, next_layer_size);
 
-            for (x, y) in md_container.iter_int_int() {
-                let model_pred = model.predict_to_int(x.into());
-                assert!(model.needs_bounds_check() || model_pred < next_layer_size);
-                let target = u64::min(next_layer_size - 1, model_pred) as usize;
-                next_layer_data[target].push((x, y));
+        let mut targets: Vec<usize> = Vec::with_capacity(data.len());
+        for (model, range) in models.iter().zip(starts.windows(2)) {
+            for (x, _y) in data[range[0]..range[1]].iter() {
+                let model_pred = model.predict_to_int(&x.to_model_input());
+                assert!(model.needs_bounds_check() || model_pred < next_layer_size,
+                        "{} model gave an index of {} which is out of bounds of {}",
+                        model_type, model_pred, next_layer_size);
+                targets.push(u64::min(next_layer_size - 1, model_pred) as usize);
             }
+        }
 
-            models.push(model);
+        // group the data by target, keeping the order within each group
+        let mut next_starts: Vec<usize> = vec![0; next_layer_size as usize + 1];
+        for target in targets.iter() {
+            next_starts[target + 1] += 1;
+        }
+        for idx in 1..next_starts.len() {
+            next_starts[idx] += next_starts[idx - 1];
+        }
+        let mut next_data: Vec<(T, usize)> = data.to_vec();
+        let mut fill = next_starts.clone();
+        for (item, target) in data.iter().zip(targets.iter()) {
+            next_data[fill[*target]] = *item;
+            fill[*target] += 1;
         }
+        std::mem::drop(targets);
 
-        data_partitions = next_layer_data
-            .into_iter()
-            .map(ModelData::IntKeyToIntPos)
-            .collect();
+        // keep each partition sorted, even if the layer was not monotonic
+        let mut groups: Vec<&mut [(T, usize)]> = Vec::with_capacity(next_layer_size as usize);
+        let mut rest: &mut [(T, usize)] = &mut next_data;
+        for range in next_starts.windows(2) {
+            let (group, tail) = rest.split_at_mut(range[1] - range[0]);
+            groups.push(group);
+            rest = tail;
+        }
+        groups.par_iter_mut().for_each(|group| group.sort_by_key(|(_x, y)| *y));
 
-        current_model_count *= branch_factor;
+        data = Arc::new(next_data);
+        starts = next_starts;
+        current_model_count = next_layer_size;
         rmi.push(models);
     }
 
-    info!("Training last level {} model", last_model);
-    let mut last_layer = Vec::new();
-    let mut last_layer_max_l1s: Vec<u64> = Vec::new();
-    let mut model_avg_error: f64 = 0.0;
-    let mut model_avg_l2_error: f64 = 0.0;
-    let mut model_avg_log2_error: f64 = 0.0;
-    let mut model_max_log2_error: f64 = 0.0;
-    let mut model_max_error = 0;
-    let mut model_max_error_idx = 0;
-
-    let mut n = 1;
-    for (midx, model_data) in data_partitions.into_iter().enumerate() {
-        let md_container = RMITrainingData::new(&model_data);
-        let last_model = train_model(last_model.as_str(), &md_container);
-        let mut max_error = 0;
-        
-        for (idx, (x, y)) in md_container.iter_int_int().enumerate() {
-            let pred = last_model.predict_to_int(x.into());
-            let err = u64::max(y, pred) - u64::min(y, pred);
-
-            if let Some(bound) = last_model.error_bound() {
-                if err > bound {
-                    warn!("Precision issue: model reports max bound of {}, \
-                           but an error of {} was observed on input {} at index {}. Prediction: {} Actual: {}",
-                          bound, err, x, idx, pred, y);
-                }
-            }
+    trace!("Training last level {} model layer (num models = {})",
+           last_model, current_model_count);
+    std::mem::drop(data);
 
-            max_error = u64::max(max_error, err);
-            model_avg_error += ((max_error as f64) - model_avg_error) / (n as f64);
-            model_avg_l2_error += ((max_error as f64).powf(2.0) - model_avg_l2_error) / (n as f64);
-            let log2_error = ((2 * max_error + 2) as f64).log2();
-            model_avg_log2_error += (log2_error - model_avg_log2_error) / (n as f64);
-            model_max_log2_error = f64::max(model_max_log2_error, log2_error);
-            n += 1;
+    // include the neighboring points of each leaf to support lower
+    // bound searches, as in the two layer case
+    let mut leaf_partitions: Vec<Vec<(T, usize)>> =
+        (0..current_model_count).map(|_| Vec::new()).collect();
+    let mut last: Option<(usize, (T, usize))> = None;
+    for (x, y) in leaf_data.iter() {
+        let target = u64::min(current_model_count - 1,
+                              leaf_index(&rmi, &x.to_model_input())) as usize;
+        if let Some((last_target, last_item)) = last {
+            if last_target != target {
+                leaf_partitions[last_target].push((x, y));
+                leaf_partitions[target].push(last_item);
+            }
         }
-        if max_error > model_max_error {
-            model_max_error = max_error;
-            model_max_error_idx = midx;
+
+        leaf_partitions[target].push((x, y));
+        last = Some((target, (x, y)));
+    }
+
+    let leaf_models: Vec<Box<dyn Model>> = leaf_partitions.into_par_iter()
+        .map(|mut model_data| {
+            model_data.sort_by_key(|(_x, y)| *y);
+            model_data.dedup_by_key(|(_x, y)| *y);
+            let container = RMITrainingData::new(Box::new(model_data));
+            train_model(last_model, &container)
+        }).collect::<Result<_, _>>()?;
+
+    return Ok(finish_leaf_layer(md_container, rmi, leaf_models,
+                                all_models.join(","), branch_factor, fallback));
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use crate::train::tests::{test_keys, test_data};
+
+    fn check_lower_bounds(rmi: &TrainedRMI, keys: &[u64]) {
+        for &key in keys.iter() {
+            for probe in &[key, key + 1] {
+                let lb = keys.partition_point(|&k| k < *probe);
+                let lb = usize::min(lb, keys.len() - 1) as u64;
+                let (guess, err) = rmi.lookup(*probe);
+                let diff = if guess > lb { guess - lb } else { lb - guess };
+                assert!(diff <= err,
+                        "lower bound of {} is {} but lookup returned {} +/- {}",
+                        probe, lb, guess, err);
+            }
         }
+    }
+
+    #[test]
+    fn test_three_layer() {
+        let keys = test_keys(20_000);
+        let rmi = train(&test_data(20_000), "linear,linear,linear", 16).unwrap();
+        assert_eq!(rmi.rmi.len(), 3);
+        assert_eq!(rmi.rmi[1].len(), 16);
+        assert_eq!(rmi.rmi[2].len(), 256);
+        assert_eq!(rmi.last_layer_bounds.len(), 256);
+        check_lower_bounds(&rmi, &keys);
+    }
+
+    #[test]
+    fn test_four_layer() {
+        let keys: Vec<u64> = (0..20_000u64).map(|i| i * i * i + 5).collect();
+        let data: Vec<(u64, usize)> = keys.iter().copied()
+            .enumerate().map(|(idx, key)| (key, idx)).collect();
+        let md = RMITrainingData::new(Box::new(data));
 
-        last_layer.push(last_model);
-        last_layer_max_l1s.push(max_error);
+        let rmi = train(&md, "radix,linear,cubic,linear", 8).unwrap();
+        assert_eq!(rmi.rmi[3].len(), 512);
+        assert_eq!(rmi.models, "radix,linear,cubic,linear");
+        check_lower_bounds(&rmi, &keys);
     }
-    rmi.push(last_layer);
-
-    return TrainedRMI {
-        model_avg_error,
-        model_avg_l2_error,
-        model_avg_log2_error,
-        model_max_error,
-        model_max_error_idx,
-        model_max_log2_error,
-        last_layer_max_l1s,
-        rmi,
-        models: format!("{},{}", model_list.join(","), last_model),
-        branching_factor: branch_factor
-    };
 }
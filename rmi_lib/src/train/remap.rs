@@ -0,0 +1,232 @@
+// < begin copyright > 
+// Copyright Ryan Marcus 2020
+// 
+// See root directory of this project for license terms.
+// 
+// < end copyright > 
+ 
+
+use crate::models::*;
+use crate::train::LeafAllocation;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+// Least squares statistics of the (key, position) points in a group of
+// slots. Groups are combined with the pairwise update of Chan et al.,
+// which stays accurate for large keys.
+#[derive(Clone, Copy, Default)]
+struct LineStats {
+    n: f64,
+    mean_x: f64,
+    mean_y: f64,
+    cxx: f64,
+    cxy: f64,
+    cyy: f64
+}
+
+impl LineStats {
+    fn point(x: f64, y: f64) -> LineStats {
+        return LineStats { n: 1.0, mean_x: x, mean_y: y, ..Default::default() };
+    }
+
+    fn merge(&self, other: &LineStats) -> LineStats {
+        if self.n == 0.0 {
+            return *other;
+        }
+        if other.n == 0.0 {
+            return *self;
+        }
+
+        let n = self.n + other.n;
+        let dx = other.mean_x - self.mean_x;
+        let dy = other.mean_y - self.mean_y;
+        let w = self.n * other.n / n;
+        return LineStats {
+            n,
+            mean_x: self.mean_x + dx * other.n / n,
+            mean_y: self.mean_y + dy * other.n / n,
+            cxx: self.cxx + other.cxx + dx * dx * w,
+            cxy: self.cxy + other.cxy + dx * dy * w,
+            cyy: self.cyy + other.cyy + dy * dy * w
+        };
+    }
+
+    // sum of the squared errors of the best fitting line
+    fn sse(&self) -> f64 {
+        if self.cxx <= 0.0 {
+            return self.cyy;
+        }
+        return f64::max(0.0, self.cyy - self.cxy * self.cxy / self.cxx);
+    }
+}
+
+// A possible merge of the group starting at slot `left` with the group
+// after it, valid while neither group has changed since.
+struct MergeCandidate {
+    cost: f64,
+    left: usize,
+    right: usize,
+    versions: (u64, u64)
+}
+
+impl PartialEq for MergeCandidate {
+    fn eq(&self, other: &Self) -> bool {
+        return self.cmp(other) == Ordering::Equal;
+    }
+}
+
+impl Eq for MergeCandidate {}
+
+impl PartialOrd for MergeCandidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        return Some(self.cmp(other));
+    }
+}
+
+impl Ord for MergeCandidate {
+    // reversed, so that the binary heap pops the cheapest merge first
+    fn cmp(&self, other: &Self) -> Ordering {
+        return other.cost.total_cmp(&self.cost)
+            .then_with(|| other.left.cmp(&self.left));
+    }
+}
+
+// Gives each leaf about the same number of keys. Each slot goes to the
+// leaf that contains its middle key.
+fn equi_depth(counts: &[u64], num_leaves: u64) -> Vec<u32> {
+    let total: u64 = counts.iter().sum();
+    let mut before = 0;
+    return counts.iter().map(|&count| {
+        let middle = before as f64 + count as f64 / 2.0;
+        before += count;
+        let leaf = (middle * num_leaves as f64 / total as f64) as u64;
+        u64::min(num_leaves - 1, leaf) as u32
+    }).collect();
+}
+
+// Greedily merges neighboring groups of slots, starting from one group
+// per slot, until there is one group per leaf. Each step merges the two
+// groups whose keys gain the least squared error from being fit by one
+// line instead of two.
+fn merge_neighbors(mut stats: Vec<LineStats>, num_leaves: u64) -> Vec<u32> {
+    let num_slots = stats.len();
+    // groups are identified by their first slot, and linked in key order
+    let mut next: Vec<usize> = (1..=num_slots).collect();
+    let mut prev: Vec<usize> = (0..num_slots).map(|idx| idx.wrapping_sub(1)).collect();
+    let mut versions = vec![0u64; num_slots];
+    let mut alive = vec![true; num_slots];
+
+    let candidate = |stats: &[LineStats], versions: &[u64], left: usize, right: usize| {
+        let cost = stats[left].merge(&stats[right]).sse()
+            - stats[left].sse() - stats[right].sse();
+        return MergeCandidate { cost, left, right, versions: (versions[left], versions[right]) };
+    };
+
+    let mut heap: BinaryHeap<MergeCandidate> = (1..num_slots)
+        .map(|idx| candidate(&stats, &versions, idx - 1, idx))
+        .collect();
+
+    let mut num_groups = num_slots as u64;
+    while num_groups > num_leaves {
+        let MergeCandidate { left, right, versions: (left_ver, right_ver), .. } =
+            heap.pop().unwrap();
+        if !alive[left] || !alive[right]
+            || versions[left] != left_ver || versions[right] != right_ver {
+            continue;
+        }
+
+        stats[left] = stats[left].merge(&stats[right]);
+        alive[right] = false;
+        versions[left] += 1;
+        next[left] = next[right];
+        if next[left] < num_slots {
+            prev[next[left]] = left;
+            heap.push(candidate(&stats, &versions, left, next[left]));
+        }
+        if prev[left] < num_slots {
+            heap.push(candidate(&stats, &versions, prev[left], left));
+        }
+        num_groups -= 1;
+    }
+
+    let mut table = Vec::with_capacity(num_slots);
+    let mut leaf = 0;
+    let mut group = 0;
+    while group < num_slots {
+        for _slot in group..next[group] {
+            table.push(leaf);
+        }
+        leaf += 1;
+        group = next[group];
+    }
+    return table;
+}
+
+// Builds the table that maps each of the `num_slots` outputs of the top
+// model to one of `num_leaves` leaves, using the keys of `data` (which
+// must not be scaled).
+pub fn leaf_table<T: TrainingKey>(top_model: &dyn Model,
+                                 data: &RMITrainingData<T>,
+                                 num_slots: u64,
+                                 num_leaves: u64,
+                                 allocation: LeafAllocation) -> Vec<u32> {
+    assert!(num_slots >= num_leaves);
+    let slot_of = |x: &ModelInput| RemappedModel::slot(top_model, num_slots, x) as usize;
+
+    return match allocation {
+        LeafAllocation::EquiDepth { .. } => {
+            let mut counts = vec![0u64; num_slots as usize];
+            for (x, _y) in data.iter_model_input() {
+                counts[slot_of(&x)] += 1;
+            }
+            equi_depth(&counts, num_leaves)
+        },
+        LeafAllocation::Merge { .. } => {
+            let mut stats = vec![LineStats::default(); num_slots as usize];
+            for (x, y) in data.iter_model_input() {
+                let slot = slot_of(&x);
+                stats[slot] = stats[slot].merge(&LineStats::point(x.as_float(), y as f64));
+            }
+            merge_neighbors(stats, num_leaves)
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_equi_depth() {
+        let counts = [10, 0, 0, 10, 20, 0, 0, 0];
+        assert_eq!(equi_depth(&counts, 4), vec![0, 1, 1, 1, 3, 3, 3, 3]);
+        assert_eq!(equi_depth(&[5; 8], 8), (0..8).collect::<Vec<u32>>());
+    }
+
+    #[test]
+    fn test_merge_neighbors() {
+        // a line, then a region with a very different slope
+        let mut stats = vec![LineStats::default(); 8];
+        for i in 0..80 {
+            let y = if i < 40 { i as f64 } else { 40.0 + (i - 40) as f64 * 50.0 };
+            stats[i / 10] = stats[i / 10].merge(&LineStats::point(i as f64, y));
+        }
+
+        let table = merge_neighbors(stats, 2);
+        assert_eq!(table, vec![0, 0, 0, 0, 1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn test_line_stats() {
+        let points: Vec<(f64, f64)> = (0..100).map(|i| (1e15 + i as f64 * 1e3, (i * i) as f64)).collect();
+        let all = points.iter().fold(LineStats::default(), |s, &(x, y)| s.merge(&LineStats::point(x, y)));
+        let halves = points[..37].iter().fold(LineStats::default(), |s, &(x, y)| s.merge(&LineStats::point(x, y)))
+            .merge(&points[37..].iter().fold(LineStats::default(), |s, &(x, y)| s.merge(&LineStats::point(x, y))));
+
+        assert!((all.sse() - halves.sse()).abs() < 1e-6 * all.sse());
+
+        // points on a line have no error
+        let line = (0..50).fold(LineStats::default(), |s, i| s.merge(&LineStats::point(i as f64, 3.0 * i as f64 + 2.0)));
+        assert!(line.sse() < 1e-6);
+    }
+}
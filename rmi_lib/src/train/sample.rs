@@ -0,0 +1,121 @@
+// < begin copyright > 
+// Copyright Ryan Marcus 2020
+// 
+// See root directory of this project for license terms.
+// 
+// < end copyright > 
+ 
+
+use crate::models::*;
+use crate::error::RmiError;
+use crate::train::Sampling;
+
+// splitmix64, so that samples are reproducible from a seed without
+// depending on an RNG crate
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        return z ^ (z >> 31);
+    }
+
+    // uniform in [0, 1)
+    fn next_f64(&mut self) -> f64 {
+        return (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64;
+    }
+}
+
+// Draws the (sorted) indexes of the sample. The first and last keys are
+// always included, so that the sample spans the same keys and positions
+// as the full data.
+fn sample_indexes(num_rows: usize, sampling: Sampling, seed: u64) -> Vec<usize> {
+    let mut rng = SplitMix64(seed);
+    let mut indexes = Vec::new();
+
+    match sampling {
+        Sampling::Uniform { rate } => {
+            if rate >= 1.0 {
+                return (0..num_rows).collect();
+            }
+
+            // skip ahead by geometrically distributed gaps instead of
+            // visiting every key
+            let log_skip = f64::ln(1.0 - rate);
+            let mut idx = 0;
+            while idx < num_rows {
+                indexes.push(idx);
+                let gap = (f64::ln(1.0 - rng.next_f64()) / log_skip).floor();
+                idx = idx.saturating_add(gap as usize + 1);
+            }
+        },
+        Sampling::Stratified { rate } => {
+            let stratum_size = usize::max(1, (1.0 / rate).round() as usize);
+            indexes.push(0);
+            for start in (0..num_rows).step_by(stratum_size) {
+                let size = usize::min(stratum_size, num_rows - start);
+                let idx = start + (rng.next_u64() % size as u64) as usize;
+                if idx != 0 { indexes.push(idx); }
+            }
+        }
+    }
+
+    if indexes.last() != Some(&(num_rows - 1)) {
+        indexes.push(num_rows - 1);
+    }
+    return indexes;
+}
+
+// Returns a sample of data to fit models on. Only the sampled keys are
+// read, so drawing the sample does not take a full pass over the data.
+// The sampled keys keep their positions in the full data.
+pub(crate) fn sample<T: TrainingKey>(data: &RMITrainingData<T>,
+                                     sampling: Sampling,
+                                     seed: u64) -> Result<RMITrainingData<T>, RmiError> {
+    let rate = match sampling {
+        Sampling::Uniform { rate } | Sampling::Stratified { rate } => rate
+    };
+    if !(rate > 0.0 && rate <= 1.0) {
+        return Err(RmiError::InvalidSampleRate(rate));
+    }
+
+    let sampled: Vec<(T, usize)> = sample_indexes(data.len(), sampling, seed)
+        .into_iter()
+        .map(|idx| data.get(idx))
+        .collect();
+    return Ok(RMITrainingData::new(Box::new(sampled)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sample_indexes() {
+        for sampling in &[Sampling::Uniform { rate: 0.01 }, Sampling::Stratified { rate: 0.01 }] {
+            let idxs = sample_indexes(100_000, *sampling, 42);
+            assert_eq!(idxs[0], 0);
+            assert_eq!(*idxs.last().unwrap(), 99_999);
+            assert!(idxs.windows(2).all(|w| w[0] < w[1]));
+            assert!(idxs.len() > 800 && idxs.len() < 1200,
+                    "{:?} drew {} of 100000 keys", sampling, idxs.len());
+        }
+
+        assert_eq!(sample_indexes(10, Sampling::Uniform { rate: 1.0 }, 0), (0..10).collect::<Vec<_>>());
+        assert_eq!(sample_indexes(10, Sampling::Stratified { rate: 1.0 }, 0), (0..10).collect::<Vec<_>>());
+        assert_eq!(sample_indexes(1, Sampling::Stratified { rate: 0.1 }, 0), vec![0]);
+    }
+
+    #[test]
+    fn test_invalid_rate() {
+        let data: Vec<(u64, usize)> = (0..10).map(|i| (i, i as usize)).collect();
+        let md = RMITrainingData::new(Box::new(data));
+        assert!(matches!(sample(&md, Sampling::Uniform { rate: 0.0 }, 0),
+                         Err(RmiError::InvalidSampleRate(_))));
+        assert!(matches!(sample(&md, Sampling::Stratified { rate: 1.5 }, 0),
+                         Err(RmiError::InvalidSampleRate(_))));
+    }
+}
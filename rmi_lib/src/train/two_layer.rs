@@ -7,14 +7,38 @@
  
 use crate::models::TrainingKey;
 use crate::models::*;
-use crate::train::{validate, train_model, TrainedRMI};
+use crate::train::{validate, train_model, TrainedRMI, TrainingOptions, Fallback};
+use crate::error::RmiError;
 use crate::train::lower_bound_correction::LowerBoundCorrection;
+use crate::train::remap::leaf_table;
 use log::*;
 
-fn error_between(v1: u64, v2: u64, max_pred: u64) -> u64 {
-    let pred1 = u64::min(v1, max_pred);
-    let pred2 = u64::min(v2, max_pred);
-    return u64::max(pred1, pred2) - u64::min(pred1, pred2);
+// How far `actual` is below and above the prediction `pred`, after
+// clamping both to `max_pred`.
+fn errors_between(pred: u64, actual: u64, max_pred: u64) -> (u64, u64) {
+    let pred = u64::min(pred, max_pred);
+    let actual = u64::min(actual, max_pred);
+    return (pred.saturating_sub(actual), actual.saturating_sub(pred));
+}
+
+// The number of keys routed to a leaf, and how far below and above the
+// leaf's prediction the lower bound of a key routed to it can be.
+#[derive(Clone, Copy, Debug, Default)]
+struct LeafError {
+    count: u64,
+    below: u64,
+    above: u64
+}
+
+impl LeafError {
+    fn include(&mut self, (below, above): (u64, u64)) {
+        self.below = u64::max(self.below, below);
+        self.above = u64::max(self.above, above);
+    }
+
+    fn max(&self) -> u64 {
+        return u64::max(self.below, self.above);
+    }
 }
 
 fn build_models_from<T: TrainingKey>(data: &RMITrainingData<T>,
@@ -22,7 +46,8 @@ fn build_models_from<T: TrainingKey>(data: &RMITrainingData<T>,
                                     model_type: &str,
                                     start_idx: usize, end_idx: usize,
                                     first_model_idx: usize,
-                                    num_models: usize) -> Vec<Box<dyn Model>> {
+                                    num_models: usize)
+                                    -> Result<Vec<Box<dyn Model>>, RmiError> {
 
     assert!(end_idx > start_idx,
             "start index was {} but end index was {}",
@@ -59,13 +84,13 @@ fn build_models_from<T: TrainingKey>(data: &RMITrainingData<T>,
             second_layer_data.push((x, y));
             
             let container = RMITrainingData::new(Box::new(second_layer_data));
-            let leaf_model = train_model(model_type, &container);
+            let leaf_model = train_model(model_type, &container)?;
             leaf_models.push(leaf_model);
             
             
             // leave empty models for any we skipped.
             for _skipped_idx in (last_target+1)..target {
-                leaf_models.push(train_model(model_type, &dummy_md));
+                leaf_models.push(train_model(model_type, &dummy_md)?);
             }
             assert_eq!(leaf_models.len() + first_model_idx, target);
 
@@ -86,28 +111,46 @@ fn build_models_from<T: TrainingKey>(data: &RMITrainingData<T>,
     // train the last remaining model
     assert!(! second_layer_data.is_empty());
     let container = RMITrainingData::new(Box::new(second_layer_data));
-    let leaf_model = train_model(model_type, &container);
+    let leaf_model = train_model(model_type, &container)?;
     leaf_models.push(leaf_model);
     assert!(leaf_models.len() <= num_models);
     
     // add models at the end with nothing mapped into them
     for _skipped_idx in (last_target+1)..(first_model_idx + num_models) as usize {
-        leaf_models.push(train_model(model_type, &dummy_md));
+        leaf_models.push(train_model(model_type, &dummy_md)?);
     }
     assert_eq!(num_models as usize, leaf_models.len());
-    return leaf_models;
+    return Ok(leaf_models);
 }
 
-pub fn train_two_layer<T: TrainingKey>(md_container: &mut RMITrainingData<T>,
+// Fits the top model on top_data and the leaf models on leaf_data, which
+// are either md_container itself or samples of it. The last level errors
+// are always computed over md_container.
+pub fn train_two_layer<T: TrainingKey>(md_container: &RMITrainingData<T>,
+                                      top_data: &mut RMITrainingData<T>,
+                                      leaf_data: &RMITrainingData<T>,
                                       layer1_model: &str, layer2_model: &str,
-                                      num_leaf_models: u64) -> TrainedRMI {
-    validate(&[String::from(layer1_model), String::from(layer2_model)]);
-
-    let num_rows = md_container.len();
+                                      num_leaf_models: u64,
+                                      options: &TrainingOptions) -> Result<TrainedRMI, RmiError> {
+    validate(&[String::from(layer1_model), String::from(layer2_model)])?;
+
+    let target_range = md_container.target_range();
+    let num_slots = match options.leaf_allocation {
+        Some(allocation) => num_leaf_models * allocation.oversample(),
+        None => num_leaf_models
+    };
 
     trace!("Training top-level {} model layer", layer1_model);
-    md_container.set_scale(num_leaf_models as f64 / num_rows as f64);
-    let top_model = train_model(layer1_model, &md_container);
+    top_data.set_scale(num_slots as f64 / target_range as f64);
+    let mut top_model = train_model(layer1_model, top_data)?;
+    top_data.set_scale(1.0);
+
+    if let Some(allocation) = options.leaf_allocation {
+        trace!("Remapping {} top model slots onto {} leaves", num_slots, num_leaf_models);
+        let table = leaf_table(top_model.as_ref(), top_data, num_slots,
+                               num_leaf_models, allocation);
+        top_model = Box::new(RemappedModel::new(top_model, table));
+    }
 
     // Check monotonicity if in debug mode
     #[cfg(debug_assertions)]
@@ -125,95 +168,97 @@ pub fn train_two_layer<T: TrainingKey>(md_container: &mut RMITrainingData<T>,
 
     trace!("Training second-level {} model layer (num models = {})",
           layer2_model, num_leaf_models);
-    md_container.set_scale(1.0);
 
     // find a prediction boundary near the middle
     let midpoint_model = num_leaf_models / 2;
-    let split_idx = md_container.lower_bound_by(|x| {
+    let split_idx = leaf_data.lower_bound_by(|x| {
         let model_idx = top_model.predict_to_int(&x.0.to_model_input());
         let model_target = u64::min(num_leaf_models - 1, model_idx);
         return model_target.cmp(&midpoint_model);
     });
 
     // make sure the split point that we got is valid
-    if split_idx > 0 && split_idx < md_container.len() {
-        let key_at = top_model.predict_to_int(&md_container.get_key(split_idx)
+    if split_idx > 0 && split_idx < leaf_data.len() {
+        let key_at = top_model.predict_to_int(&leaf_data.get_key(split_idx)
                                               .to_model_input());
-        let key_pr = top_model.predict_to_int(&md_container.get_key(split_idx - 1)
+        let key_pr = top_model.predict_to_int(&leaf_data.get_key(split_idx - 1)
                                               .to_model_input());
         assert!(key_at > key_pr);
     }
 
-    let mut leaf_models = if split_idx >= md_container.len() {
-        build_models_from(&md_container, &top_model, layer2_model,
-                          0, md_container.len(), 0,
-                          num_leaf_models as usize)
+    // if the split leaves nothing for the second half, build every leaf in one pass
+    let leaf_models = if split_idx + 1 >= leaf_data.len() {
+        build_models_from(leaf_data, &top_model, layer2_model,
+                          0, leaf_data.len(), 0,
+                          num_leaf_models as usize)?
     } else {
         let split_idx_target = u64::min(num_leaf_models - 1,
                                         top_model.predict_to_int(
-                                            &md_container.get_key(split_idx)
+                                            &leaf_data.get_key(split_idx)
                                                 .to_model_input()))
             as usize;
 
         let first_half_models = split_idx_target as usize;
         let second_half_models = num_leaf_models as usize - split_idx_target as usize;
 
-        let (mut hf1, mut hf2)
-            = rayon::join(|| build_models_from(&md_container, &top_model, layer2_model,
+        let (hf1, hf2)
+            = rayon::join(|| build_models_from(leaf_data, &top_model, layer2_model,
                                                0, split_idx,
                                                0,
                                                first_half_models),
-                          || build_models_from(&md_container, &top_model, layer2_model,
-                                               split_idx + 1, md_container.len(),
+                          || build_models_from(leaf_data, &top_model, layer2_model,
+                                               split_idx + 1, leaf_data.len(),
                                                split_idx_target,
                                                second_half_models));
 
         let mut leaf_models = Vec::new();
-        leaf_models.append(&mut hf1);
-        leaf_models.append(&mut hf2);
+        leaf_models.append(&mut hf1?);
+        leaf_models.append(&mut hf2?);
         leaf_models
     };
 
-    trace!("Computing lower bound stats...");
-    let lb_corrections = LowerBoundCorrection::new(
-        |x| top_model.predict_to_int(&x.to_model_input()), num_leaf_models, md_container
-    );
-
-    trace!("Fixing empty models...");
-    // replace any empty model with a model that returns the correct constant
-    // (for LB predictions), if the underlying model supports it.
-    let mut could_not_replace = false;
-    for idx in 0..(num_leaf_models as usize)-1 {
-        assert_eq!(lb_corrections.first_key(idx).is_none(),
-                   lb_corrections.last_key(idx).is_none());
+    return Ok(finish_leaf_layer(md_container, vec![vec![top_model]], leaf_models,
+                                format!("{},{}", layer1_model, layer2_model),
+                                num_leaf_models, options.fallback.as_ref()));
+}
 
-        if lb_corrections.last_key(idx).is_none() {
-            // model is empty!
-            let upper_bound = lb_corrections.next_index(idx);
-            if !leaf_models[idx].set_to_constant_model(upper_bound as u64) {
-                could_not_replace = true;
-            }
+// Evaluates every layer above the leaves, returning the (unclamped) index
+// of the leaf model responsible for the given input.
+pub(super) fn leaf_index(upper_layers: &[Vec<Box<dyn Model>>], inp: &ModelInput) -> u64 {
+    let mut model_idx = 0;
+    for (layer_idx, layer) in upper_layers.iter().enumerate() {
+        let pred = layer[model_idx].predict_to_int(inp);
+        if layer_idx == upper_layers.len() - 1 {
+            return pred;
         }
-    }
 
-    if could_not_replace {
-        warn!("Some empty models could not be replaced with constants, \
-               negative lookup performance may be poor.");
+        let next_layer_size = upper_layers[layer_idx + 1].len() as u64;
+        model_idx = u64::min(next_layer_size - 1, pred) as usize;
     }
-    
-    
-    trace!("Computing last level errors...");
+
+    // no layers above the leaves, so there must be a single leaf
+    return 0;
+}
+
+// Computes the errors of each leaf for lower bound searches, where
+// `leaf_pred` gives the prediction of a leaf model.
+fn last_level_errors<T: TrainingKey, F>(md_container: &RMITrainingData<T>,
+                                        upper_layers: &[Vec<Box<dyn Model>>],
+                                        lb_corrections: &LowerBoundCorrection<T>,
+                                        leaf_pred: F) -> Vec<LeafError>
+where F: Fn(usize, &ModelInput) -> u64 {
+    let num_leaf_models = lb_corrections.num_leaf_models();
+    let target_range = md_container.target_range();
+
     // evaluate model, compute last level errors
-    let mut last_layer_max_l1s = vec![(0, 0) ; num_leaf_models as usize];
+    let mut errors = vec![LeafError::default(); num_leaf_models as usize];
     for (x, y) in md_container.iter_model_input() {
-        let leaf_idx = top_model.predict_to_int(&x);
+        let leaf_idx = leaf_index(upper_layers, &x);
         let target = u64::min(num_leaf_models - 1, leaf_idx) as usize;
         
-        let pred = leaf_models[target].predict_to_int(&x);
-        let err = error_between(pred, y as u64, md_container.len() as u64);
-
-        let cur_val = last_layer_max_l1s[target];
-        last_layer_max_l1s[target] = (cur_val.0 + 1, u64::max(err, cur_val.1));
+        let pred = leaf_pred(target, &x);
+        errors[target].count += 1;
+        errors[target].include(errors_between(pred, y as u64, target_range as u64));
     }    
 
     // for lower bound searches, we need to make sure that:
@@ -225,35 +270,40 @@ pub fn train_two_layer<T: TrainingKey>(md_container: &mut RMITrainingData<T>,
     //        key in this leaf, but not in the case where this leaf has no keys)
     let mut large_corrections = 0;
     for leaf_idx in 0..num_leaf_models as usize {
-        let curr_err = last_layer_max_l1s[leaf_idx].1;
+        let curr_err = errors[leaf_idx].max();
         let upper_error = {
             let (idx_of_next, key_of_next) = lb_corrections.next(leaf_idx);
-            let pred = leaf_models[leaf_idx].predict_to_int(
-                &key_of_next.minus_epsilon().to_model_input()
-            );
-            error_between(pred, idx_of_next as u64 + 1, md_container.len() as u64)
+            let pred = leaf_pred(leaf_idx, &key_of_next.minus_epsilon().to_model_input());
+            errors_between(pred, idx_of_next as u64 + 1, target_range as u64)
         };
         
         let lower_error = {
             let first_key_before = lb_corrections.prev_key(leaf_idx);
 
-            let prev_idx = if leaf_idx == 0 { 0 } else { leaf_idx - 1 };
-            let first_idx = lb_corrections.next_index(prev_idx);
+            // keys before the first leaf's keys belong at the first position
+            let first_idx = if leaf_idx == 0 {
+                lb_corrections.first_index(0)
+                    .unwrap_or_else(|| lb_corrections.next_index(0))
+            } else {
+                lb_corrections.next_index(leaf_idx - 1)
+            };
 
-            let pred = leaf_models[leaf_idx].predict_to_int(
-                &first_key_before.plus_epsilon().to_model_input()
-            );
-            error_between(pred, first_idx as u64, md_container.len() as u64)
+            let pred = leaf_pred(leaf_idx, &first_key_before.plus_epsilon().to_model_input());
+            errors_between(pred, first_idx as u64, target_range as u64)
         };
           
             
-        let new_err = *(&[curr_err, upper_error, lower_error]).iter().max().unwrap()
-            + lb_corrections.longest_run(leaf_idx);
+        let leaf = &mut errors[leaf_idx];
+        leaf.include(upper_error);
+        leaf.include(lower_error);
 
-        let num_items_in_leaf = last_layer_max_l1s[leaf_idx].0;
-        last_layer_max_l1s[leaf_idx] = (num_items_in_leaf, new_err);
+        // lower bounds of keys between two keys of the leaf
+        let largest_gap = lb_corrections.largest_gap(leaf_idx);
+        leaf.below += largest_gap;
+        leaf.above += largest_gap;
 
-        if new_err - curr_err > 512 && num_items_in_leaf > 100 {
+        let new_err = leaf.max();
+        if new_err - curr_err > 512 && leaf.count > 100 {
             large_corrections += 1;
         }
     }
@@ -262,45 +312,173 @@ pub fn train_two_layer<T: TrainingKey>(md_container: &mut RMITrainingData<T>,
         trace!("Of {} models, {} needed large lower bound corrections.",
               num_leaf_models, large_corrections);
     }
-                        
-    trace!("Evaluating two-layer RMI...");
-    let (m_idx, m_err) = last_layer_max_l1s
+
+    return errors;
+}
+
+// Replaces the leaves whose error is larger than `fallback.max_error`
+// with fallback nodes, as long as a node has a smaller error than the
+// leaf's model. Updates the errors of the replaced leaves.
+fn add_fallback_nodes<T: TrainingKey>(md_container: &RMITrainingData<T>,
+                                      upper_layers: &[Vec<Box<dyn Model>>],
+                                      leaf_models: Vec<Box<dyn Model>>,
+                                      lb_corrections: &LowerBoundCorrection<T>,
+                                      errors: &mut [LeafError],
+                                      fallback: &Fallback) -> Vec<Box<dyn Model>> {
+    let num_leaf_models = leaf_models.len() as u64;
+    let candidates: Vec<usize> = (0..leaf_models.len())
+        .filter(|idx| errors[*idx].max() > fallback.max_error)
+        .collect();
+    if candidates.is_empty() {
+        return leaf_models;
+    }
+
+    if !FallbackLeaf::supports(leaf_models[0].as_ref()) {
+        warn!("{} leaves have an error over {}, but {} models cannot use fallback nodes",
+              candidates.len(), fallback.max_error, leaf_models[0].function_name());
+        return leaf_models;
+    }
+
+    trace!("Building fallback nodes for {} leaves...", candidates.len());
+    // the distinct keys (with their first position) of each candidate leaf
+    let mut leaf_keys: Vec<Option<Vec<(f64, usize)>>> = vec![None; leaf_models.len()];
+    for idx in candidates.iter() {
+        leaf_keys[*idx] = Some(Vec::new());
+    }
+    for (x, y) in md_container.iter_model_input() {
+        let target = u64::min(num_leaf_models - 1, leaf_index(upper_layers, &x)) as usize;
+        if let Some(keys) = leaf_keys[target].as_mut() {
+            let x = x.as_float();
+            if keys.last().map(|(last_x, _y)| *last_x != x).unwrap_or(true) {
+                keys.push((x, y));
+            }
+        }
+    }
+
+    let mut wrapped: Vec<FallbackLeaf> = leaf_models.into_iter()
+        .map(FallbackLeaf::wrap)
+        .collect();
+    for idx in candidates.iter() {
+        let keys = leaf_keys[*idx].take().unwrap();
+        wrapped[*idx].use_node(&keys, lb_corrections.next_index(*idx), fallback.node_size);
+    }
+
+    let node_errors = last_level_errors(md_container, upper_layers, lb_corrections,
+                                        |idx, x| wrapped[idx].predict_to_int(x));
+    let mut num_replaced = 0;
+    for idx in candidates {
+        if node_errors[idx].max() < errors[idx].max() {
+            errors[idx] = node_errors[idx];
+            num_replaced += 1;
+        } else {
+            wrapped[idx].use_model();
+        }
+    }
+
+    info!("Replaced {} of {} leaf models with fallback nodes of {} keys",
+          num_replaced, num_leaf_models, fallback.node_size);
+    if num_replaced == 0 {
+        return wrapped.into_iter().map(|leaf| leaf.into_inner()).collect();
+    }
+    return wrapped.into_iter().map(|leaf| Box::new(leaf) as Box<dyn Model>).collect();
+}
+
+// Corrects the leaf models of an RMI for lower bound searches, computes the
+// last level errors, and assembles the final RMI.
+pub(super) fn finish_leaf_layer<T: TrainingKey>(md_container: &RMITrainingData<T>,
+                                               mut upper_layers: Vec<Vec<Box<dyn Model>>>,
+                                               mut leaf_models: Vec<Box<dyn Model>>,
+                                               models: String,
+                                               branching_factor: u64,
+                                               fallback: Option<&Fallback>) -> TrainedRMI {
+    let num_rows = md_container.len();
+    let target_range = md_container.target_range();
+    let num_leaf_models = leaf_models.len() as u64;
+
+    trace!("Computing lower bound stats...");
+    let lb_corrections = LowerBoundCorrection::new(
+        |x| leaf_index(&upper_layers, &x.to_model_input()), num_leaf_models, md_container
+    );
+
+    trace!("Fixing empty models...");
+    // replace any empty model with a model that returns the correct constant
+    // (for LB predictions), if the underlying model supports it.
+    let mut could_not_replace = false;
+    for idx in 0..num_leaf_models as usize {
+        assert_eq!(lb_corrections.first_key(idx).is_none(),
+                   lb_corrections.last_key(idx).is_none());
+
+        if lb_corrections.last_key(idx).is_none() {
+            // model is empty!
+            let upper_bound = lb_corrections.next_index(idx);
+            if !leaf_models[idx].set_to_constant_model(upper_bound as u64) {
+                could_not_replace = true;
+            }
+        }
+    }
+
+    if could_not_replace {
+        warn!("Some empty models could not be replaced with constants, \
+               negative lookup performance may be poor.");
+    }
+
+    trace!("Computing last level errors...");
+    let mut errors = last_level_errors(
+        md_container, &upper_layers, &lb_corrections,
+        |idx, x| leaf_models[idx].predict_to_int(x)
+    );
+
+    if let Some(fallback) = fallback {
+        leaf_models = add_fallback_nodes(md_container, &upper_layers, leaf_models,
+                                         &lb_corrections, &mut errors,
+                                         fallback);
+    }
+
+    trace!("Evaluating RMI...");
+    let leaf_errors: Vec<(u64, u64)> = errors.iter()
+        .map(|leaf| (leaf.count, leaf.max())).collect();
+    let (m_idx, m_err) = leaf_errors
         .iter().enumerate()
         .max_by_key(|(_idx, &x)| x.1).unwrap();
     
     let model_max_error = m_err.1;
     let model_max_error_idx = m_idx;
 
-    let model_avg_error: f64 = last_layer_max_l1s
+    let model_avg_error: f64 = leaf_errors
         .iter().map(|(n, err)| n * err).sum::<u64>() as f64 / num_rows as f64;
 
-    let model_avg_l2_error: f64 = last_layer_max_l1s
+    let model_avg_l2_error: f64 = leaf_errors
         .iter()
         .map(|(n, err)| ((n*err) as f64).powf(2.0) / num_rows as f64).sum::<f64>();
     
-    let model_avg_log2_error: f64 = last_layer_max_l1s
+    let model_avg_log2_error: f64 = leaf_errors
         .iter().map(|(n, err)| (*n as f64)*((2*err + 2) as f64).log2()).sum::<f64>() / num_rows as f64;
 
     let model_max_log2_error: f64 = (model_max_error as f64).log2();
     
-    let final_errors = last_layer_max_l1s.into_iter()
-        .map(|(_n, err)| err).collect();
-    
+    let final_bounds: Vec<[u64; 2]> = errors.iter()
+        .map(|leaf| [leaf.below, leaf.above]).collect();
+
+    // give every leaf the same parameter layout
+    share_layer_arrays(&mut leaf_models);
+
+    upper_layers.push(leaf_models);
     return TrainedRMI {
-        num_rmi_rows: md_container.len(),
-        num_data_rows: md_container.len(),
+        num_rmi_rows: target_range,
+        num_data_rows: num_rows,
         model_avg_error,
         model_avg_l2_error,
         model_avg_log2_error,
         model_max_error,
         model_max_error_idx,
         model_max_log2_error,
-        last_layer_max_l1s: final_errors,
-        rmi: vec![vec![top_model], leaf_models],
-        models: format!("{},{}", layer1_model, layer2_model),
-        branching_factor: num_leaf_models,
+        last_layer_bounds: final_bounds.into(),
+        rmi: upper_layers,
+        models,
+        branching_factor,
+        key_type: md_container.key_type(),
+        key_prefix: md_container.key_prefix().to_vec(),
         cache_fix: None,
         build_time: 0
     };
-
 }
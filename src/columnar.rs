@@ -0,0 +1,266 @@
+// < begin copyright > 
+// Copyright Ryan Marcus 2020
+// 
+// See root directory of this project for license terms.
+// 
+// < end copyright > 
+ 
+
+// Loads a key column from an Arrow IPC file or a Parquet file. The key type is
+// derived from the physical type of the column (e.g., timestamps are loaded as
+// signed 64-bit integers).
+
+use crate::load::RMIMMap;
+use rmi_lib::{RMITrainingData, RMITrainingDataIteratorProvider, KeyType, TrainingKey};
+use arrow_array::{Array, RecordBatch};
+use arrow_array::cast::AsArray;
+use arrow_array::types::{Int32Type, Int64Type, UInt32Type, UInt64Type};
+use arrow_buffer::{ArrowNativeType, Buffer, ScalarBuffer};
+use arrow_ipc::reader::{read_footer_length, FileDecoder};
+use arrow_ipc::convert::fb_to_schema;
+use arrow_ipc::root_as_footer;
+use arrow_schema::DataType as ArrowType;
+use memmap::MmapOptions;
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use parquet::arrow::ProjectionMask;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::Read;
+use std::ptr::NonNull;
+use std::sync::Arc;
+
+#[derive(Clone, Copy, Debug)]
+pub enum ColumnarFormat {
+    ArrowIPC,
+    Parquet
+}
+
+// Checks the magic bytes at the start of the file.
+pub fn detect_format(filepath: &str) -> Option<ColumnarFormat> {
+    let mut magic = [0u8; 6];
+    let mut fd = File::open(filepath).ok()?;
+    fd.read_exact(&mut magic).ok()?;
+
+    if &magic == b"ARROW1" {
+        return Some(ColumnarFormat::ArrowIPC);
+    }
+
+    if &magic[0..4] == b"PAR1" {
+        return Some(ColumnarFormat::Parquet);
+    }
+
+    return None;
+}
+
+// The keys of a column, stored as the (zero-copy) value buffers of each
+// record batch, and the positions read from a second column, if any.
+struct ArrowColumnAdapter<T: ArrowNativeType> {
+    chunks: Vec<ScalarBuffer<T>>,
+    starts: Vec<usize>,
+    length: usize,
+    positions: Option<Vec<usize>>
+}
+
+impl <T: ArrowNativeType> ArrowColumnAdapter<T> {
+    fn new(columns: &[Arc<dyn Array>],
+           positions: Option<Vec<usize>>) -> Result<ArrowColumnAdapter<T>, String> {
+        let mut chunks = Vec::with_capacity(columns.len());
+        let mut starts = Vec::with_capacity(columns.len());
+        let mut length = 0;
+
+        for col in columns {
+            if col.null_count() > 0 {
+                return Err(format!("Key column contains {} null values", col.null_count()));
+            }
+            
+            let data = col.to_data();
+            starts.push(length);
+            length += data.len();
+            chunks.push(ScalarBuffer::new(data.buffers()[0].clone(), data.offset(), data.len()));
+        }
+
+        return Ok(ArrowColumnAdapter { chunks, starts, length, positions });
+    }
+}
+
+impl <T: ArrowNativeType> ArrowColumnAdapter<T> {
+    fn position(&self, idx: usize) -> usize {
+        return self.positions.as_ref().map(|p| p[idx]).unwrap_or(idx);
+    }
+}
+
+impl <T: ArrowNativeType + TrainingKey> RMITrainingDataIteratorProvider for ArrowColumnAdapter<T> {
+    type InpType = T;
+    fn cdf_iter(&self) -> Box<dyn Iterator<Item = (Self::InpType, usize)> + '_> {
+        Box::new(self.chunks.iter()
+                 .flat_map(|chunk| chunk.iter().copied())
+                 .enumerate()
+                 .map(move |(idx, key)| (key, self.position(idx))))
+    }
+
+    fn get(&self, idx: usize) -> Option<(Self::InpType, usize)> {
+        if idx >= self.length { return None; }
+        let chunk = self.starts.partition_point(|start| *start <= idx) - 1;
+        return Some((self.chunks[chunk][idx - self.starts[chunk]], self.position(idx)));
+    }
+
+    fn key_type(&self) -> KeyType {
+        T::key_type()
+    }
+
+    fn len(&self) -> usize { self.length }
+}
+
+fn to_rmimmap(columns: &[Arc<dyn Array>], data_type: &ArrowType,
+              positions: Option<Vec<usize>>) -> Result<RMIMMap, String> {
+    return Ok(match data_type {
+        ArrowType::UInt32 =>
+            RMIMMap::UINT32(RMITrainingData::new(Box::new(ArrowColumnAdapter::<u32>::new(columns, positions)?))),
+        ArrowType::UInt64 =>
+            RMIMMap::UINT64(RMITrainingData::new(Box::new(ArrowColumnAdapter::<u64>::new(columns, positions)?))),
+        ArrowType::Int32 | ArrowType::Date32 | ArrowType::Time32(_) =>
+            RMIMMap::INT32(RMITrainingData::new(Box::new(ArrowColumnAdapter::<i32>::new(columns, positions)?))),
+        ArrowType::Int64 | ArrowType::Date64 | ArrowType::Time64(_)
+            | ArrowType::Timestamp(_, _) | ArrowType::Duration(_) =>
+            RMIMMap::INT64(RMITrainingData::new(Box::new(ArrowColumnAdapter::<i64>::new(columns, positions)?))),
+        ArrowType::Float32 =>
+            RMIMMap::FLOAT32(RMITrainingData::new(Box::new(ArrowColumnAdapter::<f32>::new(columns, positions)?))),
+        ArrowType::Float64 =>
+            RMIMMap::FLOAT64(RMITrainingData::new(Box::new(ArrowColumnAdapter::<f64>::new(columns, positions)?))),
+        ArrowType::Dictionary(_, _) =>
+            return Err(String::from("Dictionary-encoded columns cannot be used as keys; \
+                                     store the key column with plain encoding")),
+        _ => return Err(format!("Columns of type {} cannot be used as keys", data_type))
+    });
+}
+
+// Reads an integer column as positions. Negative values are rejected.
+fn to_positions(columns: &[Arc<dyn Array>]) -> Result<Vec<usize>, String> {
+    fn checked<V: TryInto<usize> + Copy + std::fmt::Display>(v: V) -> Result<usize, String> {
+        return v.try_into().map_err(|_| format!("{} is not a valid position", v));
+    }
+
+    let mut positions = Vec::new();
+    for col in columns {
+        if col.null_count() > 0 {
+            return Err(format!("Position column contains {} null values", col.null_count()));
+        }
+
+        let values: Result<Vec<usize>, String> = match col.data_type() {
+            ArrowType::UInt32 =>
+                col.as_primitive::<UInt32Type>().values().iter().map(|v| checked(*v)).collect(),
+            ArrowType::UInt64 =>
+                col.as_primitive::<UInt64Type>().values().iter().map(|v| checked(*v)).collect(),
+            ArrowType::Int32 =>
+                col.as_primitive::<Int32Type>().values().iter().map(|v| checked(*v)).collect(),
+            ArrowType::Int64 =>
+                col.as_primitive::<Int64Type>().values().iter().map(|v| checked(*v)).collect(),
+            dt => Err(format!("Columns of type {} cannot be used as positions", dt))
+        };
+        positions.extend(values?);
+    }
+    return Ok(positions);
+}
+
+fn column_of(batch: &RecordBatch, filepath: &str, column: &str) -> Result<Arc<dyn Array>, String> {
+    return batch.column_by_name(column).cloned()
+        .ok_or_else(|| format!("{} has no column named {}", filepath, column));
+}
+
+fn load_arrow_ipc(filepath: &str) -> Result<Vec<RecordBatch>, String> {
+    let fd = File::open(filepath)
+        .map_err(|e| format!("Unable to open data file at {}: {}", filepath, e))?;
+    let mmap = unsafe { MmapOptions::new().map(&fd) }
+        .map_err(|e| format!("Unable to map {}: {}", filepath, e))?;
+
+    // wrap the mapping in an Arrow buffer so that the record batches point
+    // directly into the file instead of copying it
+    let ptr = NonNull::new(mmap.as_ptr() as *mut u8).unwrap();
+    let len = mmap.len();
+    let buffer = unsafe { Buffer::from_custom_allocation(ptr, len, Arc::new(mmap)) };
+
+    if len < 10 {
+        return Err(format!("{} is too short to be an Arrow IPC file", filepath));
+    }
+    let trailer_start = len - 10;
+    let footer_len = read_footer_length(buffer[trailer_start..].try_into().unwrap())
+        .map_err(|e| format!("Unable to read the footer of {}: {}", filepath, e))?;
+    let footer = root_as_footer(&buffer[trailer_start - footer_len..trailer_start])
+        .map_err(|e| format!("Unable to read the footer of {}: {}", filepath, e))?;
+
+    let schema = footer.schema()
+        .ok_or_else(|| format!("The footer of {} has no schema", filepath))?;
+    let mut decoder = FileDecoder::new(Arc::new(fb_to_schema(schema)), footer.version());
+
+    for block in footer.dictionaries().iter().flatten() {
+        let block_len = block.bodyLength() as usize + block.metaDataLength() as usize;
+        let data = buffer.slice_with_length(block.offset() as _, block_len);
+        decoder.read_dictionary(block, &data)
+            .map_err(|e| format!("Unable to read a dictionary of {}: {}", filepath, e))?;
+    }
+
+    // compressed record batches (LZ4 or ZSTD) are reported as errors here
+    let mut batches = Vec::new();
+    for block in footer.recordBatches().iter().flatten() {
+        let block_len = block.bodyLength() as usize + block.metaDataLength() as usize;
+        let data = buffer.slice_with_length(block.offset() as _, block_len);
+        let batch = decoder.read_record_batch(block, &data)
+            .map_err(|e| format!("Unable to read a record batch of {}: {}", filepath, e))?;
+        batches.extend(batch);
+    }
+
+    return Ok(batches);
+}
+
+fn load_parquet(filepath: &str, columns: &[&str]) -> Result<Vec<RecordBatch>, String> {
+    let fd = File::open(filepath)
+        .map_err(|e| format!("Unable to open data file at {}: {}", filepath, e))?;
+    let builder = ParquetRecordBatchReaderBuilder::try_new(fd)
+        .map_err(|e| format!("Unable to read {}: {}", filepath, e))?;
+
+    // only decode the key (and position) columns
+    let col_idxs = columns.iter().map(|column| {
+        builder.schema().index_of(column)
+            .map_err(|_| format!("{} has no column named {}", filepath, column))
+    }).collect::<Result<Vec<usize>, String>>()?;
+    let mask = ProjectionMask::roots(builder.parquet_schema(), col_idxs);
+    let reader = builder.with_projection(mask).build()
+        .map_err(|e| format!("Unable to read {}: {}", filepath, e))?;
+
+    return reader
+        .map(|batch| batch.map_err(|e| format!("Unable to read {}: {}", filepath, e)))
+        .collect();
+}
+
+// Loads the named key column of an Arrow IPC or Parquet file, and optionally
+// the positions of those keys from a second, integer column.
+pub fn load_columnar_data(filepath: &str,
+                          format: ColumnarFormat,
+                          column: &str,
+                          position_column: Option<&str>) -> Result<(usize, RMIMMap), String> {
+    let mut names = vec![column];
+    names.extend(position_column);
+    let batches = match format {
+        ColumnarFormat::ArrowIPC => load_arrow_ipc(filepath)?,
+        ColumnarFormat::Parquet => load_parquet(filepath, &names)?
+    };
+
+    let columns = batches.iter()
+        .map(|batch| column_of(batch, filepath, column))
+        .collect::<Result<Vec<Arc<dyn Array>>, String>>()?;
+    let positions = match position_column {
+        Some(pos_col) => {
+            let pos_columns = batches.iter()
+                .map(|batch| column_of(batch, filepath, pos_col))
+                .collect::<Result<Vec<Arc<dyn Array>>, String>>()?;
+            Some(to_positions(&pos_columns)?)
+        },
+        None => None
+    };
+
+    let data_type = columns.first()
+        .map(|col| col.data_type().clone())
+        .ok_or_else(|| format!("{} contains no record batches", filepath))?;
+    let num_rows = columns.iter().map(|col| col.len()).sum();
+    return Ok((num_rows, to_rmimmap(&columns, &data_type, positions)?));
+}
@@ -8,18 +8,126 @@
  
 use memmap::MmapOptions;
 use rmi_lib::{RMITrainingData, RMITrainingDataIteratorProvider, KeyType};
-use byteorder::{LittleEndian, ReadBytesExt};
+use rmi_lib::{StringKey, StringKeyData, StringKeyDataBuilder, TrainingKey};
+use crate::sort::{sort_strings, SortOptions};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use log::*;
 use std::fs::File;
 use std::convert::TryInto;
+use std::str::FromStr;
+use std::io::{BufRead, BufReader, Read, Write};
 
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum DataType {
     UINT64,
     UINT32,
-    FLOAT64
+    UINT128,
+    INT32,
+    INT64,
+    FLOAT32,
+    FLOAT64,
+    STRINGS,
+    LPSTRINGS
+}
+
+// The contents of an input file: memory mapped if it is uncompressed,
+// otherwise (or if it is read from stdin) held in memory.
+pub enum DataBuffer {
+    Mmap(memmap::Mmap),
+    Vec(Vec<u8>)
+}
+
+impl std::ops::Deref for DataBuffer {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        return match self {
+            DataBuffer::Mmap(mmap) => mmap,
+            DataBuffer::Vec(v) => v
+        };
+    }
+}
+
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+
+fn is_compressed(start: &[u8]) -> bool {
+    return start.starts_with(&ZSTD_MAGIC) || start.starts_with(&GZIP_MAGIC);
+}
+
+// The input path that refers to stdin.
+pub const STDIN_PATH: &str = "-";
+
+// Opens filepath (or stdin, if filepath is "-") for streaming, decompressing
+// it if it is a zstd or gzip file (detected by its magic bytes, not its
+// extension).
+pub fn open_stream(filepath: &str) -> Result<Box<dyn Read>, String> {
+    let inner: Box<dyn Read> = if filepath == STDIN_PATH {
+        Box::new(std::io::stdin())
+    } else {
+        Box::new(File::open(filepath)
+                 .map_err(|e| format!("Unable to open data file at {}: {}", filepath, e))?)
+    };
+    let mut reader = BufReader::new(inner);
+    let start = reader.fill_buf()
+        .map_err(|e| format!("Unable to read {}: {}", filepath, e))?;
+
+    if start.starts_with(&ZSTD_MAGIC) {
+        return Ok(Box::new(zstd::stream::read::Decoder::with_buffer(reader)
+                           .map_err(|e| format!("Unable to decompress {}: {}", filepath, e))?));
+    }
+
+    if start.starts_with(&GZIP_MAGIC) {
+        return Ok(Box::new(flate2::bufread::MultiGzDecoder::new(reader)));
+    }
+
+    return Ok(Box::new(reader));
+}
+
+// Memory maps filepath, or decompresses it into memory if it is compressed.
+// Stdin cannot be mapped, so it is read into memory until the producer
+// closes it.
+pub fn open_data(filepath: &str) -> Result<DataBuffer, String> {
+    if filepath == STDIN_PATH {
+        let mut data = Vec::new();
+        open_stream(filepath)?.read_to_end(&mut data)
+            .map_err(|e| format!("Unable to read stdin: {}", e))?;
+        return Ok(DataBuffer::Vec(data));
+    }
+
+    let fd = File::open(filepath)
+        .map_err(|e| format!("Unable to open data file at {}: {}", filepath, e))?;
+
+    // empty files cannot be mapped
+    if fd.metadata().map(|m| m.len() == 0).unwrap_or(false) {
+        return Ok(DataBuffer::Vec(Vec::new()));
+    }
+
+    let mmap = unsafe { MmapOptions::new().map(&fd) }
+        .map_err(|e| format!("Unable to map {}: {}", filepath, e))?;
+    if !is_compressed(&mmap) {
+        return Ok(DataBuffer::Mmap(mmap));
+    }
+
+    info!("Decompressing {}...", filepath);
+    let mut data = Vec::new();
+    open_stream(filepath)?.read_to_end(&mut data)
+        .map_err(|e| format!("Unable to decompress {}: {}", filepath, e))?;
+    return Ok(DataBuffer::Vec(data));
+}
+
+// Returns the position stored after the key of a (key, position) record, or
+// the record's index if the records only hold keys.
+fn record_position(data: &[u8], base: usize, key_size: usize, stride: usize, idx: usize) -> usize {
+    if stride == key_size { return idx; }
+    let pos = &data[base + key_size..base + key_size + 8];
+    return u64::from_le_bytes(pos.try_into().unwrap()) as usize;
 }
 
 struct SliceAdapterU64 {
-    data: memmap::Mmap,
+    data: DataBuffer,
+    offset: usize,
+    stride: usize,
     length: usize
 }
 
@@ -31,9 +139,10 @@ impl RMITrainingDataIteratorProvider for SliceAdapterU64 {
     
     fn get(&self, idx: usize) -> Option<(Self::InpType, usize)> {
         if idx >= self.length { return None; };
-        let mi = u64::from_le_bytes((&self.data[8 + idx * 8..8 + (idx + 1) * 8])
+        let base = self.offset + idx * self.stride;
+        let mi = u64::from_le_bytes((&self.data[base..base + 8])
                                     .try_into().unwrap());
-        return Some((mi.into(), idx));
+        return Some((mi, record_position(&self.data, base, 8, self.stride, idx)));
     }
     
     fn key_type(&self) -> KeyType {
@@ -45,7 +154,9 @@ impl RMITrainingDataIteratorProvider for SliceAdapterU64 {
 
 
 struct SliceAdapterU32 {
-    data: memmap::Mmap,
+    data: DataBuffer,
+    offset: usize,
+    stride: usize,
     length: usize
 }
 
@@ -57,9 +168,10 @@ impl RMITrainingDataIteratorProvider for SliceAdapterU32 {
     
     fn get(&self, idx: usize) -> Option<(Self::InpType, usize)> {
         if idx >= self.length { return None; };
-        let mi = (&self.data[8 + idx * 4..8 + (idx + 1) * 4])
-            .read_u32::<LittleEndian>().unwrap().into();
-        return Some((mi, idx));
+        let base = self.offset + idx * self.stride;
+        let mi = (&self.data[base..base + 4])
+            .read_u32::<LittleEndian>().unwrap();
+        return Some((mi, record_position(&self.data, base, 4, self.stride, idx)));
     }
     
     fn key_type(&self) -> KeyType {
@@ -69,8 +181,122 @@ impl RMITrainingDataIteratorProvider for SliceAdapterU32 {
     fn len(&self) -> usize { self.length }
 }
 
+struct SliceAdapterU128 {
+    data: DataBuffer,
+    offset: usize,
+    stride: usize,
+    length: usize
+}
+
+impl RMITrainingDataIteratorProvider for SliceAdapterU128 {
+    type InpType = u128;
+    fn cdf_iter(&self) -> Box<dyn Iterator<Item = (Self::InpType, usize)> + '_> {
+        Box::new((0..self.length).map(move |i| self.get(i).unwrap()))
+    }
+    
+    fn get(&self, idx: usize) -> Option<(Self::InpType, usize)> {
+        if idx >= self.length { return None; };
+        let base = self.offset + idx * self.stride;
+        let mi = (&self.data[base..base + 16])
+            .read_u128::<LittleEndian>().unwrap();
+        return Some((mi, record_position(&self.data, base, 16, self.stride, idx)));
+    }
+    
+    fn key_type(&self) -> KeyType {
+        KeyType::U128
+    }
+    
+    fn len(&self) -> usize { self.length }
+}
+
+struct SliceAdapterI32 {
+    data: DataBuffer,
+    offset: usize,
+    stride: usize,
+    length: usize
+}
+
+impl RMITrainingDataIteratorProvider for SliceAdapterI32 {
+    type InpType = i32;
+    fn cdf_iter(&self) -> Box<dyn Iterator<Item = (Self::InpType, usize)> + '_> {
+        Box::new((0..self.length).map(move |i| self.get(i).unwrap()))
+    }
+    
+    fn get(&self, idx: usize) -> Option<(Self::InpType, usize)> {
+        if idx >= self.length { return None; };
+        let base = self.offset + idx * self.stride;
+        let mi = (&self.data[base..base + 4])
+            .read_i32::<LittleEndian>().unwrap();
+        return Some((mi, record_position(&self.data, base, 4, self.stride, idx)));
+    }
+    
+    fn key_type(&self) -> KeyType {
+        KeyType::I32
+    }
+    
+    fn len(&self) -> usize { self.length }
+}
+
+struct SliceAdapterI64 {
+    data: DataBuffer,
+    offset: usize,
+    stride: usize,
+    length: usize
+}
+
+impl RMITrainingDataIteratorProvider for SliceAdapterI64 {
+    type InpType = i64;
+    fn cdf_iter(&self) -> Box<dyn Iterator<Item = (Self::InpType, usize)> + '_> {
+        Box::new((0..self.length).map(move |i| self.get(i).unwrap()))
+    }
+    
+    fn get(&self, idx: usize) -> Option<(Self::InpType, usize)> {
+        if idx >= self.length { return None; };
+        let base = self.offset + idx * self.stride;
+        let mi = (&self.data[base..base + 8])
+            .read_i64::<LittleEndian>().unwrap();
+        return Some((mi, record_position(&self.data, base, 8, self.stride, idx)));
+    }
+    
+    fn key_type(&self) -> KeyType {
+        KeyType::I64
+    }
+    
+    fn len(&self) -> usize { self.length }
+}
+
+struct SliceAdapterF32 {
+    data: DataBuffer,
+    offset: usize,
+    stride: usize,
+    length: usize
+}
+
+impl RMITrainingDataIteratorProvider for SliceAdapterF32 {
+    type InpType = f32;
+    fn cdf_iter(&self) -> Box<dyn Iterator<Item = (Self::InpType, usize)> + '_> {
+        Box::new((0..self.length).map(move |i| self.get(i).unwrap()))
+    }
+    
+    fn get(&self, idx: usize) -> Option<(Self::InpType, usize)> {
+        if idx >= self.length { return None; };
+        let base = self.offset + idx * self.stride;
+        let mi = (&self.data[base..base + 4])
+            .read_f32::<LittleEndian>().unwrap();
+        return Some((mi, record_position(&self.data, base, 4, self.stride, idx)));
+    }
+    
+    fn key_type(&self) -> KeyType {
+        KeyType::F32
+    }
+    
+    fn len(&self) -> usize { self.length }
+}
+
 struct SliceAdapterF64 {
-    data: memmap::Mmap,
+    data: DataBuffer,
+    offset: usize,
+    stride: usize,
     length: usize
 }
 
@@ -82,9 +308,10 @@ impl RMITrainingDataIteratorProvider for SliceAdapterF64 {
     
     fn get(&self, idx: usize) -> Option<(Self::InpType, usize)> {
         if idx >= self.length { return None; };
-        let mi = (&self.data[8 + idx * 8..8 + (idx + 1) * 8])
-            .read_f64::<LittleEndian>().unwrap().into();
-        return Some((mi, idx));
+        let base = self.offset + idx * self.stride;
+        let mi = (&self.data[base..base + 8])
+            .read_f64::<LittleEndian>().unwrap();
+        return Some((mi, record_position(&self.data, base, 8, self.stride, idx)));
     }
     
     fn key_type(&self) -> KeyType {
@@ -94,10 +321,42 @@ impl RMITrainingDataIteratorProvider for SliceAdapterF64 {
     fn len(&self) -> usize { self.length }
 }
 
+// keys parsed from a column of a delimited text file. Unless a position
+// column was given, positions are implicit and only the keys are stored.
+struct TextColumnAdapter<T> {
+    keys: Vec<T>,
+    positions: Option<Vec<usize>>
+}
+
+impl <T: TrainingKey> RMITrainingDataIteratorProvider for TextColumnAdapter<T> {
+    type InpType = T;
+    fn cdf_iter(&self) -> Box<dyn Iterator<Item = (Self::InpType, usize)> + '_> {
+        Box::new((0..self.keys.len()).map(move |i| self.get(i).unwrap()))
+    }
+
+    fn get(&self, idx: usize) -> Option<(Self::InpType, usize)> {
+        let key = *self.keys.as_slice().get(idx)?;
+        let pos = self.positions.as_ref().map(|p| p[idx]).unwrap_or(idx);
+        return Some((key, pos));
+    }
+
+    fn key_type(&self) -> KeyType {
+        T::key_type()
+    }
+
+    fn len(&self) -> usize { self.keys.len() }
+}
+
+#[allow(clippy::upper_case_acronyms)]
 pub enum RMIMMap {
     UINT64(RMITrainingData<u64>),
     UINT32(RMITrainingData<u32>),
-    FLOAT64(RMITrainingData<f64>)
+    UINT128(RMITrainingData<u128>),
+    INT32(RMITrainingData<i32>),
+    INT64(RMITrainingData<i64>),
+    FLOAT32(RMITrainingData<f32>),
+    FLOAT64(RMITrainingData<f64>),
+    STRING(RMITrainingData<StringKey>)
 }
 
 macro_rules! dynamic {
@@ -105,7 +364,12 @@ macro_rules! dynamic {
         match $data {
             load::RMIMMap::UINT64(mut x) => $funcname(&mut x, $($p),*),
             load::RMIMMap::UINT32(mut x) => $funcname(&mut x, $($p),*),
+            load::RMIMMap::UINT128(mut x) => $funcname(&mut x, $($p),*),
+            load::RMIMMap::INT32(mut x) => $funcname(&mut x, $($p),*),
+            load::RMIMMap::INT64(mut x) => $funcname(&mut x, $($p),*),
+            load::RMIMMap::FLOAT32(mut x) => $funcname(&mut x, $($p),*),
             load::RMIMMap::FLOAT64(mut x) => $funcname(&mut x, $($p),*),
+            load::RMIMMap::STRING(mut x) => $funcname(&mut x, $($p),*),
         }
     }
 }
@@ -116,10 +380,28 @@ impl RMIMMap {
         match self {
             RMIMMap::UINT64(x) => RMIMMap::UINT64(x.soft_copy()),
             RMIMMap::UINT32(x) => RMIMMap::UINT32(x.soft_copy()),
+            RMIMMap::UINT128(x) => RMIMMap::UINT128(x.soft_copy()),
+            RMIMMap::INT32(x) => RMIMMap::INT32(x.soft_copy()),
+            RMIMMap::INT64(x) => RMIMMap::INT64(x.soft_copy()),
+            RMIMMap::FLOAT32(x) => RMIMMap::FLOAT32(x.soft_copy()),
             RMIMMap::FLOAT64(x) => RMIMMap::FLOAT64(x.soft_copy()),
+            RMIMMap::STRING(x) => RMIMMap::STRING(x.soft_copy()),
         }
     }
 
+    pub fn key_type(&self) -> KeyType {
+        return match self {
+            // 32-bit unsigned keys are looked up as 64-bit keys
+            RMIMMap::UINT64(_) | RMIMMap::UINT32(_) => KeyType::U64,
+            RMIMMap::UINT128(_) => KeyType::U128,
+            RMIMMap::INT32(_) => KeyType::I32,
+            RMIMMap::INT64(_) => KeyType::I64,
+            RMIMMap::FLOAT32(_) => KeyType::F32,
+            RMIMMap::FLOAT64(_) => KeyType::F64,
+            RMIMMap::STRING(_) => KeyType::Str,
+        };
+    }
+
     pub fn into_u64(self) -> Option<RMITrainingData<u64>> {
         match self {
             RMIMMap::UINT64(x) => Some(x),
@@ -129,29 +411,488 @@ impl RMIMMap {
 }
                 
 
-pub fn load_data(filepath: &str,
-                 dt: DataType) -> (usize, RMIMMap) {
-    let fd = File::open(filepath).unwrap_or_else(|_| {
-        panic!("Unable to open data file at {}", filepath)
+// Input files may begin with a self-describing header:
+//
+//   magic            8 bytes, "RMIKEYS\0"
+//   key type         u8, see DataType::tag
+//   flags            u8, bit 0 set if the keys are sorted, bit 1 set if
+//                    each key is followed by its position (u64)
+//   reserved         6 zero bytes
+//   number of keys   u64
+//   keys             starting at byte 24, in the same layout as the
+//                    corresponding headerless file
+//
+// All integers are little endian. Files without the header are SOSD-style
+// (a u64 count followed by the keys) and their key type is taken from the
+// file name. STRINGS files have no count, and cannot store positions.
+const HEADER_MAGIC: &[u8; 8] = b"RMIKEYS\0";
+const HEADER_LEN: usize = 24;
+const HEADER_FLAG_SORTED: u8 = 1;
+const HEADER_FLAG_POSITIONS: u8 = 2;
+
+// names accepted by --key-type, in the order they are matched against file
+// names (uint64 before int64, lpstrings before strings)
+const DATA_TYPE_NAMES: [(&str, DataType); 9] = [
+    ("uint64", DataType::UINT64),
+    ("uint128", DataType::UINT128),
+    ("uint32", DataType::UINT32),
+    ("int64", DataType::INT64),
+    ("int32", DataType::INT32),
+    ("float32", DataType::FLOAT32),
+    ("f64", DataType::FLOAT64),
+    ("lpstrings", DataType::LPSTRINGS),
+    ("strings", DataType::STRINGS),
+];
+
+impl DataType {
+    pub fn from_name(name: &str) -> Option<DataType> {
+        return DATA_TYPE_NAMES.iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, dt)| *dt);
+    }
+
+    pub fn names() -> Vec<&'static str> {
+        return DATA_TYPE_NAMES.iter().map(|(n, _)| *n).collect();
+    }
+
+    // only the file name is checked, so directories like /data/f64s/ do not
+    // change the detected type
+    fn from_file_name(filepath: &str) -> Option<DataType> {
+        let file_name = std::path::Path::new(filepath)
+            .file_name()
+            .and_then(|f| f.to_str())
+            .unwrap_or(filepath);
+        return DATA_TYPE_NAMES.iter()
+            .find(|(n, _)| file_name.contains(n))
+            .map(|(_, dt)| *dt);
+    }
+
+    fn tag(self) -> u8 {
+        return match self {
+            DataType::UINT32 => 0,
+            DataType::UINT64 => 1,
+            DataType::FLOAT64 => 2,
+            DataType::UINT128 => 3,
+            DataType::INT32 => 4,
+            DataType::INT64 => 5,
+            DataType::LPSTRINGS => 6,
+            DataType::FLOAT32 => 7,
+            DataType::STRINGS => 8,
+        };
+    }
+
+    fn from_tag(tag: u8) -> Option<DataType> {
+        return DATA_TYPE_NAMES.iter()
+            .map(|(_, dt)| *dt)
+            .find(|dt| dt.tag() == tag);
+    }
+
+    // size of each key in bytes, for fixed-width types
+    fn key_size(self) -> Option<usize> {
+        return match self {
+            DataType::UINT32 | DataType::INT32 | DataType::FLOAT32 => Some(4),
+            DataType::UINT64 | DataType::INT64 | DataType::FLOAT64 => Some(8),
+            DataType::UINT128 => Some(16),
+            DataType::STRINGS | DataType::LPSTRINGS => None
+        };
+    }
+}
+
+pub fn write_header<W: Write>(out: &mut W, dt: DataType, num_items: u64) -> std::io::Result<()> {
+    out.write_all(HEADER_MAGIC)?;
+    out.write_u8(dt.tag())?;
+    out.write_u8(HEADER_FLAG_SORTED)?;
+    out.write_all(&[0; 6])?;
+    out.write_u64::<LittleEndian>(num_items)?;
+    return Ok(());
+}
+
+struct InputHeader {
+    data_type: Option<DataType>,
+    sorted: bool,
+    positions: bool,
+    num_items: usize
+}
+
+fn read_header(data: &[u8]) -> Option<InputHeader> {
+    if data.len() < HEADER_LEN || &data[0..8] != HEADER_MAGIC {
+        return None;
+    }
+
+    let num_items = (&data[16..24]).read_u64::<LittleEndian>().unwrap() as usize;
+    return Some(InputHeader {
+        data_type: DataType::from_tag(data[8]),
+        sorted: data[9] & HEADER_FLAG_SORTED != 0,
+        positions: data[9] & HEADER_FLAG_POSITIONS != 0,
+        num_items
     });
+}
 
-    let mmap = unsafe { MmapOptions::new().map(&fd).unwrap() };
-    let num_items = (&mmap[0..8]).read_u64::<LittleEndian>().unwrap() as usize;
+// Iterates over the lines of a STRINGS file, without their line endings
+// (\n or \r\n). A read error ends the iteration and is stored in err.
+fn read_lines<'a, R: BufRead + 'a>(mut reader: R,
+                                  err: &'a mut Option<String>) -> impl Iterator<Item = Vec<u8>> + 'a {
+    return std::iter::from_fn(move || {
+        let mut line = Vec::new();
+        match reader.read_until(b'\n', &mut line) {
+            Ok(0) => return None,
+            Ok(_) => {},
+            Err(e) => {
+                *err = Some(e.to_string());
+                return None;
+            }
+        }
+        if line.last() == Some(&b'\n') {
+            line.pop();
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+        }
+        return Some(line);
+    });
+}
+
+// Splits the payload of an LPSTRINGS file into the individual keys. Each key
+// is stored as a u64 length followed by its bytes, and then its position if
+// positions is set.
+type SplitStrings<'a> = (Vec<&'a [u8]>, Option<Vec<usize>>);
+
+fn split_strings(data: &[u8], num_items: Option<usize>,
+                 has_positions: bool) -> Result<SplitStrings<'_>, String> {
+    // without a count, read records until the input runs out
+    let truncated = || String::from("A string key extends past the end of the file");
+    let mut inp = data;
+    let mut keys = Vec::with_capacity(num_items.unwrap_or(0));
+    let mut positions = if has_positions { Some(Vec::with_capacity(keys.capacity())) } else { None };
+    while num_items.map(|n| keys.len() < n).unwrap_or(!inp.is_empty()) {
+        let len = inp.read_u64::<LittleEndian>().map_err(|_| truncated())? as usize;
+        if len > inp.len() {
+            return Err(truncated());
+        }
+        keys.push(&inp[..len]);
+        inp = &inp[len..];
+
+        if let Some(positions) = positions.as_mut() {
+            let pos = inp.read_u64::<LittleEndian>().map_err(|_| truncated())?;
+            positions.push(pos as usize);
+        }
+    }
+    return Ok((keys, positions));
+}
+
+// Loads a STRINGS file from its stream, one line at a time, so that only the
+// encoded keys are kept in memory.
+fn load_lines<R: BufRead>(reader: R, filepath: &str, num_items: Option<usize>,
+                          sort: Option<&SortOptions>) -> Result<(usize, RMIMMap), String> {
+    let mut err = None;
+    let (num_keys, builder) = match sort {
+        Some(opts) => (sort_strings(read_lines(reader, &mut err), opts)?, None),
+        None => {
+            let mut builder = StringKeyDataBuilder::new();
+            let mut num_keys = 0;
+            for line in read_lines(reader, &mut err) {
+                builder.push(&line, num_keys);
+                num_keys += 1;
+            }
+            (num_keys, Some(builder))
+        }
+    };
+
+    if let Some(e) = err {
+        return Err(format!("Unable to read {}: {}", filepath, e));
+    }
+    if let Some(n) = num_items {
+        if n != num_keys {
+            return Err(format!("The header declares {} keys, but there are {} lines", n, num_keys));
+        }
+    }
+
+    return match (builder, sort) {
+        (Some(builder), _) =>
+            Ok((num_keys, RMIMMap::STRING(RMITrainingData::new(Box::new(builder.finish()))))),
+        (None, Some(opts)) => load_data(&opts.sidecar, None, None),
+        (None, None) => unreachable!()
+    };
+}
+
+// Loads the keys in filepath. The key type is given by key_type if set,
+// otherwise by the file's header, otherwise by the file name. String keys
+// are sorted here if requested; the caller sorts other keys with
+// sort::sort_data.
+pub fn load_data(filepath: &str,
+                 key_type: Option<DataType>,
+                 sort: Option<&SortOptions>) -> Result<(usize, RMIMMap), String> {
+    // read just enough to find the key type, since files with one string
+    // key per line are streamed instead of mapped
+    let mut stream = open_stream(filepath)?;
+    let mut start = Vec::with_capacity(HEADER_LEN);
+    (&mut stream).take(HEADER_LEN as u64).read_to_end(&mut start)
+        .map_err(|e| format!("Unable to read {}: {}", filepath, e))?;
+
+    let (dt, offset, num_items, has_positions) = match read_header(&start) {
+        Some(header) => {
+            if !header.sorted && sort.is_none() {
+                return Err(format!("{} is marked as unsorted, but an RMI can only be built over \
+                                    sorted keys; use --sort", filepath));
+            }
+            if header.positions && sort.is_some() {
+                return Err(format!("{} stores explicit positions, which would not match the \
+                                    sorted keys; sort it (and its positions) before training",
+                                   filepath));
+            }
+            let dt = key_type.or(header.data_type).ok_or_else(|| {
+                format!("{} has an unknown key type tag {}; use --key-type", filepath, start[8])
+            })?;
+            (dt, HEADER_LEN, Some(header.num_items), header.positions)
+        },
+        None => {
+            let dt = key_type.or_else(|| DataType::from_file_name(filepath)).ok_or_else(|| {
+                if filepath == STDIN_PATH {
+                    return String::from("Cannot determine the key type of stdin. Start the input \
+                                         with a header, or pass --key-type");
+                }
+                return format!("Cannot determine the key type of {}. Add a header, pass --key-type, \
+                                or name the file with one of: {}",
+                               filepath, DataType::names().join(", "));
+            })?;
+            if let DataType::STRINGS = dt {
+                (dt, 0, None, false)
+            } else if filepath == STDIN_PATH {
+                // a producer writing to a pipe may not know how many keys
+                // it will write, so keys on stdin are not preceded by a count
+                // (it is computed from the input's length below)
+                (dt, 0, None, false)
+            } else {
+                if start.len() < 8 {
+                    return Err(format!("{} is too short to contain a key count", filepath));
+                }
+                let num_items = (&start[0..8]).read_u64::<LittleEndian>().unwrap() as usize;
+                (dt, 8, Some(num_items), false)
+            }
+        }
+    };
+
+    if let DataType::STRINGS = dt {
+        if has_positions {
+            return Err(String::from("Files with one key per line cannot store positions"));
+        }
+        let rest = std::io::Cursor::new(start.split_off(offset));
+        return load_lines(BufReader::new(rest.chain(stream)), filepath, num_items, sort);
+    }
+
+    // stdin cannot be reopened, so the rest of it is appended to the bytes
+    // already read
+    let mmap = if filepath == STDIN_PATH {
+        stream.read_to_end(&mut start)
+            .map_err(|e| format!("Unable to read stdin: {}", e))?;
+        DataBuffer::Vec(start)
+    } else {
+        drop(stream);
+        open_data(filepath)?
+    };
+
+    let num_items = match (num_items, dt.key_size()) {
+        (Some(n), _) => Some(n),
+        (None, Some(size)) if !mmap.len().is_multiple_of(size) =>
+            return Err(format!("stdin ended in the middle of a key ({} bytes of {}-byte keys)",
+                               mmap.len(), size)),
+        (None, Some(size)) => Some(mmap.len() / size),
+        (None, None) => None
+    };
+
+    // size of each (key, position) record, for fixed-width keys
+    let stride = dt.key_size().map(|size| if has_positions { size + 8 } else { size });
+    if let Some(stride) = stride {
+        let num_items = num_items.unwrap();
+        let required = num_items.checked_mul(stride).and_then(|n| n.checked_add(offset));
+        if required.map(|n| mmap.len() < n).unwrap_or(true) {
+            return Err(format!("{} declares {} keys, but is only {} bytes long",
+                               filepath, num_items, mmap.len()));
+        }
+    }
+
+    if let DataType::LPSTRINGS = dt {
+        let (keys, positions) = split_strings(&mmap[offset..], num_items, has_positions)?;
+        if let Some(opts) = sort {
+            sort_strings(keys.iter().map(|k| k.to_vec()), opts)?;
+            return load_data(&opts.sidecar, None, None);
+        }
+        let skd = match positions {
+            Some(positions) => StringKeyData::with_positions(&keys, &positions),
+            None => StringKeyData::new(&keys)
+        };
+        return Ok((keys.len(), RMIMMap::STRING(RMITrainingData::new(Box::new(skd)))));
+    }
+
+    let num_items = num_items.unwrap();
+    let stride = stride.unwrap();
 
     let rtd = match dt {
         DataType::UINT64 =>
             RMIMMap::UINT64(RMITrainingData::new(Box::new(
-                SliceAdapterU64 { data: mmap, length: num_items }
+                SliceAdapterU64 { data: mmap, offset, stride, length: num_items }
             ))),
         DataType::UINT32 =>
             RMIMMap::UINT32(RMITrainingData::new(Box::new(
-                SliceAdapterU32 { data: mmap, length: num_items }
+                SliceAdapterU32 { data: mmap, offset, stride, length: num_items }
+            ))),
+        DataType::UINT128 =>
+            RMIMMap::UINT128(RMITrainingData::new(Box::new(
+                SliceAdapterU128 { data: mmap, offset, stride, length: num_items }
+            ))),
+        DataType::INT32 =>
+            RMIMMap::INT32(RMITrainingData::new(Box::new(
+                SliceAdapterI32 { data: mmap, offset, stride, length: num_items }
+            ))),
+        DataType::INT64 =>
+            RMIMMap::INT64(RMITrainingData::new(Box::new(
+                SliceAdapterI64 { data: mmap, offset, stride, length: num_items }
+            ))),
+        DataType::FLOAT32 =>
+            RMIMMap::FLOAT32(RMITrainingData::new(Box::new(
+                SliceAdapterF32 { data: mmap, offset, stride, length: num_items }
             ))),
         DataType::FLOAT64 =>
             RMIMMap::FLOAT64(RMITrainingData::new(Box::new(
-                SliceAdapterF64 { data: mmap, length: num_items }
-            )))
+                SliceAdapterF64 { data: mmap, offset, stride, length: num_items }
+            ))),
+        DataType::STRINGS | DataType::LPSTRINGS => unreachable!()
+    };
+
+    return Ok((num_items, rtd));
+}
+
+// Selects the column holding the keys of a delimited text file, and
+// optionally a column holding their positions, either by name in the header
+// row or by zero-based index.
+pub struct TextColumn {
+    pub column: String,
+    pub position_column: Option<String>,
+    pub delimiter: u8,
+    pub has_header: bool
+}
+
+fn column_index<R: Read>(reader: &mut csv::Reader<R>, filepath: &str,
+                         column: &str, has_header: bool) -> Result<usize, String> {
+    if let Ok(idx) = column.parse::<usize>() {
+        return Ok(idx);
+    }
+
+    if !has_header {
+        return Err(format!("Column {} is not an index, but {} has no header row", column, filepath));
+    }
+    let headers = reader.byte_headers()
+        .map_err(|e| format!("Unable to read the header row of {}: {}", filepath, e))?;
+    return headers.iter()
+        .position(|h| h == column.as_bytes())
+        .ok_or_else(|| format!("{} has no column named {}", filepath, column));
+}
+
+// Reads the selected column of a delimited text file record by record,
+// passing each field to on_field along with its position (if there is a
+// position column) and its (1-based) line number. Stops at the first
+// error, including any returned by on_field.
+fn read_text_column<F>(filepath: &str, opts: &TextColumn, mut on_field: F) -> Result<(), String>
+where F: FnMut(&[u8], Option<usize>, u64) -> Result<(), String> {
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(opts.delimiter)
+        .has_headers(opts.has_header)
+        .from_reader(open_stream(filepath)?);
+
+    let col_idx = column_index(&mut reader, filepath, &opts.column, opts.has_header)?;
+    let pos_idx = match opts.position_column.as_ref() {
+        Some(col) => Some(column_index(&mut reader, filepath, col, opts.has_header)?),
+        None => None
     };
 
-    return (num_items, rtd);
+    let mut record = csv::ByteRecord::new();
+    loop {
+        match reader.read_byte_record(&mut record) {
+            Ok(true) => {},
+            Ok(false) => break,
+            Err(e) => return Err(format!("Unable to parse {}: {}", filepath, e))
+        }
+
+        let line = record.position().map(|p| p.line()).unwrap_or(0);
+        let field = record.get(col_idx).ok_or_else(|| {
+            format!("Line {} of {} has no column {}", line, filepath, opts.column)
+        })?;
+        let pos = match pos_idx {
+            Some(pos_idx) => Some(record.get(pos_idx)
+                .and_then(|f| std::str::from_utf8(f).ok())
+                .and_then(|f| f.trim().parse::<usize>().ok())
+                .ok_or_else(|| format!("Line {} of {} has no valid position", line, filepath))?),
+            None => None
+        };
+        on_field(field, pos, line)?;
+    }
+    return Ok(());
+}
+
+fn parse_text_column<T: TrainingKey + FromStr>(filepath: &str,
+                                               opts: &TextColumn) -> Result<RMITrainingData<T>, String> {
+    let mut keys = Vec::new();
+    let mut positions = Vec::new();
+    read_text_column(filepath, opts, |field, pos, line| {
+        let key = std::str::from_utf8(field).ok()
+            .and_then(|f| f.trim().parse::<T>().ok())
+            .ok_or_else(|| {
+                format!("Could not parse {:?} on line {} of {} as a key",
+                        String::from_utf8_lossy(field), line, filepath)
+            })?;
+        keys.push(key);
+        positions.extend(pos);
+        return Ok(());
+    })?;
+
+    let positions = if opts.position_column.is_some() { Some(positions) } else { None };
+    return Ok(RMITrainingData::new(Box::new(TextColumnAdapter { keys, positions })));
+}
+
+// Loads the keys in one column of a delimited text (e.g., CSV or TSV) file.
+// The keys are parsed as key_type, or as unsigned 64-bit integers if no type
+// is given. As with load_data, only string keys are sorted here.
+pub fn load_text_data(filepath: &str,
+                      opts: &TextColumn,
+                      key_type: Option<DataType>,
+                      sort: Option<&SortOptions>) -> Result<(usize, RMIMMap), String> {
+    let rtd = match key_type.unwrap_or(DataType::UINT64) {
+        DataType::UINT64 => RMIMMap::UINT64(parse_text_column(filepath, opts)?),
+        DataType::UINT32 => RMIMMap::UINT32(parse_text_column(filepath, opts)?),
+        DataType::UINT128 => RMIMMap::UINT128(parse_text_column(filepath, opts)?),
+        DataType::INT32 => RMIMMap::INT32(parse_text_column(filepath, opts)?),
+        DataType::INT64 => RMIMMap::INT64(parse_text_column(filepath, opts)?),
+        DataType::FLOAT32 => RMIMMap::FLOAT32(parse_text_column(filepath, opts)?),
+        DataType::FLOAT64 => RMIMMap::FLOAT64(parse_text_column(filepath, opts)?),
+        DataType::STRINGS | DataType::LPSTRINGS => {
+            let mut keys: Vec<Vec<u8>> = Vec::new();
+            let mut positions = Vec::new();
+            read_text_column(filepath, opts, |field, pos, _line| {
+                keys.push(field.to_vec());
+                positions.extend(pos);
+                return Ok(());
+            })?;
+            if let Some(sort_opts) = sort {
+                sort_strings(keys.into_iter(), sort_opts)?;
+                return load_data(&sort_opts.sidecar, None, None);
+            }
+            let skd = match opts.position_column {
+                Some(_) => StringKeyData::with_positions(&keys, &positions),
+                None => StringKeyData::new(&keys)
+            };
+            RMIMMap::STRING(RMITrainingData::new(Box::new(skd)))
+        }
+    };
+
+    let num_rows = match &rtd {
+        RMIMMap::UINT64(x) => x.len(),
+        RMIMMap::UINT32(x) => x.len(),
+        RMIMMap::UINT128(x) => x.len(),
+        RMIMMap::INT32(x) => x.len(),
+        RMIMMap::INT64(x) => x.len(),
+        RMIMMap::FLOAT32(x) => x.len(),
+        RMIMMap::FLOAT64(x) => x.len(),
+        RMIMMap::STRING(x) => x.len(),
+    };
+    return Ok((num_rows, rtd));
 }
@@ -11,13 +11,14 @@
 
 #[macro_use]
 mod load;
+mod columnar;
+mod sort;
 
-use load::{load_data, DataType};
-use rmi_lib::{train, train_bounded};
-use rmi_lib::KeyType;
+use load::{load_data, load_text_data, DataType, TextColumn, STDIN_PATH};
+use rmi_lib::{train_with_options, train_bounded, Fallback, LeafAllocation, Sampling, TrainingOptions};
 use rmi_lib::optimizer;
 
-use json::*;
+use json::{object, JsonValue};
 use log::*;
 use std::f64;
 use std::fs::File;
@@ -29,6 +30,16 @@ use rayon::prelude::*;
 use indicatif::{ProgressBar, ProgressStyle};
 use clap::{App, Arg};
 
+// Reports an input, training, or code generation error and exits.
+fn or_exit<T, E: std::fmt::Display>(res: Result<T, E>) -> T {
+    return match res {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+}
 
 fn main() {
     env_logger::init();
@@ -38,7 +49,7 @@ fn main() {
         .author("Ryan Marcus <ryan@ryanmarc.us>")
         .about("Learns recursive model indexes")
         .arg(Arg::with_name("input")
-             .help("Path to input file containing data")
+             .help("Path to input file containing data, or - to read the keys from stdin")
              .index(1).required(true))
         .arg(Arg::with_name("namespace")
              .help("Namespace to use in generated code")
@@ -76,6 +87,9 @@ fn main() {
         .arg(Arg::with_name("no-errors")
              .long("no-errors")
              .help("do not save last-level errors, and modify the RMI function signature"))
+        .arg(Arg::with_name("rust")
+             .long("rust")
+             .help("generate Rust source code instead of C/C++"))
         .arg(Arg::with_name("threads")
              .long("threads")
              .short("t")
@@ -85,6 +99,39 @@ fn main() {
              .long("bounded")
              .value_name("line_size")
              .help("construct an error-bounded RMI using the cachefix method for the given line size"))
+        .arg(Arg::with_name("sample-rate")
+             .long("sample-rate")
+             .value_name("rate")
+             .help("fit the models above the leaves on this fraction (between 0 and 1) of the keys; errors are still computed over every key"))
+        .arg(Arg::with_name("sample-strategy")
+             .long("sample-strategy")
+             .value_name("strategy")
+             .possible_values(&["uniform", "stratified"])
+             .requires("sample-rate")
+             .help("how to draw the sample: each key independently (uniform) or one key from each run of 1/rate keys (stratified), default = stratified"))
+        .arg(Arg::with_name("sample-leaves")
+             .long("sample-leaves")
+             .requires("sample-rate")
+             .help("fit the leaf models on the sample as well"))
+        .arg(Arg::with_name("fallback-error")
+             .long("fallback-error")
+             .value_name("error")
+             .help("replace leaf models whose maximum error exceeds this with a sorted sample of their keys, searched at lookup time"))
+        .arg(Arg::with_name("fallback-node-size")
+             .long("fallback-node-size")
+             .value_name("keys")
+             .requires("fallback-error")
+             .help("number of keys to store in each fallback node, default = 16"))
+        .arg(Arg::with_name("leaf-allocation")
+             .long("leaf-allocation")
+             .value_name("strategy")
+             .possible_values(&["equidepth", "merge"])
+             .help("remap the top model's output so each leaf gets about the same number of keys (equidepth), or so that more leaves cover the parts of the data that are hard to fit (merge); two-layer RMIs only"))
+        .arg(Arg::with_name("leaf-oversample")
+             .long("leaf-oversample")
+             .value_name("factor")
+             .requires("leaf-allocation")
+             .help("number of top model outputs per leaf model that the remapping chooses from, default = 4"))
         .arg(Arg::with_name("max-size")
              .long("max-size")
              .value_name("BYTES")
@@ -95,10 +142,48 @@ fn main() {
         .arg(Arg::with_name("zero-build-time")
              .long("zero-build-time")
              .help("zero out the model build time field"))
+        .arg(Arg::with_name("key-type")
+             .long("key-type")
+             .value_name("type")
+             .help("type of the keys in the input file (uint32, uint64, uint128, int32, int64, float32, f64, strings, or lpstrings), overriding the file header and file name"))
+        .arg(Arg::with_name("column")
+             .long("column")
+             .value_name("name")
+             .help("read the keys from this column (a name from the header row, or a zero-based index) of a CSV/TSV file"))
+        .arg(Arg::with_name("position-column")
+             .long("position-column")
+             .value_name("name")
+             .requires("column")
+             .conflicts_with("sort")
+             .help("read the position of each key from this column instead of numbering the keys 0, 1, 2, ..."))
+        .arg(Arg::with_name("delimiter")
+             .long("delimiter")
+             .value_name("char")
+             .help("field delimiter used with --column, default = tab for .tsv files and comma otherwise"))
+        .arg(Arg::with_name("no-header")
+             .long("no-header")
+             .help("the file read with --column has no header row"))
+        .arg(Arg::with_name("sort")
+             .long("sort")
+             .help("sort the input keys, writing them to a sidecar file that the RMI's positions refer to"))
+        .arg(Arg::with_name("sorted-output")
+             .long("sorted-output")
+             .value_name("file")
+             .requires("sort")
+             .help("where --sort writes the sorted keys, default = the input file name + .sorted"))
+        .arg(Arg::with_name("dedup")
+             .long("dedup")
+             .requires("sort")
+             .help("with --sort, remove duplicate keys"))
         .arg(Arg::with_name("optimize")
              .long("optimize")
              .value_name("file")
              .help("Search for Pareto efficient RMI configurations. Specify the name of the output file."))
+        .arg(Arg::with_name("optimize-sample-size")
+             .long("optimize-sample-size")
+             .value_name("keys")
+             .requires("optimize")
+             .help("evaluate candidate configurations on a sample of about this many keys, then retrain the final configurations on all keys"))
         .get_matches();
 
     // set the max number of threads to 4 by default, otherwise Rayon goes
@@ -119,26 +204,118 @@ fn main() {
     
     info!("Reading {}...", fp);
 
-    let mut key_type = KeyType::U64;
-    let (num_rows, data) = if fp.contains("uint64") {
-        load_data(&fp, DataType::UINT64)
-    } else if fp.contains("uint32") {
-        load_data(&fp, DataType::UINT32)
-    } else if fp.contains("f64") {
-        key_type = KeyType::F64;
-        load_data(&fp, DataType::FLOAT64)
+    let key_type_override = matches.value_of("key-type").map(|name| {
+        or_exit(DataType::from_name(name).ok_or_else(|| {
+            format!("Unknown key type {}, must be one of: {}",
+                    name, DataType::names().join(", "))
+        }))
+    });
+    let sort_opts = if matches.is_present("sort") {
+        Some(sort::SortOptions {
+            sidecar: matches.value_of("sorted-output")
+                .map(String::from)
+                .unwrap_or_else(|| {
+                    if fp == STDIN_PATH {
+                        or_exit(Err("Reading from stdin, so --sort needs a --sorted-output path"))
+                    }
+                    format!("{}.sorted", fp)
+                }),
+            dedup: matches.is_present("dedup")
+        })
     } else {
-        panic!("Data file must contain uint64, uint32, or f64.");
+        None
+    };
+
+    let (num_rows, data) = match (columnar::detect_format(fp), matches.value_of("column")) {
+        (Some(format), column) => {
+            let column = or_exit(column.ok_or_else(|| {
+                format!("{} is an Arrow IPC or Parquet file; select the key column with --column", fp)
+            }));
+            if key_type_override.is_some() {
+                or_exit(Err("--key-type cannot be used with Arrow IPC or Parquet input, \
+                             whose key type is the type of the key column"))
+            }
+            or_exit(columnar::load_columnar_data(fp, format, column,
+                                                 matches.value_of("position-column")))
+        },
+        (None, Some(column)) => {
+            let delimiter = match matches.value_of("delimiter") {
+                Some("\\t") => b'\t',
+                Some(d) if d.len() == 1 => d.as_bytes()[0],
+                Some(d) => or_exit(Err(format!("Delimiter must be a single character, got {}", d))),
+                None if fp.ends_with(".tsv") => b'\t',
+                None => b','
+            };
+            let opts = TextColumn {
+                column: column.to_string(),
+                position_column: matches.value_of("position-column").map(String::from),
+                delimiter,
+                has_header: !matches.is_present("no-header")
+            };
+            or_exit(load_text_data(fp, &opts, key_type_override, sort_opts.as_ref()))
+        },
+        (None, None) => or_exit(load_data(fp, key_type_override, sort_opts.as_ref()))
+    };
+
+    let (num_rows, data) = match &sort_opts {
+        Some(opts) => or_exit(sort::sort_data(data, opts)),
+        None => (num_rows, data)
+    };
+
+    let sampling = matches.value_of("sample-rate").map(|rate| {
+        let rate = or_exit(rate.parse::<f64>()
+                           .map_err(|_| format!("Sample rate must be a number, got {}", rate)));
+        match matches.value_of("sample-strategy") {
+            Some("uniform") => Sampling::Uniform { rate },
+            _ => Sampling::Stratified { rate }
+        }
+    });
+    let fallback = matches.value_of("fallback-error").map(|max_error| {
+        let max_error = or_exit(max_error.parse::<u64>().map_err(
+            |_| format!("Fallback error must be a positive integer, got {}", max_error)));
+        let node_size = matches.value_of("fallback-node-size")
+            .map(|size| or_exit(size.parse::<usize>().ok().filter(|&size| size > 0).ok_or_else(
+                || format!("Fallback node size must be a positive integer, got {}", size))))
+            .unwrap_or(16);
+        Fallback { max_error, node_size }
+    });
+    let leaf_allocation = matches.value_of("leaf-allocation").map(|strategy| {
+        let oversample = matches.value_of("leaf-oversample")
+            .map(|factor| or_exit(factor.parse::<u64>().map_err(
+                |_| format!("Leaf oversample must be a positive integer, got {}", factor))))
+            .unwrap_or(4);
+        match strategy {
+            "merge" => LeafAllocation::Merge { oversample },
+            _ => LeafAllocation::EquiDepth { oversample }
+        }
+    });
+    let train_opts = TrainingOptions {
+        sampling,
+        sample_leaves: matches.is_present("sample-leaves"),
+        fallback,
+        leaf_allocation,
+        ..Default::default()
     };
+    let key_type = data.key_type();
 
     if matches.is_present("optimize") {
-        let results = dynamic!(optimizer::find_pareto_efficient_configs,
-                               data, 10);
+        let results = match matches.value_of("optimize-sample-size") {
+            Some(size) => {
+                let sample_size = or_exit(size.parse::<usize>().map_err(
+                    |_| format!("Sample size must be a positive integer, got {}", size)));
+                or_exit(dynamic!(optimizer::find_pareto_efficient_configs_sampled,
+                                 data, 10, sample_size))
+            },
+            None => or_exit(dynamic!(optimizer::find_pareto_efficient_configs,
+                                     data, 10))
+        };
 
         optimizer::RMIStatistics::display_table(&results);
 
         let nmspc_prefix = if matches.value_of("namespace").is_some() {
             matches.value_of("namespace").unwrap()
+        } else if fp == STDIN_PATH {
+            "rmi"
         } else {
             let path = Path::new(fp);
             path.file_name().map(|s| s.to_str()).unwrap_or(Some("rmi")).unwrap()
@@ -180,10 +357,7 @@ fn main() {
             for el in v {
                 let layers = String::from(el["layers"].as_str().unwrap());
                 let branching = el["branching factor"].as_u64().unwrap();
-                let namespace = match el["namespace"].as_str() {
-                    Some(s) => Some(String::from(s)),
-                    None => None
-                };
+                let namespace = el["namespace"].as_str().map(String::from);
 
                 to_test.push((layers, branching, namespace));
             }
@@ -200,7 +374,8 @@ fn main() {
                            models, *branch_factor);
                     
                     let loc_data = data.soft_copy();
-                    let mut trained_model = dynamic!(train, loc_data, models, *branch_factor);
+                    let mut trained_model = or_exit(dynamic!(train_with_options, loc_data,
+                                                             models, *branch_factor, &train_opts));
                     
                     let size_bs = rmi_lib::rmi_size(&trained_model);
                     
@@ -225,12 +400,12 @@ fn main() {
                     }
                     
                     if let Some(nmspc) = namespace {
-                        rmi_lib::output_rmi(
-                            &nmspc,
+                        or_exit(rmi_lib::output_rmi(
+                            nmspc,
                             trained_model,
                             data_dir,
                             key_type,
-                            true).unwrap();
+                            true));
                         
                     }
                     
@@ -273,13 +448,14 @@ fn main() {
                     .unwrap();
         
                 let trained_model = match matches.value_of("bounded") {
-                    None => dynamic!(train, data, models, branch_factor),
+                    None => or_exit(dynamic!(train_with_options, data, models, branch_factor,
+                                             &train_opts)),
                     Some(s) => {
                         let line_size = s.parse::<usize>()
                             .expect("Line size must be a positive integer.");
                         let d_u64 = data.into_u64()
                             .expect("Can only construct a bounded RMI on u64 data.");
-                        train_bounded(&d_u64, models, branch_factor, line_size)
+                        or_exit(train_bounded(&d_u64, models, branch_factor, line_size))
                     }
                 };
                 trained_model
@@ -288,8 +464,7 @@ fn main() {
                 let max_size = max_size_str.parse::<usize>().unwrap();
                 info!("Constructing RMI with size less than {}", max_size);
 
-                let trained_model = dynamic!(rmi_lib::train_for_size, data, max_size);
-                trained_model
+                or_exit(dynamic!(rmi_lib::train_for_size, data, max_size))
             }
         };
         
@@ -325,12 +500,21 @@ fn main() {
                 trained_model.build_time = 0;
             }
 
-            rmi_lib::output_rmi(
-                &namespace,
-                trained_model,
-                data_dir,
-                key_type,
-                !no_errors).unwrap();
+            if matches.is_present("rust") {
+                or_exit(rmi_lib::output_rmi_rust(
+                    &namespace,
+                    trained_model,
+                    data_dir,
+                    key_type,
+                    !no_errors));
+            } else {
+                or_exit(rmi_lib::output_rmi(
+                    &namespace,
+                    trained_model,
+                    data_dir,
+                    key_type,
+                    !no_errors));
+            }
         } else {
             trace!("Skipping code generation due to CLI flag");
         }
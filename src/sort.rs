@@ -0,0 +1,255 @@
+// < begin copyright > 
+// Copyright Ryan Marcus 2020
+// 
+// See root directory of this project for license terms.
+// 
+// < end copyright > 
+ 
+
+// Sorts input keys that are not already sorted. The sorted keys are written
+// to a sidecar file in the self-describing input format (see load.rs), and
+// the RMI is trained on that file, so the positions it predicts are
+// positions in the sidecar file. Inputs with more than RUN_SIZE keys are
+// sorted in runs that are spilled to disk next to the sidecar file and then
+// merged.
+
+use crate::load::{load_data, write_header, DataType, RMIMMap};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use log::*;
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+
+const RUN_SIZE: usize = 1 << 24;
+
+pub struct SortOptions {
+    // where to write the sorted keys
+    pub sidecar: String,
+
+    // drop duplicate keys
+    pub dedup: bool
+}
+
+trait SortKey: Sized {
+    fn write_key<W: Write>(&self, out: &mut W) -> io::Result<()>;
+    fn read_key<R: Read>(inp: &mut R) -> io::Result<Self>;
+    fn cmp_key(&self, other: &Self) -> Ordering;
+}
+
+macro_rules! int_sort_key {
+    ($t: ty, $write: ident, $read: ident) => {
+        impl SortKey for $t {
+            fn write_key<W: Write>(&self, out: &mut W) -> io::Result<()> {
+                return out.$write::<LittleEndian>(*self);
+            }
+
+            fn read_key<R: Read>(inp: &mut R) -> io::Result<Self> {
+                return inp.$read::<LittleEndian>();
+            }
+
+            fn cmp_key(&self, other: &Self) -> Ordering { self.cmp(other) }
+        }
+    }
+}
+
+// NaN keys are rejected before sorting, so the partial order is total
+macro_rules! float_sort_key {
+    ($t: ty, $write: ident, $read: ident) => {
+        impl SortKey for $t {
+            fn write_key<W: Write>(&self, out: &mut W) -> io::Result<()> {
+                return out.$write::<LittleEndian>(*self);
+            }
+
+            fn read_key<R: Read>(inp: &mut R) -> io::Result<Self> {
+                return inp.$read::<LittleEndian>();
+            }
+
+            fn cmp_key(&self, other: &Self) -> Ordering { self.partial_cmp(other).unwrap() }
+        }
+    }
+}
+
+int_sort_key!(u32, write_u32, read_u32);
+int_sort_key!(u64, write_u64, read_u64);
+int_sort_key!(u128, write_u128, read_u128);
+int_sort_key!(i32, write_i32, read_i32);
+int_sort_key!(i64, write_i64, read_i64);
+float_sort_key!(f32, write_f32, read_f32);
+float_sort_key!(f64, write_f64, read_f64);
+
+// strings are stored as in LPSTRINGS files: a u64 length, then the bytes
+impl SortKey for Vec<u8> {
+    fn write_key<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        out.write_u64::<LittleEndian>(self.len() as u64)?;
+        return out.write_all(self);
+    }
+
+    fn read_key<R: Read>(inp: &mut R) -> io::Result<Self> {
+        let len = inp.read_u64::<LittleEndian>()? as usize;
+        let mut key = vec![0; len];
+        inp.read_exact(&mut key)?;
+        return Ok(key);
+    }
+
+    fn cmp_key(&self, other: &Self) -> Ordering { self.cmp(other) }
+}
+
+// a key in the merge heap, along with the run it came from
+struct RunHead<K>(K, usize);
+
+impl <K: SortKey> PartialEq for RunHead<K> {
+    fn eq(&self, other: &Self) -> bool { self.cmp(other) == Ordering::Equal }
+}
+
+impl <K: SortKey> Eq for RunHead<K> { }
+
+impl <K: SortKey> PartialOrd for RunHead<K> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> { Some(self.cmp(other)) }
+}
+
+impl <K: SortKey> Ord for RunHead<K> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        return self.0.cmp_key(&other.0).then(self.1.cmp(&other.1));
+    }
+}
+
+fn spill_run<K: SortKey>(run: &mut Vec<K>, path: &str) -> io::Result<()> {
+    run.sort_by(|a, b| a.cmp_key(b));
+    let mut out = BufWriter::new(File::create(path)?);
+    for key in run.drain(..) {
+        key.write_key(&mut out)?;
+    }
+    return out.flush();
+}
+
+// Writes the sorted keys to the sidecar file, returning how many were
+// written.
+fn write_sorted<K: SortKey>(keys: impl Iterator<Item = io::Result<K>>,
+                            dt: DataType, opts: &SortOptions) -> io::Result<usize> {
+    let mut out = BufWriter::new(File::create(&opts.sidecar)?);
+
+    // the count is filled in once all keys are written
+    write_header(&mut out, dt, 0)?;
+
+    let mut count = 0;
+    let mut last: Option<K> = None;
+    for key in keys {
+        let key = key?;
+        if opts.dedup {
+            if let Some(l) = &last {
+                if l.cmp_key(&key) == Ordering::Equal { continue; }
+            }
+        }
+
+        key.write_key(&mut out)?;
+        count += 1;
+        last = Some(key);
+    }
+
+    let mut fd = out.into_inner().map_err(|e| e.into_error())?;
+    fd.seek(SeekFrom::Start(0))?;
+    write_header(&mut fd, dt, count as u64)?;
+    return Ok(count);
+}
+
+fn external_sort<K: SortKey>(keys: impl Iterator<Item = K>,
+                             dt: DataType, opts: &SortOptions) -> io::Result<usize> {
+    let mut run = Vec::new();
+    let mut run_paths: Vec<String> = Vec::new();
+    for key in keys {
+        run.push(key);
+        if run.len() == RUN_SIZE {
+            let path = format!("{}.run{}", opts.sidecar, run_paths.len());
+            spill_run(&mut run, &path)?;
+            run_paths.push(path);
+        }
+    }
+
+    if run_paths.is_empty() {
+        run.sort_by(|a, b| a.cmp_key(b));
+        return write_sorted(run.into_iter().map(Ok), dt, opts);
+    }
+
+    if !run.is_empty() {
+        let path = format!("{}.run{}", opts.sidecar, run_paths.len());
+        spill_run(&mut run, &path)?;
+        run_paths.push(path);
+    }
+
+    info!("Merging {} sorted runs...", run_paths.len());
+    let mut readers = Vec::with_capacity(run_paths.len());
+    let mut heap = BinaryHeap::with_capacity(run_paths.len());
+    for (idx, path) in run_paths.iter().enumerate() {
+        let mut reader = BufReader::new(File::open(path)?);
+        heap.push(Reverse(RunHead(K::read_key(&mut reader)?, idx)));
+        readers.push(reader);
+    }
+
+    // every run holds at least one key, so a read past the end of a run
+    // means that run is exhausted
+    let merged = std::iter::from_fn(|| {
+        let Reverse(RunHead(key, idx)) = heap.pop()?;
+        match K::read_key(&mut readers[idx]) {
+            Ok(next) => heap.push(Reverse(RunHead(next, idx))),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {},
+            Err(e) => return Some(Err(e))
+        }
+        return Some(Ok(key));
+    });
+
+    let count = write_sorted(merged, dt, opts);
+    for path in &run_paths {
+        std::fs::remove_file(path)?;
+    }
+    return count;
+}
+
+fn check_no_nan(keys: impl Iterator<Item = f64>) -> Result<(), String> {
+    if let Some(idx) = keys.into_iter().position(|k| k.is_nan()) {
+        return Err(format!("The key at position {} is NaN, which cannot be sorted", idx));
+    }
+    return Ok(());
+}
+
+// Sorts string keys, returning the number of keys written to the sidecar
+// file.
+pub fn sort_strings(keys: impl Iterator<Item = Vec<u8>>, opts: &SortOptions) -> Result<usize, String> {
+    return external_sort(keys, DataType::LPSTRINGS, opts)
+        .map_err(|e| format!("Unable to write sorted keys to {}: {}", opts.sidecar, e));
+}
+
+// Sorts the keys of data and loads the sorted keys back from the sidecar
+// file.
+pub fn sort_data(data: RMIMMap, opts: &SortOptions) -> Result<(usize, RMIMMap), String> {
+    macro_rules! sort_keys {
+        ($x: expr, $dt: expr) => {
+            external_sort((0..$x.len()).map(|idx| $x.get_key(idx)), $dt, opts)
+        }
+    }
+
+    let res = match data {
+        RMIMMap::UINT64(x) => sort_keys!(x, DataType::UINT64),
+        RMIMMap::UINT32(x) => sort_keys!(x, DataType::UINT32),
+        RMIMMap::UINT128(x) => sort_keys!(x, DataType::UINT128),
+        RMIMMap::INT32(x) => sort_keys!(x, DataType::INT32),
+        RMIMMap::INT64(x) => sort_keys!(x, DataType::INT64),
+        RMIMMap::FLOAT32(x) => {
+            check_no_nan((0..x.len()).map(|idx| x.get_key(idx) as f64))?;
+            sort_keys!(x, DataType::FLOAT32)
+        },
+        RMIMMap::FLOAT64(x) => {
+            check_no_nan((0..x.len()).map(|idx| x.get_key(idx)))?;
+            sort_keys!(x, DataType::FLOAT64)
+        },
+        // string keys were already sorted by the loader with sort_strings,
+        // since only the encoded keys are kept afterwards
+        RMIMMap::STRING(x) => return Ok((x.len(), RMIMMap::STRING(x)))
+    };
+
+    let num_keys = res.map_err(|e| {
+        format!("Unable to write sorted keys to {}: {}", opts.sidecar, e)
+    })?;
+    info!("Wrote {} sorted keys to {}", num_keys, opts.sidecar);
+    return load_data(&opts.sidecar, None, None);
+}